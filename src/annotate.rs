@@ -0,0 +1,80 @@
+use crate::settings::Annotation;
+
+use chrono::{DateTime, Local};
+use std::fmt::Write;
+
+/// Keeps track of when the annotation text needs to be sent to the camera again.
+///
+/// The firmware only draws whatever text we last gave it, so for the time to tick
+/// we have to re-send it every second.
+pub struct Annotator {
+    annotation: Annotation,
+    name: String,
+    last: Option<i64>,
+}
+
+impl Annotator {
+    pub fn new(annotation: Annotation, name: &str) -> Annotator {
+        Annotator {
+            annotation: annotation,
+            name: name.to_string(),
+            last: None,
+        }
+    }
+
+    pub fn annotation(&self) -> &Annotation {
+        &self.annotation
+    }
+
+    /// Returns the new text if the second has ticked over since the last call
+    pub fn text(&mut self, now: DateTime<Local>) -> Option<String> {
+        let second = now.timestamp();
+        if self.last == Some(second) {
+            return None;
+        }
+        self.last = Some(second);
+        Some(render(&self.annotation.text, &self.name, now))
+    }
+}
+
+/// Expands `{name}` and strftime-style specifiers.
+/// A bad specifier leaves the text as it was rather than panicking.
+pub fn render(text: &str, name: &str, now: DateTime<Local>) -> String {
+    // a % in the name is part of the name, not a specifier
+    let format = text.replace("{name}", &name.replace('%', "%%"));
+    let mut out = String::new();
+    match write!(out, "{}", now.format(&format)) {
+        Ok(()) => out,
+        Err(_) => text.replace("{name}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn renders_name_and_time() {
+        let now = Local.with_ymd_and_hms(2021, 8, 30, 15, 30, 40).unwrap();
+        assert_eq!(render(&Annotation::default().text, "porch", now), "porch 2021-08-30 15:30:40");
+        assert_eq!(render("{name} %H:%M", "100%Y", now), "100%Y 15:30");
+        assert_eq!(render("%%{name}%%", "porch", now), "%porch%");
+        // bad specifiers
+        assert_eq!(render("{name} %Q", "porch", now), "porch %Q");
+        assert_eq!(render("{name} %", "50%", now), "50% %");
+    }
+
+    #[test]
+    fn text_once_a_second() {
+        let annotation = Annotation {
+            text: "{name} %S".to_string(),
+            ..Annotation::default()
+        };
+        let mut annotator = Annotator::new(annotation, "porch");
+        let now = Local.with_ymd_and_hms(2021, 8, 30, 15, 30, 40).unwrap();
+        assert_eq!(annotator.text(now), Some("porch 40".to_string()));
+        assert_eq!(annotator.text(now + Duration::milliseconds(500)), None);
+        assert_eq!(annotator.text(now + Duration::seconds(1)), Some("porch 41".to_string()));
+    }
+}
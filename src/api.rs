@@ -0,0 +1,296 @@
+/*
+JSON API for home automation, served by the HTTP server under /api.
+
+GET    /api/cameras                      status of every camera
+GET    /api/cameras/<name>/settings      camera and motion settings, armed or not
+PUT    /api/cameras/<name>/settings      change camera settings, only the fields given
+PUT    /api/cameras/<name>/motion        change motion settings, only the fields given
+POST   /api/cameras/<name>/arm
+POST   /api/cameras/<name>/disarm
+POST   /api/cameras/<name>/record        {"seconds": 30}, up to an hour
+POST   /api/cameras/<name>/still         takes a still, returns where it went
+GET    /api/events?camera=&from=&to=     from and to are RFC 3339, also &label=
+                                         for events the classifier saw that in
+GET    /api/events/<event>
+GET    /api/events/<event>/<file>        download one of the event's files
+DELETE /api/events/<event>               delete the event and its files, a 409
+                                         until the hash chain has logged it
+
+Changes are handed to the camera thread, which applies them between frames.
+On a camera with a day/night schedule, ISO, shutter speed, exposure mode, AWB
+and grayscale set here only last until the schedule next switches profile, see
+schedule.rs.
+*/
+use crate::config::patch;
+use crate::http::{header, query_param};
+use crate::index::{EventIndex, Query};
+use crate::live::{Command, Live};
+use crate::motion::MotionSettings;
+use crate::retention::{Outcome, Retention};
+use crate::settings::CameraSettings;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Read;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use tiny_http::{Method, Request, Response};
+
+type JsonResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+/// `path` is what comes after "/api/"
+pub fn handle(mut request: Request, cameras: &[Arc<Live>], retention: &Retention, path: &str, query: &str) {
+    let method = request.method().clone();
+    let parts: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+
+    let mut body = String::new();
+    let has_body = method == Method::Put || method == Method::Post;
+    if has_body && request.as_reader().take(64 * 1024).read_to_string(&mut body).is_err() {
+        let _ = request.respond(error(400, "Unreadable request body"));
+        return;
+    }
+
+    let response = match (&method, parts.as_slice()) {
+        (Method::Get, ["cameras"]) => ok(&cameras.iter().map(|c| camera_json(c)).collect::<Vec<Value>>()),
+        (_, ["cameras", name, action]) => match cameras.iter().find(|c| c.name == *name) {
+            Some(camera) => camera_action(&method, camera, action, &body),
+            None => error(404, "No such camera"),
+        },
+        (_, ["events", ..]) => match retention.index() {
+            Some(index) => {
+                // downloads stream a file instead of returning JSON
+                if let (Method::Get, ["events", name, file]) = (&method, parts.as_slice()) {
+                    download(request, index, name, file);
+                    return;
+                }
+                events(&method, retention, index, &parts[1..], query)
+            }
+            None => error(404, "Event index is turned off"),
+        },
+        _ => error(404, "Not found"),
+    };
+    let _ = request.respond(response);
+}
+
+fn camera_action(method: &Method, camera: &Live, action: &str, body: &str) -> JsonResponse {
+    let status = camera.status();
+    match (method, action) {
+        (Method::Get, "settings") => ok(&json!({
+            "settings": status.settings,
+            "motion": status.motion,
+            "armed": status.armed,
+        })),
+        (Method::Put, "settings") => {
+            let current = match status.settings {
+                Some(settings) => settings,
+                None => return error(503, "Camera isn't running"),
+            };
+            let settings: CameraSettings = match changes(body).and_then(|changes| patch(&current, changes)) {
+                Ok(settings) => settings,
+                Err(e) => return error(400, &e),
+            };
+            if settings.camera_num != current.camera_num
+                || settings.name != current.name
+                || settings.encoding != current.encoding
+                || settings.width != current.width
+                || settings.height != current.height
+                || settings.framerate != current.framerate
+                || settings.bitrate != current.bitrate
+                || settings.still_width != current.still_width
+                || settings.still_height != current.still_height
+            {
+                return error(400, "camera_num, name, encoding, width, height, framerate, bitrate, still_width and still_height can't change while running");
+            }
+            send(camera, Command::Settings(settings))
+        }
+        (Method::Put, "motion") => {
+            let current = match status.motion {
+                Some(motion) => motion,
+                None => return error(503, "Camera isn't running"),
+            };
+            let motion: MotionSettings = match changes(body).and_then(|changes| patch(&current, changes)) {
+                Ok(motion) => motion,
+                Err(e) => return error(400, &e),
+            };
+            if motion.width != current.width || motion.height != current.height {
+                return error(400, "Motion frame size can't change while running");
+            }
+            send(camera, Command::Motion(motion))
+        }
+        (Method::Post, "arm") => send(camera, Command::Arm(true)),
+        (Method::Post, "disarm") => send(camera, Command::Arm(false)),
+        (Method::Post, "record") => match record_duration(body) {
+            Ok(duration) => send(camera, Command::Record(duration)),
+            Err(e) => error(400, &e),
+        },
+        (Method::Post, "still") => {
+            let (reply, result) = mpsc::channel();
+            if !camera.send(Command::Still(reply)) {
+                return error(503, "Camera isn't running");
+            }
+            match result.recv_timeout(Duration::from_secs(30)) {
+                Ok(Ok(path)) => ok(&json!({ "path": path })),
+                Ok(Err(e)) => error(500, &e),
+                Err(_) => error(504, "Camera didn't take the still in time"),
+            }
+        }
+        _ => error(404, "Not found"),
+    }
+}
+
+// An hour is plenty for one go, and keeps the end time a sane Instant
+const MAX_RECORD_SECONDS: u64 = 3600;
+
+/// {"seconds": n} from a record request, 30 if there's no body
+fn record_duration(body: &str) -> Result<Duration, String> {
+    if body.trim().is_empty() {
+        return Ok(Duration::from_secs(30));
+    }
+    let value: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let seconds = match value.get("seconds") {
+        Some(seconds) => seconds.as_u64().ok_or("seconds has to be a whole number")?,
+        None => 30,
+    };
+    if seconds == 0 || seconds > MAX_RECORD_SECONDS {
+        return Err(format!("seconds has to be from 1 to {}", MAX_RECORD_SECONDS));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+fn events(method: &Method, retention: &Retention, index: &EventIndex, parts: &[&str], query: &str) -> JsonResponse {
+    match (method, parts) {
+        (Method::Get, []) => {
+            let time = |name: &str| -> Result<Option<DateTime<Local>>, String> {
+                match query_param(query, name) {
+                    Some(value) => DateTime::parse_from_rfc3339(&value)
+                        .map(|t| Some(t.with_timezone(&Local)))
+                        .map_err(|_| format!("{} isn't an RFC 3339 time", name)),
+                    None => Ok(None),
+                }
+            };
+            let query = match (time("from"), time("to")) {
+                (Ok(from), Ok(to)) => Query {
+                    camera: query_param(query, "camera"),
+                    from: from,
+                    to: to,
+                    label: query_param(query, "label"),
+                },
+                (Err(e), _) | (_, Err(e)) => return error(400, &e),
+            };
+            match index.query(&query) {
+                Ok(entries) => ok(&entries),
+                Err(e) => error(500, &e.to_string()),
+            }
+        }
+        (Method::Get, [name]) => match index.get(name) {
+            Ok(Some(entry)) => ok(&entry),
+            Ok(None) => error(404, "No such event"),
+            Err(e) => error(500, &e.to_string()),
+        },
+        (Method::Delete, [name]) => match retention.delete(name, &[]) {
+            Ok(Outcome::Deleted(Some(entry))) => ok(&entry),
+            Ok(Outcome::Deleted(None)) | Ok(Outcome::NotFound) => error(404, "No such event"),
+            Ok(Outcome::NotLogged) => error(409, "The hash chain hasn't logged the event yet, try again shortly"),
+            Err(e) => error(500, &e),
+        },
+        _ => error(404, "Not found"),
+    }
+}
+
+/// Only files the index lists for the event can be downloaded
+fn download(request: Request, index: &EventIndex, name: &str, file_name: &str) {
+    let entry = match index.get(name) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return respond(request, error(404, "No such event")),
+        Err(e) => return respond(request, error(500, &e.to_string())),
+    };
+    let path = match entry.files.iter().find(|f| f.path.file_name() == Some(std::ffi::OsStr::new(file_name))) {
+        Some(file) => file.path.clone(),
+        None => return respond(request, error(404, "No such file")),
+    };
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => return respond(request, error(500, &e.to_string())),
+    };
+    let content_type = match path.extension().and_then(|e| e.to_str()) {
+        Some("jpg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("json") => "application/json",
+        Some("dng") => "image/x-adobe-dng",
+        Some("h264") => "video/h264",
+        Some("mp4") => "video/mp4",
+        _ => "application/octet-stream",
+    };
+    let _ = request.respond(Response::from_file(file).with_header(header("Content-Type", content_type)));
+}
+
+fn respond(request: Request, response: JsonResponse) {
+    let _ = request.respond(response);
+}
+
+fn send(camera: &Live, command: Command) -> JsonResponse {
+    if camera.send(command) {
+        Response::from_string(json!({ "ok": true }).to_string())
+            .with_status_code(202)
+            .with_header(header("Content-Type", "application/json"))
+    } else {
+        error(503, "Camera isn't running")
+    }
+}
+
+fn camera_json(camera: &Live) -> Value {
+    let status = camera.status();
+    json!({
+        "name": camera.name,
+        "armed": status.armed,
+        "last_frame": status.last_frame,
+        "event": status.event,
+        "tamper": status.tamper,
+    })
+}
+
+/// The JSON object of fields to change in a PUT
+fn changes(body: &str) -> Result<Value, String> {
+    serde_json::from_str(body).map_err(|e| e.to_string())
+}
+
+fn ok<T: Serialize + ?Sized>(body: &T) -> JsonResponse {
+    let body = serde_json::to_string(body).unwrap_or_else(|_| "null".to_string());
+    Response::from_string(body).with_header(header("Content-Type", "application/json"))
+}
+
+fn error(status: u16, message: &str) -> JsonResponse {
+    Response::from_string(json!({ "error": message }).to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_seconds() {
+        assert_eq!(record_duration(""), Ok(Duration::from_secs(30)));
+        assert_eq!(record_duration("{}"), Ok(Duration::from_secs(30)));
+        assert_eq!(record_duration(r#"{"seconds": 90}"#), Ok(Duration::from_secs(90)));
+        assert!(record_duration(r#"{"seconds": 0}"#).is_err());
+        assert!(record_duration(r#"{"seconds": -5}"#).is_err());
+        assert!(record_duration(r#"{"seconds": 18446744073709551615}"#).is_err());
+        assert!(record_duration("seconds=5").is_err());
+    }
+
+    #[test]
+    fn patch_only_given_fields() {
+        let current = MotionSettings::default();
+        let patched: MotionSettings = patch(&current, changes(r#"{"min_score": 0.5}"#).unwrap()).unwrap();
+        assert_eq!(patched.min_score, 0.5);
+        assert_eq!(patched.width, current.width);
+        assert_eq!(patched.quiet_frames, current.quiet_frames);
+        assert!(patch(&current, changes(r#"{"width": "wide"}"#).unwrap()).is_err());
+        assert!(changes("width=wide").is_err());
+    }
+}
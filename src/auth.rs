@@ -0,0 +1,213 @@
+/*
+Accounts and API tokens for the HTTP and RTSP servers.
+
+Users log in with HTTP Basic auth (browsers prompt for it, NVRs take it in the
+URL), scripts send "Authorization: Bearer <token>". Only hashes are kept:
+Argon2 for passwords, SHA-256 for tokens, which are long and random enough
+that a fast hash is fine.
+
+auth.json looks like:
+
+{
+  "users": [{ "name": "alan", "password": "$argon2id$v=19$...", "role": "admin" }],
+  "tokens": [{ "name": "home-assistant", "hash": "9f86d0...", "role": "view" }],
+  "anonymous": null
+}
+
+Make the hashes with "hash-password" and "new-token", see main.rs.
+
+Without auth.json the HTTP and RTSP servers only listen on localhost. To let
+anyone on the network in without logging in, say so with an auth.json of
+{ "anonymous": "view" } (or "admin").
+*/
+use crate::hex;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Admin can do everything view can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Streams, snapshots, status and the event list
+    View,
+    /// Changing settings, arming, recording and deleting events
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub name: String,
+    /// Argon2 hash in PHC format
+    pub password: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiToken {
+    /// What the token is for, just for the logs
+    pub name: String,
+    /// SHA-256 of the token, in hex
+    pub hash: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    pub users: Vec<User>,
+    pub tokens: Vec<ApiToken>,
+    /// What people get without logging in, nothing by default
+    pub anonymous: Option<Role>,
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// No credentials, or wrong ones. Ask for them.
+    Unauthorized,
+    /// Logged in, but not allowed to do that
+    Forbidden,
+}
+
+pub struct Auth {
+    settings: AuthSettings,
+    /// Argon2 is slow on purpose, too slow to run for every HLS segment and
+    /// snapshot, so Authorization headers that checked out are remembered by hash
+    verified: Mutex<HashMap<[u8; 32], Role>>,
+}
+
+impl Auth {
+    pub fn new(settings: AuthSettings) -> Auth {
+        Auth {
+            settings: settings,
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the Authorization header (if any) allows something needing `needed`
+    pub fn check(&self, authorization: Option<&str>, needed: Role) -> Result<Role, Denied> {
+        let role = match authorization {
+            Some(authorization) => self.role(authorization),
+            None => self.settings.anonymous,
+        };
+        match role {
+            Some(role) if role >= needed => Ok(role),
+            // logged in without enough access
+            Some(_) if authorization.is_some() => Err(Denied::Forbidden),
+            // anonymous access isn't enough, let them log in
+            _ => Err(Denied::Unauthorized),
+        }
+    }
+
+    fn role(&self, authorization: &str) -> Option<Role> {
+        let key: [u8; 32] = Sha256::digest(authorization.as_bytes()).into();
+        if let Some(role) = self.verified.lock().unwrap().get(&key) {
+            return Some(*role);
+        }
+
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        let role = if scheme.eq_ignore_ascii_case("Bearer") {
+            let hash = hash_token(credentials.trim());
+            self.settings.tokens.iter().find(|t| t.hash.eq_ignore_ascii_case(&hash)).map(|t| t.role)?
+        } else if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = BASE64.decode(credentials.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (name, password) = decoded.split_once(':')?;
+            let user = self.settings.users.iter().find(|u| u.name == name)?;
+            let hash = PasswordHash::new(&user.password).ok()?;
+            Argon2::default().verify_password(password.as_bytes(), &hash).ok()?;
+            user.role
+        } else {
+            return None;
+        };
+
+        let mut verified = self.verified.lock().unwrap();
+        // only ever a handful of clients, so a full cache means something odd; start over
+        if verified.len() >= 64 {
+            verified.clear();
+        }
+        verified.insert(key, role);
+        Some(role)
+    }
+}
+
+/// Argon2 hash for the "password" field of a user
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// A new random token, and the hash that goes in auth.json
+pub fn new_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(&bytes);
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(&Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(name: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", name, password)))
+    }
+
+    #[test]
+    fn users_tokens_and_anonymous() {
+        let (token, hash) = new_token();
+        let auth = Auth::new(AuthSettings {
+            users: vec![User {
+                name: "alan".to_string(),
+                password: hash_password("hunter2").unwrap(),
+                role: Role::Admin,
+            }],
+            tokens: vec![ApiToken {
+                name: "home-assistant".to_string(),
+                hash: hash,
+                role: Role::View,
+            }],
+            anonymous: None,
+        });
+
+        assert_eq!(auth.check(Some(&basic("alan", "hunter2")), Role::Admin), Ok(Role::Admin));
+        // remembered the second time round
+        assert_eq!(auth.check(Some(&basic("alan", "hunter2")), Role::View), Ok(Role::Admin));
+        assert_eq!(auth.check(Some(&basic("alan", "wrong")), Role::View), Err(Denied::Unauthorized));
+        assert_eq!(auth.check(Some(&basic("bob", "hunter2")), Role::View), Err(Denied::Unauthorized));
+
+        let bearer = format!("Bearer {}", token);
+        assert_eq!(auth.check(Some(&bearer), Role::View), Ok(Role::View));
+        assert_eq!(auth.check(Some(&bearer), Role::Admin), Err(Denied::Forbidden));
+        assert_eq!(auth.check(Some("Bearer nope"), Role::View), Err(Denied::Unauthorized));
+
+        assert_eq!(auth.check(None, Role::View), Err(Denied::Unauthorized));
+    }
+
+    #[test]
+    fn anonymous_viewers() {
+        let auth = Auth::new(AuthSettings {
+            anonymous: Some(Role::View),
+            ..Default::default()
+        });
+        assert_eq!(auth.check(None, Role::View), Ok(Role::View));
+        assert_eq!(auth.check(None, Role::Admin), Err(Denied::Unauthorized));
+    }
+}
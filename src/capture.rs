@@ -0,0 +1,135 @@
+use crate::event;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How timelapse stills are named
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    /// camera0_000001.jpg, camera0_000002.jpg ...
+    Sequential,
+    /// camera0_20210830-153012-250.jpg
+    DateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimelapseSettings {
+    /// Seconds between stills
+    pub interval: u64,
+    pub naming: Naming,
+    /// Stop after this many stills. None keeps going
+    pub frames: Option<u32>,
+}
+
+impl Default for TimelapseSettings {
+    fn default() -> Self {
+        TimelapseSettings {
+            interval: 60,
+            naming: Naming::DateTime,
+            frames: None,
+        }
+    }
+}
+
+/// A quick series of full resolution stills when motion starts,
+/// using MMAL_PARAMETER_CAMERA_BURST_CAPTURE
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BurstSettings {
+    pub count: u32,
+}
+
+impl Default for BurstSettings {
+    fn default() -> Self {
+        BurstSettings { count: 5 }
+    }
+}
+
+/// Where the snapshot taken when motion starts comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotSource {
+    /// Full resolution still from the camera's capture port. Video keeps recording,
+    /// though the firmware may drop a frame or two while it switches modes
+    StillPort,
+    /// The video frame that triggered the motion, through a separate image encoder.
+    /// Lower resolution but no hiccup in the video
+    VideoFrame,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SnapshotSettings {
+    pub source: SnapshotSource,
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        SnapshotSettings {
+            source: SnapshotSource::StillPort,
+        }
+    }
+}
+
+/// Keeps timelapse captures on schedule. Captures are due at start + n * interval,
+/// so time spent capturing doesn't make the schedule drift.
+pub struct Timelapse {
+    settings: TimelapseSettings,
+    start: Option<Instant>,
+    frame: u32,
+}
+
+impl Timelapse {
+    pub fn new(settings: TimelapseSettings) -> Timelapse {
+        Timelapse {
+            settings: settings,
+            start: None,
+            frame: 0,
+        }
+    }
+
+    /// How long until the next still is due. None once we've taken all of them
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        if let Some(frames) = self.settings.frames {
+            if self.frame >= frames {
+                return None;
+            }
+        }
+        let start = *self.start.get_or_insert(now);
+        let due = start + Duration::from_secs(self.settings.interval) * self.frame;
+        Some(due.saturating_duration_since(now))
+    }
+
+    /// File name for the next still. Call once per capture
+    pub fn next_name(&mut self, camera: &str, now: DateTime<Local>) -> String {
+        self.frame += 1;
+        match self.settings.naming {
+            Naming::Sequential => format!("{}_{:06}.jpg", camera, self.frame),
+            Naming::DateTime => format!("{}.jpg", event::file_stem(camera, &now)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timelapse_keeps_time() {
+        let mut timelapse = Timelapse::new(TimelapseSettings {
+            interval: 10,
+            naming: Naming::Sequential,
+            frames: Some(2),
+        });
+        let start = Instant::now();
+        assert_eq!(timelapse.wait(start), Some(Duration::ZERO));
+        assert_eq!(timelapse.next_name("porch", Local::now()), "porch_000001.jpg");
+        // a slow capture doesn't push the next one back
+        assert_eq!(timelapse.wait(start + Duration::from_secs(3)), Some(Duration::from_secs(7)));
+        assert_eq!(timelapse.next_name("porch", Local::now()), "porch_000002.jpg");
+        assert_eq!(timelapse.wait(start + Duration::from_secs(20)), None);
+    }
+}
@@ -0,0 +1,437 @@
+/*
+Tamper-evident log of recorded events, for when footage might end up as
+evidence.
+
+Once an event's files are written, their SHA-256 hashes go into a record in
+an append-only JSON-lines log. Each record includes the hash of the one before,
+and is signed with this Pi's Ed25519 key, so records can't be changed, dropped
+or slipped in without it showing:
+
+{ "seq": 12, "time": "2021-08-30T15:30:40+01:00", "event": "camera0_20210830-153012-250",
+  "files": [{ "path": "...", "size": 48213, "sha256": "..." }],
+  "prev": "<hash of record 11>", "hash": "...", "signature": "..." }
+
+`rust-security verify` checks the log and then every file in it, reporting
+missing or changed ones. Events deleted on purpose (from the API, or by the
+uploader) show up as missing too. Deletions wait for an event to be logged,
+see retention.rs, so nothing goes before it's been hashed.
+
+The key is made on first run. Keep a copy of the public key (the .pub next to
+it) somewhere else, since anyone with the Pi could make a new key and a whole
+new log. The same goes for the end of the log: records cut off the end can only
+be noticed by comparing against a hash copied elsewhere.
+*/
+use crate::hex;
+use crate::index::IndexEntry;
+use crate::live::Live;
+use crate::monitor::Change;
+
+use chrono::Local;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChainSettings {
+    pub log: PathBuf,
+    /// PKCS#8 Ed25519 key, made if it isn't there. The public key goes next to it, as hex.
+    pub key: PathBuf,
+}
+
+impl Default for ChainSettings {
+    fn default() -> Self {
+        ChainSettings {
+            log: PathBuf::from("recordings").join("chain.jsonl"),
+            key: PathBuf::from("chain-key.pk8"),
+        }
+    }
+}
+
+impl ChainSettings {
+    pub fn public_key(&self) -> PathBuf {
+        self.key.with_extension("pub")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHash {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The part of a record that's hashed, serialized in this field order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Link {
+    seq: u64,
+    time: String,
+    event: String,
+    files: Vec<FileHash>,
+    prev: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    link: Link,
+    hash: String,
+    signature: String,
+}
+
+impl Link {
+    fn hash(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        hex::encode(&Sha256::digest(json.as_bytes()))
+    }
+}
+
+pub struct Chain {
+    log: PathBuf,
+    key: Ed25519KeyPair,
+    seq: u64,
+    prev: String,
+}
+
+impl Chain {
+    /// Loads the key, making one if needed, and finds where the log left off.
+    /// Half a record left on the end by a crash is ended with a newline, so it
+    /// stays on its own line rather than spoiling the next record.
+    pub fn open(settings: &ChainSettings) -> Result<Chain, String> {
+        if !settings.key.exists() {
+            generate(settings)?;
+        }
+        let pkcs8 = fs::read(&settings.key).map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+
+        let mut chain = Chain {
+            log: settings.log.clone(),
+            key: key,
+            seq: 0,
+            prev: "0".repeat(64),
+        };
+        if let Some(last) = read_records(&settings.log)?.into_iter().rev().find_map(|(_, record)| record) {
+            chain.seq = last.link.seq + 1;
+            chain.prev = last.hash;
+        }
+        end_line(&settings.log)?;
+        Ok(chain)
+    }
+
+    /// Hashes an event's files and adds them to the log
+    pub fn append(&mut self, entry: &IndexEntry) -> Result<(), String> {
+        let mut files = Vec::new();
+        for file in &entry.files {
+            let (size, sha256) = hash_file(&file.path).map_err(|e| format!("{}: {}", file.path.display(), e))?;
+            files.push(FileHash {
+                path: file.path.clone(),
+                size: size,
+                sha256: sha256,
+            });
+        }
+        let link = Link {
+            seq: self.seq,
+            time: Local::now().to_rfc3339(),
+            event: entry.event.name(),
+            files: files,
+            prev: self.prev.clone(),
+        };
+        let hash = link.hash();
+        let signature = hex::encode(self.key.sign(hash.as_bytes()).as_ref());
+        let record = Record {
+            link: link,
+            hash: hash,
+            signature: signature,
+        };
+
+        let mut line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+        line.push('\n');
+        if let Some(parent) = self.log.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log)
+            .map_err(|e| format!("{}: {}", self.log.display(), e))?;
+        // one write, so a crash can't leave half a record in the middle of the log
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("{}: {}", self.log.display(), e))?;
+
+        self.seq += 1;
+        self.prev = record.hash;
+        Ok(())
+    }
+}
+
+/// Logs every camera's finished events. Call from its own thread.
+pub fn run(settings: ChainSettings, cameras: Vec<Arc<Live>>) {
+    let chain = match Chain::open(&settings) {
+        Ok(chain) => Arc::new(Mutex::new(chain)),
+        Err(e) => {
+            println!("chain: {}", e);
+            return;
+        }
+    };
+
+    let mut threads = Vec::new();
+    for camera in cameras {
+        let chain = chain.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("{}-chain", camera.name))
+            .spawn(move || {
+                for change in camera.subscribe_changes() {
+                    if let Change::Finalized(entry) = change {
+                        if let Err(e) = chain.lock().unwrap().append(&entry) {
+                            println!("chain: unable to log {}: {}", entry.event.name(), e);
+                        }
+                    }
+                }
+            })
+            .unwrap();
+        threads.push(thread);
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+/// Checks the log against the public key (hex), then the files it lists.
+/// Returns how many records there were and what's wrong.
+pub fn verify(log: &Path, public_key: &str) -> Result<(u64, Vec<String>), String> {
+    let public_key = hex::decode(public_key.trim()).ok_or("The public key isn't hex")?;
+    let public_key = UnparsedPublicKey::new(&ED25519, public_key);
+
+    let mut problems = Vec::new();
+    let mut records = 0;
+    let mut seq = 0;
+    let mut prev = "0".repeat(64);
+    for (line, record) in read_records(log)? {
+        let record = match record {
+            Some(record) => record,
+            None => {
+                problems.push(format!("line {}: not a record", line));
+                continue;
+            }
+        };
+        records += 1;
+        let link = &record.link;
+        if link.seq != seq {
+            problems.push(format!("line {}: record {} where {} should be, some were removed or added", line, link.seq, seq));
+        }
+        if link.prev != prev {
+            problems.push(format!("line {}: doesn't follow on from the record before", line));
+        }
+        if link.hash() != record.hash {
+            problems.push(format!("line {}: has been changed", line));
+        }
+        let signature = hex::decode(&record.signature).unwrap_or_default();
+        if public_key.verify(record.hash.as_bytes(), &signature).is_err() {
+            problems.push(format!("line {}: isn't signed by this key", line));
+        }
+
+        for file in &link.files {
+            match hash_file(&file.path) {
+                Ok((size, sha256)) => {
+                    if size != file.size || sha256 != file.sha256 {
+                        problems.push(format!("{}: has been changed ({})", file.path.display(), link.event));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    problems.push(format!("{}: is missing ({})", file.path.display(), link.event));
+                }
+                Err(e) => problems.push(format!("{}: {}", file.path.display(), e)),
+            }
+        }
+
+        seq = link.seq + 1;
+        prev = record.hash;
+    }
+    Ok((records, problems))
+}
+
+/// Whether the log has a record of the event yet
+pub fn logged(log: &Path, event: &str) -> Result<bool, String> {
+    Ok(read_records(log)?.into_iter().any(|(_, record)| matches!(record, Some(r) if r.link.event == event)))
+}
+
+/// Adds a newline if the log doesn't end with one
+fn end_line(path: &Path) -> Result<(), String> {
+    let error = |e: io::Error| format!("{}: {}", path.display(), e);
+    let mut file = match OpenOptions::new().read(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(error(e)),
+    };
+    if file.metadata().map_err(error)?.len() == 0 {
+        return Ok(());
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1)).and_then(|_| file.read_exact(&mut last)).map_err(error)?;
+    if last[0] != b'\n' {
+        println!("chain: {} ends part way through a record, starting a new line", path.display());
+        file.write_all(b"\n").and_then(|_| file.sync_data()).map_err(error)?;
+    }
+    Ok(())
+}
+
+/// Line numbers and records, None for lines that don't parse. No log is an empty one.
+fn read_records(path: &Path) -> Result<Vec<(usize, Option<Record>)>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path.display(), e))?;
+        records.push((i + 1, serde_json::from_str::<Record>(&line).ok()));
+    }
+    Ok(records)
+}
+
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hex::encode(&hasher.finalize())))
+}
+
+fn generate(settings: &ChainSettings) -> Result<(), String> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|e| e.to_string())?;
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|e| e.to_string())?;
+    if let Some(parent) = settings.key.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&settings.key)
+        .map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+    file.write_all(pkcs8.as_ref()).map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+
+    let public = hex::encode(key.public_key().as_ref());
+    let path = settings.public_key();
+    fs::write(&path, format!("{}\n", public)).map_err(|e| format!("{}: {}", path.display(), e))?;
+    println!("chain: made a signing key, keep a copy of its public key somewhere safe: {}", public);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::MotionEvent;
+
+    fn entry(dir: &Path, camera: &str, contents: &[u8]) -> IndexEntry {
+        let mut event = MotionEvent::new(camera);
+        event.end();
+        fs::write(dir.join(format!("{}.h264", event.name())), contents).unwrap();
+        IndexEntry::new(&event, dir).unwrap()
+    }
+
+    #[test]
+    fn append_and_verify() {
+        let dir = std::env::temp_dir().join(format!("chain-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let settings = ChainSettings {
+            log: dir.join("chain.jsonl"),
+            key: dir.join("keys").join("chain-key.pk8"),
+        };
+        let first = entry(&dir, "porch", b"first");
+        let second = entry(&dir, "drive", b"second");
+
+        Chain::open(&settings).unwrap().append(&first).unwrap();
+        assert!(logged(&settings.log, &first.event.name()).unwrap());
+        assert!(!logged(&settings.log, &second.event.name()).unwrap());
+        // a restart carries on from the end of the log
+        Chain::open(&settings).unwrap().append(&second).unwrap();
+
+        let public_key = fs::read_to_string(settings.public_key()).unwrap();
+        assert_eq!(verify(&settings.log, &public_key).unwrap(), (2, Vec::new()));
+
+        // files changed or deleted since
+        fs::write(&first.files[0].path, b"edited").unwrap();
+        fs::remove_file(&second.files[0].path).unwrap();
+        let (records, problems) = verify(&settings.log, &public_key).unwrap();
+        assert_eq!(records, 2);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].ends_with(&format!("has been changed ({})", first.event.name())));
+        assert!(problems[1].ends_with(&format!("is missing ({})", second.event.name())));
+
+        // another key didn't sign any of it
+        let other = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let other = hex::encode(Ed25519KeyPair::from_pkcs8(other.as_ref()).unwrap().public_key().as_ref());
+        let (_, problems) = verify(&settings.log, &other).unwrap();
+        assert_eq!(problems.iter().filter(|p| p.ends_with("isn't signed by this key")).count(), 2);
+        assert!(verify(&settings.log, "not hex").is_err());
+
+        let log = fs::read_to_string(&settings.log).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+
+        // a record edited after the fact
+        fs::write(&settings.log, format!("{}\n{}\n", lines[0].replace("porch", "patio"), lines[1])).unwrap();
+        let (_, problems) = verify(&settings.log, &public_key).unwrap();
+        assert!(problems.contains(&"line 1: has been changed".to_string()));
+        assert!(!problems.iter().any(|p| p.starts_with("line 2")));
+
+        // and one dropped
+        fs::write(&settings.log, format!("{}\n", lines[1])).unwrap();
+        let (records, problems) = verify(&settings.log, &public_key).unwrap();
+        assert_eq!(records, 1);
+        assert!(problems[0].starts_with("line 1: record 1 where 0 should be"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn carries_on_after_half_a_record() {
+        let dir = std::env::temp_dir().join(format!("chain-torn-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let settings = ChainSettings {
+            log: dir.join("chain.jsonl"),
+            key: dir.join("chain-key.pk8"),
+        };
+        let first = entry(&dir, "porch", b"first");
+        let second = entry(&dir, "drive", b"second");
+        Chain::open(&settings).unwrap().append(&first).unwrap();
+
+        // the power went part way through writing the next one
+        let mut log = OpenOptions::new().append(true).open(&settings.log).unwrap();
+        log.write_all(b"{\"seq\":1,\"time\":\"2021-").unwrap();
+        drop(log);
+
+        Chain::open(&settings).unwrap().append(&second).unwrap();
+        let log = fs::read_to_string(&settings.log).unwrap();
+        assert_eq!(log.lines().count(), 3);
+        assert!(log.ends_with('\n'));
+
+        let public_key = fs::read_to_string(settings.public_key()).unwrap();
+        let (records, problems) = verify(&settings.log, &public_key).unwrap();
+        assert_eq!(records, 2);
+        assert_eq!(problems, vec!["line 2: not a record".to_string()]);
+        assert!(logged(&settings.log, &second.event.name()).unwrap());
+
+        // a log that's fine is left alone
+        Chain::open(&settings).unwrap();
+        assert_eq!(fs::read_to_string(&settings.log).unwrap(), log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
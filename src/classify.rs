@@ -0,0 +1,390 @@
+/*
+Object classification of motion, to tell people from cats and headlights.
+
+Runs a small ONNX image classifier (MobileNet and the like) on the CPU, over a
+square crop around where the motion is. Events get labelled with what it
+saw, and with `require` set, motion only starts an event once one of those
+labels turns up, e.g. "record only if person".
+
+The labels file has one line per model output, in order, and a model with a
+different number of outputs is refused. Lines can repeat, since the scores for
+lines with the same text are added up. That way the hundred-odd dog and cat
+breeds in ImageNet can all just say "animal". Blank lines and "-" are outputs
+to ignore, like a background class.
+
+One model is shared by every camera, on its own thread so classifying doesn't
+hold up the frames. Crops that come in while it's busy are skipped.
+*/
+use crate::event::Label;
+use crate::i420::Layout;
+use crate::motion::BoundingBox;
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tract_onnx::prelude::*;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClassifierSettings {
+    /// ONNX model taking a 1x3xSxS float RGB image and giving one score per class
+    pub model: PathBuf,
+    pub labels: PathBuf,
+    /// S, the model's input size
+    pub input_size: u32,
+    /// Pixels are scaled to 0-1, then have the mean taken off and get divided by std, per channel
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    /// Turn the model's scores into probabilities. Off for models that already do.
+    pub softmax: bool,
+    /// Labels below this are ignored
+    pub min_confidence: f32,
+    /// Only start events when one of these is seen. Empty starts them on any motion.
+    pub require: Vec<String>,
+    /// Frames between crops sent off during motion
+    pub interval_frames: u32,
+}
+
+impl Default for ClassifierSettings {
+    fn default() -> Self {
+        ClassifierSettings {
+            model: PathBuf::from("models").join("classifier.onnx"),
+            labels: PathBuf::from("models").join("labels.txt"),
+            input_size: 224,
+            // ImageNet's
+            mean: [0.485, 0.456, 0.406],
+            std: [0.229, 0.224, 0.225],
+            softmax: true,
+            min_confidence: 0.5,
+            require: Vec::new(),
+            interval_frames: 10,
+        }
+    }
+}
+
+type Model = TypedRunnableModel<TypedModel>;
+
+struct Request {
+    crop: Vec<f32>,
+    reply: Sender<Vec<Label>>,
+}
+
+/// The model, loaded once and shared by the cameras
+#[derive(Clone)]
+pub struct Classifier {
+    settings: ClassifierSettings,
+    requests: Arc<Mutex<Sender<Request>>>,
+}
+
+impl fmt::Debug for Classifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Classifier").field("settings", &self.settings).finish()
+    }
+}
+
+impl Classifier {
+    /// Loads the model and starts the thread that runs it
+    pub fn start(settings: ClassifierSettings) -> Result<Classifier, String> {
+        let labels = fs::read_to_string(&settings.labels).map_err(|e| format!("{}: {}", settings.labels.display(), e))?;
+        let labels: Vec<String> = labels.lines().map(|l| l.trim().to_string()).collect();
+        let size = settings.input_size as usize;
+        let model = tract_onnx::onnx()
+            .model_for_path(&settings.model)
+            .and_then(|model| model.with_input_fact(0, f32::fact([1, 3, size, size]).into()))
+            .and_then(|model| model.into_optimized())
+            .and_then(|model| model.into_runnable())
+            .map_err(|e| format!("{}: {}", settings.model.display(), e))?;
+        // labels would be paired with the wrong outputs, or some left without
+        let outputs = model
+            .model()
+            .output_fact(0)
+            .ok()
+            .and_then(|fact| fact.shape.as_concrete().map(|shape| shape.iter().product::<usize>()));
+        if let Some(outputs) = outputs {
+            if outputs != labels.len() {
+                return Err(format!(
+                    "{}: {} lines, but {} has {} outputs",
+                    settings.labels.display(),
+                    labels.len(),
+                    settings.model.display(),
+                    outputs
+                ));
+            }
+        }
+
+        let (requests, incoming) = mpsc::channel();
+        let worker = settings.clone();
+        std::thread::Builder::new()
+            .name("classifier".to_string())
+            .spawn(move || run(&worker, &model, &labels, incoming))
+            .unwrap();
+        Ok(Classifier {
+            settings: settings,
+            requests: Arc::new(Mutex::new(requests)),
+        })
+    }
+}
+
+fn run(settings: &ClassifierSettings, model: &Model, labels: &[String], incoming: Receiver<Request>) {
+    for request in incoming {
+        let started = Instant::now();
+        match classify(settings, model, labels, request.crop) {
+            Ok(found) => {
+                let _ = request.reply.send(found);
+            }
+            Err(e) => {
+                println!("classifier: {}", e);
+                let _ = request.reply.send(Vec::new());
+            }
+        }
+        if started.elapsed().as_secs() >= 2 {
+            println!("classifier: took {:?}, a smaller model would keep up better", started.elapsed());
+        }
+    }
+}
+
+fn classify(settings: &ClassifierSettings, model: &Model, labels: &[String], crop: Vec<f32>) -> Result<Vec<Label>, String> {
+    let size = settings.input_size as usize;
+    let input: Tensor = tract_ndarray::Array4::from_shape_vec((1, 3, size, size), crop)
+        .map_err(|e| e.to_string())?
+        .into();
+    let outputs = model.run(tvec!(input.into())).map_err(|e| e.to_string())?;
+    let scores: Vec<f32> = outputs[0].to_array_view::<f32>().map_err(|e| e.to_string())?.iter().cloned().collect();
+    Ok(labelled(settings, labels, scores))
+}
+
+/// What the model's scores add up to, most confident first
+fn labelled(settings: &ClassifierSettings, labels: &[String], scores: Vec<f32>) -> Vec<Label> {
+    let scores = if settings.softmax { softmax(&scores) } else { scores };
+
+    let mut totals: HashMap<&str, f32> = HashMap::new();
+    for (label, score) in labels.iter().zip(&scores) {
+        if label.is_empty() || label == "-" {
+            continue;
+        }
+        *totals.entry(label).or_insert(0.0) += score;
+    }
+    let mut found: Vec<Label> = totals
+        .into_iter()
+        .filter(|(_, confidence)| *confidence >= settings.min_confidence)
+        .map(|(label, confidence)| Label {
+            label: label.to_string(),
+            confidence: confidence,
+        })
+        .collect();
+    found.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal));
+    found
+}
+
+fn softmax(scores: &[f32]) -> Vec<f32> {
+    let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = scores.iter().map(|s| (s - max).exp()).collect();
+    let total: f32 = exps.iter().sum();
+    exps.iter().map(|e| e / total.max(f32::MIN_POSITIVE)).collect()
+}
+
+/// One camera's use of the classifier
+pub struct CameraClassifier {
+    classifier: Classifier,
+    /// The motion detection frame size
+    width: u32,
+    height: u32,
+    reply: Sender<Vec<Label>>,
+    results: Receiver<Vec<Label>>,
+    busy: bool,
+    frames: u32,
+}
+
+impl CameraClassifier {
+    pub fn new(classifier: Classifier, width: u32, height: u32) -> CameraClassifier {
+        let (reply, results) = mpsc::channel();
+        CameraClassifier {
+            classifier: classifier,
+            width: width,
+            height: height,
+            reply: reply,
+            results: results,
+            busy: false,
+            frames: 0,
+        }
+    }
+
+    /// True if events wait for a required label
+    pub fn gates(&self) -> bool {
+        !self.classifier.settings.require.is_empty()
+    }
+
+    /// Whether any of the labels is one events wait for
+    pub fn wanted(&self, labels: &[Label]) -> bool {
+        labels.iter().any(|l| self.classifier.settings.require.contains(&l.label))
+    }
+
+    /// Sends off the area around the motion in this I420 frame, unless the
+    /// last one's still being looked at or it hasn't been interval_frames yet
+    pub fn submit(&mut self, frame: &[u8], bounding_box: &BoundingBox) {
+        self.frames += 1;
+        if self.busy || self.frames < self.classifier.settings.interval_frames {
+            return;
+        }
+        let crop = crop(frame, self.width, self.height, bounding_box, &self.classifier.settings);
+        let request = Request {
+            crop: crop,
+            reply: self.reply.clone(),
+        };
+        if self.classifier.requests.lock().unwrap().send(request).is_ok() {
+            self.busy = true;
+            self.frames = 0;
+        }
+    }
+
+    /// What was in the last crop sent off, once it's done
+    pub fn poll(&mut self) -> Option<Vec<Label>> {
+        match self.results.try_recv() {
+            Ok(labels) => {
+                self.busy = false;
+                Some(labels)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.busy = false;
+                None
+            }
+        }
+    }
+}
+
+/// A square around the bounding box with some margin, scaled to the model's
+/// input size, as normalized planar RGB
+fn crop(frame: &[u8], width: u32, height: u32, bounding_box: &BoundingBox, settings: &ClassifierSettings) -> Vec<f32> {
+    let size = settings.input_size.max(1) as usize;
+    let layout = Layout::new(width, height);
+
+    let side = (bounding_box.width.max(bounding_box.height) as f32 * 1.2).max(16.0).min(width.min(height) as f32);
+    let center_x = bounding_box.x as f32 + bounding_box.width as f32 / 2.0;
+    let center_y = bounding_box.y as f32 + bounding_box.height as f32 / 2.0;
+    let left = (center_x - side / 2.0).max(0.0).min(width as f32 - side);
+    let top = (center_y - side / 2.0).max(0.0).min(height as f32 - side);
+
+    let mut planes = vec![0.0; 3 * size * size];
+    for ty in 0..size {
+        let y = ((top + ty as f32 * side / size as f32) as usize).min(height as usize - 1);
+        for tx in 0..size {
+            let x = ((left + tx as f32 * side / size as f32) as usize).min(width as usize - 1);
+            let rgb = layout.rgb(frame, x, y);
+            for (channel, value) in rgb.iter().enumerate() {
+                let value = value.clamp(0.0, 255.0) / 255.0;
+                planes[channel * size * size + ty * size + tx] = (value - settings.mean[channel]) / settings.std[channel];
+            }
+        }
+    }
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn adds_up_repeated_labels() {
+        let settings = ClassifierSettings {
+            softmax: false,
+            min_confidence: 0.5,
+            ..ClassifierSettings::default()
+        };
+        let lines = labels(&["cat", "dog", "", "-", "dog", "person"]);
+        let found = labelled(&settings, &lines, vec![0.1, 0.3, 0.9, 0.9, 0.35, 0.6]);
+        let found: Vec<(&str, f32)> = found.iter().map(|l| (l.label.as_str(), l.confidence)).collect();
+        // blank and "-" outputs never come out, however high they score
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, "dog");
+        assert!((found[0].1 - 0.65).abs() < 1e-6);
+        assert_eq!(found[1], ("person", 0.6));
+    }
+
+    #[test]
+    fn softmax_of_big_scores() {
+        let probabilities = softmax(&[1000.0, 1001.0, 1002.0]);
+        let small = softmax(&[0.0, 1.0, 2.0]);
+        for (big, small) in probabilities.iter().zip(&small) {
+            assert!(big.is_finite());
+            assert!((big - small).abs() < 1e-6);
+        }
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(probabilities[2] > probabilities[1] && probabilities[1] > probabilities[0]);
+        assert!(softmax(&[]).is_empty());
+
+        let settings = ClassifierSettings {
+            min_confidence: 0.3,
+            ..ClassifierSettings::default()
+        };
+        let found = labelled(&settings, &labels(&["cat", "dog", "cat"]), vec![5.0, 5.0, 5.0]);
+        assert_eq!(found[0].label, "cat");
+        assert!((found[0].confidence - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    /// Where in the frame each sample of a 4x4 crop came from, found by
+    /// cropping one frame whose luma is x and another whose luma is y
+    fn sampled(width: u32, height: u32, bounding_box: BoundingBox) -> Vec<(u32, u32)> {
+        let settings = ClassifierSettings {
+            input_size: 4,
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            ..ClassifierSettings::default()
+        };
+        let plane = |by_x: bool| -> Vec<u32> {
+            let luma: Vec<u8> = (0..height).flat_map(|y| (0..width).map(move |x| if by_x { x } else { y } as u8)).collect();
+            let frame = Layout::new(width, height).frame(&luma);
+            let planes = crop(&frame, width, height, &bounding_box, &settings);
+            assert_eq!(planes.len(), 3 * 4 * 4);
+            // gray, so every channel's the same
+            assert_eq!(planes[..16], planes[16..32]);
+            assert_eq!(planes[..16], planes[32..]);
+            planes[..16].iter().map(|value| (value * 255.0).round() as u32).collect()
+        };
+        plane(true).into_iter().zip(plane(false)).collect()
+    }
+
+    fn bounding_box(x: u32, y: u32, width: u32, height: u32) -> BoundingBox {
+        BoundingBox {
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+        }
+    }
+
+    #[test]
+    fn crops_a_square_around_the_motion() {
+        // 10x10 gets a fifth more on each side, then goes up to the 16 minimum
+        let samples = sampled(64, 48, bounding_box(20, 20, 10, 10));
+        assert_eq!(samples[0], (17, 17));
+        assert_eq!(samples[1], (21, 17));
+        assert_eq!(samples[4], (17, 21));
+        assert_eq!(samples[15], (29, 29));
+        let samples = sampled(64, 48, bounding_box(10, 10, 30, 20));
+        assert_eq!((samples[0], samples[15]), ((7, 2), (34, 29)));
+
+        // kept inside the frame at the edges
+        let samples = sampled(64, 48, bounding_box(0, 0, 4, 4));
+        assert_eq!((samples[0], samples[15]), ((0, 0), (12, 12)));
+        let samples = sampled(64, 48, bounding_box(60, 44, 4, 4));
+        assert_eq!((samples[0], samples[15]), ((48, 32), (60, 44)));
+
+        // no bigger than the frame's short side
+        let samples = sampled(64, 48, bounding_box(50, 0, 14, 48));
+        assert_eq!((samples[0], samples[15]), ((16, 0), (52, 36)));
+        let samples = sampled(32, 32, bounding_box(0, 0, 32, 32));
+        assert_eq!((samples[0], samples[15]), ((0, 0), (24, 24)));
+        let samples = sampled(12, 12, bounding_box(5, 5, 2, 2));
+        assert_eq!((samples[0], samples[15]), ((0, 0), (9, 9)));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::capture::{BurstSettings, SnapshotSettings, TimelapseSettings};
+use crate::classify::Classifier;
+use crate::encryption::Encryption;
+use crate::illumination::IlluminationSettings;
+use crate::index::IndexSettings;
+use crate::motion::MotionSettings;
+use crate::schedule::Schedule;
+use crate::settings::CameraSettings;
+use crate::tamper::TamperSettings;
+use crate::thumbnail::ThumbnailSettings;
+use crate::tracking::TrackingSettings;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Everything needed to run one camera.
+///
+/// Compute Module boards have two CSI ports, so we keep one of these per camera
+/// and each gets its own settings, motion detector and recording directory.
+/// See `load_cameras` for setting them from cameras.json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    pub settings: CameraSettings,
+    pub motion: MotionSettings,
+    /// When to ignore motion because the lighting changed
+    pub illumination: IlluminationSettings,
+    pub recording_dir: PathBuf,
+    /// Switches between day and night exposure profiles
+    pub schedule: Option<Schedule>,
+    /// Stills every so often, into recording_dir/timelapse
+    pub timelapse: Option<TimelapseSettings>,
+    /// Stills when motion starts, next to the event's other files
+    pub burst: Option<BurstSettings>,
+    /// A JPEG of the moment motion starts, attached to the event
+    pub snapshot: Option<SnapshotSettings>,
+    /// Thumbnail and animated preview written when an event ends
+    pub thumbnail: Option<ThumbnailSettings>,
+    /// JSON-lines file every finished event gets appended to.
+    /// Shared by all cameras, from index.json.
+    #[serde(skip)]
+    pub index: Option<PathBuf>,
+    /// Labels what's moving, and can hold events off until it's something in
+    /// particular. Shared by all cameras, from classifier.json.
+    #[serde(skip)]
+    pub classifier: Option<Classifier>,
+    /// Follows moving things, and can hold events off until one goes somewhere
+    pub tracking: Option<TrackingSettings>,
+    /// Watches for the camera being covered, defocused or moved
+    pub tamper: Option<TamperSettings>,
+    /// Encrypts stills, snapshots and thumbnails as they're written.
+    /// Shared by all cameras, from encryption.json.
+    #[serde(skip)]
+    pub encryption: Option<Encryption>,
+}
+
+impl CameraConfig {
+    /// Config for the camera on the given CSI port, recording into a
+    /// subdirectory named after the camera
+    pub fn for_camera(camera_num: i32) -> CameraConfig {
+        let name = format!("camera{}", camera_num);
+        CameraConfig {
+            recording_dir: PathBuf::from("recordings").join(&name),
+            settings: CameraSettings {
+                camera_num: camera_num,
+                name: name,
+                ..CameraSettings::default()
+            },
+            motion: MotionSettings::default(),
+            illumination: IlluminationSettings::default(),
+            schedule: None,
+            timelapse: None,
+            burst: None,
+            snapshot: Some(SnapshotSettings::default()),
+            thumbnail: Some(ThumbnailSettings::default()),
+            index: IndexSettings::default().path,
+            classifier: None,
+            tracking: Some(TrackingSettings::default()),
+            tamper: Some(TamperSettings::default()),
+            encryption: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.settings.name
+    }
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        CameraConfig::for_camera(0)
+    }
+}
+
+/// Configs for the `count` cameras found, with whatever cameras.json says
+/// about each one. It holds an array, one entry per CSI port, and each entry
+/// only needs the fields that differ from the defaults:
+///
+/// [
+///   { "settings": { "name": "porch" }, "motion": { "min_score": 0.3 },
+///     "timelapse": { "interval": 300, "naming": "sequential" }, "burst": { "count": 3 } },
+///   { "settings": { "name": "garden" }, "recording_dir": "/mnt/usb/garden",
+///     "schedule": { "trigger": { "type": "sun", "latitude": 51.5, "longitude": -0.1 },
+///                   "night": { "grayscale": true } } }
+/// ]
+///
+/// Cameras get their name from settings.name, and record into a directory of
+/// that name under "recordings" unless recording_dir says otherwise. The event
+/// index is the same for every camera, so it's set in index.json instead.
+pub fn load_cameras(path: &Path, count: usize) -> Result<Vec<CameraConfig>, String> {
+    let entries: Vec<Value> = load_json(path)?.unwrap_or_default();
+    if entries.len() > count {
+        println!("{} has {} cameras, only found {}", path.display(), entries.len(), count);
+    }
+
+    let mut configs = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut config = CameraConfig::for_camera(i as i32);
+        if let Some(entry) = entries.get(i) {
+            if entry.get("index").is_some() {
+                return Err(format!("{}: camera {}: the event index is shared by every camera, set it in index.json", path.display(), i));
+            }
+            config = patch(&config, entry.clone()).map_err(|e| format!("{}: camera {}: {}", path.display(), i, e))?;
+            // the port is where the entry is in the array
+            config.settings.camera_num = i as i32;
+            if entry.get("recording_dir").is_none() {
+                config.recording_dir = PathBuf::from("recordings").join(config.name());
+            }
+        }
+        configs.push(config);
+    }
+
+    let mut names: Vec<&str> = configs.iter().map(|c| c.name()).collect();
+    names.sort_unstable();
+    names.dedup();
+    if names.len() != configs.len() {
+        return Err(format!("{}: cameras need different names", path.display()));
+    }
+    Ok(configs)
+}
+
+/// `current` with the fields in `changes` replaced, going into objects so
+/// only the fields given change
+pub fn patch<T: Serialize + DeserializeOwned>(current: &T, changes: Value) -> Result<T, String> {
+    let mut value = serde_json::to_value(current).map_err(|e| e.to_string())?;
+    merge(&mut value, changes);
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+fn merge(value: &mut Value, changes: Value) {
+    match (value, changes) {
+        (Value::Object(value), Value::Object(changes)) => {
+            for (key, change) in changes {
+                merge(value.entry(key).or_insert(Value::Null), change);
+            }
+        }
+        (value, change) => *value = change,
+    }
+}
+
+/// Settings from a JSON file. None if there's no such file, which is how the
+/// optional features get left turned off.
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, String> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    serde_json::from_str(&json).map(Some).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_json_missing_bad_and_good() {
+        let dir = std::env::temp_dir().join(format!("config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let missing: Option<MotionSettings> = load_json(&dir.join("missing.json")).unwrap();
+        assert!(missing.is_none());
+
+        let bad = dir.join("bad.json");
+        fs::write(&bad, "{ \"width\": \"wide\" }").unwrap();
+        let error = load_json::<MotionSettings>(&bad).unwrap_err();
+        assert!(error.starts_with(&bad.display().to_string()));
+
+        let good = dir.join("good.json");
+        fs::write(&good, "{ \"min_score\": 0.5 }").unwrap();
+        let motion: MotionSettings = load_json(&good).unwrap().unwrap();
+        assert_eq!(motion.min_score, 0.5);
+        assert_eq!(motion.width, MotionSettings::default().width);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cameras_json() {
+        let dir = std::env::temp_dir().join(format!("cameras-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cameras.json");
+
+        let configs = load_cameras(&path, 2).unwrap();
+        assert_eq!(configs[1].name(), "camera1");
+        assert_eq!(configs[1].settings.camera_num, 1);
+        assert_eq!(configs[1].index, IndexSettings::default().path);
+
+        fs::write(
+            &path,
+            r#"[
+                { "settings": { "name": "porch", "camera_num": 5 }, "motion": { "min_score": 0.5 },
+                  "timelapse": { "interval": 300 }, "burst": {} },
+                { "settings": { "grayscale": true }, "recording_dir": "/mnt/usb/garden", "tamper": null,
+                  "schedule": { "trigger": { "type": "brightness", "night_below": 40, "day_above": 80 } } }
+            ]"#,
+        )
+        .unwrap();
+        let configs = load_cameras(&path, 2).unwrap();
+        assert_eq!(configs[0].name(), "porch");
+        assert_eq!(configs[0].settings.camera_num, 0);
+        assert_eq!(configs[0].recording_dir, PathBuf::from("recordings/porch"));
+        assert_eq!(configs[0].motion.min_score, 0.5);
+        assert_eq!(configs[0].motion.width, MotionSettings::default().width);
+        assert!(configs[0].tamper.is_some());
+        assert_eq!(configs[1].name(), "camera1");
+        assert!(configs[1].settings.grayscale);
+        assert_eq!(configs[1].recording_dir, PathBuf::from("/mnt/usb/garden"));
+        assert!(configs[1].tamper.is_none());
+        assert!(configs[0].schedule.is_none());
+        let timelapse = configs[0].timelapse.as_ref().unwrap();
+        assert_eq!(timelapse.interval, 300);
+        assert_eq!(timelapse.naming, TimelapseSettings::default().naming);
+        assert_eq!(configs[0].burst.as_ref().unwrap().count, BurstSettings::default().count);
+        assert!(configs[1].timelapse.is_none() && configs[1].burst.is_none());
+        assert!(configs[1].schedule.is_some());
+        // a camera that's gone missing is left out
+        assert_eq!(load_cameras(&path, 1).unwrap().len(), 1);
+
+        fs::write(&path, r#"[{ "settings": { "name": "porch" } }, { "settings": { "name": "porch" } }]"#).unwrap();
+        assert!(load_cameras(&path, 2).is_err());
+        fs::write(&path, r#"[{ "motion": { "width": "wide" } }]"#).unwrap();
+        assert!(load_cameras(&path, 1).unwrap_err().contains("camera 0"));
+        fs::write(&path, r#"[{}, { "index": "garden.jsonl" }]"#).unwrap();
+        assert!(load_cameras(&path, 2).unwrap_err().contains("camera 1: the event index"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
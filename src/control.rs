@@ -0,0 +1,226 @@
+/*
+Setters for camera control port parameters, ported from RaspiCamControl.c
+
+These are all safe to call while the camera is running, so we can change
+things like exposure without tearing down the pipeline.
+*/
+use crate::ffi;
+use crate::settings::{Annotation, AwbMode, CameraSettings, ExposureMode, ISO};
+use crate::CameraError;
+
+use std::mem;
+use std::os::raw::c_char;
+use std::ptr;
+
+// same limit raspistill uses for a single "key=value" tag
+const MAX_EXIF_PAYLOAD_LENGTH: usize = 128;
+
+pub fn set_iso(control: *mut ffi::MMAL_PORT_T, iso: ISO) -> Result<(), CameraError> {
+    let status = unsafe { ffi::mmal_port_parameter_set_uint32(control, ffi::MMAL_PARAMETER_ISO as u32, iso) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to set ISO to {}", iso)
+        })
+    }
+    Ok(())
+}
+
+/// Shutter speed in microseconds. 0 means auto
+pub fn set_shutter_speed(control: *mut ffi::MMAL_PORT_T, speed: u32) -> Result<(), CameraError> {
+    let status = unsafe { ffi::mmal_port_parameter_set_uint32(control, ffi::MMAL_PARAMETER_SHUTTER_SPEED as u32, speed) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to set shutter speed to {}", speed)
+        })
+    }
+    Ok(())
+}
+
+pub fn set_exposure_mode(control: *mut ffi::MMAL_PORT_T, mode: ExposureMode) -> Result<(), CameraError> {
+    let mut param: ffi::MMAL_PARAMETER_EXPOSUREMODE_T = unsafe { mem::zeroed() };
+    param.hdr.id = ffi::MMAL_PARAMETER_EXPOSURE_MODE as u32;
+    param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_EXPOSUREMODE_T>() as u32;
+    param.value = mode;
+    let status = unsafe { ffi::mmal_port_parameter_set(control, &param.hdr) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to set exposure mode to {}", mode)
+        })
+    }
+    Ok(())
+}
+
+pub fn set_awb_mode(control: *mut ffi::MMAL_PORT_T, mode: AwbMode) -> Result<(), CameraError> {
+    let mut param: ffi::MMAL_PARAMETER_AWBMODE_T = unsafe { mem::zeroed() };
+    param.hdr.id = ffi::MMAL_PARAMETER_AWB_MODE as u32;
+    param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_AWBMODE_T>() as u32;
+    param.value = mode;
+    let status = unsafe { ffi::mmal_port_parameter_set(control, &param.hdr) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to set AWB mode to {}", mode)
+        })
+    }
+    Ok(())
+}
+
+/// Grayscale is done the same way raspistill does it: color effects with U and V fixed at 128
+pub fn set_grayscale(control: *mut ffi::MMAL_PORT_T, grayscale: bool) -> Result<(), CameraError> {
+    let mut param: ffi::MMAL_PARAMETER_COLOURFX_T = unsafe { mem::zeroed() };
+    param.hdr.id = ffi::MMAL_PARAMETER_COLOUR_EFFECT as u32;
+    param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_COLOURFX_T>() as u32;
+    param.enable = if grayscale { 1 } else { 0 };
+    param.u = 128;
+    param.v = 128;
+    let status = unsafe { ffi::mmal_port_parameter_set(control, &param.hdr) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Unable to set color effects".to_string()
+        })
+    }
+    Ok(())
+}
+
+/// Burns `text` into the picture, styled by `annotation`. Call this again to change the text.
+pub fn set_annotation(control: *mut ffi::MMAL_PORT_T, annotation: &Annotation, text: &str) -> Result<(), CameraError> {
+    let mut param: ffi::MMAL_PARAMETER_CAMERA_ANNOTATE_V4_T = unsafe { mem::zeroed() };
+    param.hdr.id = ffi::MMAL_PARAMETER_ANNOTATE as u32;
+    param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_CAMERA_ANNOTATE_V4_T>() as u32;
+    param.enable = 1;
+
+    // leave room for the trailing NUL, zeroed() already put it there
+    let max = param.text.len() - 1;
+    for (i, b) in text.bytes().take(max).enumerate() {
+        param.text[i] = b as c_char;
+    }
+
+    param.text_size = annotation.text_size;
+    if let Some(color) = annotation.text_color {
+        param.custom_text_colour = 1;
+        param.custom_text_Y = (color & 0xff) as u8;
+        param.custom_text_U = ((color >> 8) & 0xff) as u8;
+        param.custom_text_V = ((color >> 16) & 0xff) as u8;
+    }
+    if let Some(color) = annotation.background_color {
+        param.enable_text_background = 1;
+        param.custom_background_colour = 1;
+        param.custom_background_Y = (color & 0xff) as u8;
+        param.custom_background_U = ((color >> 8) & 0xff) as u8;
+        param.custom_background_V = ((color >> 16) & 0xff) as u8;
+    }
+    param.justify = annotation.justify;
+    param.x_offset = annotation.x_offset;
+    param.y_offset = annotation.y_offset;
+
+    let status = unsafe { ffi::mmal_port_parameter_set(control, &param.hdr) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Unable to set annotation".to_string()
+        })
+    }
+    Ok(())
+}
+
+pub fn clear_annotation(control: *mut ffi::MMAL_PORT_T) -> Result<(), CameraError> {
+    let mut param: ffi::MMAL_PARAMETER_CAMERA_ANNOTATE_V4_T = unsafe { mem::zeroed() };
+    param.hdr.id = ffi::MMAL_PARAMETER_ANNOTATE as u32;
+    param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_CAMERA_ANNOTATE_V4_T>() as u32;
+    param.enable = 0;
+
+    let status = unsafe { ffi::mmal_port_parameter_set(control, &param.hdr) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Unable to clear annotation".to_string()
+        })
+    }
+    Ok(())
+}
+
+/// Adds a "key=value" tag like "IFD0.Model=camera0" to JPEGs from the encoder.
+/// Must be called before the encoder output port is enabled.
+pub fn set_exif_tag(port: *mut ffi::MMAL_PORT_T, tag: &str) -> Result<(), CameraError> {
+    if tag.len() >= MAX_EXIF_PAYLOAD_LENGTH {
+        return Err(CameraError {
+            code: 1,
+            message: format!("EXIF tag too long: {}", tag)
+        })
+    }
+
+    // MMAL_PARAMETER_EXIF_T ends with a variable length data array, so allocate
+    // room for the tag after it. u32s keep the header aligned.
+    let size = mem::size_of::<ffi::MMAL_PARAMETER_EXIF_T>() + tag.len() + 1;
+    let mut buffer = vec![0u32; (size + 3) / 4];
+    let param = buffer.as_mut_ptr() as *mut ffi::MMAL_PARAMETER_EXIF_T;
+    let status = unsafe {
+        (*param).hdr.id = ffi::MMAL_PARAMETER_EXIF as u32;
+        (*param).hdr.size = size as u32;
+        ptr::copy_nonoverlapping(tag.as_ptr(), (*param).data.as_mut_ptr() as *mut u8, tag.len());
+        ffi::mmal_port_parameter_set(port, &(*param).hdr)
+    };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to set EXIF tag {}", tag)
+        })
+    }
+    Ok(())
+}
+
+pub fn set_exif_disabled(port: *mut ffi::MMAL_PORT_T, disabled: bool) -> Result<(), CameraError> {
+    let status = unsafe {
+        ffi::mmal_port_parameter_set_boolean(port, ffi::MMAL_PARAMETER_EXIF_DISABLE as u32, if disabled { 1 } else { 0 })
+    };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Unable to set EXIF disable".to_string()
+        })
+    }
+    Ok(())
+}
+
+/// Has the firmware append raw Bayer data to the next still.
+/// Like EXIF, raspistill sets this before every capture while the port is not enabled.
+pub fn set_raw_capture(still_port: *mut ffi::MMAL_PORT_T, enabled: bool) -> Result<(), CameraError> {
+    let status = unsafe {
+        ffi::mmal_port_parameter_set_boolean(still_port, ffi::MMAL_PARAMETER_ENABLE_RAW_CAPTURE as u32, if enabled { 1 } else { 0 })
+    };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "RAW was requested, but failed to enable".to_string()
+        })
+    }
+    Ok(())
+}
+
+pub fn set_burst_capture(control: *mut ffi::MMAL_PORT_T, enabled: bool) -> Result<(), CameraError> {
+    let status = unsafe {
+        ffi::mmal_port_parameter_set_boolean(control, ffi::MMAL_PARAMETER_CAMERA_BURST_CAPTURE as u32, if enabled { 1 } else { 0 })
+    };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Unable to set burst capture".to_string()
+        })
+    }
+    Ok(())
+}
+
+/// Applies the exposure related parts of `settings`: ISO, shutter speed,
+/// exposure mode, AWB and grayscale
+pub fn set_exposure(control: *mut ffi::MMAL_PORT_T, settings: &CameraSettings) -> Result<(), CameraError> {
+    set_iso(control, settings.iso)?;
+    set_shutter_speed(control, settings.shutter_speed)?;
+    set_exposure_mode(control, settings.exposure_mode)?;
+    set_awb_mode(control, settings.awb_mode)?;
+    set_grayscale(control, settings.grayscale)?;
+    Ok(())
+}
@@ -0,0 +1,217 @@
+/*
+A standalone MMAL image encoder that we feed frames to by hand, rather than
+tunnelling it to a camera port. Used to turn a video frame into a JPEG without
+touching the still port.
+*/
+use crate::ffi;
+use crate::pipeline;
+use crate::{encoder_buffer_callback, CameraError, CaptureData, MMAL_ENCODING_JPEG};
+
+use std::mem::{self, MaybeUninit};
+use std::os::raw::c_char;
+use std::ptr::{self, NonNull};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// Input buffers just go back to their pool once the encoder is done with them
+unsafe extern "C" fn input_buffer_callback(_port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    ffi::mmal_buffer_header_release(buffer);
+}
+
+pub struct ImageEncoder {
+    encoder: NonNull<ffi::MMAL_COMPONENT_T>,
+    input_pool: NonNull<ffi::MMAL_POOL_T>,
+    output_pool: NonNull<ffi::MMAL_POOL_T>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageEncoder {
+    /// Encoder for I420 frames of the given size. Frames have to use the padded
+    /// layout in i420.rs, which is what the camera produces.
+    pub fn new(width: u32, height: u32) -> Result<ImageEncoder, CameraError> {
+        let mut encoder_ptr = MaybeUninit::<*mut ffi::MMAL_COMPONENT_T>::uninit();
+        let component: *const c_char = ffi::MMAL_COMPONENT_DEFAULT_IMAGE_ENCODER.as_ptr() as *const c_char;
+        let status = unsafe { ffi::mmal_component_create(component, encoder_ptr.as_mut_ptr()) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            return Err(CameraError {
+                code: 1,
+                message: "Failed to create frame encoder".to_string()
+            })
+        }
+        let encoder = NonNull::new(unsafe { encoder_ptr.assume_init() }).unwrap();
+
+        let (input, output) = unsafe { (*encoder.as_ref().input, *encoder.as_ref().output) };
+
+        unsafe { pipeline::set_i420(input, width, height) };
+        let status = unsafe { ffi::mmal_port_format_commit(input) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            unsafe { ffi::mmal_component_destroy(encoder.as_ptr()) };
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to set frame encoder input format".to_string()
+            })
+        }
+
+        unsafe {
+            ffi::mmal_format_copy((*output).format, (*input).format);
+            (*(*output).format).encoding = MMAL_ENCODING_JPEG;
+            for port in [input, output].iter() {
+                let port = *port;
+                (*port).buffer_size = (*port).buffer_size_recommended.max((*port).buffer_size_min);
+                (*port).buffer_num = (*port).buffer_num_recommended.max((*port).buffer_num_min);
+            }
+        }
+        let status = unsafe { ffi::mmal_port_format_commit(output) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            unsafe { ffi::mmal_component_destroy(encoder.as_ptr()) };
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to set frame encoder output format".to_string()
+            })
+        }
+
+        unsafe { ffi::mmal_port_parameter_set_uint32(output, ffi::MMAL_PARAMETER_JPEG_Q_FACTOR as u32, 90) };
+
+        let status = unsafe { ffi::mmal_component_enable(encoder.as_ptr()) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            unsafe { ffi::mmal_component_destroy(encoder.as_ptr()) };
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to enable frame encoder".to_string()
+            })
+        }
+
+        let input_pool = unsafe { ffi::mmal_port_pool_create(input, (*input).buffer_num, (*input).buffer_size) };
+        let output_pool = unsafe { ffi::mmal_port_pool_create(output, (*output).buffer_num, (*output).buffer_size) };
+        let (input_pool, output_pool) = match (NonNull::new(input_pool), NonNull::new(output_pool)) {
+            (Some(i), Some(o)) => (i, o),
+            (i, o) => {
+                unsafe {
+                    // whichever one did get made
+                    if let Some(i) = i {
+                        ffi::mmal_port_pool_destroy(input, i.as_ptr());
+                    }
+                    if let Some(o) = o {
+                        ffi::mmal_port_pool_destroy(output, o.as_ptr());
+                    }
+                    ffi::mmal_component_disable(encoder.as_ptr());
+                    ffi::mmal_component_destroy(encoder.as_ptr());
+                }
+                return Err(CameraError {
+                    code: 1,
+                    message: "Failed to create frame encoder buffer pools".to_string()
+                })
+            }
+        };
+
+        let status = unsafe { ffi::mmal_port_enable(input, Some(input_buffer_callback)) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            unsafe {
+                ffi::mmal_component_disable(encoder.as_ptr());
+                ffi::mmal_port_pool_destroy(input, input_pool.as_ptr());
+                ffi::mmal_port_pool_destroy(output, output_pool.as_ptr());
+                ffi::mmal_component_destroy(encoder.as_ptr());
+            }
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to enable frame encoder input port".to_string()
+            })
+        }
+
+        Ok(ImageEncoder {
+            encoder: encoder,
+            input_pool: input_pool,
+            output_pool: output_pool,
+            width: width,
+            height: height,
+        })
+    }
+
+    /// Encodes one I420 frame to a JPEG
+    pub fn encode(&mut self, frame: &[u8]) -> Result<Vec<u8>, CameraError> {
+        let (input, output) = unsafe { (*self.encoder.as_ref().input, *self.encoder.as_ref().output) };
+
+        let (done, finished) = mpsc::channel();
+        let capture = Arc::new(Mutex::new(CaptureData {
+            data: Vec::new(),
+            pool: self.output_pool.as_ptr(),
+            done: done,
+        }));
+        unsafe { (*output).userdata = Arc::as_ptr(&capture) as *mut ffi::MMAL_PORT_USERDATA_T };
+        let status = unsafe { ffi::mmal_port_enable(output, Some(encoder_buffer_callback)) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            unsafe { (*output).userdata = ptr::null_mut() };
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to enable frame encoder output port".to_string()
+            })
+        }
+
+        let result = unsafe { self.send(input, output, frame) }.and_then(|_| {
+            match finished.recv_timeout(Duration::from_secs(5)) {
+                Ok(true) => Ok(()),
+                _ => Err(CameraError {
+                    code: 1,
+                    message: "Frame encoder didn't produce a JPEG".to_string()
+                }),
+            }
+        });
+
+        unsafe {
+            ffi::mmal_port_disable(output);
+            (*output).userdata = ptr::null_mut();
+        }
+
+        result?;
+        let data = mem::replace(&mut capture.lock().unwrap().data, Vec::new());
+        Ok(data)
+    }
+
+    unsafe fn send(&self, input: *mut ffi::MMAL_PORT_T, output: *mut ffi::MMAL_PORT_T, frame: &[u8]) -> Result<(), CameraError> {
+        // output buffers first, so there's somewhere for the JPEG to go
+        let queue = (*self.output_pool.as_ptr()).queue;
+        for _ in 0..ffi::mmal_queue_length(queue) {
+            let buffer = ffi::mmal_queue_get(queue);
+            if buffer.is_null() || ffi::mmal_port_send_buffer(output, buffer) != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+                println!("Unable to send a buffer to frame encoder output port");
+            }
+        }
+
+        let buffer = ffi::mmal_queue_wait((*self.input_pool.as_ptr()).queue);
+        if buffer.is_null() || (*buffer).alloc_size < frame.len() as u32 {
+            if !buffer.is_null() {
+                ffi::mmal_buffer_header_release(buffer);
+            }
+            return Err(CameraError {
+                code: 1,
+                message: "Frame doesn't fit in a frame encoder input buffer".to_string()
+            })
+        }
+        ffi::mmal_buffer_header_mem_lock(buffer);
+        ptr::copy_nonoverlapping(frame.as_ptr(), (*buffer).data, frame.len());
+        ffi::mmal_buffer_header_mem_unlock(buffer);
+        (*buffer).offset = 0;
+        (*buffer).length = frame.len() as u32;
+        (*buffer).flags = ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END;
+
+        if ffi::mmal_port_send_buffer(input, buffer) != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to send frame to frame encoder".to_string()
+            })
+        }
+        Ok(())
+    }
+
+    pub fn destroy(&self) {
+        unsafe {
+            let (input, output) = (*self.encoder.as_ref().input, *self.encoder.as_ref().output);
+            ffi::mmal_port_disable(input);
+            ffi::mmal_component_disable(self.encoder.as_ptr());
+            ffi::mmal_port_pool_destroy(input, self.input_pool.as_ptr());
+            ffi::mmal_port_pool_destroy(output, self.output_pool.as_ptr());
+            ffi::mmal_component_destroy(self.encoder.as_ptr());
+        }
+    }
+}
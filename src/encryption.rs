@@ -0,0 +1,181 @@
+/*
+Encrypts recordings and stills as they're written, so a stolen SD card doesn't
+give away any footage.
+
+Files are age files (https://age-encryption.org) for one or more X25519 public
+keys, saved with ".age" on the end of their names. The Pi only ever has the
+public keys, so it can write footage but can't read it back. Make the key
+somewhere else and keep it there:
+
+    age-keygen -o camera-key.txt     # prints the public key for encryption.json
+    rust-security decrypt camera-key.txt camera0_20210830-153012-250.jpg.age
+
+age itself (or rage) decrypts them too.
+
+Event video is encrypted as it's recorded, through a FileWriter, so none of
+it is ever on the card in the clear.
+
+Event metadata, the sidecar JSON and the index, stays readable so events can
+still be listed and searched. Pictures written encrypted can't be shown in the
+web UI or attached to notifications, those fall back to the live view.
+*/
+use age::stream::StreamWriter;
+use age::x25519::{Identity, Recipient};
+use age::{Decryptor, Encryptor, IdentityFile};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionSettings {
+    /// age public keys, like "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p".
+    /// Any one of their private keys can decrypt.
+    pub recipients: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Encryption {
+    recipients: Vec<Recipient>,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let recipients: Vec<String> = self.recipients.iter().map(|r| r.to_string()).collect();
+        f.debug_struct("Encryption").field("recipients", &recipients).finish()
+    }
+}
+
+impl Encryption {
+    pub fn new(settings: &EncryptionSettings) -> Result<Encryption, String> {
+        if settings.recipients.is_empty() {
+            return Err("No recipients".to_string());
+        }
+        let mut recipients = Vec::new();
+        for recipient in &settings.recipients {
+            recipients.push(recipient.parse::<Recipient>().map_err(|e| format!("{}: {}", recipient, e))?);
+        }
+        Ok(Encryption {
+            recipients: recipients,
+        })
+    }
+
+    /// Encrypts `data` into `path` with .age added. Returns the path written.
+    pub fn write(&self, path: &Path, data: &[u8]) -> io::Result<PathBuf> {
+        let (path, mut writer) = self.create(path)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(path)
+    }
+
+    /// Starts an encrypted file at `path` with .age added, for writing a bit at a time
+    pub fn create(&self, path: &Path) -> io::Result<(PathBuf, FileWriter)> {
+        let path = encrypted_path(path);
+        let recipients = self.recipients.iter().map(|r| Box::new(r.clone()) as Box<dyn age::Recipient>).collect();
+        let to_io = |e: age::EncryptError| io::Error::new(io::ErrorKind::Other, e.to_string());
+        let file = BufWriter::new(File::create(&path)?);
+        let writer = Encryptor::with_recipients(recipients).wrap_output(file).map_err(to_io)?;
+        Ok((path, FileWriter::Encrypted(writer)))
+    }
+}
+
+/// A file written a bit at a time, encrypted or not. It has to be finished:
+/// age writes its last chunk differently, and can't decrypt a file without it.
+pub enum FileWriter {
+    Plain(BufWriter<File>),
+    Encrypted(StreamWriter<BufWriter<File>>),
+}
+
+impl FileWriter {
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            FileWriter::Plain(file) => file,
+            FileWriter::Encrypted(writer) => writer.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            FileWriter::Plain(file) => file.write(data),
+            FileWriter::Encrypted(writer) => writer.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            FileWriter::Plain(file) => file.flush(),
+            FileWriter::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Starts a file at `path` to write a bit at a time, encrypted if there's
+/// `encryption`. Returns the path it's going to.
+pub fn create(encryption: Option<&Encryption>, path: &Path) -> io::Result<(PathBuf, FileWriter)> {
+    match encryption {
+        Some(encryption) => encryption.create(path),
+        None => Ok((path.to_path_buf(), FileWriter::Plain(BufWriter::new(File::create(path)?)))),
+    }
+}
+
+/// Writes `data` to `path`, encrypted if there's `encryption`. Returns the path written.
+pub fn write(encryption: Option<&Encryption>, path: &Path, data: &[u8]) -> io::Result<PathBuf> {
+    match encryption {
+        Some(encryption) => encryption.write(path, data),
+        None => fs::write(path, data).map(|_| path.to_path_buf()),
+    }
+}
+
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("age")
+}
+
+fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".age");
+    path.with_file_name(name)
+}
+
+/// Private keys from an age identity file, as made by age-keygen
+pub fn load_identities(path: &Path) -> Result<Vec<Identity>, String> {
+    let identities = IdentityFile::from_file(path.to_string_lossy().into_owned())
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .into_identities();
+    if identities.is_empty() {
+        return Err(format!("{}: no keys", path.display()));
+    }
+    Ok(identities)
+}
+
+/// Decrypts an .age file next to itself, without the .age. Returns the path written.
+pub fn decrypt_file(identities: &[Identity], path: &Path) -> Result<PathBuf, String> {
+    if !is_encrypted(path) {
+        return Err(format!("{} doesn't end in .age", path.display()));
+    }
+    let out = path.with_extension("");
+    let input = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let decryptor = match Decryptor::new(input).map_err(|e| format!("{}: {}", path.display(), e))? {
+        Decryptor::Recipients(decryptor) => decryptor,
+        Decryptor::Passphrase(_) => return Err(format!("{} is encrypted with a passphrase", path.display())),
+    };
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    // never overwrite, that might be the only copy of something
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&out)
+        .map_err(|e| format!("{}: {}", out.display(), e))?;
+    if let Err(e) = io::copy(&mut reader, &mut output) {
+        // a file that fails its MAC partway shouldn't be left looking complete
+        let _ = fs::remove_file(&out);
+        return Err(format!("{}: {}", path.display(), e));
+    }
+    Ok(out)
+}
@@ -0,0 +1,174 @@
+use crate::motion::{BoundingBox, Motion};
+use crate::tracking::TrackedObject;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A stretch of time where a camera saw motion.
+///
+/// Events carry the camera name so footage from multiple cameras
+/// can share a directory without file names colliding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotionEvent {
+    pub camera: String,
+    pub started: DateTime<Local>,
+    pub ended: Option<DateTime<Local>>,
+    /// Still of the moment motion started
+    pub snapshot: Option<PathBuf>,
+    /// Highest motion score seen during the event
+    pub peak_score: f32,
+    /// Small JPEG of the frame with the most motion
+    pub thumbnail: Option<PathBuf>,
+    /// Animated GIF of keyframes
+    pub preview: Option<PathBuf>,
+    /// Where motion was over the course of the event
+    pub track: Vec<TrackPoint>,
+    /// Started by hand rather than by motion
+    #[serde(default)]
+    pub manual: bool,
+    /// What the classifier saw, most confident first
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    /// Things that moved during the event, and the paths they took
+    #[serde(default)]
+    pub objects: Vec<TrackedObject>,
+}
+
+/// Something the classifier saw during an event, with its best confidence
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Label {
+    pub label: String,
+    pub confidence: f32,
+}
+
+/// Where the motion was at one point during an event
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TrackPoint {
+    pub time: DateTime<Local>,
+    pub score: f32,
+    pub bounding_box: BoundingBox,
+}
+
+/// The part of an event in progress that goes in the camera's status. The
+/// whole MotionEvent gets a track point every frame, which is a lot to copy
+/// into the status each time.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventSummary {
+    pub name: String,
+    pub started: DateTime<Local>,
+    pub manual: bool,
+    pub peak_score: f32,
+    pub snapshot: Option<PathBuf>,
+    pub labels: Vec<Label>,
+}
+
+// Enough for several minutes of continuous motion, after which we stop
+// recording the track rather than let it grow forever
+const MAX_TRACK_POINTS: usize = 10000;
+// The same goes for objects, past this it's probably leaves blowing about
+const MAX_OBJECTS: usize = 100;
+
+impl MotionEvent {
+    pub fn new(camera: &str) -> MotionEvent {
+        MotionEvent {
+            camera: camera.to_string(),
+            started: Local::now(),
+            ended: None,
+            snapshot: None,
+            peak_score: 0.0,
+            thumbnail: None,
+            preview: None,
+            track: Vec::new(),
+            manual: false,
+            labels: Vec::new(),
+            objects: Vec::new(),
+        }
+    }
+
+    /// Records motion seen during the event
+    pub fn add_motion(&mut self, motion: &Motion) {
+        self.peak_score = self.peak_score.max(motion.score);
+        if self.track.len() < MAX_TRACK_POINTS {
+            self.track.push(TrackPoint {
+                time: Local::now(),
+                score: motion.score,
+                bounding_box: motion.bounding_box,
+            });
+        }
+    }
+
+    /// Keeps the best confidence seen for each label
+    pub fn add_labels(&mut self, labels: &[Label]) {
+        for label in labels {
+            match self.labels.iter_mut().find(|l| l.label == label.label) {
+                Some(existing) => existing.confidence = existing.confidence.max(label.confidence),
+                None => self.labels.push(label.clone()),
+            }
+        }
+        self.labels.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal));
+    }
+
+    /// Records something that moved during the event
+    pub fn add_object(&mut self, object: TrackedObject) {
+        if self.objects.len() < MAX_OBJECTS {
+            self.objects.push(object);
+        }
+    }
+
+    pub fn end(&mut self) {
+        self.ended = Some(Local::now());
+    }
+
+    /// Something like "front-door_20210830-153012-250"
+    pub fn name(&self) -> String {
+        file_stem(&self.camera, &self.started)
+    }
+
+    pub fn summary(&self) -> EventSummary {
+        EventSummary {
+            name: self.name(),
+            started: self.started,
+            manual: self.manual,
+            peak_score: self.peak_score,
+            snapshot: self.snapshot.clone(),
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// Where a file for this event with the given extension should go
+    pub fn path(&self, dir: &Path, extension: &str) -> PathBuf {
+        dir.join(format!("{}.{}", self.name(), extension))
+    }
+
+    /// Writes the event out as JSON next to its footage, so tools can find
+    /// out about an event without parsing file names
+    pub fn write_sidecar(&self, dir: &Path) -> io::Result<PathBuf> {
+        let path = self.path(dir, "json");
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json)?;
+        Ok(path)
+    }
+}
+
+/// Down to the millisecond, so an event starting the same second another
+/// ended doesn't overwrite its files or index entry
+pub fn file_stem(camera: &str, time: &DateTime<Local>) -> String {
+    format!("{}_{}", camera, time.format("%Y%m%d-%H%M%S-%3f"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn stems_differ_within_a_second() {
+        let time = Local.with_ymd_and_hms(2021, 8, 30, 15, 30, 12).unwrap();
+        assert_eq!(file_stem("porch", &time), "porch_20210830-153012-000");
+        let later = time + chrono::Duration::milliseconds(250);
+        assert_eq!(file_stem("porch", &later), "porch_20210830-153012-250");
+    }
+}
@@ -0,0 +1,453 @@
+/*
+EXIF tags for stills.
+
+There are two ways these end up in a JPEG:
+* MMAL's image encoder takes "key=value" strings through MMAL_PARAMETER_EXIF,
+  same as raspistill's add_exif_tag(). See Exif::mmal_tags()
+* For JPEGs that didn't come out of the MMAL encoder, like event thumbnails,
+  insert_into_jpeg() builds the APP1 segment itself
+
+Custom tags use the same "IFD0.Artist" style keys in both cases, with values
+written the way MMAL takes them: text as is, numbers separated by spaces or
+commas, and rationals as "1/100". Our own writer only knows the tags in TAGS,
+which covers the ones that make sense to set by hand; anything else is refused
+rather than written with a guessed type. A custom tag replaces one we'd have
+written ourselves, like IFD0.Model.
+*/
+use crate::illumination::CameraGains;
+use crate::settings::{CameraSettings, ISO_AUTO};
+use crate::tiff::{self, Entry};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Gps {
+    /// Degrees, north is positive
+    pub latitude: f64,
+    /// Degrees, east is positive
+    pub longitude: f64,
+    /// Meters above sea level
+    pub altitude: Option<f64>,
+}
+
+/// What to tag stills with, beyond what we know at capture time
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExifSettings {
+    pub gps: Option<Gps>,
+    /// Extra tags like ("IFD0.Artist", "Alan")
+    pub tags: Vec<(String, String)>,
+}
+
+/// Tags for one capture
+#[derive(Debug, Clone)]
+pub struct Exif {
+    pub time: DateTime<Local>,
+    pub camera: String,
+    /// In microseconds
+    pub exposure: Option<u32>,
+    pub iso: Option<u32>,
+    pub gps: Option<Gps>,
+    pub tags: Vec<(String, String)>,
+}
+
+impl Exif {
+    /// Tags for a capture happening now. Exposure and gain come from what the
+    /// camera last reported, ISO is estimated from analog gain when it's on auto.
+    pub fn new(settings: &CameraSettings, exif: &ExifSettings, gains: Option<CameraGains>) -> Exif {
+        let iso = if settings.iso != ISO_AUTO {
+            Some(settings.iso)
+        } else {
+            gains.map(|g| (g.analog_gain * 100.0).round() as u32)
+        };
+        Exif {
+            time: Local::now(),
+            camera: settings.name.clone(),
+            exposure: gains.map(|g| g.exposure),
+            iso: iso,
+            gps: exif.gps,
+            tags: exif.tags.clone(),
+        }
+    }
+
+    /// "key=value" strings for MMAL_PARAMETER_EXIF
+    pub fn mmal_tags(&self) -> Vec<String> {
+        let time = self.time.format("%Y:%m:%d %H:%M:%S").to_string();
+        let mut tags = vec![
+            format!("IFD0.Make={}", MAKE),
+            format!("IFD0.Model={}", self.camera),
+            format!("IFD0.DateTime={}", time),
+            format!("EXIF.DateTimeOriginal={}", time),
+            format!("EXIF.DateTimeDigitized={}", time),
+        ];
+        if let Some(exposure) = self.exposure {
+            tags.push(format!("EXIF.ExposureTime={}/1000000", exposure));
+        }
+        if let Some(iso) = self.iso {
+            tags.push(format!("EXIF.ISOSpeedRatings={}", iso));
+        }
+        if let Some(gps) = self.gps {
+            let (lat, lat_ref) = dms(gps.latitude, 'N', 'S');
+            let (lon, lon_ref) = dms(gps.longitude, 'E', 'W');
+            tags.push("GPS.GPSVersionID=2 2 0 0".to_string());
+            tags.push(format!("GPS.GPSLatitudeRef={}", lat_ref));
+            tags.push(format!("GPS.GPSLatitude={}/1,{}/1,{}/100", lat.0, lat.1, lat.2));
+            tags.push(format!("GPS.GPSLongitudeRef={}", lon_ref));
+            tags.push(format!("GPS.GPSLongitude={}/1,{}/1,{}/100", lon.0, lon.1, lon.2));
+            if let Some(altitude) = gps.altitude {
+                tags.push(format!("GPS.GPSAltitudeRef={}", if altitude < 0.0 { 1 } else { 0 }));
+                tags.push(format!("GPS.GPSAltitude={}/100", (altitude.abs() * 100.0).round() as u32));
+            }
+        }
+        for (key, value) in &self.tags {
+            tags.push(format!("{}={}", key, value));
+        }
+        tags
+    }
+
+    /// The TIFF structure that goes inside an APP1 segment
+    pub fn to_tiff(&self) -> io::Result<Vec<u8>> {
+        let time = self.time.format("%Y:%m:%d %H:%M:%S").to_string();
+
+        let mut ifd0 = vec![
+            Entry::ascii(0x010F, MAKE),
+            Entry::ascii(0x0110, &self.camera),
+            Entry::ascii(0x0132, &time),
+        ];
+        let mut exif = vec![
+            Entry::ascii(0x9003, &time),
+            Entry::ascii(0x9004, &time),
+        ];
+        if let Some(exposure) = self.exposure {
+            exif.push(Entry::rationals(0x829A, &[(exposure, 1_000_000)]));
+        }
+        if let Some(iso) = self.iso {
+            exif.push(Entry::short(0x8827, iso.min(u16::MAX as u32) as u16));
+        }
+        let mut gps = Vec::new();
+        if let Some(g) = self.gps {
+            let (lat, lat_ref) = dms(g.latitude, 'N', 'S');
+            let (lon, lon_ref) = dms(g.longitude, 'E', 'W');
+            gps.push(Entry::bytes(0x0000, &[2, 2, 0, 0]));
+            gps.push(Entry::ascii(0x0001, &lat_ref.to_string()));
+            gps.push(Entry::rationals(0x0002, &[(lat.0, 1), (lat.1, 1), (lat.2, 100)]));
+            gps.push(Entry::ascii(0x0003, &lon_ref.to_string()));
+            gps.push(Entry::rationals(0x0004, &[(lon.0, 1), (lon.1, 1), (lon.2, 100)]));
+            if let Some(altitude) = g.altitude {
+                gps.push(Entry::bytes(0x0005, &[if altitude < 0.0 { 1 } else { 0 }]));
+                gps.push(Entry::rationals(0x0006, &[((altitude.abs() * 100.0).round() as u32, 100)]));
+            }
+        }
+
+        for (key, value) in &self.tags {
+            let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("EXIF tag {}: {}", key, message));
+            let (ifd, name) = key.split_once('.').ok_or_else(|| invalid("should look like IFD0.Artist"))?;
+            let &(_, _, tag, kind) = TAGS
+                .iter()
+                .find(|(i, n, _, _)| *i == ifd && *n == name)
+                .ok_or_else(|| invalid("not supported"))?;
+            let entry = kind.entry(tag, value).ok_or_else(|| invalid(&format!("{:?} isn't a valid value", value)))?;
+            let entries = match ifd {
+                "IFD0" => &mut ifd0,
+                "EXIF" => &mut exif,
+                _ => &mut gps,
+            };
+            entries.retain(|e| e.tag != tag);
+            entries.push(entry);
+        }
+
+        // Pointers to the EXIF and GPS IFDs are LONGs in IFD0. Fill in the
+        // offsets once we know how big everything before them is.
+        ifd0.push(Entry::long(0x8769, 0));
+        if !gps.is_empty() {
+            ifd0.push(Entry::long(0x8825, 0));
+        }
+        // entries have to be sorted by tag
+        ifd0.sort_by_key(|e| e.tag);
+        exif.sort_by_key(|e| e.tag);
+        gps.sort_by_key(|e| e.tag);
+
+        let ifd0_offset = 8;
+        let exif_offset = ifd0_offset + tiff::ifd_size(&ifd0);
+        let gps_offset = exif_offset + tiff::ifd_size(&exif);
+        for entry in ifd0.iter_mut() {
+            if entry.tag == 0x8769 {
+                entry.data = (exif_offset as u32).to_be_bytes().to_vec();
+            }
+            if entry.tag == 0x8825 {
+                entry.data = (gps_offset as u32).to_be_bytes().to_vec();
+            }
+        }
+
+        let mut out = tiff::header();
+        tiff::write_ifd(&mut out, &ifd0);
+        tiff::write_ifd(&mut out, &exif);
+        if !gps.is_empty() {
+            tiff::write_ifd(&mut out, &gps);
+        }
+        Ok(out)
+    }
+}
+
+const MAKE: &str = "RaspberryPi";
+
+/// How a tag's value is written
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Ascii,
+    Byte,
+    Short,
+    Rational,
+    SRational,
+    /// UserComment's character code header and then the text
+    Comment,
+}
+
+/// Tags custom keys can set: IFD, name, tag and type
+const TAGS: &[(&str, &str, u16, Kind)] = &[
+    ("IFD0", "ImageDescription", 0x010E, Kind::Ascii),
+    ("IFD0", "Make", 0x010F, Kind::Ascii),
+    ("IFD0", "Model", 0x0110, Kind::Ascii),
+    ("IFD0", "Orientation", 0x0112, Kind::Short),
+    ("IFD0", "XResolution", 0x011A, Kind::Rational),
+    ("IFD0", "YResolution", 0x011B, Kind::Rational),
+    ("IFD0", "ResolutionUnit", 0x0128, Kind::Short),
+    ("IFD0", "Software", 0x0131, Kind::Ascii),
+    ("IFD0", "DateTime", 0x0132, Kind::Ascii),
+    ("IFD0", "Artist", 0x013B, Kind::Ascii),
+    ("IFD0", "Copyright", 0x8298, Kind::Ascii),
+    ("EXIF", "ExposureTime", 0x829A, Kind::Rational),
+    ("EXIF", "FNumber", 0x829D, Kind::Rational),
+    ("EXIF", "ExposureProgram", 0x8822, Kind::Short),
+    ("EXIF", "ISOSpeedRatings", 0x8827, Kind::Short),
+    ("EXIF", "DateTimeOriginal", 0x9003, Kind::Ascii),
+    ("EXIF", "DateTimeDigitized", 0x9004, Kind::Ascii),
+    ("EXIF", "ShutterSpeedValue", 0x9201, Kind::SRational),
+    ("EXIF", "ApertureValue", 0x9202, Kind::Rational),
+    ("EXIF", "BrightnessValue", 0x9203, Kind::SRational),
+    ("EXIF", "ExposureBiasValue", 0x9204, Kind::SRational),
+    ("EXIF", "MaxApertureValue", 0x9205, Kind::Rational),
+    ("EXIF", "MeteringMode", 0x9207, Kind::Short),
+    ("EXIF", "LightSource", 0x9208, Kind::Short),
+    ("EXIF", "Flash", 0x9209, Kind::Short),
+    ("EXIF", "FocalLength", 0x920A, Kind::Rational),
+    ("EXIF", "UserComment", 0x9286, Kind::Comment),
+    ("EXIF", "ColorSpace", 0xA001, Kind::Short),
+    ("EXIF", "WhiteBalance", 0xA403, Kind::Short),
+    ("EXIF", "DigitalZoomRatio", 0xA404, Kind::Rational),
+    ("EXIF", "FocalLengthIn35mmFilm", 0xA405, Kind::Short),
+    ("EXIF", "SceneCaptureType", 0xA406, Kind::Short),
+    ("EXIF", "ImageUniqueID", 0xA420, Kind::Ascii),
+    ("GPS", "GPSVersionID", 0x0000, Kind::Byte),
+    ("GPS", "GPSLatitudeRef", 0x0001, Kind::Ascii),
+    ("GPS", "GPSLatitude", 0x0002, Kind::Rational),
+    ("GPS", "GPSLongitudeRef", 0x0003, Kind::Ascii),
+    ("GPS", "GPSLongitude", 0x0004, Kind::Rational),
+    ("GPS", "GPSAltitudeRef", 0x0005, Kind::Byte),
+    ("GPS", "GPSAltitude", 0x0006, Kind::Rational),
+    ("GPS", "GPSTimeStamp", 0x0007, Kind::Rational),
+    ("GPS", "GPSSatellites", 0x0008, Kind::Ascii),
+    ("GPS", "GPSStatus", 0x0009, Kind::Ascii),
+    ("GPS", "GPSMeasureMode", 0x000A, Kind::Ascii),
+    ("GPS", "GPSDOP", 0x000B, Kind::Rational),
+    ("GPS", "GPSSpeedRef", 0x000C, Kind::Ascii),
+    ("GPS", "GPSSpeed", 0x000D, Kind::Rational),
+    ("GPS", "GPSTrackRef", 0x000E, Kind::Ascii),
+    ("GPS", "GPSTrack", 0x000F, Kind::Rational),
+    ("GPS", "GPSImgDirectionRef", 0x0010, Kind::Ascii),
+    ("GPS", "GPSImgDirection", 0x0011, Kind::Rational),
+    ("GPS", "GPSMapDatum", 0x0012, Kind::Ascii),
+    ("GPS", "GPSDateStamp", 0x001D, Kind::Ascii),
+];
+
+impl Kind {
+    /// None if the value doesn't parse as this kind
+    fn entry(self, tag: u16, value: &str) -> Option<Entry> {
+        let numbers = value.split(|c: char| c == ',' || c.is_whitespace()).filter(|n| !n.is_empty());
+        Some(match self {
+            Kind::Ascii => Entry::ascii(tag, value),
+            Kind::Comment => {
+                // 8 byte character code header, then the text
+                let mut data = b"ASCII\0\0\0".to_vec();
+                data.extend_from_slice(value.as_bytes());
+                Entry::undefined(tag, &data)
+            }
+            Kind::Byte => Entry::bytes(tag, &numbers.map(|n| n.parse().ok()).collect::<Option<Vec<u8>>>()?),
+            Kind::Short => Entry::shorts(tag, &numbers.map(|n| n.parse().ok()).collect::<Option<Vec<u16>>>()?),
+            Kind::Rational => Entry::rationals(tag, &numbers.map(fraction).collect::<Option<Vec<(u32, u32)>>>()?),
+            Kind::SRational => Entry::srationals(tag, &numbers.map(fraction).collect::<Option<Vec<(i32, i32)>>>()?),
+        })
+        .filter(|entry| entry.count > 0)
+    }
+}
+
+/// "1/100", or a whole number
+fn fraction<T: std::str::FromStr + From<u8>>(s: &str) -> Option<(T, T)> {
+    match s.split_once('/') {
+        Some((num, den)) => Some((num.parse().ok()?, den.parse().ok()?)),
+        None => Some((s.parse().ok()?, T::from(1))),
+    }
+}
+
+/// Degrees, minutes and hundredths of seconds, plus the hemisphere letter
+fn dms(degrees: f64, positive: char, negative: char) -> ((u32, u32, u32), char) {
+    let hemisphere = if degrees < 0.0 { negative } else { positive };
+    let degrees = degrees.abs();
+    let d = degrees.floor();
+    let m = ((degrees - d) * 60.0).floor();
+    let s = ((degrees - d) * 60.0 - m) * 60.0;
+    ((d as u32, m as u32, (s * 100.0).round() as u32), hemisphere)
+}
+
+/// Returns a copy of `jpeg` with our EXIF APP1 segment in place of any existing one
+pub fn insert_into_jpeg(jpeg: &[u8], exif: &Exif) -> io::Result<Vec<u8>> {
+    if jpeg.len() < 4 || jpeg[0] != 0xFF || jpeg[1] != 0xD8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a JPEG"));
+    }
+
+    let tiff = exif.to_tiff()?;
+    let length = 2 + 6 + tiff.len();
+    if length > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "EXIF data too big for APP1"));
+    }
+    let mut app1 = vec![0xFF, 0xE1];
+    app1.extend_from_slice(&(length as u16).to_be_bytes());
+    app1.extend_from_slice(b"Exif\0\0");
+    app1.extend_from_slice(&tiff);
+
+    let mut out = Vec::with_capacity(jpeg.len() + app1.len());
+    out.extend_from_slice(&jpeg[..2]);
+
+    // Walk the APPn segments at the start. JFIF's APP0 has to stay first,
+    // and any EXIF already there gets dropped.
+    let mut pos = 2;
+    let mut inserted = false;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF && (0xE0..=0xEF).contains(&jpeg[pos + 1]) {
+        let marker = jpeg[pos + 1];
+        let size = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let end = pos + 2 + size;
+        if end > jpeg.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated JPEG segment"));
+        }
+        let segment = &jpeg[pos..end];
+        let is_exif = marker == 0xE1 && segment.len() >= 10 && &segment[4..10] == b"Exif\0\0";
+        if marker != 0xE0 && !inserted {
+            out.extend_from_slice(&app1);
+            inserted = true;
+        }
+        if !is_exif {
+            out.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+    if !inserted {
+        out.extend_from_slice(&app1);
+    }
+    out.extend_from_slice(&jpeg[pos..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn exif(tags: &[(&str, &str)]) -> Exif {
+        Exif {
+            time: Local.with_ymd_and_hms(2021, 8, 30, 15, 30, 12).unwrap(),
+            camera: "porch".to_string(),
+            exposure: Some(10000),
+            iso: Some(200),
+            gps: Some(Gps {
+                latitude: 51.5,
+                longitude: -0.25,
+                altitude: Some(-3.0),
+            }),
+            tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    fn value(ifd: &[(u16, u16, u32, Vec<u8>)], tag: u16) -> Vec<u8> {
+        ifd.iter().find(|e| e.0 == tag).map(|e| e.3.clone()).unwrap_or_default()
+    }
+
+    fn offset(ifd: &[(u16, u16, u32, Vec<u8>)], tag: u16) -> usize {
+        let v = value(ifd, tag);
+        u32::from_be_bytes([v[0], v[1], v[2], v[3]]) as usize
+    }
+
+    #[test]
+    fn writes_tiff() {
+        let exif = exif(&[
+            ("IFD0.Artist", "Alan"),
+            ("IFD0.Model", "front porch"),
+            ("EXIF.FNumber", "28/10"),
+            ("EXIF.ExposureBiasValue", "-1/3"),
+            ("GPS.GPSSpeed", "5"),
+        ]);
+        let tiff = exif.to_tiff().unwrap();
+
+        let ifd0 = tiff::read_ifd(&tiff, 8);
+        let tags: Vec<u16> = ifd0.iter().map(|e| e.0).collect();
+        assert_eq!(tags, vec![0x010F, 0x0110, 0x0132, 0x013B, 0x8769, 0x8825]);
+        assert_eq!(value(&ifd0, 0x0110), b"front porch\0");
+        assert_eq!(value(&ifd0, 0x0132), b"2021:08:30 15:30:12\0");
+
+        let exif_ifd = tiff::read_ifd(&tiff, offset(&ifd0, 0x8769));
+        assert_eq!(value(&exif_ifd, 0x829A), [0, 0, 0x27, 0x10, 0, 0x0F, 0x42, 0x40]);
+        assert_eq!(value(&exif_ifd, 0x829D), [0, 0, 0, 28, 0, 0, 0, 10]);
+        assert_eq!(value(&exif_ifd, 0x8827), [0, 200]);
+        assert_eq!(value(&exif_ifd, 0x9204), [255, 255, 255, 255, 0, 0, 0, 3]);
+
+        let gps = tiff::read_ifd(&tiff, offset(&ifd0, 0x8825));
+        assert_eq!(value(&gps, 0x0001), b"N\0");
+        assert_eq!(value(&gps, 0x0002), [0, 0, 0, 51, 0, 0, 0, 1, 0, 0, 0, 30, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 100]);
+        assert_eq!(value(&gps, 0x0003), b"W\0");
+        assert_eq!(value(&gps, 0x0005), [1]);
+        assert_eq!(value(&gps, 0x0006), [0, 0, 1, 44, 0, 0, 0, 100]);
+        assert_eq!(value(&gps, 0x000D), [0, 0, 0, 5, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn refuses_what_it_cant_write() {
+        assert!(exif(&[("IFD0.HostComputer", "pi")]).to_tiff().is_err());
+        assert!(exif(&[("Artist", "Alan")]).to_tiff().is_err());
+        assert!(exif(&[("EXIF.FNumber", "f2.8")]).to_tiff().is_err());
+        assert!(exif(&[("IFD0.Orientation", "")]).to_tiff().is_err());
+    }
+
+    #[test]
+    fn mmal_tags() {
+        let tags = exif(&[("IFD0.Artist", "Alan")]).mmal_tags();
+        assert!(tags.contains(&"EXIF.ExposureTime=10000/1000000".to_string()));
+        assert!(tags.contains(&"GPS.GPSLatitude=51/1,30/1,0/100".to_string()));
+        assert!(tags.contains(&"GPS.GPSLongitudeRef=W".to_string()));
+        assert_eq!(tags.last().unwrap(), "IFD0.Artist=Alan");
+    }
+
+    #[test]
+    fn replaces_exif_in_jpegs() {
+        let app0 = [0xFF, 0xE0, 0, 7, b'J', b'F', b'I', b'F', 0];
+        let old_exif = [0xFF, 0xE1, 0, 10, b'E', b'x', b'i', b'f', 0, 0, 1, 2];
+        let rest = [0xFF, 0xDB, 0, 3, 9, 0xFF, 0xD9];
+        let jpeg = [&[0xFF, 0xD8][..], &app0, &old_exif, &rest].concat();
+
+        let exif = exif(&[]);
+        let out = insert_into_jpeg(&jpeg, &exif).unwrap();
+        let tiff = exif.to_tiff().unwrap();
+        assert_eq!(&out[..2], [0xFF, 0xD8]);
+        // APP0 stays first
+        assert_eq!(&out[2..11], app0);
+        assert_eq!(&out[11..13], [0xFF, 0xE1]);
+        assert_eq!(u16::from_be_bytes([out[13], out[14]]) as usize, 8 + tiff.len());
+        assert_eq!(&out[15..21], b"Exif\0\0");
+        assert_eq!(&out[21..21 + tiff.len()], &tiff[..]);
+        assert_eq!(&out[21 + tiff.len()..], rest);
+
+        // and again, without piling up APP1s
+        assert_eq!(insert_into_jpeg(&out, &exif).unwrap(), out);
+        assert!(insert_into_jpeg(b"GIF89a", &exif).is_err());
+    }
+}
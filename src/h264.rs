@@ -0,0 +1,216 @@
+/*
+H.264 from the video encoder, shared with whoever is streaming it.
+
+The encoder gives us Annex B byte streams (NAL units separated by 00 00 01 or
+00 00 00 01 start codes). Each buffer is split into NAL units once here, and
+every subscriber gets the same access units.
+*/
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+
+/// NAL units making up one frame
+#[derive(Debug, Clone)]
+pub struct AccessUnit {
+    /// Presentation time, from the encoder's buffer timestamps
+    pub timestamp: Duration,
+    /// NAL units without start codes
+    pub nals: Vec<Vec<u8>>,
+    pub keyframe: bool,
+}
+
+impl AccessUnit {
+    pub fn from_annexb(data: &[u8], timestamp: Duration) -> AccessUnit {
+        let nals: Vec<Vec<u8>> = split_annexb(data).into_iter().map(|nal| nal.to_vec()).collect();
+        let keyframe = nals.iter().any(|nal| nal_type(nal) == NAL_IDR);
+        AccessUnit {
+            timestamp: timestamp,
+            nals: nals,
+            keyframe: keyframe,
+        }
+    }
+
+    /// SPS, PPS and an IDR slice, the least a decoder can start from
+    #[cfg(test)]
+    pub fn keyframe(timestamp: u64) -> AccessUnit {
+        let mut data = Vec::new();
+        for nal in [&[0x67, 0x42, 0xc0, 0x1f, 0xda, 0x01][..], &[0x68, 0xce, 0x3c, 0x80], &[0x65, 0x88, 0x84, 0x21]].iter() {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        AccessUnit::from_annexb(&data, Duration::from_millis(timestamp))
+    }
+
+    pub fn has(&self, kind: u8) -> bool {
+        self.nals.iter().any(|nal| nal_type(nal) == kind)
+    }
+}
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map(|b| b & 0x1F).unwrap_or(0)
+}
+
+/// NAL units in an Annex B stream, start codes removed
+pub fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                // a 4 byte start code leaves a trailing zero on the previous NAL
+                let mut end = i;
+                if end > s && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                nals.push(&data[s..end]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(s) = start {
+        if s < data.len() {
+            nals.push(&data[s..]);
+        }
+    }
+    nals
+}
+
+/// "42c01f" style profile-level-id, from bytes 1-3 of the SPS
+pub fn profile_level_id(sps: &[u8]) -> String {
+    if sps.len() < 4 {
+        return "42e01f".to_string();
+    }
+    format!("{:02x}{:02x}{:02x}", sps[1], sps[2], sps[3])
+}
+
+struct Subscriber {
+    sender: SyncSender<Arc<AccessUnit>>,
+    // after a dropped frame there's nothing to decode until the next keyframe
+    waiting_for_keyframe: bool,
+}
+
+/// Fans access units out to streaming clients.
+///
+/// The camera thread never blocks on a client: each one gets a small queue,
+/// and a client that falls behind skips ahead to the next keyframe.
+pub struct VideoFeed {
+    subscribers: Mutex<Vec<Subscriber>>,
+    parameter_sets: Mutex<Option<(Vec<u8>, Vec<u8>)>>,
+}
+
+// about a second of video at 30fps
+const QUEUE_LENGTH: usize = 32;
+
+impl Default for VideoFeed {
+    fn default() -> Self {
+        VideoFeed::new()
+    }
+}
+
+impl VideoFeed {
+    pub fn new() -> VideoFeed {
+        VideoFeed {
+            subscribers: Mutex::new(Vec::new()),
+            parameter_sets: Mutex::new(None),
+        }
+    }
+
+    /// Access units from the next keyframe on
+    pub fn subscribe(&self) -> Receiver<Arc<AccessUnit>> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            sender: sender,
+            waiting_for_keyframe: true,
+        });
+        receiver
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// Latest SPS and PPS the encoder sent
+    pub fn parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.parameter_sets.lock().unwrap().clone()
+    }
+
+    pub fn publish(&self, unit: AccessUnit) {
+        let sps = unit.nals.iter().find(|nal| nal_type(nal) == NAL_SPS);
+        let pps = unit.nals.iter().find(|nal| nal_type(nal) == NAL_PPS);
+        if let (Some(sps), Some(pps)) = (sps, pps) {
+            *self.parameter_sets.lock().unwrap() = Some((sps.clone(), pps.clone()));
+        }
+
+        let unit = Arc::new(unit);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|subscriber| {
+            if subscriber.waiting_for_keyframe {
+                if !unit.keyframe {
+                    return true;
+                }
+                subscriber.waiting_for_keyframe = false;
+            }
+            match subscriber.sender.try_send(unit.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.waiting_for_keyframe = true;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_both_start_codes() {
+        let data = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88, 0x00];
+        let nals = split_annexb(&data);
+        assert_eq!(nals, vec![&[0x67, 0x42][..], &[0x68, 0xce][..], &[0x65, 0x88, 0x00][..]]);
+        assert!(split_annexb(&[0x65, 0x88]).is_empty());
+        assert!(split_annexb(&[0, 0, 1]).is_empty());
+
+        let unit = AccessUnit::from_annexb(&data, Duration::from_millis(40));
+        assert!(unit.keyframe);
+        assert!(unit.has(NAL_SPS) && unit.has(NAL_PPS));
+        assert!(!AccessUnit::from_annexb(&[0, 0, 1, 0x41, 0x9a], Duration::ZERO).keyframe);
+        assert_eq!(profile_level_id(&[0x67, 0x42]), "42e01f");
+        assert_eq!(profile_level_id(&[0x67, 0x64, 0x00, 0x28]), "640028");
+    }
+
+    #[test]
+    fn subscribers_start_at_keyframes() {
+        let feed = VideoFeed::new();
+        let receiver = feed.subscribe();
+        feed.publish(AccessUnit::from_annexb(&[0, 0, 1, 0x41, 1], Duration::ZERO));
+        feed.publish(AccessUnit::from_annexb(&[0, 0, 1, 0x67, 2, 0, 0, 1, 0x68, 3, 0, 0, 1, 0x65, 4], Duration::ZERO));
+        assert_eq!(receiver.try_recv().unwrap().nals[2], vec![0x65, 4]);
+        assert_eq!(feed.parameter_sets(), Some((vec![0x67, 2], vec![0x68, 3])));
+
+        // a subscriber that falls behind skips to the next keyframe
+        for _ in 0..QUEUE_LENGTH + 5 {
+            feed.publish(AccessUnit::from_annexb(&[0, 0, 1, 0x41, 5], Duration::ZERO));
+        }
+        while receiver.try_recv().is_ok() {}
+        feed.publish(AccessUnit::from_annexb(&[0, 0, 1, 0x41, 6], Duration::ZERO));
+        assert!(receiver.try_recv().is_err());
+        feed.publish(AccessUnit::from_annexb(&[0, 0, 1, 0x65, 7], Duration::ZERO));
+        assert_eq!(receiver.try_recv().unwrap().nals[0], vec![0x65, 7]);
+
+        drop(receiver);
+        feed.publish(AccessUnit::from_annexb(&[0, 0, 1, 0x65, 8], Duration::ZERO));
+        assert_eq!(feed.subscribers(), 0);
+    }
+}
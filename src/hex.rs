@@ -0,0 +1,30 @@
+/*
+Lowercase hex, for hashes, signatures, keys and tokens.
+*/
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Either case. None for odd lengths and anything that isn't hex.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(encode(&[0, 0x0f, 0xa5, 0xff]), "000fa5ff");
+        assert_eq!(decode("000fA5ff"), Some(vec![0, 0x0f, 0xa5, 0xff]));
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        // a multibyte character can't be split into digits
+        assert_eq!(decode("é0"), None);
+    }
+}
@@ -0,0 +1,196 @@
+/*
+HLS live output, so phones and browsers can watch without plugins.
+
+A thread per camera cuts the H.264 stream into MPEG-TS segments at keyframes
+and keeps the last few in memory. The HTTP server serves the playlist and
+segments from there:
+
+GET /hls/<camera>/index.m3u8
+GET /hls/<camera>/<sequence>.ts
+*/
+use crate::h264;
+use crate::live::Live;
+use crate::ts::Muxer;
+
+use std::collections::VecDeque;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct HlsSettings {
+    /// Segments are cut at the first keyframe after this long, so set the
+    /// encoder's intra period to match
+    pub segment_duration: Duration,
+    /// How many segments the playlist holds
+    pub segments: usize,
+}
+
+impl Default for HlsSettings {
+    fn default() -> Self {
+        HlsSettings {
+            segment_duration: Duration::from_secs(2),
+            segments: 6,
+        }
+    }
+}
+
+pub struct Segment {
+    pub sequence: u64,
+    pub duration: Duration,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// Rolling window of finished segments
+#[derive(Default)]
+pub struct Playlist {
+    segments: Mutex<VecDeque<Segment>>,
+}
+
+impl Playlist {
+    pub fn new() -> Playlist {
+        Playlist {
+            segments: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn segment(&self, sequence: u64) -> Option<Arc<Vec<u8>>> {
+        let segments = self.segments.lock().unwrap();
+        segments.iter().find(|s| s.sequence == sequence).map(|s| s.data.clone())
+    }
+
+    /// The m3u8, or None until there are enough segments for players to start on
+    pub fn m3u8(&self) -> Option<String> {
+        let segments = self.segments.lock().unwrap();
+        if segments.len() < 2 {
+            return None;
+        }
+        let target = segments.iter().map(|s| s.duration.as_secs_f64().ceil() as u64).max().unwrap_or(1);
+        let mut m3u8 = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target, segments[0].sequence
+        );
+        for segment in segments.iter() {
+            m3u8.push_str(&format!("#EXTINF:{:.3},\n{}.ts\n", segment.duration.as_secs_f64(), segment.sequence));
+        }
+        Some(m3u8)
+    }
+
+    fn push(&self, segment: Segment, keep: usize) {
+        let mut segments = self.segments.lock().unwrap();
+        segments.push_back(segment);
+        while segments.len() > keep {
+            segments.pop_front();
+        }
+    }
+}
+
+/// Segments the camera's video until the feed goes away. Call from its own thread.
+pub fn run(settings: HlsSettings, live: Arc<Live>) {
+    let units = live.video.subscribe();
+    let mut muxer = Muxer::new();
+    let mut sequence = 0;
+    let mut data = Vec::new();
+    let mut start: Option<Duration> = None;
+
+    loop {
+        let unit = match units.recv_timeout(Duration::from_secs(5)) {
+            Ok(unit) => unit,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if unit.keyframe {
+            if let Some(started) = start {
+                let duration = unit.timestamp.saturating_sub(started);
+                if duration >= settings.segment_duration {
+                    live.hls.push(
+                        Segment {
+                            sequence: sequence,
+                            duration: duration,
+                            data: Arc::new(std::mem::take(&mut data)),
+                        },
+                        settings.segments,
+                    );
+                    sequence += 1;
+                    start = None;
+                }
+            }
+            if start.is_none() {
+                start = Some(unit.timestamp);
+                muxer.tables(&mut data);
+            }
+        }
+        // subscribers start on a keyframe, so there's always a segment going by now
+        if start.is_none() {
+            continue;
+        }
+
+        let mut extra = Vec::new();
+        if unit.keyframe && !unit.has(h264::NAL_SPS) {
+            if let Some((sps, pps)) = live.video.parameter_sets() {
+                extra = vec![sps, pps];
+            }
+        }
+        muxer.write(&mut data, &unit, &extra);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::AccessUnit;
+
+    fn unit(timestamp: u64, keyframe: bool) -> AccessUnit {
+        if keyframe {
+            return AccessUnit::keyframe(timestamp);
+        }
+        let mut data = vec![0, 0, 0, 1, 0x41];
+        data.extend_from_slice(&[0x88; 300]);
+        AccessUnit::from_annexb(&data, Duration::from_millis(timestamp))
+    }
+
+    #[test]
+    fn segments_at_keyframes() {
+        let live = Live::new("porch", 5);
+        let settings = HlsSettings {
+            segment_duration: Duration::from_millis(500),
+            segments: 3,
+        };
+        {
+            let live = live.clone();
+            std::thread::spawn(move || run(settings, live));
+        }
+        while live.video.subscribers() == 0 {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(live.hls.m3u8().is_none());
+
+        // keyframe every 10 frames at 20fps, so segments of half a second
+        for i in 0..60 {
+            live.video.publish(unit(i * 50, i % 10 == 0));
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        for _ in 0..100 {
+            if live.hls.segment(4).is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let m3u8 = live.hls.m3u8().unwrap();
+        assert!(m3u8.starts_with("#EXTM3U\n"));
+        assert!(m3u8.contains("#EXT-X-TARGETDURATION:1\n"));
+        assert!(m3u8.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+        assert!(m3u8.contains("#EXTINF:0.500,\n4.ts\n"));
+        assert!(!m3u8.contains("1.ts"));
+
+        assert!(live.hls.segment(1).is_none());
+        let segment = live.hls.segment(4).unwrap();
+        assert_eq!(segment.len() % 188, 0);
+        // PAT first, then PMT, then video
+        assert_eq!(&segment[..3], &[0x47, 0x40, 0x00]);
+        assert_eq!(&segment[188..191], &[0x47, 0x50, 0x00]);
+        assert_eq!(&segment[376..379], &[0x47, 0x41, 0x00]);
+    }
+}
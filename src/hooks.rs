@@ -0,0 +1,506 @@
+/*
+Webhooks and scripts run when events start, end, and once their files are
+written ("finalized"), and when the camera's tampered with.
+
+Webhooks get a JSON POST:
+
+{ "trigger": "end", "camera": "camera0", "name": "camera0_20210830-153012-250",
+  "event": { ... }, "files": [ ... ] }
+
+files is only filled in for finalized. The event's track and the paths of its
+objects are left out, they can be huge and they're in the sidecar anyway.
+Tampering gets its own body, sent when it starts and again when it's over
+(with "ended" set):
+
+{ "trigger": "tamper", "camera": "camera0",
+  "tamper": { "tamper": "covered", "started": ..., "ended": null } }
+
+Deliveries are queued on disk (queue.rs) before the first try. A target
+that's down gets retried with backoff, in order, and deliveries survive a
+restart. They're given up on after a day by default. Queued deliveries only
+hold the URL and body: headers, which tend to be credentials, are looked up
+in hooks.json when the delivery goes out, and a delivery for a webhook that's
+been taken out of hooks.json is dropped.
+
+Scripts get the same JSON in EVENT_JSON, and the useful parts in EVENT_TRIGGER,
+EVENT_CAMERA, EVENT_NAME, EVENT_STARTED, EVENT_ENDED, EVENT_PEAK_SCORE,
+EVENT_MANUAL, EVENT_SNAPSHOT, EVENT_THUMBNAIL, EVENT_PREVIEW, EVENT_LABELS
+(what the classifier saw, comma separated) and EVENT_FILES (one path per
+line). For tampering it's EVENT_TRIGGER, EVENT_CAMERA, EVENT_TAMPER (covered,
+defocused or moved), EVENT_STARTED and EVENT_ENDED. They're not retried.
+*/
+use crate::event::MotionEvent;
+use crate::index::EventFile;
+use crate::live::Live;
+use crate::monitor::Change;
+use crate::queue::{self, DiskQueue};
+use crate::tamper::TamperEvent;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Start,
+    End,
+    Finalized,
+    Tamper,
+}
+
+fn all_triggers() -> Vec<Trigger> {
+    vec![Trigger::Start, Trigger::End, Trigger::Finalized, Trigger::Tamper]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default = "all_triggers")]
+    pub on: Vec<Trigger>,
+    /// Like an Authorization header for the target
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Script {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "all_triggers")]
+    pub on: Vec<Trigger>,
+    /// Seconds before it gets killed
+    #[serde(default = "script_timeout")]
+    pub timeout: u64,
+}
+
+fn script_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HookSettings {
+    pub webhooks: Vec<Webhook>,
+    pub scripts: Vec<Script>,
+    /// Where webhook deliveries wait until they go through
+    pub queue: PathBuf,
+    /// Hours to keep retrying a delivery
+    pub give_up_after: u64,
+}
+
+impl Default for HookSettings {
+    fn default() -> Self {
+        HookSettings {
+            webhooks: Vec::new(),
+            scripts: Vec::new(),
+            queue: PathBuf::from("hooks-queue"),
+            give_up_after: 24,
+        }
+    }
+}
+
+/// A webhook POST waiting to go out. Saved as is, so nothing secret goes in here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    body: String,
+    queued: DateTime<Local>,
+    attempts: u32,
+    next_attempt: DateTime<Local>,
+}
+
+/// Fires hooks for every camera and delivers webhooks until the program exits.
+/// Call from its own thread.
+pub fn run(settings: HookSettings, cameras: Vec<Arc<Live>>) {
+    let queue = match DiskQueue::open(&settings.queue, "hooks") {
+        Ok(queue) => Arc::new(queue),
+        Err(e) => {
+            println!("hooks: unable to create {}: {}", settings.queue.display(), e);
+            return;
+        }
+    };
+
+    let (wake, woken) = mpsc::channel();
+    for camera in cameras {
+        let settings = settings.clone();
+        let wake = wake.clone();
+        let queue = queue.clone();
+        std::thread::Builder::new()
+            .name(format!("{}-hooks", camera.name))
+            .spawn(move || {
+                for change in camera.subscribe_changes() {
+                    fire(&settings, &change, &wake, &queue);
+                }
+            })
+            .unwrap();
+    }
+
+    let give_up_after = chrono::Duration::hours(settings.give_up_after as i64);
+    loop {
+        let wait = deliver(&queue, &settings.webhooks, give_up_after);
+        match woken.recv_timeout(wait) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+fn fire(settings: &HookSettings, change: &Change, wake: &Sender<()>, queue: &DiskQueue) {
+    let (trigger, body, env) = match change {
+        Change::Started(event) => event_payload(Trigger::Start, event, &[]),
+        Change::Ended(event) => event_payload(Trigger::End, event, &[]),
+        Change::Finalized(entry) => event_payload(Trigger::Finalized, &entry.event, &entry.files),
+        Change::TamperStarted(tamper) | Change::TamperEnded(tamper) => tamper_payload(tamper),
+    };
+
+    for webhook in settings.webhooks.iter().filter(|w| w.on.contains(&trigger)) {
+        let now = Local::now();
+        let delivery = Delivery {
+            url: webhook.url.clone(),
+            body: body.clone(),
+            queued: now,
+            attempts: 0,
+            next_attempt: now,
+        };
+        match queue.push(&delivery) {
+            Ok(_) => {
+                let _ = wake.send(());
+            }
+            Err(e) => println!("hooks: unable to queue {}: {}", webhook.url, e),
+        }
+    }
+
+    for script in settings.scripts.iter().filter(|s| s.on.contains(&trigger)) {
+        let mut command = Command::new(&script.command);
+        command
+            .args(&script.args)
+            .env("EVENT_JSON", &body)
+            .env("EVENT_TRIGGER", json!(trigger).as_str().unwrap_or(""))
+            .envs(env.clone());
+        let name = script.command.clone();
+        let timeout = Duration::from_secs(script.timeout);
+        // scripts can take a while, and shouldn't hold up the next event
+        std::thread::spawn(move || run_script(&name, command, timeout));
+    }
+}
+
+/// The webhook body and the script environment for a motion event
+fn event_payload(trigger: Trigger, event: &MotionEvent, files: &[EventFile]) -> (Trigger, String, Vec<(&'static str, String)>) {
+    let mut event = event.clone();
+    event.track.clear();
+    for object in &mut event.objects {
+        object.path.clear();
+    }
+    let body = json!({
+        "trigger": trigger,
+        "camera": event.camera,
+        "name": event.name(),
+        "event": event,
+        "files": files,
+    })
+    .to_string();
+    let env = vec![
+        ("EVENT_CAMERA", event.camera.clone()),
+        ("EVENT_NAME", event.name()),
+        ("EVENT_STARTED", event.started.to_rfc3339()),
+        ("EVENT_ENDED", event.ended.map(|t| t.to_rfc3339()).unwrap_or_default()),
+        ("EVENT_PEAK_SCORE", event.peak_score.to_string()),
+        ("EVENT_MANUAL", if event.manual { "1" } else { "0" }.to_string()),
+        ("EVENT_SNAPSHOT", path_var(&event.snapshot)),
+        ("EVENT_THUMBNAIL", path_var(&event.thumbnail)),
+        ("EVENT_PREVIEW", path_var(&event.preview)),
+        ("EVENT_LABELS", event.labels.iter().map(|l| l.label.as_str()).collect::<Vec<&str>>().join(",")),
+        (
+            "EVENT_FILES",
+            files.iter().map(|f| f.path.display().to_string()).collect::<Vec<String>>().join("\n"),
+        ),
+    ];
+    (trigger, body, env)
+}
+
+fn tamper_payload(tamper: &TamperEvent) -> (Trigger, String, Vec<(&'static str, String)>) {
+    let body = json!({
+        "trigger": Trigger::Tamper,
+        "camera": tamper.camera,
+        "tamper": tamper,
+    })
+    .to_string();
+    let env = vec![
+        ("EVENT_CAMERA", tamper.camera.clone()),
+        ("EVENT_TAMPER", json!(tamper.tamper).as_str().unwrap_or("").to_string()),
+        ("EVENT_STARTED", tamper.started.to_rfc3339()),
+        ("EVENT_ENDED", tamper.ended.map(|t| t.to_rfc3339()).unwrap_or_default()),
+    ];
+    (Trigger::Tamper, body, env)
+}
+
+fn run_script(name: &str, mut command: Command, timeout: Duration) {
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            println!("hooks: unable to run {}: {}", name, e);
+            return;
+        }
+    };
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    println!("hooks: {} failed, {}", name, status);
+                }
+                return;
+            }
+            Ok(None) if started.elapsed() >= timeout => {
+                println!("hooks: {} took too long, killing it", name);
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(100)),
+            Err(e) => {
+                println!("hooks: {}: {}", name, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Tries every delivery that's due, oldest first. Returns how long until the next one is.
+fn deliver(queue: &DiskQueue, webhooks: &[Webhook], give_up_after: chrono::Duration) -> Duration {
+    let mut wait = Duration::from_secs(60);
+    // once a target fails, the rest of its deliveries wait their turn so they stay in order
+    let mut failed: HashSet<String> = HashSet::new();
+    for (path, mut delivery) in queue.jobs::<Delivery>() {
+        if failed.contains(&delivery.url) {
+            continue;
+        }
+        let webhook = match webhooks.iter().find(|w| w.url == delivery.url) {
+            Some(webhook) => webhook,
+            None => {
+                println!("hooks: {} isn't in hooks.json any more, dropping a delivery to it", delivery.url);
+                queue::remove(&path);
+                continue;
+            }
+        };
+        let now = Local::now();
+        if delivery.next_attempt > now {
+            wait = wait.min((delivery.next_attempt - now).to_std().unwrap_or_default());
+            failed.insert(delivery.url.clone());
+            continue;
+        }
+
+        let result = post(&delivery, &webhook.headers);
+        delivery.attempts += 1;
+        let error = match result {
+            Ok(()) => {
+                queue::remove(&path);
+                continue;
+            }
+            Err((e, false)) => {
+                println!("hooks: {} refused a delivery, dropping it: {}", delivery.url, e);
+                queue::remove(&path);
+                continue;
+            }
+            Err((e, true)) => e,
+        };
+        if now - delivery.queued > give_up_after {
+            println!("hooks: giving up on a delivery to {} after {} tries: {}", delivery.url, delivery.attempts, error);
+            queue::remove(&path);
+            continue;
+        }
+
+        failed.insert(delivery.url.clone());
+        let backoff = backoff(delivery.attempts);
+        if delivery.attempts == 1 {
+            println!("hooks: {} failed, will retry: {}", delivery.url, error);
+        }
+        delivery.next_attempt = now + chrono::Duration::from_std(backoff).unwrap_or_else(|_| chrono::Duration::hours(1));
+        wait = wait.min(backoff);
+        if let Err(e) = queue::save(&path, &delivery) {
+            println!("hooks: {}: {}", path.display(), e);
+        }
+    }
+    wait
+}
+
+/// Err with whether it's worth trying again
+fn post(delivery: &Delivery, headers: &HashMap<String, String>) -> Result<(), (String, bool)> {
+    let mut request = ureq::post(&delivery.url)
+        .timeout(Duration::from_secs(10))
+        .set("Content-Type", "application/json");
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    match request.send_string(&delivery.body) {
+        Ok(_) => Ok(()),
+        // the target's up but doesn't want it, trying again won't change its mind
+        Err(ureq::Error::Status(status, _)) if (400..500).contains(&status) && status != 408 && status != 429 => {
+            Err((format!("HTTP {}", status), false))
+        }
+        Err(e) => Err((e.to_string(), true)),
+    }
+}
+
+/// 5s, 10s, 20s... up to an hour
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(5u64.saturating_mul(1 << attempts.saturating_sub(1).min(10)).min(3600))
+}
+
+fn path_var(path: &Option<PathBuf>) -> String {
+    path.as_ref().map(|p| p.display().to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A request's path, body and X-Key header
+    type Seen = (String, String, Option<String>);
+
+    /// Answers requests with `statuses` in turn. Gives back what it saw.
+    fn target(statuses: Vec<u16>) -> (String, std::thread::JoinHandle<Vec<Seen>>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let thread = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for status in statuses {
+                let mut request = match server.recv_timeout(Duration::from_secs(5)).unwrap() {
+                    Some(request) => request,
+                    None => break,
+                };
+                let key = request.headers().iter().find(|h| h.field.equiv("X-Key")).map(|h| h.value.as_str().to_string());
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                seen.push((request.url().to_string(), body, key));
+                request.respond(tiny_http::Response::empty(status)).unwrap();
+            }
+            seen
+        });
+        (url, thread)
+    }
+
+    fn delivery(url: &str, body: &str) -> Delivery {
+        let now = Local::now();
+        Delivery {
+            url: url.to_string(),
+            body: body.to_string(),
+            queued: now,
+            attempts: 0,
+            next_attempt: now,
+        }
+    }
+
+    fn webhook(url: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            on: all_triggers(),
+            headers: vec![("X-Key".to_string(), "secret".to_string())].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn retries_in_order_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("hooks-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (url, target) = target(vec![500, 200, 200]);
+        let hook = format!("{}/hook", url);
+        let webhooks = vec![webhook(&hook)];
+        let day = chrono::Duration::days(1);
+
+        let queue = DiskQueue::open(&dir, "hooks").unwrap();
+        queue.push(&delivery(&hook, "first")).unwrap();
+        queue.push(&delivery(&hook, "second")).unwrap();
+        queue.push(&delivery(&format!("{}/removed", url), "nobody")).unwrap();
+
+        // the first one fails, so the second waits behind it
+        let wait = deliver(&queue, &webhooks, day);
+        assert_eq!(wait, Duration::from_secs(5));
+        let jobs = queue.jobs::<Delivery>();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!((jobs[0].1.body.as_str(), jobs[0].1.attempts), ("first", 1));
+        let next = jobs[0].1.next_attempt - Local::now();
+        assert!(next > chrono::Duration::seconds(4) && next <= chrono::Duration::seconds(5));
+        assert_eq!((jobs[1].1.body.as_str(), jobs[1].1.attempts), ("second", 0));
+
+        // not due yet
+        assert!(deliver(&queue, &webhooks, day) <= Duration::from_secs(5));
+        assert_eq!(queue.jobs::<Delivery>().len(), 2);
+
+        // a restart, after the backoff's run out
+        drop(queue);
+        let queue = DiskQueue::open(&dir, "hooks").unwrap();
+        let (path, mut first) = queue.jobs::<Delivery>().remove(0);
+        first.next_attempt = Local::now() - chrono::Duration::seconds(1);
+        queue::save(&path, &first).unwrap();
+        assert_eq!(deliver(&queue, &webhooks, day), Duration::from_secs(60));
+        assert!(queue.jobs::<Delivery>().is_empty());
+
+        let seen = target.join().unwrap();
+        let bodies: Vec<&str> = seen.iter().map(|(_, body, _)| body.as_str()).collect();
+        assert_eq!(bodies, vec!["first", "first", "second"]);
+        assert!(seen.iter().all(|(path, _, key)| path == "/hook" && key.as_deref() == Some("secret")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_refused_and_old_deliveries() {
+        let dir = std::env::temp_dir().join(format!("hooks-test-refused-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (url, target) = target(vec![400, 503]);
+        let refused = format!("{}/refused", url);
+        let down = format!("{}/down", url);
+        let webhooks = vec![webhook(&refused), webhook(&down)];
+
+        let queue = DiskQueue::open(&dir, "hooks").unwrap();
+        queue.push(&delivery(&refused, "no thanks")).unwrap();
+        let mut old = delivery(&down, "too late");
+        old.queued = Local::now() - chrono::Duration::hours(2);
+        queue.push(&old).unwrap();
+
+        deliver(&queue, &webhooks, chrono::Duration::hours(1));
+        assert!(queue.jobs::<Delivery>().is_empty());
+        assert_eq!(target.join().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_client_errors_are_final() {
+        let statuses = vec![400, 404, 408, 429, 500, 503, 204];
+        let (url, target) = target(statuses.clone());
+        let results: Vec<Result<(), bool>> = statuses
+            .iter()
+            .map(|_| post(&delivery(&url, "{}"), &HashMap::new()).map_err(|(_, retry)| retry))
+            .collect();
+        assert_eq!(results, vec![Err(false), Err(false), Err(true), Err(true), Err(true), Err(true), Ok(())]);
+        target.join().unwrap();
+
+        // nobody listening
+        let closed = format!("http://{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        assert!(matches!(post(&delivery(&closed, "{}"), &HashMap::new()), Err((_, true))));
+    }
+
+    #[test]
+    fn backs_off_up_to_an_hour() {
+        let secs: Vec<u64> = [0, 1, 2, 3, 8, 11, 1000].iter().map(|&a| backoff(a).as_secs()).collect();
+        assert_eq!(secs, vec![5, 5, 10, 20, 640, 3600, 3600]);
+    }
+
+    #[test]
+    fn kills_slow_scripts() {
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        let started = Instant::now();
+        run_script("sleep", command, Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,543 @@
+/*
+Small HTTP server for looking at cameras from a browser.
+
+GET /stream.mjpeg   multipart JPEG stream
+GET /snapshot.jpg   single JPEG
+GET /status         JSON about each camera
+GET /hls/<camera>/index.m3u8 and its segments, see hls.rs
+POST /whep/<camera> for WebRTC, see whep.rs
+/api/... for control, see api.rs
+
+Add ?camera=<name> to pick a camera, otherwise the first one is used.
+Each request gets its own thread since streams stay open indefinitely.
+
+With auth turned on, everything needs a login or token. Watching (streams,
+snapshots, status, GETs under /api) needs the view role, anything under /api
+that changes something needs admin. WebRTC sessions can be hung up by whoever
+started them, or an admin.
+*/
+use crate::api;
+use crate::auth::{Auth, AuthSettings, Denied, Role};
+use crate::hls::HlsSettings;
+use crate::live::{self, Live, MjpegStream};
+use crate::retention::Retention;
+use crate::tls::{self, TlsSettings};
+use crate::whep::Whep;
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig, StatusCode};
+
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    pub address: String,
+    /// Frames per second sent to stream clients
+    pub stream_fps: u32,
+    /// Segment H.264 for HLS players
+    pub hls: Option<HlsSettings>,
+    /// Answer WHEP offers
+    pub webrtc: bool,
+    /// Event index the API lists events from
+    pub index: Option<PathBuf>,
+    /// Hash chain log, events aren't deleted until they're in it
+    pub chain: Option<PathBuf>,
+    /// Users and tokens. None leaves the server open to anyone who can reach it.
+    pub auth: Option<AuthSettings>,
+    /// Serve HTTPS instead of HTTP
+    pub tls: Option<TlsSettings>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            address: "0.0.0.0:8080".to_string(),
+            stream_fps: 5,
+            hls: Some(HlsSettings::default()),
+            webrtc: true,
+            index: None,
+            chain: None,
+            auth: None,
+            tls: None,
+        }
+    }
+}
+
+/// Serves requests until the listener fails. Call from its own thread.
+pub fn serve(settings: HttpSettings, cameras: Vec<Arc<Live>>) -> Result<(), String> {
+    let server = match &settings.tls {
+        Some(tls_settings) => {
+            let (certificate, private_key) = tls::load(tls_settings)?;
+            Server::https(
+                &settings.address,
+                SslConfig {
+                    certificate: certificate,
+                    private_key: private_key,
+                },
+            )
+        }
+        None => Server::http(&settings.address),
+    }
+    .map_err(|e| format!("Unable to listen on {}: {}", settings.address, e))?;
+    let scheme = if settings.tls.is_some() { "https" } else { "http" };
+    println!("http: listening on {}://{}", scheme, settings.address);
+
+    let whep = if settings.webrtc { Some(Whep::new()?) } else { None };
+    let shared = Arc::new(Shared {
+        cameras: cameras,
+        whep: whep,
+        retention: Retention::new(settings.index.as_deref(), settings.chain.as_deref()),
+        auth: settings.auth.clone().map(Auth::new),
+    });
+    for request in server.incoming_requests() {
+        let shared = shared.clone();
+        std::thread::spawn(move || handle(request, &shared));
+    }
+    Ok(())
+}
+
+struct Shared {
+    cameras: Vec<Arc<Live>>,
+    whep: Option<Whep>,
+    retention: Retention,
+    auth: Option<Auth>,
+}
+
+fn handle(request: Request, shared: &Shared) {
+    let cameras = &shared.cameras[..];
+    let url = request.url().to_string();
+    let (path, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url.as_str(), ""),
+    };
+
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .map(|h| h.value.as_str().to_string());
+    // without auth, anyone who can reach the server can do anything
+    let mut role = Role::Admin;
+    if let (Some(auth), Some(needed)) = (&shared.auth, permission(request.method(), path)) {
+        match auth.check(authorization.as_deref(), needed) {
+            Ok(granted) => role = granted,
+            Err(Denied::Unauthorized) => {
+                respond(
+                    request,
+                    Response::from_string("Log in first")
+                        .with_status_code(401)
+                        .with_header(header("WWW-Authenticate", "Basic realm=\"camera\", charset=\"UTF-8\"")),
+                );
+                return;
+            }
+            Err(Denied::Forbidden) => {
+                respond(request, Response::from_string("Needs an admin").with_status_code(403));
+                return;
+            }
+        }
+    }
+
+    if let Some(whep_path) = path.strip_prefix("/whep/") {
+        match &shared.whep {
+            Some(w) => whep(request, cameras, w, whep_path, authorization.as_deref(), role),
+            None => respond(request, Response::from_string("Not found").with_status_code(404)),
+        }
+        return;
+    }
+
+    if let Some(api_path) = path.strip_prefix("/api/") {
+        api::handle(request, cameras, &shared.retention, api_path, query);
+        return;
+    }
+
+    if *request.method() != Method::Get {
+        respond(request, Response::empty(405));
+        return;
+    }
+
+    if path == "/status" {
+        let body = status(cameras).to_string();
+        respond(request, Response::from_string(body).with_header(header("Content-Type", "application/json")));
+        return;
+    }
+
+    if let Some(hls_path) = path.strip_prefix("/hls/") {
+        hls(request, cameras, hls_path);
+        return;
+    }
+
+    let camera = match find_camera(cameras, query) {
+        Some(camera) => camera.clone(),
+        None => {
+            respond(request, Response::from_string("No such camera").with_status_code(404));
+            return;
+        }
+    };
+
+    match path {
+        "/snapshot.jpg" => match camera.snapshot() {
+            Some(frame) => respond(
+                request,
+                Response::from_data(frame.jpeg.to_vec())
+                    .with_header(header("Content-Type", "image/jpeg"))
+                    .with_header(header("Cache-Control", "no-cache")),
+            ),
+            None => respond(request, Response::from_string("No frame from camera").with_status_code(503)),
+        },
+        "/stream.mjpeg" => {
+            let content_type = format!("multipart/x-mixed-replace; boundary={}", live::BOUNDARY);
+            let response = Response::new(
+                StatusCode(200),
+                vec![header("Content-Type", &content_type), header("Cache-Control", "no-cache")],
+                MjpegStream::new(camera),
+                None,
+                None,
+            );
+            // returns once the client goes away or the camera stops sending frames
+            respond(request, response);
+        }
+        _ => respond(request, Response::from_string("Not found").with_status_code(404)),
+    }
+}
+
+/// "<camera>/index.m3u8" or "<camera>/<sequence>.ts"
+fn hls(request: Request, cameras: &[Arc<Live>], path: &str) {
+    let (name, file) = match path.find('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (path, ""),
+    };
+    let camera = match cameras.iter().find(|c| c.name == name) {
+        Some(camera) => camera,
+        None => {
+            respond(request, Response::from_string("No such camera").with_status_code(404));
+            return;
+        }
+    };
+
+    if file == "index.m3u8" {
+        match camera.hls.m3u8() {
+            Some(m3u8) => respond(
+                request,
+                Response::from_string(m3u8)
+                    .with_header(header("Content-Type", "application/vnd.apple.mpegurl"))
+                    .with_header(header("Cache-Control", "no-cache"))
+                    .with_header(header("Access-Control-Allow-Origin", "*")),
+            ),
+            // players retry, and there'll be segments in a few seconds
+            None => respond(request, Response::from_string("Stream starting").with_status_code(503)),
+        }
+        return;
+    }
+
+    let segment = file
+        .strip_suffix(".ts")
+        .and_then(|sequence| sequence.parse().ok())
+        .and_then(|sequence| camera.hls.segment(sequence));
+    match segment {
+        Some(data) => respond(
+            request,
+            Response::from_data(data.to_vec())
+                .with_header(header("Content-Type", "video/mp2t"))
+                .with_header(header("Access-Control-Allow-Origin", "*")),
+        ),
+        None => respond(request, Response::from_string("Not found").with_status_code(404)),
+    }
+}
+
+/// POST "<camera>" with an offer, DELETE "<camera>/<session>". Sessions can
+/// only be hung up by whoever started them, or an admin.
+fn whep(mut request: Request, cameras: &[Arc<Live>], whep: &Whep, path: &str, authorization: Option<&str>, role: Role) {
+    let cors = |response: Response<_>| {
+        response
+            .with_header(header("Access-Control-Allow-Origin", "*"))
+            .with_header(header("Access-Control-Allow-Methods", "POST, DELETE, OPTIONS"))
+            .with_header(header("Access-Control-Allow-Headers", "Authorization, Content-Type"))
+            .with_header(header("Access-Control-Expose-Headers", "Location"))
+    };
+
+    match request.method() {
+        Method::Options => respond(request, cors(Response::from_data(Vec::new()).with_status_code(204))),
+        Method::Post => {
+            let camera = match cameras.iter().find(|c| c.name == path) {
+                Some(camera) => camera.clone(),
+                None => {
+                    respond(request, cors(Response::from_string("No such camera").with_status_code(404)));
+                    return;
+                }
+            };
+            let mut offer = String::new();
+            // an offer is a few kB, anything much bigger isn't one
+            if request.as_reader().take(64 * 1024).read_to_string(&mut offer).is_err() {
+                respond(request, cors(Response::from_string("Bad offer").with_status_code(400)));
+                return;
+            }
+            match whep.offer(camera, offer, authorization) {
+                Ok((id, answer)) => {
+                    let location = format!("/whep/{}/{}", path, id);
+                    respond(
+                        request,
+                        cors(Response::from_string(answer)
+                            .with_status_code(201)
+                            .with_header(header("Content-Type", "application/sdp"))
+                            .with_header(header("Location", &location))),
+                    );
+                }
+                Err(e) => respond(request, cors(Response::from_string(e).with_status_code(400))),
+            }
+        }
+        Method::Delete => {
+            let id = path.rsplit('/').next().unwrap_or("");
+            let status = match whep.started_by(id, authorization) {
+                None => 404,
+                Some(false) if role < Role::Admin => 403,
+                _ => {
+                    whep.delete(id);
+                    200
+                }
+            };
+            respond(request, cors(Response::from_data(Vec::new()).with_status_code(status)));
+        }
+        _ => respond(request, Response::empty(405)),
+    }
+}
+
+/// Role a request needs, None for ones anybody can make
+fn permission(method: &Method, path: &str) -> Option<Role> {
+    match method {
+        // CORS preflights never carry credentials
+        Method::Options if path.starts_with("/whep/") => None,
+        Method::Get => Some(Role::View),
+        _ if path.starts_with("/api/") => Some(Role::Admin),
+        _ => Some(Role::View),
+    }
+}
+
+fn respond<R: std::io::Read>(request: Request, response: Response<R>) {
+    // clients going away mid-response isn't worth reporting
+    let _ = request.respond(response);
+}
+
+pub fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Looks up "camera=<name>" in a query string
+pub fn find_camera<'a>(cameras: &'a [Arc<Live>], query: &str) -> Option<&'a Arc<Live>> {
+    match query_param(query, "camera") {
+        Some(name) => cameras.iter().find(|c| c.name == name),
+        None => cameras.first(),
+    }
+}
+
+/// Value of `name` in a query string, percent-decoded
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+        .next()?;
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => decoded.push(b),
+                    None => decoded.extend_from_slice(&bytes[i..i + 3]),
+                }
+                i += 3;
+                continue;
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    Some(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+fn status(cameras: &[Arc<Live>]) -> serde_json::Value {
+    let cameras: Vec<serde_json::Value> = cameras
+        .iter()
+        .map(|camera| {
+            let status = camera.status();
+            serde_json::json!({
+                "name": camera.name,
+                "last_frame": status.last_frame,
+                "event": status.event,
+                "tamper": status.tamper,
+                "stream_clients": camera.clients(),
+            })
+        })
+        .collect();
+    serde_json::json!({ "cameras": cameras })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{hash_password, User};
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn start(auth: Option<AuthSettings>) -> (String, Arc<Live>) {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let camera = Live::new("porch", 5);
+        let settings = HttpSettings {
+            address: address.clone(),
+            hls: None,
+            webrtc: false,
+            auth: auth,
+            ..Default::default()
+        };
+        let cameras = vec![camera.clone()];
+        std::thread::spawn(move || serve(settings, cameras));
+        for _ in 0..50 {
+            if TcpStream::connect(&address).is_ok() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        (address, camera)
+    }
+
+    /// Status line, headers and body of a request
+    fn request(address: &str, method: &str, path: &str, authorization: Option<&str>) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, address);
+        if let Some(authorization) = authorization {
+            head += &format!("Authorization: {}\r\n", authorization);
+        }
+        head += "Content-Length: 0\r\n\r\n";
+        stream.write_all(head.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, head, response[end + 4..].to_vec())
+    }
+
+    #[test]
+    fn snapshot_status_and_stream() {
+        let (address, camera) = start(None);
+        camera.publish(b"first jpeg".to_vec());
+
+        let (status, head, body) = request(&address, "GET", "/snapshot.jpg", None);
+        assert_eq!(status, 200);
+        assert!(head.contains("Content-Type: image/jpeg"));
+        assert_eq!(body, b"first jpeg");
+
+        assert_eq!(request(&address, "GET", "/snapshot.jpg?camera=garden", None).0, 404);
+        assert_eq!(request(&address, "POST", "/snapshot.jpg", None).0, 405);
+
+        let (status, _, body) = request(&address, "GET", "/status", None);
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["cameras"][0]["name"], "porch");
+        assert_eq!(json["cameras"][0]["stream_clients"], 0);
+
+        // tiny_http sends the stream in chunks of several kB, so the frames have to be real sized
+        let frame = |n: u8| {
+            let mut jpeg = vec![n; 20000];
+            jpeg[..4].copy_from_slice(&[0xFF, 0xD8, 0xFF, 0xE0]);
+            jpeg
+        };
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.write_all(b"GET /stream.mjpeg?camera=porch HTTP/1.1\r\nHost: camera\r\n\r\n").unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut n = 0;
+        while received.windows(7).filter(|w| w == b"--frame").count() < 3 {
+            n += 1;
+            camera.publish(frame(n));
+            std::thread::sleep(Duration::from_millis(50));
+            while let Ok(read) = stream.read(&mut buf) {
+                received.extend_from_slice(&buf[..read]);
+                if read < buf.len() {
+                    break;
+                }
+            }
+            assert!(n < 50, "no stream");
+        }
+        assert_eq!(camera.clients(), 1);
+        let received = String::from_utf8_lossy(&received);
+        assert!(received.contains("multipart/x-mixed-replace; boundary=frame"));
+        assert!(received.contains("--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 20000\r\n\r\n"));
+
+        drop(stream);
+        // the server notices on its next write
+        for i in 0..50 {
+            camera.publish(frame(i));
+            if camera.clients() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(camera.clients(), 0);
+    }
+
+    #[test]
+    fn needs_login() {
+        let auth = AuthSettings {
+            users: vec![
+                User {
+                    name: "alan".to_string(),
+                    password: hash_password("hunter2").unwrap(),
+                    role: Role::Admin,
+                },
+                User {
+                    name: "viewer".to_string(),
+                    password: hash_password("letmesee").unwrap(),
+                    role: Role::View,
+                },
+            ],
+            ..Default::default()
+        };
+        let (address, _camera) = start(Some(auth));
+        let basic = |name: &str, password: &str| {
+            use base64::Engine;
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", name, password))
+            )
+        };
+        let admin = basic("alan", "hunter2");
+        let viewer = basic("viewer", "letmesee");
+
+        let (status, head, _) = request(&address, "GET", "/status", None);
+        assert_eq!(status, 401);
+        assert!(head.contains("WWW-Authenticate: Basic"));
+        assert_eq!(request(&address, "GET", "/status", Some(&basic("alan", "wrong"))).0, 401);
+        // checking a password takes a while in debug builds, so the snapshot could go stale
+        assert_eq!(request(&address, "GET", "/status", Some(&viewer)).0, 200);
+        assert_eq!(request(&address, "GET", "/api/cameras", Some(&viewer)).0, 200);
+        assert_eq!(request(&address, "POST", "/api/cameras/porch/arm", Some(&viewer)).0, 403);
+        // nothing's taking commands from the camera in this test
+        assert_eq!(request(&address, "POST", "/api/cameras/porch/arm", Some(&admin)).0, 202);
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(permission(&Method::Get, "/stream.mjpeg"), Some(Role::View));
+        assert_eq!(permission(&Method::Get, "/api/events"), Some(Role::View));
+        assert_eq!(permission(&Method::Put, "/api/cameras/porch/settings"), Some(Role::Admin));
+        assert_eq!(permission(&Method::Delete, "/api/events/porch-20210830-153012-250"), Some(Role::Admin));
+        assert_eq!(permission(&Method::Post, "/whep/porch"), Some(Role::View));
+        assert_eq!(permission(&Method::Delete, "/whep/porch/abc"), Some(Role::View));
+        assert_eq!(permission(&Method::Options, "/whep/porch"), None);
+        assert_eq!(permission(&Method::Options, "/api/cameras"), Some(Role::Admin));
+    }
+
+    #[test]
+    fn query_params() {
+        assert_eq!(query_param("camera=front%20door&x=1", "camera"), Some("front door".to_string()));
+        assert_eq!(query_param("label=a+cat", "label"), Some("a cat".to_string()));
+        assert_eq!(query_param("cameras=1", "camera"), None);
+        assert_eq!(query_param("", "camera"), None);
+    }
+}
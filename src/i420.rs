@@ -0,0 +1,117 @@
+/*
+The I420 frames motion detection runs on, from the resizer in pipeline.rs.
+
+Like every MMAL video port, the resizer pads each row out to a multiple of 32
+bytes and the height to a multiple of 16 lines, and the U and V planes come
+after the padded Y plane. So luma isn't just the first width * height bytes.
+Everything that reads these frames goes through Layout rather than working
+the offsets out for itself.
+*/
+
+/// Rows padded to 32 bytes and height to 16 lines, the way MMAL ports lay out I420
+pub fn padded(width: u32, height: u32) -> (u32, u32) {
+    ((width + 31) & !31, (height + 15) & !15)
+}
+
+/// Where everything is in a frame of a given size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    stride: usize,
+    rows: usize,
+}
+
+impl Layout {
+    pub fn new(width: u32, height: u32) -> Layout {
+        let (stride, rows) = padded(width, height);
+        Layout {
+            width: width,
+            height: height,
+            stride: stride as usize,
+            rows: rows as usize,
+        }
+    }
+
+    /// Bytes in a whole frame, padding included
+    pub fn size(&self) -> usize {
+        self.v_plane() + self.chroma_len()
+    }
+
+    fn u_plane(&self) -> usize {
+        self.stride * self.rows
+    }
+
+    fn v_plane(&self) -> usize {
+        self.u_plane() + self.chroma_len()
+    }
+
+    fn chroma_len(&self) -> usize {
+        (self.stride / 2) * (self.rows / 2)
+    }
+
+    /// The luma plane with the padding taken out, width * height bytes.
+    /// Shorter if the frame is.
+    pub fn luma(&self, frame: &[u8]) -> Vec<u8> {
+        let width = self.width as usize;
+        let mut luma = Vec::with_capacity(width * self.height as usize);
+        for y in 0..self.height as usize {
+            match frame.get(y * self.stride..y * self.stride + width) {
+                Some(row) => luma.extend_from_slice(row),
+                None => break,
+            }
+        }
+        luma
+    }
+
+    /// RGB at (x, y), unclamped. Gray if the frame stops after the Y plane.
+    pub fn rgb(&self, frame: &[u8], x: usize, y: usize) -> [f32; 3] {
+        let luma = *frame.get(y * self.stride + x).unwrap_or(&0) as f32;
+        if frame.len() < self.size() {
+            return [luma, luma, luma];
+        }
+        let chroma = (y / 2) * (self.stride / 2) + x / 2;
+        let u = frame[self.u_plane() + chroma] as f32 - 128.0;
+        let v = frame[self.v_plane() + chroma] as f32 - 128.0;
+        [luma + 1.402 * v, luma - 0.344 * u - 0.714 * v, luma + 1.772 * u]
+    }
+
+    /// A gray frame with this layout around `luma`, which is width * height bytes
+    #[cfg(test)]
+    pub fn frame(&self, luma: &[u8]) -> Vec<u8> {
+        let width = self.width as usize;
+        let mut frame = vec![128; self.size()];
+        for (y, row) in luma.chunks(width).enumerate() {
+            frame[y * self.stride..y * self.stride + row.len()].copy_from_slice(row);
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding() {
+        let layout = Layout::new(100, 50);
+        assert_eq!(padded(100, 50), (128, 64));
+        assert_eq!(layout.size(), 128 * 64 * 3 / 2);
+
+        let luma: Vec<u8> = (0..100 * 50).map(|i| (i % 7) as u8).collect();
+        let mut frame = layout.frame(&luma);
+        assert_eq!(layout.luma(&frame), luma);
+        assert_eq!(layout.rgb(&frame, 3, 0), [3.0, 3.0, 3.0]);
+
+        // U for the 2x2 block at (2, 0)
+        frame[128 * 64 + 1] = 228;
+        let rgb = layout.rgb(&frame, 3, 0);
+        assert_eq!(rgb[0], 3.0);
+        assert!(rgb[2] > 150.0);
+
+        // only the Y plane
+        assert_eq!(layout.rgb(&frame[..128 * 64], 3, 0), [3.0, 3.0, 3.0]);
+        // a frame that's cut short
+        assert_eq!(layout.luma(&frame[..128 * 10]).len(), 100 * 10);
+    }
+}
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+
+/// Exposure values the camera reports back through MMAL_PARAMETER_CAMERA_SETTINGS
+/// whenever AGC/AWB changes something
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraGains {
+    /// In microseconds
+    pub exposure: u32,
+    pub analog_gain: f32,
+    pub digital_gain: f32,
+    pub awb_red_gain: f32,
+    pub awb_blue_gain: f32,
+}
+
+impl CameraGains {
+    /// How much light the sensor is being told to make up for
+    pub fn total(&self) -> f32 {
+        self.exposure as f32 * self.analog_gain * self.digital_gain
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IlluminationSettings {
+    /// Fractional change in mean luma from one frame to the next that counts
+    /// as the lights changing, rather than something moving
+    pub max_luma_jump: f32,
+    /// Fractional change in exposure * gain, or in either AWB gain, since the last baseline
+    pub max_gain_change: f32,
+    /// AGC takes a while to settle, so ignore motion for this many frames after a change
+    pub settle_frames: u32,
+}
+
+impl Default for IlluminationSettings {
+    fn default() -> Self {
+        IlluminationSettings {
+            max_luma_jump: 0.15,
+            max_gain_change: 0.25,
+            settle_frames: 15,
+        }
+    }
+}
+
+/// Spots global lighting changes (clouds, lights switching on, AGC/AWB shifts)
+/// so they don't get treated as motion
+pub struct IlluminationWatch {
+    settings: IlluminationSettings,
+    previous_luma: Option<f32>,
+    baseline: Option<CameraGains>,
+    settling: u32,
+}
+
+impl IlluminationWatch {
+    pub fn new(settings: IlluminationSettings) -> IlluminationWatch {
+        IlluminationWatch {
+            settings: settings,
+            previous_luma: None,
+            baseline: None,
+            settling: 0,
+        }
+    }
+
+    /// Returns true while motion should be ignored, meaning the motion detector
+    /// should re-baseline on the current frame instead
+    pub fn unstable(&mut self, mean_luma: f32, gains: Option<CameraGains>) -> bool {
+        let mut changed = false;
+
+        if let Some(previous) = self.previous_luma {
+            if previous > 0.0 && ((mean_luma - previous) / previous).abs() > self.settings.max_luma_jump {
+                changed = true;
+            }
+        }
+        self.previous_luma = Some(mean_luma);
+
+        if let Some(gains) = gains {
+            match self.baseline {
+                Some(baseline) => {
+                    let max = self.settings.max_gain_change;
+                    // AWB gains sit anywhere from about 1 to 3, so they're compared
+                    // relatively like everything else
+                    if relative_change(baseline.total(), gains.total()) > max
+                        || relative_change(baseline.awb_red_gain, gains.awb_red_gain) > max
+                        || relative_change(baseline.awb_blue_gain, gains.awb_blue_gain) > max
+                    {
+                        changed = true;
+                        self.baseline = Some(gains);
+                    }
+                }
+                None => self.baseline = Some(gains),
+            }
+        }
+
+        if changed {
+            self.settling = self.settings.settle_frames;
+        }
+        if self.settling > 0 {
+            self.settling -= 1;
+            return true;
+        }
+        false
+    }
+}
+
+/// How far `after` is from `before`, as a fraction of `before`. Nothing to
+/// compare against counts as no change.
+fn relative_change(before: f32, after: f32) -> f32 {
+    if before > 0.0 {
+        ((after - before) / before).abs()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gains(awb_red_gain: f32) -> CameraGains {
+        CameraGains {
+            exposure: 10000,
+            analog_gain: 1.0,
+            digital_gain: 1.0,
+            awb_red_gain: awb_red_gain,
+            awb_blue_gain: 1.5,
+        }
+    }
+
+    #[test]
+    fn awb_changes_are_relative() {
+        let mut watch = IlluminationWatch::new(IlluminationSettings {
+            settle_frames: 1,
+            ..IlluminationSettings::default()
+        });
+        assert!(!watch.unstable(100.0, Some(gains(3.0))));
+        // 0.5 is a big step at 1.0 but not at 3.0
+        assert!(!watch.unstable(100.0, Some(gains(3.5))));
+        assert!(watch.unstable(100.0, Some(gains(4.5))));
+        assert!(!watch.unstable(100.0, Some(gains(4.5))));
+    }
+}
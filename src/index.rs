@@ -0,0 +1,282 @@
+/*
+An append-only index of events, one JSON object per line.
+
+Every camera appends to the same file when an event ends, so UIs and scripts
+can find events by camera and time without crawling the recording directories.
+It's recordings/events.jsonl unless index.json says otherwise:
+
+{ "path": "/mnt/usb/events.jsonl" }
+
+and { "path": null } turns it off. The API and the uploader use the same one.
+Writes go through one lock, so lines from different camera threads don't
+interleave and a delete rewriting the file doesn't lose an append.
+*/
+use crate::event::MotionEvent;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+static WRITE: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// One line of the index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    #[serde(flatten)]
+    pub event: MotionEvent,
+    /// Everything in the recording directory belonging to the event
+    pub files: Vec<EventFile>,
+}
+
+/// What to look for. Empty fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub camera: Option<String>,
+    /// Events that were still going at or after this time
+    pub from: Option<DateTime<Local>>,
+    /// Events that started before this time
+    pub to: Option<DateTime<Local>>,
+    /// Events the classifier saw this in, like "person"
+    pub label: Option<String>,
+}
+
+impl IndexEntry {
+    /// The event along with the files in `dir` named after it
+    pub fn new(event: &MotionEvent, dir: &Path) -> io::Result<IndexEntry> {
+        Ok(IndexEntry {
+            event: event.clone(),
+            files: event_files(event, dir)?,
+        })
+    }
+}
+
+impl Query {
+    pub fn matches(&self, event: &MotionEvent) -> bool {
+        if let Some(camera) = &self.camera {
+            if &event.camera != camera {
+                return false;
+            }
+        }
+        if let Some(from) = self.from {
+            if event.ended.unwrap_or(event.started) < from {
+                return false;
+            }
+        }
+        if let Some(to) = self.to {
+            if event.started >= to {
+                return false;
+            }
+        }
+        if let Some(label) = &self.label {
+            if !event.labels.iter().any(|l| &l.label == label) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// From index.json
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IndexSettings {
+    /// None for no index
+    pub path: Option<PathBuf>,
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        IndexSettings {
+            path: Some(PathBuf::from("recordings").join("events.jsonl")),
+        }
+    }
+}
+
+pub struct EventIndex {
+    path: PathBuf,
+}
+
+impl EventIndex {
+    pub fn new(path: &Path) -> EventIndex {
+        EventIndex {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Adds a finished event, along with the files in `dir` named after it
+    pub fn append(&self, event: &MotionEvent, dir: &Path) -> io::Result<IndexEntry> {
+        let entry = IndexEntry::new(event, dir)?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let _lock = WRITE.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(entry)
+    }
+
+    /// Events matching the query, oldest first
+    pub fn query(&self, query: &Query) -> io::Result<Vec<IndexEntry>> {
+        let mut entries: Vec<IndexEntry> = self.entries()?.into_iter().filter(|e| query.matches(&e.event)).collect();
+        entries.sort_by_key(|e| e.event.started);
+        Ok(entries)
+    }
+
+    /// The event with the given name, like "camera0_20210830-153012-250"
+    pub fn get(&self, name: &str) -> io::Result<Option<IndexEntry>> {
+        Ok(self.entries()?.into_iter().find(|e| e.event.name() == name))
+    }
+
+    /// Deletes an event's files and drops it from the index.
+    /// Returns what was removed, None if there was no such event.
+    pub fn remove(&self, name: &str) -> io::Result<Option<IndexEntry>> {
+        let _lock = WRITE.lock().unwrap();
+        let (removed, kept): (Vec<IndexEntry>, Vec<IndexEntry>) = self.entries()?.into_iter().partition(|e| e.event.name() == name);
+        let entry = match removed.into_iter().next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        for file in &entry.files {
+            match fs::remove_file(&file.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        // write the rest out to a new file and swap it in, so a crash can't leave half an index
+        let temp = self.path.with_extension("jsonl.tmp");
+        let mut out = String::new();
+        for entry in &kept {
+            out.push_str(&serde_json::to_string(entry)?);
+            out.push('\n');
+        }
+        fs::write(&temp, out)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(Some(entry))
+    }
+
+    fn entries(&self) -> io::Result<Vec<IndexEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            // lines that don't parse (like one cut short by a crash) are skipped
+            if let Ok(entry) = serde_json::from_str::<IndexEntry>(&line?) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Files like "camera0_20210830-153012-250.h264" and "camera0_20210830-153012-250-thumb.jpg"
+fn event_files(event: &MotionEvent, dir: &Path) -> io::Result<Vec<EventFile>> {
+    let name = event.name();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if !file_name.starts_with(&name) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push(EventFile {
+                path: entry.path(),
+                size: metadata.len(),
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Label;
+    use chrono::TimeZone;
+
+    fn event(camera: &str, minute: u32, label: Option<&str>) -> MotionEvent {
+        let mut event = MotionEvent::new(camera);
+        event.started = Local.with_ymd_and_hms(2021, 8, 30, 15, minute, 0).unwrap();
+        event.ended = Some(event.started + chrono::Duration::seconds(90));
+        if let Some(label) = label {
+            event.add_labels(&[Label {
+                label: label.to_string(),
+                confidence: 0.9,
+            }]);
+        }
+        event
+    }
+
+    fn names(entries: &[IndexEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.event.name()).collect()
+    }
+
+    #[test]
+    fn query_and_remove() {
+        let dir = std::env::temp_dir().join(format!("index-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let index = EventIndex::new(&dir.join("index.jsonl"));
+
+        let porch = event("porch", 30, Some("person"));
+        let drive = event("drive", 10, None);
+        let later = event("porch", 50, Some("cat"));
+        for e in [&porch, &drive, &later].iter() {
+            fs::write(dir.join(format!("{}.h264", e.name())), b"video").unwrap();
+            fs::write(dir.join(format!("{}-thumb.jpg", e.name())), b"jpeg").unwrap();
+            index.append(e, &dir).unwrap();
+        }
+        // a line cut short by a crash
+        OpenOptions::new().append(true).open(dir.join("index.jsonl")).unwrap().write_all(b"{\"camera\":").unwrap();
+
+        let all = index.query(&Query::default()).unwrap();
+        assert_eq!(names(&all), vec![drive.name(), porch.name(), later.name()]);
+        assert_eq!(all[1].files.len(), 2);
+        assert_eq!(all[1].files.iter().map(|f| f.size).sum::<u64>(), 9);
+
+        let query = |camera: Option<&str>, from: Option<u32>, to: Option<u32>, label: Option<&str>| Query {
+            camera: camera.map(String::from),
+            from: from.map(|m| Local.with_ymd_and_hms(2021, 8, 30, 15, m, 0).unwrap()),
+            to: to.map(|m| Local.with_ymd_and_hms(2021, 8, 30, 15, m, 0).unwrap()),
+            label: label.map(String::from),
+        };
+        assert_eq!(names(&index.query(&query(Some("porch"), None, None, None)).unwrap()), vec![porch.name(), later.name()]);
+        // still going at 15:11, so it counts
+        assert_eq!(names(&index.query(&query(None, Some(11), Some(30), None)).unwrap()), vec![drive.name()]);
+        assert_eq!(names(&index.query(&query(None, None, None, Some("cat"))).unwrap()), vec![later.name()]);
+        assert!(index.query(&query(Some("drive"), None, None, Some("person"))).unwrap().is_empty());
+
+        let removed = index.remove(&porch.name()).unwrap().unwrap();
+        assert_eq!(removed.event.name(), porch.name());
+        for file in &removed.files {
+            assert!(!file.path.exists());
+        }
+        assert!(dir.join(format!("{}.h264", drive.name())).exists());
+        assert!(index.get(&porch.name()).unwrap().is_none());
+        assert_eq!(index.get(&later.name()).unwrap().unwrap().event.name(), later.name());
+        assert!(index.remove(&porch.name()).unwrap().is_none());
+        assert_eq!(names(&index.query(&Query::default()).unwrap()), vec![drive.name(), later.name()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
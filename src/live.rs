@@ -0,0 +1,244 @@
+/*
+State a camera thread shares with the servers.
+
+The camera thread only ever publishes into here and never waits on a client.
+Clients wait on the condvar for the next frame, so a slow or disconnecting
+client just misses frames instead of holding up recording.
+*/
+use crate::event::EventSummary;
+use crate::h264::VideoFeed;
+use crate::hls::Playlist;
+use crate::monitor::Change;
+use crate::motion::MotionSettings;
+use crate::settings::CameraSettings;
+use crate::tamper::TamperEvent;
+
+use chrono::{DateTime, Local};
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Latest JPEG from a camera
+#[derive(Clone)]
+pub struct Frame {
+    pub sequence: u64,
+    pub time: DateTime<Local>,
+    pub jpeg: Arc<Vec<u8>>,
+}
+
+/// What the camera is up to, for /status
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub last_frame: Option<DateTime<Local>>,
+    /// The event in progress
+    pub event: Option<EventSummary>,
+    pub settings: Option<CameraSettings>,
+    pub motion: Option<MotionSettings>,
+    pub armed: bool,
+    pub tamper: Option<TamperEvent>,
+}
+
+/// Things the API asks a camera thread to do. They're picked up between frames.
+pub enum Command {
+    /// Exposure, annotation and capture settings. Sizes can't change while running.
+    Settings(CameraSettings),
+    Motion(MotionSettings),
+    Arm(bool),
+    /// Record an event now, for at least this long
+    Record(Duration),
+    /// Take a still, replying with where it went
+    Still(Sender<Result<PathBuf, String>>),
+}
+
+pub struct Live {
+    pub name: String,
+    /// H.264 from the video encoder
+    pub video: VideoFeed,
+    /// Recent HLS segments of that video
+    pub hls: Playlist,
+    frame: Mutex<Option<Frame>>,
+    ready: Condvar,
+    status: Mutex<Status>,
+    clients: AtomicUsize,
+    /// Time between frames for stream clients
+    interval: Duration,
+    published: Mutex<Option<Instant>>,
+    // a snapshot request keeps frames coming for a little while
+    snapshot_requested: Mutex<Option<Instant>>,
+    commands: Mutex<Sender<Command>>,
+    receiver: Mutex<Option<Receiver<Command>>>,
+    changes: Mutex<Vec<Sender<Change>>>,
+}
+
+impl Live {
+    pub fn new(name: &str, fps: u32) -> Arc<Live> {
+        let (commands, receiver) = mpsc::channel();
+        Arc::new(Live {
+            name: name.to_string(),
+            video: VideoFeed::new(),
+            hls: Playlist::new(),
+            frame: Mutex::new(None),
+            ready: Condvar::new(),
+            status: Mutex::new(Status::default()),
+            clients: AtomicUsize::new(0),
+            interval: Duration::from_millis(1000 / fps.max(1) as u64),
+            published: Mutex::new(None),
+            snapshot_requested: Mutex::new(None),
+            commands: Mutex::new(commands),
+            receiver: Mutex::new(Some(receiver)),
+            changes: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether anyone is waiting on a JPEG and it's time for another one.
+    /// Encoding isn't free, so the camera thread checks this before doing it.
+    pub fn wants_frame(&self) -> bool {
+        if let Some(published) = *self.published.lock().unwrap() {
+            if published.elapsed() < self.interval {
+                return false;
+            }
+        }
+        if self.clients() > 0 {
+            return true;
+        }
+        match *self.snapshot_requested.lock().unwrap() {
+            Some(requested) => requested.elapsed() < Duration::from_secs(5),
+            None => false,
+        }
+    }
+
+    pub fn publish(&self, jpeg: Vec<u8>) {
+        *self.published.lock().unwrap() = Some(Instant::now());
+        let mut frame = self.frame.lock().unwrap();
+        let sequence = frame.as_ref().map(|f| f.sequence + 1).unwrap_or(0);
+        *frame = Some(Frame {
+            sequence: sequence,
+            time: Local::now(),
+            jpeg: Arc::new(jpeg),
+        });
+        self.ready.notify_all();
+    }
+
+    /// Waits for a frame newer than `after`
+    pub fn next_frame(&self, after: Option<u64>, timeout: Duration) -> Option<Frame> {
+        let frame = self.frame.lock().unwrap();
+        let (frame, _) = self
+            .ready
+            .wait_timeout_while(frame, timeout, |frame| match (frame.as_ref(), after) {
+                (Some(frame), Some(after)) => frame.sequence <= after,
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .unwrap();
+        match (frame.as_ref(), after) {
+            (Some(f), Some(after)) if f.sequence > after => Some(f.clone()),
+            (Some(f), None) => Some(f.clone()),
+            _ => None,
+        }
+    }
+
+    /// A recent frame, asking the camera thread for one if what we have is stale
+    pub fn snapshot(&self) -> Option<Frame> {
+        let current = self.frame.lock().unwrap().clone();
+        if let Some(frame) = current.as_ref() {
+            if Local::now() - frame.time < chrono::Duration::seconds(1) {
+                return current;
+            }
+        }
+        *self.snapshot_requested.lock().unwrap() = Some(Instant::now());
+        self.next_frame(current.map(|f| f.sequence), Duration::from_secs(3))
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// False if the camera thread has gone away
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.lock().unwrap().send(command).is_ok()
+    }
+
+    /// The receiving end of `send`, for the camera thread. Only the first caller gets it.
+    pub fn take_commands(&self) -> Option<Receiver<Command>> {
+        self.receiver.lock().unwrap().take()
+    }
+
+    /// Events starting and ending, from now on
+    pub fn subscribe_changes(&self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.changes.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// For the camera thread. Events are few and far between, so nobody gets dropped for being slow.
+    pub fn notify(&self, change: &Change) {
+        self.changes.lock().unwrap().retain(|sender| sender.send(change.clone()).is_ok());
+    }
+
+    pub fn update_status<F: FnOnce(&mut Status)>(&self, f: F) {
+        f(&mut self.status.lock().unwrap());
+    }
+}
+
+/// multipart/x-mixed-replace body, one JPEG part per frame.
+///
+/// Counts as a client for as long as it's alive, so dropping it when the
+/// connection goes away is what stops the camera thread encoding for it.
+pub struct MjpegStream {
+    live: Arc<Live>,
+    sequence: Option<u64>,
+    part: Vec<u8>,
+    position: usize,
+}
+
+pub const BOUNDARY: &str = "frame";
+
+impl MjpegStream {
+    pub fn new(live: Arc<Live>) -> MjpegStream {
+        live.clients.fetch_add(1, Ordering::SeqCst);
+        MjpegStream {
+            live: live,
+            sequence: None,
+            part: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for MjpegStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.part.len() {
+            let frame = self
+                .live
+                .next_frame(self.sequence, Duration::from_secs(10))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "No frames from camera"))?;
+            self.sequence = Some(frame.sequence);
+            self.part = format!(
+                "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                frame.jpeg.len()
+            )
+            .into_bytes();
+            self.part.extend_from_slice(&frame.jpeg);
+            self.part.extend_from_slice(b"\r\n");
+            self.position = 0;
+        }
+        let n = buf.len().min(self.part.len() - self.position);
+        buf[..n].copy_from_slice(&self.part[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Drop for MjpegStream {
+    fn drop(&mut self) {
+        self.live.clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -3,10 +3,70 @@ use std::mem;
 use std::mem::MaybeUninit;
 use std::os::raw::c_char;
 use std::ptr::NonNull;
-use std::sync::{Once, ONCE_INIT};
-
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::{mpsc, Arc, Mutex, Once, ONCE_INIT};
+use std::time::{Duration, Instant};
+
+mod annotate;
+mod api;
+mod auth;
+mod capture;
+mod chain;
+mod classify;
+mod config;
+mod control;
+mod encoder;
+mod encryption;
+mod event;
+mod exif;
+mod h264;
+mod hex;
+mod hls;
+mod hooks;
+mod i420;
 mod ffi;
+mod http;
+mod illumination;
+mod index;
+mod live;
+mod monitor;
+mod motion;
+mod mqtt;
+mod notify;
+mod pipeline;
+mod queue;
+mod raw;
+mod recorder;
+mod retention;
+mod rtp;
+mod rtsp;
+mod schedule;
 mod settings;
+mod tamper;
+mod thumbnail;
+mod tiff;
+mod tls;
+mod tracking;
+mod upload;
+mod ts;
+mod whep;
+
+use annotate::Annotator;
+use capture::{SnapshotSource, Timelapse};
+use encoder::ImageEncoder;
+use config::CameraConfig;
+use exif::Exif;
+use h264::AccessUnit;
+use http::HttpSettings;
+use illumination::CameraGains;
+use live::{Command, Live};
+use rtsp::RtspSettings;
+use monitor::{Change, Monitor};
+use motion::MotionSettings;
+use pipeline::VideoPipeline;
+use settings::{Annotation, CameraSettings};
+use tls::TlsSettings;
 
 fn fourcc(a: char, b: char, c: char, d: char) -> u32 {
     let a: u32 = a as u32;
@@ -25,6 +85,7 @@ const MMAL_CAMERA_CAPTURE_PORT: isize = 2;
 // TODO: hoping the value of opaque is 0. couldn't find def in raspi userland repo
 const MMAL_ENCODING_OPAQUE: u32 = 0;
 const MMAL_ENCODING_JPEG: u32 = 1195724874; //fourcc('J', 'P', 'E', 'G');
+const MMAL_ENCODING_I420: u32 = 808596553; //fourcc('I', '4', '2', '0');
 
 
 struct CameraError {
@@ -52,11 +113,124 @@ impl fmt::Debug for CameraError {
 }
 
 
-fn camera_port_callback(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
-    println!("camera port callback");
-    unsafe { ffi::mmal_buffer_header_release(buffer); }
+// MMAL_EVENT_PARAMETER_CHANGED is a macro, so bindgen doesn't pick it up
+const MMAL_EVENT_PARAMETER_CHANGED: u32 = 1212371013; //fourcc('E', 'P', 'C', 'H');
+
+/// Control port callback. The camera sends MMAL_PARAMETER_CAMERA_SETTINGS through here
+/// whenever AGC/AWB changes. port->userdata points at the Mutex inside Camera.gains
+unsafe extern "C" fn camera_port_callback(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    if (*buffer).cmd == MMAL_EVENT_PARAMETER_CHANGED {
+        let param = (*buffer).data as *const ffi::MMAL_EVENT_PARAMETER_CHANGED_T;
+        if (*param).hdr.id == ffi::MMAL_PARAMETER_CAMERA_SETTINGS as u32 && !(*port).userdata.is_null() {
+            let settings = &*((*buffer).data as *const ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T);
+            let gains = &*((*port).userdata as *const Mutex<Option<CameraGains>>);
+            if let Ok(mut gains) = gains.lock() {
+                *gains = Some(CameraGains {
+                    exposure: settings.exposure,
+                    analog_gain: rational(settings.analog_gain),
+                    digital_gain: rational(settings.digital_gain),
+                    awb_red_gain: rational(settings.awb_red_gain),
+                    awb_blue_gain: rational(settings.awb_blue_gain),
+                });
+            }
+        }
+    }
+    ffi::mmal_buffer_header_release(buffer);
+}
+
+/// Collects encoder output for Camera::capture(). port->userdata points at one of these
+struct CaptureData {
+    data: Vec<u8>,
+    pool: *mut ffi::MMAL_POOL_T,
+    done: mpsc::Sender<bool>,
 }
 
+/// Encoder output port callback, RaspiStill's encoder_buffer_callback()
+unsafe extern "C" fn encoder_buffer_callback(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    let capture = &*((*port).userdata as *const Mutex<CaptureData>);
+    let mut capture = match capture.lock() {
+        Ok(capture) => capture,
+        Err(_) => {
+            ffi::mmal_buffer_header_release(buffer);
+            return;
+        }
+    };
+
+    if (*buffer).length > 0 {
+        ffi::mmal_buffer_header_mem_lock(buffer);
+        let bytes = slice::from_raw_parts((*buffer).data.offset((*buffer).offset as isize), (*buffer).length as usize);
+        capture.data.extend_from_slice(bytes);
+        ffi::mmal_buffer_header_mem_unlock(buffer);
+    }
+
+    let flags = (*buffer).flags;
+    let failed = flags & ffi::MMAL_BUFFER_HEADER_FLAG_TRANSMISSION_FAILED != 0;
+    let complete = failed || flags & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END != 0;
+
+    ffi::mmal_buffer_header_release(buffer);
+
+    // hand the port a fresh buffer so it can keep going
+    if (*port).is_enabled != 0 {
+        let new_buffer = ffi::mmal_queue_get((*capture.pool).queue);
+        if new_buffer.is_null() || ffi::mmal_port_send_buffer(port, new_buffer) != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            println!("Unable to return a buffer to the encoder port");
+        }
+    }
+
+    if complete {
+        let _ = capture.done.send(!failed);
+    }
+}
+
+fn rational(r: ffi::MMAL_RATIONAL_T) -> f32 {
+    if r.den == 0 {
+        return 0.0;
+    }
+    r.num as f32 / r.den as f32
+}
+
+
+/// What Camera::new has made so far. Dropping it tears it all down again,
+/// so returning an error partway through doesn't leak components or leave
+/// the control port calling back into freed memory.
+struct Setup {
+    camera: Option<NonNull<ffi::MMAL_COMPONENT_T>>,
+    encoder: Option<NonNull<ffi::MMAL_COMPONENT_T>>,
+    pool: Option<NonNull<ffi::MMAL_POOL_T>>,
+    connection: Option<NonNull<ffi::MMAL_CONNECTION_T>>,
+}
+
+impl Setup {
+    /// Setup succeeded, the Camera is responsible for all of it now
+    fn keep(&mut self) {
+        self.camera = None;
+        self.encoder = None;
+        self.pool = None;
+        self.connection = None;
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(connection) = self.connection {
+                ffi::mmal_connection_destroy(connection.as_ptr());
+            }
+            if let (Some(encoder), Some(pool)) = (self.encoder, self.pool) {
+                ffi::mmal_port_pool_destroy(*encoder.as_ref().output, pool.as_ptr());
+            }
+            for component in [self.encoder, self.camera].iter().flatten() {
+                let control = component.as_ref().control;
+                if (*control).is_enabled != 0 {
+                    ffi::mmal_port_disable(control);
+                }
+                (*control).userdata = std::ptr::null_mut();
+                ffi::mmal_component_disable(component.as_ptr());
+                ffi::mmal_component_destroy(component.as_ptr());
+            }
+        }
+    }
+}
 
 /*
 TODO: could I use an enum for each of these componenets?
@@ -67,12 +241,23 @@ That might have some tricy edge cases, but I probably don't really understand ho
 struct Camera {
     camera: NonNull<ffi::MMAL_COMPONENT_T>,
     camera_enabled: bool,
+    // latest exposure/gain readings from the control port callback
+    gains: Arc<Mutex<Option<CameraGains>>>,
+    annotator: Option<Annotator>,
     encoder: NonNull<ffi::MMAL_COMPONENT_T>,
-    encoder_enabled: bool
+    encoder_enabled: bool,
+    // buffers for the encoder output port
+    pool: NonNull<ffi::MMAL_POOL_T>,
+    // still port -> encoder input
+    connection: NonNull<ffi::MMAL_CONNECTION_T>,
+    // turns video frames into JPEGs, created the first time it's needed
+    frame_encoder: Option<ImageEncoder>,
+    // H.264 and motion frames off the video port, once start_video() has run
+    video: Option<VideoPipeline>
 }
 
 impl Camera {
-    pub fn new() -> Result<Camera, CameraError> {
+    pub fn new(settings: &CameraSettings) -> Result<Camera, CameraError> {
 
         // BEGIN CAMERA COMPONENT STUFF
 
@@ -89,18 +274,29 @@ impl Camera {
         }
         let camera_ptr: *mut ffi::MMAL_COMPONENT_T = unsafe { camera_ptr.assume_init() };
         let camera = NonNull::new(camera_ptr).unwrap();
+
+        // The control port callback stashes exposure/gain readings here, the Arc
+        // keeps the Mutex at a fixed address while userdata points at it. Made
+        // before `setup` so it outlives the control port if setup fails.
+        let gains: Arc<Mutex<Option<CameraGains>>> = Arc::new(Mutex::new(None));
+        let mut setup = Setup {
+            camera: Some(camera),
+            encoder: None,
+            pool: None,
+            connection: None,
+        };
        
         
         // choose which camera to read from
         let mut param: ffi::MMAL_PARAMETER_INT32_T = unsafe { mem::zeroed() };
         param.hdr.id = ffi::MMAL_PARAMETER_CAMERA_NUM as u32;
         param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_INT32_T>() as u32;
-        param.value = 0 as i32; // believe this chooses the default camera
+        param.value = settings.camera_num; // 0 is the default camera, CM boards also have 1
         let status = unsafe { ffi::mmal_port_parameter_set(camera.as_ref().control, &param.hdr) };
         if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
             return Err(CameraError {
                 code: 1,
-                message: "Unable to set camera number".to_string()
+                message: format!("Unable to set camera number to {}", settings.camera_num)
             })
         }
 
@@ -114,18 +310,6 @@ impl Camera {
             })
         }
         
-        // TODO: figure out how to use rust callback with C library
-        /*
-        // Enable camera port and pass it the callback function
-        let status = unsafe { ffi::mmal_port_enable(camera.as_ref().control, Some(camera_port_callback)) };
-        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
-            return Err(CameraError {
-                code: 1,
-                message: "Unable to enable camera".to_string()
-            })
-        }
-        */
-        
         // Set up camera configuration
         /*
         enabled: false,
@@ -146,16 +330,19 @@ impl Camera {
         use_encoder: false,
         */
 
-        let w = 800;
-        let h = 600;
+        let w = if settings.width > 0 { settings.width } else { 800 };
+        let h = if settings.height > 0 { settings.height } else { 600 };
+        // stills can be bigger than video, so snapshots get the full sensor resolution
+        let sw = if settings.still_width > 0 { settings.still_width } else { w };
+        let sh = if settings.still_height > 0 { settings.still_height } else { h };
         
         let mut cfg: ffi::MMAL_PARAMETER_CAMERA_CONFIG_T = unsafe { mem::zeroed() };
         cfg.hdr.id = ffi::MMAL_PARAMETER_CAMERA_CONFIG as u32;
         cfg.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_CAMERA_CONFIG_T>() as u32;
         
         // https://github.com/raspberrypi/userland/blob/master/host_applications/linux/apps/raspicam/RaspiStillYUV.c#L706
-        cfg.max_stills_w = w;
-        cfg.max_stills_h = h;
+        cfg.max_stills_w = sw;
+        cfg.max_stills_h = sh;
         cfg.stills_yuv422 = 0;
         cfg.one_shot_stills = 1;
         cfg.max_preview_video_w = w;
@@ -182,32 +369,25 @@ impl Camera {
         
         
         let still_port_ptr = unsafe { *(camera_outputs.offset(MMAL_CAMERA_CAPTURE_PORT) as *mut *mut ffi::MMAL_PORT_T) };
-        let mut still_port = unsafe { *still_port_ptr };
-        let mut format = unsafe { *(still_port.format) };
-        
+
         // https://github.com/raspberrypi/userland/blob/master/host_applications/linux/apps/raspicam/RaspiStillYUV.c#L799
-        
-        //if self.use_encoder {
-            format.encoding = MMAL_ENCODING_OPAQUE;
-            /*
-        } else {
-            (*format).encoding = encoding;
-            (*format).encoding_variant = 0; //Irrelevant when not in opaque mode
+        // Written through the port's own format, copies of it don't get committed
+        unsafe {
+            let format = (*still_port_ptr).format;
+            (*format).encoding = MMAL_ENCODING_OPAQUE;
+
+            // es = elementary stream
+            let es = (*format).es;
+            (*es).video.width = (sw + 31) & !31; // VCOS_ALIGN_UP(w, 32)
+            (*es).video.height = (sh + 15) & !15; // VCOS_ALIGN_UP(h, 16)
+            (*es).video.crop.x = 0;
+            (*es).video.crop.y = 0;
+            (*es).video.crop.width = sw as i32;
+            (*es).video.crop.height = sh as i32;
+            (*es).video.frame_rate.num = 0; //STILLS_FRAME_RATE_NUM;
+            (*es).video.frame_rate.den = 1; //STILLS_FRAME_RATE_DEN;
         }
-        */
-        
-        // es = elementary stream
-        let mut es = unsafe { *(format.es) };
-        
-        es.video.width = w & !(32-1); // VCOS_ALIGN_UP ffi::vcos_align_up(w, 32);
-        es.video.height = (h + 16 - 1) & !(16-1); // ffi::vcos_align_up(h, 16);
-        es.video.crop.x = 0;
-        es.video.crop.y = 0;
-        es.video.crop.width = w as i32;
-        es.video.crop.height = h as i32;
-        es.video.frame_rate.num = 0; //STILLS_FRAME_RATE_NUM;
-        es.video.frame_rate.den = 1; //STILLS_FRAME_RATE_DEN;
-        
+
         let status = unsafe { ffi::mmal_port_format_commit(still_port_ptr) };
         if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
             return Err(CameraError {
@@ -216,10 +396,28 @@ impl Camera {
             })
         }
 
-
         // raspistill sets buffer_num
-        if still_port.buffer_num < 3 {
-            still_port.buffer_num = 3 as u32;
+        unsafe {
+            if (*still_port_ptr).buffer_num < 3 {
+                (*still_port_ptr).buffer_num = 3;
+            }
+        }
+
+        // video port, I420 for the splitter in pipeline.rs
+        let video_port_ptr = unsafe { *camera_outputs.offset(MMAL_CAMERA_VIDEO_PORT) };
+        unsafe {
+            pipeline::set_i420(video_port_ptr, w, h);
+            let es = (*(*video_port_ptr).format).es;
+            (*es).video.frame_rate.num = settings.framerate.max(1) as i32;
+            (*es).video.frame_rate.den = 1;
+            (*video_port_ptr).buffer_num = (*video_port_ptr).buffer_num.max(3);
+        }
+        let status = unsafe { ffi::mmal_port_format_commit(video_port_ptr) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to commit video port configuration".to_string()
+            })
         }
 
 
@@ -248,6 +446,7 @@ impl Camera {
         }
         let encoder_ptr: *mut ffi::MMAL_COMPONENT_T = unsafe { encoder_ptr.assume_init() };
         let encoder = NonNull::new(encoder_ptr).unwrap();
+        setup.encoder = Some(encoder);
 
         let encoder_ref = unsafe { encoder.as_ref() };
         if encoder_ref.input_num == 0 || encoder_ref.output_num == 0 {
@@ -263,27 +462,16 @@ impl Camera {
         let encoder_output: *mut *mut ffi::MMAL_PORT_T = encoder_ref.output;
 
         let encoder_input_rmut = unsafe { *encoder_input_rmut_rmut };
-        let encoder_input = unsafe { *encoder_input_rmut };
-        let mut encoder_output = unsafe { *(*encoder_output) };
-
+        let encoder_output = unsafe { *encoder_output };
 
         unsafe {
-            ffi::mmal_format_copy(encoder_output.format, encoder_input.format);
-        }
-
-        // Specify out output format
-        unsafe { (*(encoder_output.format)).encoding = MMAL_ENCODING_JPEG };
-
-        encoder_output.buffer_size = encoder_output.buffer_size_recommended;
-
-        if encoder_output.buffer_size < encoder_output.buffer_size_min {
-            encoder_output.buffer_size = encoder_output.buffer_size_min;
-        }
+            ffi::mmal_format_copy((*encoder_output).format, (*encoder_input_rmut).format);
 
-        encoder_output.buffer_num = encoder_output.buffer_num_recommended;
+            // Specify out output format
+            (*(*encoder_output).format).encoding = MMAL_ENCODING_JPEG;
 
-        if encoder_output.buffer_num < encoder_output.buffer_num_min {
-            encoder_output.buffer_num = encoder_output.buffer_num_min;
+            (*encoder_output).buffer_size = (*encoder_output).buffer_size_recommended.max((*encoder_output).buffer_size_min);
+            (*encoder_output).buffer_num = (*encoder_output).buffer_num_recommended.max((*encoder_output).buffer_num_min);
         }
 
         // Commit the port changes to the output port
@@ -327,18 +515,18 @@ impl Camera {
         /* Create pool of buffer headers for the output port to consume */
         let pool = unsafe { ffi::mmal_port_pool_create( *(encoder_ref.output), (*(*encoder_ref.output)).buffer_num, (*(*encoder_ref.output)).buffer_size) };
         
-        // not sure how to check whether we got a null pointer back
-        /*
-        if pool ==  {
-            return Err(CameraError {
-                code: 1,
-                message: "Failed to create buffer header pool for encoder output port".to_string()
-            })
-        }
-        */
-
-        //state->encoder_pool = pool;
-        //state->encoder_component = encoder;
+        let pool = match NonNull::new(pool) {
+            Some(pool) => {
+                setup.pool = Some(pool);
+                pool
+            }
+            None => {
+                return Err(CameraError {
+                    code: 1,
+                    message: "Failed to create buffer header pool for encoder output port".to_string()
+                })
+            }
+        };
 
 
         // END ENCODER STUFF
@@ -356,13 +544,10 @@ impl Camera {
         }
         let connection_ptr: *mut ffi::MMAL_CONNECTION_T = unsafe { connection_ptr.assume_init() };
         let connection = NonNull::new(connection_ptr).unwrap();
-
-        let connection_ref = unsafe { connection.as_ref() };
+        setup.connection = Some(connection);
 
         let status = unsafe { ffi::mmal_connection_enable(connection.as_ptr()) };
-        if (status != ffi::MMAL_STATUS_T_MMAL_SUCCESS) {
-            unsafe { ffi::mmal_connection_destroy(connection_ptr) };
-
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
             return Err(CameraError {
                 code: 1,
                 message: "Failed to enable connection".to_string()
@@ -370,184 +555,50 @@ impl Camera {
         }
 
 
-        // COPIED FROM C CODE, LEFT OFF HERE
+        // The capture loop that used to be pasted here from RaspiStill.c is Camera::capture() now
 
-        // TODO: these semaphore functions aren't ending up in our rust bindings
-        // Semaphore stuff to synchronize IO operations
-        // semaphore which is posted when we reach end of frame (indicates end of capture or fault)
-        let mut semaphore_ptr = MaybeUninit::<*mut ffi::MMAL_CONNECTION_T>::uninit();
-        let status = unsafe { ffi::vcos_semaphore_create(semaphore_ptr.as_mut_ptr(), "RustSec-sem", 0) };
-        if (status != ffi::MMAL_STATUS_T_MMAL_SUCCESS) {
-            // TODO: tear down everything?
+        // Enable camera control port and pass it the callback function. Last, so
+        // nothing can fail between userdata pointing at `gains` and Camera owning it
+        unsafe { (*camera.as_ref().control).userdata = Arc::as_ptr(&gains) as *mut ffi::MMAL_PORT_USERDATA_T };
+        let status = unsafe { ffi::mmal_port_enable(camera.as_ref().control, Some(camera_port_callback)) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
             return Err(CameraError {
                 code: 1,
-                message: "Failed to create semaphore".to_string()
+                message: "Unable to enable camera control port".to_string()
             })
         }
 
-        let running = true;
-        while running == true {
-            // simple timeout for a single capture
-            // TODO: fix
-            // this just uses nanosleep
-            vcos_sleep(state->timeout);
-
-            // TODO: not sure which one we need
-            if (state.datetime)
-            {
-                time_t rawtime;
-                struct tm *timeinfo;
-
-                time(&rawtime);
-                timeinfo = localtime(&rawtime);
-
-                frame = timeinfo->tm_mon+1;
-                frame *= 100;
-                frame += timeinfo->tm_mday;
-                frame *= 100;
-                frame += timeinfo->tm_hour;
-                frame *= 100;
-                frame += timeinfo->tm_min;
-                frame *= 100;
-                frame += timeinfo->tm_sec;
-            }
-            if (state.timestamp)
-            {
-                frame = (int)time(NULL);
-            }
-
-
-
-            int num, q;
-
-            // Must do this before the encoder output port is enabled since
-            // once enabled no further exif data is accepted
-            if ( state.enableExifTags )
-            {
-                struct gps_data_t *gps_data = raspi_gps_lock();
-                add_exif_tags(&state, gps_data);
-                raspi_gps_unlock();
-            }
-            else
-            {
-                mmal_port_parameter_set_boolean(
-                state.encoder_component->output[0], MMAL_PARAMETER_EXIF_DISABLE, 1);
-            }
-
-            // Same with raw, apparently need to set it for each capture, whilst port
-            // is not enabled
-            if (state.wantRAW)
-            {
-                if (mmal_port_parameter_set_boolean(camera_still_port, MMAL_PARAMETER_ENABLE_RAW_CAPTURE, 1) != MMAL_SUCCESS)
-                {
-                vcos_log_error("RAW was requested, but failed to enable");
-                }
-            }
-
-            // There is a possibility that shutter needs to be set each loop.
-            if (mmal_status_to_int(mmal_port_parameter_set_uint32(state.camera_component->control, MMAL_PARAMETER_SHUTTER_SPEED, state.camera_parameters.shutter_speed)) != MMAL_SUCCESS)
-                vcos_log_error("Unable to set shutter speed");
-
-            // Enable the encoder output port
-            encoder_output_port->userdata = (struct MMAL_PORT_USERDATA_T *)&callback_data;
-
-            if (state.common_settings.verbose)
-                fprintf(stderr, "Enabling encoder output port\n");
-
-            // Enable the encoder output port and tell it its callback function
-            status = mmal_port_enable(encoder_output_port, encoder_buffer_callback);
-
-            // Send all the buffers to the encoder output port
-            num = mmal_queue_length(state.encoder_pool->queue);
-
-            for (q=0; q<num; q++)
-            {
-                MMAL_BUFFER_HEADER_T *buffer = mmal_queue_get(state.encoder_pool->queue);
-
-                if (!buffer)
-                vcos_log_error("Unable to get a required buffer %d from pool queue", q);
-
-                if (mmal_port_send_buffer(encoder_output_port, buffer)!= MMAL_SUCCESS)
-                vcos_log_error("Unable to send a buffer to encoder output port (%d)", q);
-            }
-
-            if (state.burstCaptureMode)
-            {
-                mmal_port_parameter_set_boolean(state.camera_component->control,  MMAL_PARAMETER_CAMERA_BURST_CAPTURE, 1);
-            }
-
-            if(state.camera_parameters.enable_annotate)
-            {
-                if ((state.camera_parameters.enable_annotate & ANNOTATE_APP_TEXT) && state.common_settings.gps)
-                {
-                char *text = raspi_gps_location_string();
-                raspicamcontrol_set_annotate(state.camera_component, state.camera_parameters.enable_annotate,
-                                                text,
-                                                state.camera_parameters.annotate_text_size,
-                                                state.camera_parameters.annotate_text_colour,
-                                                state.camera_parameters.annotate_bg_colour,
-                                                state.camera_parameters.annotate_justify,
-                                                state.camera_parameters.annotate_x,
-                                                state.camera_parameters.annotate_y
-                                            );
-                free(text);
-                }
-                else
-                raspicamcontrol_set_annotate(state.camera_component, state.camera_parameters.enable_annotate,
-                                                state.camera_parameters.annotate_string,
-                                                state.camera_parameters.annotate_text_size,
-                                                state.camera_parameters.annotate_text_colour,
-                                                state.camera_parameters.annotate_bg_colour,
-                                                state.camera_parameters.annotate_justify,
-                                                state.camera_parameters.annotate_x,
-                                                state.camera_parameters.annotate_y
-                                            );
-            }
-
-            if (state.common_settings.verbose)
-                fprintf(stderr, "Starting capture %d\n", frame);
-
-            if (mmal_port_parameter_set_boolean(camera_still_port, MMAL_PARAMETER_CAPTURE, 1) != MMAL_SUCCESS)
-            {
-                vcos_log_error("%s: Failed to start capture", __func__);
-            }
-            else
-            {
-                // Wait for capture to complete
-                // For some reason using vcos_semaphore_wait_timeout sometimes returns immediately with bad parameter error
-                // even though it appears to be all correct, so reverting to untimed one until figure out why its erratic
-                vcos_semaphore_wait(&callback_data.complete_semaphore);
-                if (state.common_settings.verbose)
-                fprintf(stderr, "Finished capture %d\n", frame);
-            }
-
-            // Ensure we don't die if get callback with no open file
-            callback_data.file_handle = NULL;
-
-            if (output_file != stdout)
-            {
-                rename_file(&state, output_file, final_filename, use_filename, frame);
-            }
-            else
-            {
-                fflush(output_file);
-            }
-            // Disable encoder output port
-            status = mmal_port_disable(encoder_output_port);
-
+        // Ask for MMAL_PARAMETER_CAMERA_SETTINGS whenever AGC/AWB change something
+        let mut change: ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T = unsafe { mem::zeroed() };
+        change.hdr.id = ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST as u32;
+        change.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T>() as u32;
+        change.change_id = ffi::MMAL_PARAMETER_CAMERA_SETTINGS as u32;
+        change.enable = 1;
+        let status = unsafe { ffi::mmal_port_parameter_set(camera.as_ref().control, &change.hdr) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to request camera settings change events".to_string()
+            })
         }
-        vcos_semaphore_delete(&callback_data.complete_semaphore);
-        
 
+        // Camera owns everything from here
+        setup.keep();
 
         
         return Ok(Camera {
             camera: camera,
             // TODO: i don't like that we need all these flags. wish we could embed them in the camera type/value
-            camera_enabled: false,
+            camera_enabled: true,
+            gains: gains,
+            annotator: settings.annotation.clone().map(|a| Annotator::new(a, &settings.name)),
             
             encoder: encoder,
-            encoder_enabled: false
+            encoder_enabled: true,
+            pool: pool,
+            connection: connection,
+            frame_encoder: None,
+            video: None
         });
 
         // Configure the camera
@@ -767,10 +818,172 @@ impl Camera {
         }
     }
 
-    pub fn shutdown(&self) {
+    fn control(&self) -> *mut ffi::MMAL_PORT_T {
+        unsafe { self.camera.as_ref().control }
+    }
+
+    /// Latest exposure and gains reported by the camera, if it has sent any yet
+    pub fn gains(&self) -> Option<CameraGains> {
+        *self.gains.lock().unwrap()
+    }
+
+    /// Changes ISO, shutter speed, exposure mode, AWB and grayscale on the running camera
+    pub fn set_exposure(&self, settings: &CameraSettings) -> Result<(), CameraError> {
+        control::set_exposure(self.control(), settings)
+    }
+
+    fn still_port(&self) -> *mut ffi::MMAL_PORT_T {
+        unsafe { *self.camera.as_ref().output.offset(MMAL_CAMERA_CAPTURE_PORT) }
+    }
+
+    /// Whether the next still gets the raw Bayer block appended, see raw::extract()
+    pub fn set_raw_capture(&self, enabled: bool) -> Result<(), CameraError> {
+        control::set_raw_capture(self.still_port(), enabled)
+    }
+
+    fn encoder_output(&self) -> *mut ffi::MMAL_PORT_T {
+        unsafe { *self.encoder.as_ref().output }
+    }
+
+    /// Takes a single still and returns the encoded JPEG.
+    /// Ported from the capture loop in RaspiStill.c
+    pub fn capture(&mut self) -> Result<Vec<u8>, CameraError> {
+        let port = self.encoder_output();
+        let (done, finished) = mpsc::channel();
+        let capture = Arc::new(Mutex::new(CaptureData {
+            data: Vec::new(),
+            pool: self.pool.as_ptr(),
+            done: done,
+        }));
+
+        // Enable the encoder output port and tell it its callback function
+        unsafe { (*port).userdata = Arc::as_ptr(&capture) as *mut ffi::MMAL_PORT_USERDATA_T };
+        let status = unsafe { ffi::mmal_port_enable(port, Some(encoder_buffer_callback)) };
+        if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            return Err(CameraError {
+                code: 1,
+                message: "Unable to enable encoder output port".to_string()
+            })
+        }
+
+        // Send all the buffers to the encoder output port
+        let queue = unsafe { (*self.pool.as_ptr()).queue };
+        let num = unsafe { ffi::mmal_queue_length(queue) };
+        for q in 0..num {
+            let buffer = unsafe { ffi::mmal_queue_get(queue) };
+            if buffer.is_null() || unsafe { ffi::mmal_port_send_buffer(port, buffer) } != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+                println!("Unable to send buffer {} to encoder output port", q);
+            }
+        }
+
+        let status = unsafe { ffi::mmal_port_parameter_set_boolean(self.still_port(), ffi::MMAL_PARAMETER_CAPTURE as u32, 1) };
+        let result = if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+            Err(CameraError {
+                code: 1,
+                message: "Failed to start capture".to_string()
+            })
+        } else {
+            // Wait for capture to complete
+            match finished.recv_timeout(Duration::from_secs(10)) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(CameraError {
+                    code: 1,
+                    message: "Capture failed".to_string()
+                }),
+                Err(_) => Err(CameraError {
+                    code: 1,
+                    message: "Timed out waiting for capture".to_string()
+                }),
+            }
+        };
+
+        unsafe {
+            ffi::mmal_port_disable(port);
+            (*port).userdata = std::ptr::null_mut();
+        }
+
+        result?;
+        let data = mem::replace(&mut capture.lock().unwrap().data, Vec::new());
+        Ok(data)
+    }
+
+    /// JPEG of an I420 video frame, without interrupting the video port or using the still port
+    pub fn encode_frame(&mut self, frame: &[u8], width: u32, height: u32) -> Result<Vec<u8>, CameraError> {
+        let matches = match &self.frame_encoder {
+            Some(encoder) => encoder.width == width && encoder.height == height,
+            None => false,
+        };
+        if !matches {
+            if let Some(encoder) = self.frame_encoder.take() {
+                encoder.destroy();
+            }
+            self.frame_encoder = Some(ImageEncoder::new(width, height)?);
+        }
+        self.frame_encoder.as_mut().unwrap().encode(frame)
+    }
+
+    /// Starts the video port, returning motion detection frames (I420 at the
+    /// motion size) and every H.264 access unit. The H.264 also goes straight to `live.video`.
+    pub fn start_video(&mut self, settings: &CameraSettings, motion: &MotionSettings, live: Arc<Live>) -> Result<(mpsc::Receiver<Vec<u8>>, mpsc::Receiver<AccessUnit>), CameraError> {
+        let video_port = unsafe { *self.camera.as_ref().output.offset(MMAL_CAMERA_VIDEO_PORT) };
+        let (video, frames, units) = VideoPipeline::new(video_port, settings, motion, live)?;
+        self.video = Some(video);
+        Ok((frames, units))
+    }
+
+    /// Burst mode keeps the sensor in capture mode between stills, so a series
+    /// of them comes out much quicker
+    pub fn set_burst(&self, enabled: bool) -> Result<(), CameraError> {
+        control::set_burst_capture(self.control(), enabled)
+    }
+
+    /// Tags the next JPEG from the encoder, or turns EXIF off with None.
+    /// Like raspistill, this has to happen before the encoder output port is enabled for each capture.
+    pub fn set_exif(&self, exif: Option<&Exif>) -> Result<(), CameraError> {
+        let port = self.encoder_output();
+        match exif {
+            Some(exif) => {
+                control::set_exif_disabled(port, false)?;
+                for tag in exif.mmal_tags() {
+                    control::set_exif_tag(port, &tag)?;
+                }
+            }
+            None => control::set_exif_disabled(port, true)?,
+        }
+        Ok(())
+    }
+
+    /// Swaps the annotation for a new one, or takes it off the picture
+    pub fn set_annotation(&mut self, annotation: Option<Annotation>, name: &str) -> Result<(), CameraError> {
+        self.annotator = annotation.map(|a| Annotator::new(a, name));
+        if self.annotator.is_none() {
+            control::clear_annotation(self.control())?;
+        }
+        self.refresh_annotation()
+    }
+
+    /// Sends the annotation text again if the second has ticked over,
+    /// so the burned-in time stays current
+    pub fn refresh_annotation(&mut self) -> Result<(), CameraError> {
+        let control = self.control();
+        if let Some(annotator) = self.annotator.as_mut() {
+            if let Some(text) = annotator.text(chrono::Local::now()) {
+                control::set_annotation(control, annotator.annotation(), &text)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn shutdown(&mut self) {
+        // stops the video port before the camera goes away
+        self.video = None;
+        if let Some(encoder) = &self.frame_encoder {
+            encoder.destroy();
+        }
         unsafe {
-            //ffi::mmal_connection_disable(self.connection.unwrap().as_ptr());
-            //ffi::mmal_connection_destroy(self.connection.unwrap().as_ptr());
+            ffi::mmal_connection_disable(self.connection.as_ptr());
+            ffi::mmal_connection_destroy(self.connection.as_ptr());
+            ffi::mmal_port_pool_destroy(self.encoder_output(), self.pool.as_ptr());
             
             ffi::mmal_component_disable(self.encoder.as_ptr());
 
@@ -787,20 +1000,582 @@ impl Camera {
     }
 }
 
+/// What the firmware knows about an attached camera
+#[derive(Debug, Clone)]
+struct CameraInfo {
+    max_width: u32,
+    max_height: u32,
+}
+
+/// Asks the firmware which cameras are attached.
+///
+/// Regular Pis have one CSI port, Compute Module boards have two.
+fn cameras() -> Result<Vec<CameraInfo>, CameraError> {
+    let mut info_ptr = MaybeUninit::<*mut ffi::MMAL_COMPONENT_T>::uninit();
+    let component: *const c_char = ffi::MMAL_COMPONENT_DEFAULT_CAMERA_INFO.as_ptr() as *const c_char;
+    let status = unsafe { ffi::mmal_component_create(component, info_ptr.as_mut_ptr()) };
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Failed to create camera info component".to_string()
+        })
+    }
+    let info_ptr: *mut ffi::MMAL_COMPONENT_T = unsafe { info_ptr.assume_init() };
+
+    let mut param: ffi::MMAL_PARAMETER_CAMERA_INFO_T = unsafe { mem::zeroed() };
+    param.hdr.id = ffi::MMAL_PARAMETER_CAMERA_INFO as u32;
+    param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_CAMERA_INFO_T>() as u32;
+    let status = unsafe { ffi::mmal_port_parameter_get((*info_ptr).control, &mut param.hdr) };
+    unsafe { ffi::mmal_component_destroy(info_ptr) };
+
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: "Unable to get camera info".to_string()
+        })
+    }
+    let count = (param.num_cameras as usize).min(param.cameras.len());
+    Ok(param.cameras[..count].iter().map(|c| CameraInfo {
+        max_width: c.max_width,
+        max_height: c.max_height,
+    }).collect())
+}
+
+/// Takes a still with EXIF (and raw, if enabled) and writes it to `path`.
+/// Raw data goes to a DNG next to it. Returns the path written, which has .age
+/// on the end if the camera encrypts.
+fn capture_still(camera: &mut Camera, config: &CameraConfig, path: &Path) -> Result<PathBuf, CameraError> {
+    let settings = &config.settings;
+    let encryption = config.encryption.as_ref();
+    let exif = settings.exif.as_ref().map(|e| Exif::new(settings, e, camera.gains()));
+    camera.set_exif(exif.as_ref())?;
+    camera.set_raw_capture(settings.raw)?;
+
+    let jpeg = camera.capture()?;
+
+    let written = match encryption::write(encryption, path, &jpeg) {
+        Ok(written) => written,
+        Err(e) => return Err(CameraError {
+            code: 1,
+            message: format!("Unable to write {}: {}", path.display(), e)
+        })
+    };
+
+    if settings.raw {
+        let dng = raw::extract(&jpeg).map(|r| r.to_dng(&settings.name, camera.gains()));
+        let result = dng.and_then(|dng| encryption::write(encryption, &path.with_extension("dng"), &dng));
+        if let Err(e) = result {
+            return Err(CameraError {
+                code: 1,
+                message: format!("Unable to save raw data for {}: {}", path.display(), e)
+            })
+        }
+    }
+    Ok(written)
+}
+
+/// A handful of stills in quick succession, named after the event
+fn capture_burst(camera: &mut Camera, config: &CameraConfig, event: &event::MotionEvent, count: u32) -> Result<(), CameraError> {
+    camera.set_burst(true)?;
+    let mut result = Ok(());
+    for i in 0..count {
+        let path = config.recording_dir.join(format!("{}-{:02}.jpg", event.name(), i + 1));
+        result = capture_still(camera, config, &path).map(|_| ());
+        if result.is_err() {
+            break;
+        }
+    }
+    camera.set_burst(false)?;
+    result
+}
+
+/// Stills on a timer, like raspistill's --timelapse. Called every frame, takes
+/// a still when one is due. False once the timelapse is finished.
+fn timelapse_frame(camera: &mut Camera, config: &CameraConfig, timelapse: &mut Timelapse) -> bool {
+    match timelapse.wait(Instant::now()) {
+        Some(wait) if wait == Duration::ZERO => {}
+        Some(_) => return true,
+        None => return false,
+    }
+    let dir = config.recording_dir.join("timelapse");
+    let path = dir.join(timelapse.next_name(config.name(), chrono::Local::now()));
+    // one bad frame shouldn't end the timelapse
+    let result = std::fs::create_dir_all(&dir)
+        .map_err(|e| CameraError {
+            code: 1,
+            message: format!("Unable to create {}: {}", dir.display(), e)
+        })
+        .and_then(|_| capture_still(camera, config, &path));
+    if let Err(e) = result {
+        println!("{}: {:?}", config.name(), e);
+    }
+    true
+}
+
+/// JPEG of the moment motion started, attached to the event
+fn capture_snapshot(camera: &mut Camera, monitor: &mut Monitor, event: &event::MotionEvent, frame: &[u8]) -> Result<(), CameraError> {
+    let config = monitor.config();
+    let source = match &config.snapshot {
+        Some(snapshot) => snapshot.source,
+        None => return Ok(()),
+    };
+    let path = event.path(&config.recording_dir, "jpg");
+
+    let path = match source {
+        // full resolution, the still port can capture while the video port is recording
+        SnapshotSource::StillPort => capture_still(camera, config, &path)?,
+        SnapshotSource::VideoFrame => {
+            let jpeg = camera.encode_frame(frame, config.motion.width, config.motion.height)?;
+            match encryption::write(config.encryption.as_ref(), &path, &jpeg) {
+                Ok(written) => written,
+                Err(e) => return Err(CameraError {
+                    code: 1,
+                    message: format!("Unable to write {}: {}", path.display(), e)
+                })
+            }
+        }
+    };
+
+    monitor.attach_snapshot(path);
+    Ok(())
+}
+
+/// Snapshot and burst for an event that just started.
+///
+/// This blocks frame handling while the stills are taken, which is fine
+/// since the motion detector only needs to catch the event ending
+fn event_started(camera: &mut Camera, monitor: &mut Monitor, event: &event::MotionEvent, frame: &[u8]) {
+    if let Err(e) = capture_snapshot(camera, monitor, event, frame) {
+        println!("{}: {:?}", monitor.config().name(), e);
+    }
+    if let Some(burst) = monitor.config().burst.clone() {
+        if let Err(e) = capture_burst(camera, monitor.config(), event, burst.count) {
+            println!("{}: {:?}", monitor.config().name(), e);
+        }
+    }
+}
+
+/// Carries out something the API asked for
+fn handle_command(camera: &mut Camera, monitor: &mut Monitor, live: &Live, command: Command, frame: &[u8]) {
+    let name = monitor.config().name().to_string();
+    match command {
+        Command::Settings(settings) => {
+            let applied = camera
+                .set_exposure(&settings)
+                .and_then(|_| camera.set_annotation(settings.annotation.clone(), &settings.name));
+            if let Err(e) = applied {
+                println!("{}: {:?}", name, e);
+            }
+            monitor.set_settings(settings);
+        }
+        Command::Motion(motion) => monitor.set_motion(motion),
+        Command::Arm(armed) => {
+            println!("{}: {}", name, if armed { "armed" } else { "disarmed" });
+            monitor.set_armed(armed);
+        }
+        Command::Record(duration) => {
+            if let Some(Change::Started(event)) = monitor.trigger(duration) {
+                event_started(camera, monitor, &event, frame);
+                if let Some(event) = monitor.event() {
+                    live.notify(&Change::Started(event.clone()));
+                }
+            }
+        }
+        Command::Still(reply) => {
+            let dir = monitor.config().recording_dir.join("stills");
+            let path = dir.join(format!("{}.jpg", event::file_stem(&name, &chrono::Local::now())));
+            let result = std::fs::create_dir_all(&dir)
+                .map_err(|e| format!("Unable to create {}: {}", dir.display(), e))
+                .and_then(|_| capture_still(camera, monitor.config(), &path).map_err(|e| e.message));
+            let _ = reply.send(result);
+        }
+    }
+    publish_status(monitor, live);
+}
+
+/// Settings as they are now, for the API
+fn publish_status(monitor: &Monitor, live: &Live) {
+    live.update_status(|status| {
+        status.settings = Some(monitor.config().settings.clone());
+        status.motion = Some(monitor.config().motion.clone());
+        status.armed = monitor.armed();
+    });
+}
+
+/// Everything that happens for each frame off the video port.
+/// `frame` is I420 at the motion detection size, laid out as in i420.rs.
+fn handle_frame(camera: &mut Camera, monitor: &mut Monitor, live: &Live, commands: &mpsc::Receiver<Command>, frame: &[u8]) {
+    for command in commands.try_iter() {
+        handle_command(camera, monitor, live, command, frame);
+    }
+
+    let change = match monitor.frame(frame, camera.gains()) {
+        Some(Change::Started(event)) => {
+            event_started(camera, monitor, &event, frame);
+            // with the snapshot attached now
+            monitor.event().cloned().map(Change::Started)
+        }
+        change => change,
+    };
+
+    if let Err(e) = camera.refresh_annotation() {
+        println!("{}: {:?}", monitor.config().name(), e);
+    }
+
+    live.update_status(|status| {
+        status.last_frame = Some(chrono::Local::now());
+        status.event = monitor.event().map(|event| event.summary());
+        status.tamper = monitor.tamper().cloned();
+    });
+    if let Some(change) = &change {
+        live.notify(change);
+    }
+    if let Some(entry) = monitor.take_finalized() {
+        live.notify(&Change::Finalized(entry));
+    }
+    if let Some(change) = monitor.take_tamper() {
+        live.notify(&change);
+    }
+    // only encode when someone's watching
+    if live.wants_frame() {
+        let (width, height) = (monitor.config().motion.width, monitor.config().motion.height);
+        match camera.encode_frame(frame, width, height) {
+            Ok(jpeg) => live.publish(jpeg),
+            Err(e) => println!("{}: {:?}", monitor.config().name(), e),
+        }
+    }
+
+    if let Some(profile) = monitor.take_profile() {
+        if let Err(e) = camera.set_exposure(&profile) {
+            println!("{}: {:?}", monitor.config().name(), e);
+        }
+        publish_status(monitor, live);
+    }
+}
+
+// the video port stalling for this long means the camera's gone
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a single camera. Each camera gets its own thread.
+fn run_camera(config: CameraConfig, live: Arc<Live>) -> Result<(), CameraError> {
+    if let Err(e) = std::fs::create_dir_all(&config.recording_dir) {
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to create {}: {}", config.recording_dir.display(), e)
+        })
+    }
+
+    let commands = match live.take_commands() {
+        Some(commands) => commands,
+        None => return Err(CameraError {
+            code: 1,
+            message: "Camera is already running".to_string()
+        })
+    };
+
+    let mut camera = Camera::new(&config.settings)?;
+    // iso, shutter speed, exposure and AWB modes and grayscale from cameras.json
+    let started = camera
+        .set_exposure(&config.settings)
+        .and_then(|_| camera.refresh_annotation())
+        .and_then(|_| camera.start_video(&config.settings, &config.motion, live.clone()));
+    let (frames, units) = match started {
+        Ok(receivers) => receivers,
+        Err(e) => {
+            camera.shutdown();
+            return Err(e);
+        }
+    };
+    let mut timelapse = config.timelapse.clone().map(Timelapse::new);
+    let mut monitor = Monitor::new(config);
+    publish_status(&monitor, &live);
+
+    println!("{}: camera ready", monitor.config().name());
+
+    let result = loop {
+        let frame = match frames.recv_timeout(FRAME_TIMEOUT) {
+            Ok(frame) => frame,
+            Err(_) => break Err(CameraError {
+                code: 1,
+                message: format!("No frames from the camera for {} seconds", FRAME_TIMEOUT.as_secs())
+            }),
+        };
+        // video up to this frame goes in first, so an event starting on it
+        // begins recording from the keyframe before
+        for unit in units.try_iter() {
+            monitor.video(unit);
+        }
+        handle_frame(&mut camera, &mut monitor, &live, &commands, &frame);
+
+        let finished = match timelapse.as_mut() {
+            Some(timelapse) => !timelapse_frame(&mut camera, monitor.config(), timelapse),
+            None => false,
+        };
+        if finished {
+            println!("{}: timelapse finished", monitor.config().name());
+            timelapse = None;
+        }
+    };
+
+    camera.shutdown();
+    live.update_status(|status| status.event = None);
+    result
+}
+
+/// Commands that don't need the camera. True if one ran.
+fn run_command(args: &[String]) -> bool {
+    match args.get(1).map(|a| a.as_str()) {
+        // prints the "password" for a user in auth.json
+        Some("hash-password") => {
+            // read from stdin so it doesn't end up in shell history
+            eprintln!("Password:");
+            let mut password = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut password) {
+                println!("hash-password: {}", e);
+                return true;
+            }
+            match auth::hash_password(password.trim_end_matches(&['\r', '\n'][..])) {
+                Ok(hash) => println!("{}", hash),
+                Err(e) => println!("hash-password: {}", e),
+            }
+        }
+        // prints a token for scripts, and the hash to put in auth.json
+        Some("new-token") => {
+            let (token, hash) = auth::new_token();
+            println!("token: {}", token);
+            println!("hash:  {}", hash);
+        }
+        // decrypt <identity file> <file.age>...
+        Some("decrypt") => {
+            let identities = match args.get(2).map(|path| encryption::load_identities(Path::new(path))) {
+                Some(Ok(identities)) => identities,
+                Some(Err(e)) => {
+                    println!("decrypt: {}", e);
+                    return true;
+                }
+                None => {
+                    println!("Usage: {} decrypt <identity file> <file.age>...", args[0]);
+                    return true;
+                }
+            };
+            for path in &args[3..] {
+                match encryption::decrypt_file(&identities, Path::new(path)) {
+                    Ok(out) => println!("{}", out.display()),
+                    Err(e) => println!("decrypt: {}", e),
+                }
+            }
+        }
+        // verify [log] [public key file], checks the hash chain and the files in it
+        Some("verify") => {
+            let settings: chain::ChainSettings = match config::load_json(Path::new("chain.json")) {
+                Ok(settings) => settings.unwrap_or_default(),
+                Err(e) => {
+                    println!("{}", e);
+                    return true;
+                }
+            };
+            let log = args.get(2).map(PathBuf::from).unwrap_or(settings.log.clone());
+            let public_key = args.get(3).map(PathBuf::from).unwrap_or(settings.public_key());
+            let result = std::fs::read_to_string(&public_key)
+                .map_err(|e| format!("{}: {}", public_key.display(), e))
+                .and_then(|key| chain::verify(&log, &key));
+            match result {
+                Ok((records, problems)) => {
+                    for problem in &problems {
+                        println!("{}", problem);
+                    }
+                    println!("{} records, {} problems", records, problems.len());
+                    if !problems.is_empty() {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    println!("verify: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if run_command(&args) {
+        return;
+    }
+    if let Err(e) = run() {
+        println!("{}", e);
+    }
+}
+
+/// Loads the optional features' settings, starts the servers and runs the
+/// cameras until they stop
+fn run() -> Result<(), String> {
+    let auth: Option<auth::AuthSettings> = config::load_json(Path::new("auth.json"))?;
+    // without accounts the servers are only reachable from the Pi itself, and
+    // opening them up to everyone takes "anonymous" in auth.json
+    let host = if auth.is_some() { "0.0.0.0" } else { "127.0.0.1" };
+    if auth.is_none() {
+        println!("No auth.json, so only listening on localhost");
+    }
+    let mqtt_settings: Option<mqtt::MqttSettings> = config::load_json(Path::new("mqtt.json"))?;
+    let hook_settings: Option<hooks::HookSettings> = config::load_json(Path::new("hooks.json"))?;
+    let notify_settings: Option<notify::NotifySettings> = config::load_json(Path::new("notify.json"))?;
+    let upload_settings: Option<upload::UploadSettings> = config::load_json(Path::new("upload.json"))?;
+    let chain_settings: Option<chain::ChainSettings> = config::load_json(Path::new("chain.json"))?;
+    // nothing gets deleted until it's in here
+    let chain_log = chain_settings.as_ref().map(|c| c.log.clone());
+    let encryption = match config::load_json::<encryption::EncryptionSettings>(Path::new("encryption.json"))? {
+        Some(settings) => Some(encryption::Encryption::new(&settings).map_err(|e| format!("encryption.json: {}", e))?),
+        None => None,
+    };
+    let classifier = match config::load_json::<classify::ClassifierSettings>(Path::new("classifier.json"))? {
+        Some(settings) => Some(classify::Classifier::start(settings).map_err(|e| format!("classifier: {}", e))?),
+        None => None,
+    };
+    let tracking = tracking::load(Path::new("tracking.json"))?;
+    let index_settings: index::IndexSettings = config::load_json(Path::new("index.json"))?.unwrap_or_default();
+    let tls_settings: Option<TlsSettings> = config::load_json(Path::new("tls.json"))?;
+    if let Some(tls_settings) = &tls_settings {
+        // made up front, so the HTTP and RTSP servers don't both try to generate one
+        tls::load(tls_settings).map_err(|e| format!("tls: {}", e))?;
+    } else if auth.is_some() {
+        println!("No tls.json, so passwords and tokens go over the network in the clear");
+    }
+
     unsafe {
         ffi::bcm_host_init();
         ffi::vcos_init();
         ffi::mmal_vc_init();
     }
 
-    let mut camera = Camera::new().unwrap();
+    let infos = cameras().unwrap();
+    println!("Found {} camera(s)", infos.len());
 
-    println!("Hey");
+    let mut configs = config::load_cameras(Path::new("cameras.json"), infos.len())?;
+    for (config, info) in configs.iter_mut().zip(&infos) {
+        // full resolution stills unless cameras.json asks for something else
+        if config.settings.still_width == 0 || config.settings.still_height == 0 {
+            config.settings.still_width = info.max_width;
+            config.settings.still_height = info.max_height;
+        }
+        config.index = index_settings.path.clone();
+        config.encryption = encryption.clone();
+        config.classifier = classifier.clone();
+        if let Some(settings) = tracking.get(config.name()) {
+            config.tracking = Some(settings.clone());
+        }
+    }
 
-    camera.shutdown();
+    let http_settings = HttpSettings {
+        address: format!("{}:8080", host),
+        index: index_settings.path.clone(),
+        chain: chain_log.clone(),
+        auth: auth.clone(),
+        tls: tls_settings.clone(),
+        ..HttpSettings::default()
+    };
+    let rtsp_settings = RtspSettings {
+        address: format!("{}:8554", host),
+        auth: auth,
+        tls: tls_settings,
+        ..RtspSettings::default()
+    };
+    let lives: Vec<Arc<Live>> = configs.iter().map(|c| Live::new(c.name(), http_settings.stream_fps)).collect();
+    if let Some(hls_settings) = http_settings.hls.clone() {
+        for live in &lives {
+            let live = live.clone();
+            let hls_settings = hls_settings.clone();
+            std::thread::Builder::new()
+                .name(format!("{}-hls", live.name))
+                .spawn(move || hls::run(hls_settings, live))
+                .unwrap();
+        }
+    }
+    {
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("http".to_string())
+            .spawn(move || {
+                if let Err(e) = http::serve(http_settings, lives) {
+                    println!("http: {}", e);
+                }
+            })
+            .unwrap();
+    }
 
+    {
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("rtsp".to_string())
+            .spawn(move || {
+                if let Err(e) = rtsp::serve(rtsp_settings, lives) {
+                    println!("rtsp: {}", e);
+                }
+            })
+            .unwrap();
+    }
 
+    if let Some(mqtt_settings) = mqtt_settings {
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("mqtt".to_string())
+            .spawn(move || mqtt::run(mqtt_settings, lives))
+            .unwrap();
+    }
 
+    if let Some(hook_settings) = hook_settings {
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("hooks".to_string())
+            .spawn(move || hooks::run(hook_settings, lives))
+            .unwrap();
+    }
 
+    if let Some(notify_settings) = notify_settings {
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("notify".to_string())
+            .spawn(move || notify::run(notify_settings, lives))
+            .unwrap();
+    }
+
+    if let Some(chain_settings) = chain_settings {
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("chain".to_string())
+            .spawn(move || chain::run(chain_settings, lives))
+            .unwrap();
+    }
+
+    if let Some(mut upload_settings) = upload_settings {
+        upload_settings.index = index_settings.path;
+        upload_settings.chain = chain_log;
+        let lives = lives.clone();
+        std::thread::Builder::new()
+            .name("upload".to_string())
+            .spawn(move || upload::run(upload_settings, lives))
+            .unwrap();
+    }
+
+    let mut threads = Vec::new();
+    for (config, live) in configs.into_iter().zip(lives) {
+        let name = config.name().to_string();
+        let thread = std::thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || run_camera(config, live))
+            .unwrap();
+        threads.push((name, thread));
+    }
+
+    for (name, thread) in threads {
+        match thread.join() {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => println!("{}: {:?}", name, e),
+            Err(_) => println!("{}: camera thread panicked", name),
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,366 @@
+use crate::classify::CameraClassifier;
+use crate::config::CameraConfig;
+use crate::event::{Label, MotionEvent};
+use crate::exif::Exif;
+use crate::h264::AccessUnit;
+use crate::i420::Layout;
+use crate::index::{EventIndex, IndexEntry};
+use crate::illumination::{CameraGains, IlluminationWatch};
+use crate::motion::{self, Motion, MotionDetector, MotionSettings};
+use crate::recorder::Recorder;
+use crate::schedule::Scheduler;
+use crate::settings::CameraSettings;
+use crate::tamper::{TamperEvent, TamperWatch};
+use crate::thumbnail::Thumbnailer;
+use crate::tracking::Tracker;
+
+use chrono::Local;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// What happened to the current event on this frame
+#[derive(Debug, Clone)]
+pub enum Change {
+    Started(MotionEvent),
+    Ended(MotionEvent),
+    /// The event's thumbnails, metadata and so on are all written
+    Finalized(IndexEntry),
+    /// The camera's been covered, defocused or moved. Separate from motion events.
+    TamperStarted(TamperEvent),
+    TamperEnded(TamperEvent),
+}
+
+/// Watches frames from one camera and turns motion into events.
+pub struct Monitor {
+    config: CameraConfig,
+    detector: MotionDetector,
+    illumination: IlluminationWatch,
+    event: Option<MotionEvent>,
+    quiet_frames: u32,
+    scheduler: Option<Scheduler>,
+    profile: Option<CameraSettings>,
+    thumbnailer: Option<Thumbnailer>,
+    index: Option<EventIndex>,
+    /// Disarmed cameras keep watching but don't start events on their own
+    armed: bool,
+    /// A manual recording keeps the event going until this time
+    manual_until: Option<Instant>,
+    finalized: Option<IndexEntry>,
+    tamper: Option<TamperWatch>,
+    tamper_change: Option<Change>,
+    classifier: Option<CameraClassifier>,
+    /// A required label was just seen, so the next motion can start an event
+    confirmed: Option<(Instant, Vec<Label>)>,
+    tracker: Option<Tracker>,
+    /// Writes the event's H.264
+    recorder: Recorder,
+    /// What the camera last reported, for the thumbnail's EXIF
+    gains: Option<CameraGains>,
+}
+
+impl Monitor {
+    pub fn new(config: CameraConfig) -> Monitor {
+        let detector = MotionDetector::new(config.motion.clone());
+        let illumination = IlluminationWatch::new(config.illumination.clone());
+        let scheduler = config.schedule.clone().map(Scheduler::new);
+        let thumbnailer = config
+            .thumbnail
+            .clone()
+            .map(|settings| Thumbnailer::new(settings, config.motion.width, config.motion.height));
+        let index = config.index.as_ref().map(|path| EventIndex::new(path));
+        let tamper = config
+            .tamper
+            .clone()
+            .map(|settings| TamperWatch::new(settings, config.name(), config.motion.width, config.motion.height));
+        let tracker = config.tracking.clone().map(Tracker::new);
+        let classifier = config
+            .classifier
+            .clone()
+            .map(|classifier| CameraClassifier::new(classifier, config.motion.width, config.motion.height));
+        let recorder = Recorder::new(config.encryption.clone());
+        Monitor {
+            config: config,
+            detector: detector,
+            illumination: illumination,
+            event: None,
+            quiet_frames: 0,
+            scheduler: scheduler,
+            profile: None,
+            thumbnailer: thumbnailer,
+            index: index,
+            armed: true,
+            manual_until: None,
+            finalized: None,
+            tamper: tamper,
+            tamper_change: None,
+            classifier: classifier,
+            confirmed: None,
+            tracker: tracker,
+            recorder: recorder,
+            gains: None,
+        }
+    }
+
+    pub fn config(&self) -> &CameraConfig {
+        &self.config
+    }
+
+    /// The event currently in progress, if any
+    pub fn event(&self) -> Option<&MotionEvent> {
+        self.event.as_ref()
+    }
+
+    /// Feed an I420 frame at the motion detection size, laid out as in i420.rs,
+    /// through the motion detector, along with the latest gains the camera reported.
+    ///
+    /// Returns the event when it starts and again once it has finished.
+    pub fn frame(&mut self, frame: &[u8], gains: Option<CameraGains>) -> Option<Change> {
+        let luma = Layout::new(self.config.motion.width, self.config.motion.height).luma(frame);
+        let mean_luma = motion::mean_luma(&luma);
+        if gains.is_some() {
+            self.gains = gains;
+        }
+
+        if let Some(tamper) = self.tamper.as_mut() {
+            if let Some(change) = tamper.frame(&luma) {
+                match &change {
+                    Change::TamperStarted(event) => println!("{}: tamper, looks {:?}", self.config.name(), event.tamper),
+                    _ => println!("{}: tamper over", self.config.name()),
+                }
+                self.tamper_change = Some(change);
+            }
+        }
+
+        if let Some(scheduler) = self.scheduler.as_mut() {
+            let profile = scheduler.update(Local::now(), mean_luma).cloned();
+            if let Some(profile) = profile {
+                println!("{}: switching to {:?} profile", self.config.name(), scheduler.period().unwrap());
+                // kept in the settings too, so the status, the API and EXIF say what's in use
+                self.config.settings = self.config.settings.with_exposure(&profile);
+                self.profile = Some(self.config.settings.clone());
+            }
+        }
+
+        if let Some(classifier) = self.classifier.as_mut() {
+            if let Some(labels) = classifier.poll() {
+                match self.event.as_mut() {
+                    Some(event) => event.add_labels(&labels),
+                    None if classifier.wanted(&labels) => self.confirmed = Some((Instant::now(), labels)),
+                    None => {}
+                }
+            }
+        }
+
+        let motion = if self.illumination.unstable(mean_luma, gains) {
+            // lights changed or AGC is still settling, every pixel looks different
+            self.detector.rebaseline(&luma);
+            None
+        } else {
+            self.detector.detect(&luma)
+        };
+
+        if let Some(tracker) = self.tracker.as_mut() {
+            let blobs = motion.as_ref().map_or(&[][..], |m| &m.blobs[..]);
+            for object in tracker.update(blobs) {
+                if let Some(event) = self.event.as_mut() {
+                    event.add_object(object);
+                }
+            }
+        }
+
+        match motion {
+            Some(motion) => self.motion(motion, frame),
+            None => self.quiet(frame),
+        }
+    }
+
+    /// Feed every access unit from the H.264 encoder through here, so events
+    /// get their video
+    pub fn video(&mut self, unit: AccessUnit) {
+        if let Err(e) = self.recorder.unit(unit) {
+            println!("{}: unable to write video: {}", self.config.name(), e);
+            // stop rather than complain about every frame
+            let _ = self.recorder.stop();
+        }
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn set_armed(&mut self, armed: bool) {
+        self.armed = armed;
+    }
+
+    pub fn set_settings(&mut self, settings: CameraSettings) {
+        self.config.settings = settings;
+    }
+
+    /// Frame size has to stay the same, it's whatever the video port sends
+    pub fn set_motion(&mut self, settings: MotionSettings) {
+        self.detector = MotionDetector::new(settings.clone());
+        self.config.motion = settings;
+    }
+
+    /// Starts an event by hand, or keeps the current one going, for at least `duration`.
+    /// Works whether or not the camera is armed. Durations too long to
+    /// add to the clock are ignored.
+    pub fn trigger(&mut self, duration: Duration) -> Option<Change> {
+        let until = match Instant::now().checked_add(duration) {
+            Some(until) => until,
+            None => return None,
+        };
+        self.manual_until = Some(self.manual_until.map_or(until, |u| u.max(until)));
+        self.quiet_frames = 0;
+        if self.event.is_some() {
+            return None;
+        }
+
+        let mut event = MotionEvent::new(self.config.name());
+        event.manual = true;
+        println!(
+            "{}: manual recording started -> {}",
+            self.config.name(),
+            event.path(&self.config.recording_dir, "h264").display()
+        );
+        self.start_recording(&event);
+        self.event = Some(event.clone());
+        Some(Change::Started(event))
+    }
+
+    fn start_recording(&mut self, event: &MotionEvent) {
+        let path = event.path(&self.config.recording_dir, "h264");
+        if let Err(e) = self.recorder.start(&path) {
+            println!("{}: unable to record {}: {}", self.config.name(), path.display(), e);
+        }
+    }
+
+    /// Attaches a still to the event in progress
+    pub fn attach_snapshot(&mut self, path: PathBuf) {
+        if let Some(event) = self.event.as_mut() {
+            event.snapshot = Some(path);
+        }
+    }
+
+    /// Exposure profile the scheduler wants applied, if it changed since the last call
+    pub fn take_profile(&mut self) -> Option<CameraSettings> {
+        self.profile.take()
+    }
+
+    /// The last event to end and its files, once they're all written
+    pub fn take_finalized(&mut self) -> Option<IndexEntry> {
+        self.finalized.take()
+    }
+
+    /// Tampering that started or stopped on the last frame
+    pub fn take_tamper(&mut self) -> Option<Change> {
+        self.tamper_change.take()
+    }
+
+    /// Tampering going on now
+    pub fn tamper(&self) -> Option<&TamperEvent> {
+        self.tamper.as_ref().and_then(|t| t.event())
+    }
+
+    fn motion(&mut self, motion: Motion, frame: &[u8]) -> Option<Change> {
+        if !self.armed && self.event.is_none() {
+            return None;
+        }
+        self.quiet_frames = 0;
+        if let Some(classifier) = self.classifier.as_mut() {
+            classifier.submit(frame, &motion.bounding_box);
+        }
+        if let Some(event) = self.event.as_mut() {
+            event.add_motion(&motion);
+            if let Some(thumbnailer) = self.thumbnailer.as_mut() {
+                thumbnailer.frame(frame, Some(motion.score));
+            }
+            return None;
+        }
+
+        if let Some(tracker) = &self.tracker {
+            // flicker and swaying branches don't get far enough to count
+            if tracker.gates() && !tracker.counting() {
+                return None;
+            }
+        }
+        let mut event = MotionEvent::new(self.config.name());
+        if let Some(classifier) = &self.classifier {
+            if classifier.gates() {
+                // only a label seen in the last couple of seconds counts, not one from earlier motion
+                match self.confirmed.take() {
+                    Some((seen, labels)) if seen.elapsed() < Duration::from_secs(2) => event.add_labels(&labels),
+                    _ => return None,
+                }
+            }
+        }
+        event.add_motion(&motion);
+        if let Some(thumbnailer) = self.thumbnailer.as_mut() {
+            thumbnailer.frame(frame, Some(motion.score));
+        }
+        println!(
+            "{}: motion started, score {:.3} -> {}",
+            self.config.name(),
+            motion.score,
+            event.path(&self.config.recording_dir, "h264").display()
+        );
+        self.start_recording(&event);
+        self.event = Some(event.clone());
+        Some(Change::Started(event))
+    }
+
+    fn quiet(&mut self, frame: &[u8]) -> Option<Change> {
+        if self.event.is_none() {
+            return None;
+        }
+        if let Some(thumbnailer) = self.thumbnailer.as_mut() {
+            thumbnailer.frame(frame, None);
+        }
+        if let Some(until) = self.manual_until {
+            if Instant::now() < until {
+                return None;
+            }
+            self.manual_until = None;
+        }
+        self.quiet_frames += 1;
+        if self.quiet_frames < self.config.motion.quiet_frames {
+            return None;
+        }
+
+        self.quiet_frames = 0;
+        let mut event = self.event.take().unwrap();
+        event.end();
+        if let Some(tracker) = &self.tracker {
+            // whatever's still being followed gets its path so far
+            for object in tracker.objects() {
+                event.add_object(object);
+            }
+        }
+        println!("{}: motion ended", self.config.name());
+        // closed before the index lists the event's files and their sizes
+        if let Err(e) = self.recorder.stop() {
+            println!("{}: unable to finish video: {}", self.config.name(), e);
+        }
+
+        let dir = &self.config.recording_dir;
+        if let Some(thumbnailer) = self.thumbnailer.as_mut() {
+            let (settings, gains) = (&self.config.settings, self.gains);
+            let exif = settings.exif.as_ref().map(|exif| Exif::new(settings, exif, gains));
+            if let Err(e) = thumbnailer.finish(&mut event, dir, self.config.encryption.as_ref(), exif) {
+                println!("{}: unable to write thumbnails: {}", self.config.name(), e);
+            }
+        }
+        if let Err(e) = event.write_sidecar(dir) {
+            println!("{}: unable to write event metadata: {}", self.config.name(), e);
+        }
+        let entry = match &self.index {
+            Some(index) => index.append(&event, dir).map_err(|e| format!("unable to add event to index: {}", e)),
+            None => IndexEntry::new(&event, dir).map_err(|e| format!("unable to list event files: {}", e)),
+        };
+        match entry {
+            Ok(entry) => self.finalized = Some(entry),
+            Err(e) => println!("{}: {}", self.config.name(), e),
+        }
+        Some(Change::Ended(event))
+    }
+}
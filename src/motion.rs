@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+
+/// Settings for frame-differencing motion detection.
+///
+/// Frames are expected to be the Y (luma) plane of a YUV420 frame from the
+/// video port, so one byte per pixel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionSettings {
+    pub width: u32,
+    pub height: u32,
+    /// How much a pixel's brightness has to change before we count it as changed
+    pub pixel_threshold: u8,
+    /// Fraction of changed pixels (0.0 - 1.0) that counts as motion
+    pub min_score: f32,
+    /// How many frames without motion before an event is over
+    pub quiet_frames: u32,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        MotionSettings {
+            width: 320,
+            height: 240,
+            pixel_threshold: 25,
+            min_score: 0.01,
+            quiet_frames: 150,
+        }
+    }
+}
+
+/// Area of the frame that changed, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Changed pixels are grouped into CELL x CELL squares before looking for
+// blobs, which drops lone noisy pixels. Cells up to REACH apart count as
+// touching, since differencing only picks up the leading and trailing edges
+// of something plain moving across the frame.
+const CELL: usize = 8;
+const MIN_CELL_PIXELS: u32 = 4;
+const REACH: usize = 2;
+
+/// One patch of connected change, roughly one moving thing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blob {
+    pub bounding_box: BoundingBox,
+    /// Middle of the change, weighted by how much of it there is
+    pub center_x: f32,
+    pub center_y: f32,
+    /// Pixels that changed
+    pub pixels: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Motion {
+    /// Fraction of pixels that changed
+    pub score: f32,
+    pub bounding_box: BoundingBox,
+    /// The separate patches of change, biggest first
+    pub blobs: Vec<Blob>,
+}
+
+/// Compares each frame against the previous one.
+///
+/// Each camera gets its own detector since the previous frame is kept around.
+pub struct MotionDetector {
+    settings: MotionSettings,
+    previous: Option<Vec<u8>>,
+}
+
+impl MotionDetector {
+    pub fn new(settings: MotionSettings) -> MotionDetector {
+        MotionDetector {
+            settings: settings,
+            previous: None,
+        }
+    }
+
+    pub fn settings(&self) -> &MotionSettings {
+        &self.settings
+    }
+
+    /// Use this frame as the one to compare against next, without looking for motion.
+    /// Used when the whole picture changed brightness at once.
+    pub fn rebaseline(&mut self, luma: &[u8]) {
+        let pixels = (self.settings.width * self.settings.height) as usize;
+        if luma.len() >= pixels {
+            self.previous = Some(luma[..pixels].to_vec());
+        }
+    }
+
+    /// Returns Some(Motion) if enough pixels changed since the last frame
+    pub fn detect(&mut self, luma: &[u8]) -> Option<Motion> {
+        let width = self.settings.width as usize;
+        let height = self.settings.height as usize;
+        let pixels = width * height;
+        if luma.len() < pixels {
+            return None;
+        }
+        let frame = &luma[..pixels];
+
+        let previous = match self.previous.take() {
+            Some(previous) => previous,
+            None => {
+                // nothing to compare against yet
+                self.previous = Some(frame.to_vec());
+                return None;
+            }
+        };
+
+        let threshold = self.settings.pixel_threshold;
+        let columns = (width + CELL - 1) / CELL;
+        let mut cells = vec![0u32; columns * ((height + CELL - 1) / CELL)];
+        let mut changed = 0;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (width, height, 0, 0);
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let diff = (frame[i] as i16 - previous[i] as i16).abs();
+                if diff > threshold as i16 {
+                    changed += 1;
+                    cells[(y / CELL) * columns + x / CELL] += 1;
+                    min_x = min_x.min(x);
+                    min_y = min_y.min(y);
+                    max_x = max_x.max(x);
+                    max_y = max_y.max(y);
+                }
+            }
+        }
+
+        self.previous = Some(frame.to_vec());
+
+        let score = changed as f32 / pixels as f32;
+        if changed == 0 || score < self.settings.min_score {
+            return None;
+        }
+
+        Some(Motion {
+            score: score,
+            bounding_box: BoundingBox {
+                x: min_x as u32,
+                y: min_y as u32,
+                width: (max_x - min_x + 1) as u32,
+                height: (max_y - min_y + 1) as u32,
+            },
+            blobs: blobs(&cells, columns, width, height),
+        })
+    }
+}
+
+/// Groups cells with enough change that are near each other
+fn blobs(cells: &[u32], columns: usize, width: usize, height: usize) -> Vec<Blob> {
+    let rows = cells.len() / columns.max(1);
+    let mut seen = vec![false; cells.len()];
+    let mut blobs = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..cells.len() {
+        if seen[start] || cells[start] < MIN_CELL_PIXELS {
+            continue;
+        }
+        seen[start] = true;
+        stack.push(start);
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (columns, rows, 0, 0);
+        let (mut pixels, mut sum_x, mut sum_y) = (0u32, 0.0, 0.0);
+        while let Some(cell) = stack.pop() {
+            let (cx, cy) = (cell % columns, cell / columns);
+            min_x = min_x.min(cx);
+            min_y = min_y.min(cy);
+            max_x = max_x.max(cx);
+            max_y = max_y.max(cy);
+            let count = cells[cell];
+            pixels += count;
+            sum_x += ((cx * CELL) as f32 + CELL as f32 / 2.0) * count as f32;
+            sum_y += ((cy * CELL) as f32 + CELL as f32 / 2.0) * count as f32;
+
+            for ny in cy.saturating_sub(REACH)..(cy + REACH + 1).min(rows) {
+                for nx in cx.saturating_sub(REACH)..(cx + REACH + 1).min(columns) {
+                    let neighbour = ny * columns + nx;
+                    if !seen[neighbour] && cells[neighbour] >= MIN_CELL_PIXELS {
+                        seen[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+        let x = min_x * CELL;
+        let y = min_y * CELL;
+        blobs.push(Blob {
+            bounding_box: BoundingBox {
+                x: x as u32,
+                y: y as u32,
+                width: (((max_x + 1) * CELL).min(width) - x) as u32,
+                height: (((max_y + 1) * CELL).min(height) - y) as u32,
+            },
+            center_x: (sum_x / pixels as f32).min(width as f32 - 1.0),
+            center_y: (sum_y / pixels as f32).min(height as f32 - 1.0),
+            pixels: pixels,
+        });
+    }
+    blobs.sort_by_key(|b| std::cmp::Reverse(b.pixels));
+    blobs
+}
+
+/// Average brightness (0-255) of a luma plane
+pub fn mean_luma(luma: &[u8]) -> f32 {
+    if luma.is_empty() {
+        return 0.0;
+    }
+    let total: u64 = luma.iter().map(|&y| y as u64).sum();
+    total as f32 / luma.len() as f32
+}
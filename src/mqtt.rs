@@ -0,0 +1,508 @@
+/*
+MQTT for home automation, with Home Assistant discovery so cameras show up
+there on their own.
+
+For each camera, under <topic>/<camera>/:
+
+motion        ON or OFF
+event         the event as JSON when it starts, when it ends, and once its
+              files are written, with a list of them
+snapshot      JPEG from when the last event started
+armed         ON or OFF, send ON or OFF to armed/set to change it
+tamper        ON while the camera looks covered, defocused or moved
+tamper_event  the tampering as JSON when it starts and when it's over
+availability  online while frames are coming in
+health        JSON with the last frame time and so on, every so often
+
+<topic>/status is online while we're connected, the broker sets it to offline
+if we go away. Retained topics are sent again on every reconnect, in case the
+broker restarted without keeping them.
+*/
+use crate::live::Live;
+use crate::monitor::Change;
+
+use chrono::Local;
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, Publish, QoS, Transport};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Check the broker's certificate against the system's CAs
+    pub tls: bool,
+    pub client_id: String,
+    /// Everything gets published under here
+    pub topic: String,
+    /// Home Assistant's discovery prefix, None to skip discovery
+    pub discovery_prefix: Option<String>,
+    /// Publish a JPEG when motion starts
+    pub snapshots: bool,
+    /// Seconds between health updates
+    pub health_interval: u64,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            tls: false,
+            client_id: "rust-security".to_string(),
+            topic: "rust-security".to_string(),
+            discovery_prefix: Some("homeassistant".to_string()),
+            snapshots: true,
+            health_interval: 60,
+        }
+    }
+}
+
+/// Keeps connected to the broker, reconnecting as needed. Call from its own thread.
+pub fn run(settings: MqttSettings, cameras: Vec<Arc<Live>>) {
+    let mut options = MqttOptions::new(settings.client_id.clone(), settings.host.clone(), settings.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    // snapshots are way over the 10kB default
+    options.set_max_packet_size(64 * 1024, 4 * 1024 * 1024);
+    options.set_last_will(LastWill::new(format!("{}/status", settings.topic), "offline", QoS::AtLeastOnce, true));
+    if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+        options.set_credentials(username.clone(), password.clone());
+    }
+    if settings.tls {
+        options.set_transport(Transport::tls_with_default_config());
+    }
+    let (client, mut connection) = Client::new(options, 64);
+
+    // bumped on every connect, so the camera threads know to send everything again
+    let connects = Arc::new(AtomicU64::new(0));
+    for camera in &cameras {
+        let settings = settings.clone();
+        let client = client.clone();
+        let camera = camera.clone();
+        let connects = connects.clone();
+        std::thread::Builder::new()
+            .name(format!("{}-mqtt", camera.name))
+            .spawn(move || publish_camera(&settings, client, &camera, &connects))
+            .unwrap();
+    }
+
+    for notification in connection.iter() {
+        match notification {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("mqtt: connected to {}:{}", settings.host, settings.port);
+                connects.fetch_add(1, Ordering::SeqCst);
+                // requests only go out while this loop runs, so it can't wait on them itself
+                let settings = settings.clone();
+                let client = client.clone();
+                let cameras = cameras.clone();
+                std::thread::spawn(move || announce(&settings, client, &cameras));
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => command(&settings, &cameras, &publish),
+            Ok(_) => {}
+            Err(e) => {
+                println!("mqtt: {}", e);
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+    }
+}
+
+/// Discovery configs, our status and the command subscriptions
+fn announce(settings: &MqttSettings, mut client: Client, cameras: &[Arc<Live>]) {
+    if let Some(prefix) = &settings.discovery_prefix {
+        for camera in cameras {
+            for (component, object, config) in discovery(settings, camera) {
+                let topic = format!("{}/{}/{}_{}/{}/config", prefix, component, settings.client_id, object_id(&camera.name), object);
+                publish(&mut client, &topic, true, config.to_string());
+            }
+        }
+    }
+    publish(&mut client, &format!("{}/status", settings.topic), true, "online");
+    for camera in cameras {
+        if let Err(e) = client.subscribe(format!("{}/{}/armed/set", settings.topic, camera.name), QoS::AtLeastOnce) {
+            println!("mqtt: {}", e);
+        }
+    }
+}
+
+/// Home Assistant entities for a camera: (component, object id, config)
+fn discovery(settings: &MqttSettings, camera: &Live) -> Vec<(&'static str, &'static str, Value)> {
+    let base = format!("{}/{}", settings.topic, camera.name);
+    let node = format!("{}_{}", settings.client_id, object_id(&camera.name));
+    let device = json!({
+        "identifiers": [node],
+        "name": camera.name,
+        "manufacturer": "Raspberry Pi",
+        "model": "rust-security",
+    });
+    let availability = json!([
+        { "topic": format!("{}/status", settings.topic) },
+        { "topic": format!("{}/availability", base) },
+    ]);
+    let entity = |object: &str, mut config: Value| {
+        config["unique_id"] = json!(format!("{}_{}", node, object));
+        config["device"] = device.clone();
+        config["availability"] = availability.clone();
+        config["availability_mode"] = json!("all");
+        config
+    };
+
+    vec![
+        (
+            "binary_sensor",
+            "motion",
+            entity(
+                "motion",
+                json!({
+                    "name": "Motion",
+                    "device_class": "motion",
+                    "state_topic": format!("{}/motion", base),
+                }),
+            ),
+        ),
+        (
+            "binary_sensor",
+            "tamper",
+            entity(
+                "tamper",
+                json!({
+                    "name": "Tamper",
+                    "device_class": "tamper",
+                    "state_topic": format!("{}/tamper", base),
+                    "json_attributes_topic": format!("{}/tamper_event", base),
+                }),
+            ),
+        ),
+        (
+            "camera",
+            "snapshot",
+            entity(
+                "snapshot",
+                json!({
+                    "name": "Snapshot",
+                    "topic": format!("{}/snapshot", base),
+                }),
+            ),
+        ),
+        (
+            "switch",
+            "armed",
+            entity(
+                "armed",
+                json!({
+                    "name": "Armed",
+                    "icon": "mdi:shield-home",
+                    "state_topic": format!("{}/armed", base),
+                    "command_topic": format!("{}/armed/set", base),
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "last_event",
+            entity(
+                "last_event",
+                json!({
+                    "name": "Last event",
+                    "device_class": "timestamp",
+                    "state_topic": format!("{}/event", base),
+                    "value_template": "{{ value_json.started }}",
+                    "json_attributes_topic": format!("{}/event", base),
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "last_frame",
+            entity(
+                "last_frame",
+                json!({
+                    "name": "Last frame",
+                    "device_class": "timestamp",
+                    "entity_category": "diagnostic",
+                    "state_topic": format!("{}/health", base),
+                    "value_template": "{{ value_json.last_frame }}",
+                    "json_attributes_topic": format!("{}/health", base),
+                }),
+            ),
+        ),
+    ]
+}
+
+/// Messages to <topic>/<camera>/armed/set
+fn command(settings: &MqttSettings, cameras: &[Arc<Live>], publish: &Publish) {
+    let name = match publish
+        .topic
+        .strip_prefix(&format!("{}/", settings.topic))
+        .and_then(|rest| rest.strip_suffix("/armed/set"))
+    {
+        Some(name) => name,
+        None => return,
+    };
+    let camera = match cameras.iter().find(|c| c.name == name) {
+        Some(camera) => camera,
+        None => return,
+    };
+    let payload = String::from_utf8_lossy(&publish.payload);
+    let armed = match payload.trim().to_uppercase().as_str() {
+        "ON" => true,
+        "OFF" => false,
+        _ => {
+            println!("mqtt: {} isn't ON or OFF", payload);
+            return;
+        }
+    };
+    if !camera.send(crate::live::Command::Arm(armed)) {
+        println!("mqtt: {} isn't running", camera.name);
+    }
+}
+
+/// Passes a camera's events and state on to the broker, until the camera goes away
+fn publish_camera(settings: &MqttSettings, mut client: Client, camera: &Live, connects: &AtomicU64) {
+    let base = format!("{}/{}", settings.topic, camera.name);
+    let changes = camera.subscribe_changes();
+    let interval = Duration::from_secs(settings.health_interval.max(1));
+    let mut seen_connects = 0;
+    let mut armed = None;
+    let mut online = None;
+    let mut health: Option<Instant> = None;
+
+    loop {
+        let change = match changes.recv_timeout(Duration::from_secs(1)) {
+            Ok(change) => Some(change),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let status = camera.status();
+
+        let connected = connects.load(Ordering::SeqCst);
+        if connected != seen_connects {
+            seen_connects = connected;
+            armed = None;
+            online = None;
+            health = None;
+            if change.is_none() {
+                publish(&mut client, &format!("{}/motion", base), true, on_off(status.event.is_some()));
+                publish(&mut client, &format!("{}/tamper", base), true, on_off(status.tamper.is_some()));
+            }
+        }
+
+        match change {
+            Some(Change::Started(event)) => {
+                publish(&mut client, &format!("{}/motion", base), true, "ON");
+                publish(&mut client, &format!("{}/event", base), true, serde_json::to_string(&event).unwrap_or_default());
+                if settings.snapshots {
+                    if let Some(frame) = camera.snapshot() {
+                        publish(&mut client, &format!("{}/snapshot", base), true, frame.jpeg.to_vec());
+                    }
+                }
+            }
+            Some(Change::Ended(event)) => {
+                publish(&mut client, &format!("{}/motion", base), true, "OFF");
+                publish(&mut client, &format!("{}/event", base), true, serde_json::to_string(&event).unwrap_or_default());
+            }
+            Some(Change::Finalized(entry)) => {
+                publish(&mut client, &format!("{}/event", base), true, serde_json::to_string(&entry).unwrap_or_default());
+            }
+            Some(Change::TamperStarted(tamper)) | Some(Change::TamperEnded(tamper)) => {
+                publish(&mut client, &format!("{}/tamper", base), true, on_off(tamper.ended.is_none()));
+                publish(&mut client, &format!("{}/tamper_event", base), true, serde_json::to_string(&tamper).unwrap_or_default());
+            }
+            None => {}
+        }
+
+        if armed != Some(status.armed) {
+            armed = Some(status.armed);
+            publish(&mut client, &format!("{}/armed", base), true, on_off(status.armed));
+        }
+        // a camera that's stopped sending frames for a while is as good as gone
+        let is_online = status.last_frame.map(|t| Local::now() - t < chrono::Duration::seconds(10)).unwrap_or(false);
+        if online != Some(is_online) {
+            online = Some(is_online);
+            publish(&mut client, &format!("{}/availability", base), true, if is_online { "online" } else { "offline" });
+        }
+        if health.map(|h| h.elapsed() >= interval).unwrap_or(true) {
+            health = Some(Instant::now());
+            let body = json!({
+                "last_frame": status.last_frame,
+                "armed": status.armed,
+                "motion": status.event.is_some(),
+                "stream_clients": camera.clients(),
+            });
+            publish(&mut client, &format!("{}/health", base), true, body.to_string());
+        }
+    }
+}
+
+fn publish<V: Into<Vec<u8>>>(client: &mut Client, topic: &str, retain: bool, payload: V) {
+    if let Err(e) = client.publish(topic, QoS::AtLeastOnce, retain, payload) {
+        println!("mqtt: {}: {}", topic, e);
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// Home Assistant only takes letters, digits, _ and - in ids
+fn object_id(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::MotionEvent;
+    use crate::live::Command;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    /// Packet type and flags, and the rest of the packet
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).ok()?;
+        let kind = byte[0];
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            length |= ((byte[0] & 0x7F) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).ok()?;
+        Some((kind, body))
+    }
+
+    /// Just enough of a broker for one client. Everything it's sent comes out
+    /// of `sink` as (topic, payload), with CONNECT and SUBSCRIBE as pretend topics.
+    fn broker(listener: TcpListener, sink: Sender<(String, Vec<u8>)>, client: Sender<TcpStream>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        client.send(stream.try_clone().unwrap()).unwrap();
+        while let Some((kind, body)) = read_packet(&mut stream) {
+            let reply = match kind >> 4 {
+                1 => {
+                    let _ = sink.send(("CONNECT".to_string(), body));
+                    vec![0x20, 2, 0, 0]
+                }
+                3 => {
+                    let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8_lossy(&body[2..2 + length]).into_owned();
+                    let mut payload = 2 + length;
+                    let mut reply = Vec::new();
+                    if (kind >> 1) & 3 > 0 {
+                        reply = vec![0x40, 2, body[payload], body[payload + 1]];
+                        payload += 2;
+                    }
+                    let _ = sink.send((topic, body[payload..].to_vec()));
+                    reply
+                }
+                8 => {
+                    let length = u16::from_be_bytes([body[2], body[3]]) as usize;
+                    let _ = sink.send(("SUBSCRIBE".to_string(), body[4..4 + length].to_vec()));
+                    vec![0x90, 3, body[0], body[1], 1]
+                }
+                12 => vec![0xD0, 0],
+                _ => Vec::new(),
+            };
+            if stream.write_all(&reply).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Waits until `topic` has been sent `payload`
+    fn wait_for(messages: &Receiver<(String, Vec<u8>)>, seen: &mut HashMap<String, Vec<u8>>, topic: &str, payload: &[u8]) {
+        while seen.get(topic).map(|p| &p[..]) != Some(payload) {
+            let (topic, payload) = messages.recv_timeout(Duration::from_secs(10)).expect("nothing from the client");
+            seen.insert(topic, payload);
+        }
+    }
+
+    /// Waits for anything sent to `topic`, after what's been seen already
+    fn next(messages: &Receiver<(String, Vec<u8>)>, seen: &mut HashMap<String, Vec<u8>>, topic: &str) -> Vec<u8> {
+        seen.remove(topic);
+        loop {
+            let (t, payload) = messages.recv_timeout(Duration::from_secs(10)).expect("nothing from the client");
+            if t == topic {
+                return payload;
+            }
+            seen.insert(t, payload);
+        }
+    }
+
+    #[test]
+    fn talks_to_a_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sink, messages) = mpsc::channel();
+        let (client, connection) = mpsc::channel();
+        std::thread::spawn(move || broker(listener, sink, client));
+
+        let live = Live::new("front door", 5);
+        let commands = live.take_commands().unwrap();
+        let settings = MqttSettings {
+            host: "127.0.0.1".to_string(),
+            port: port,
+            client_id: "test".to_string(),
+            topic: "home".to_string(),
+            snapshots: false,
+            ..MqttSettings::default()
+        };
+        let cameras = vec![live.clone()];
+        std::thread::spawn(move || run(settings, cameras));
+
+        let mut seen = HashMap::new();
+        let connect = next(&messages, &mut seen, "CONNECT");
+        // the last will, so the broker says we're offline if we drop
+        assert!(connect.windows(11).any(|w| w == b"home/status"));
+        assert!(connect.windows(7).any(|w| w == b"offline"));
+
+        wait_for(&messages, &mut seen, "home/status", b"online");
+        wait_for(&messages, &mut seen, "SUBSCRIBE", b"home/front door/armed/set");
+        let config: Value = serde_json::from_slice(&seen["homeassistant/switch/test_front_door/armed/config"]).unwrap();
+        assert_eq!(config["command_topic"], "home/front door/armed/set");
+        assert_eq!(config["unique_id"], "test_front_door_armed");
+        assert_eq!(config["availability"][0]["topic"], "home/status");
+        wait_for(&messages, &mut seen, "home/front door/armed", b"OFF");
+        wait_for(&messages, &mut seen, "home/front door/availability", b"offline");
+
+        // someone flips the switch in Home Assistant
+        let topic = b"home/front door/armed/set";
+        let mut packet = vec![0x30, (2 + topic.len() + 2) as u8, 0, topic.len() as u8];
+        packet.extend_from_slice(topic);
+        packet.extend_from_slice(b"ON");
+        connection.recv().unwrap().write_all(&packet).unwrap();
+        let command = commands.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(matches!(command, Command::Arm(true)));
+
+        live.update_status(|status| {
+            status.armed = true;
+            status.last_frame = Some(Local::now());
+        });
+        wait_for(&messages, &mut seen, "home/front door/armed", b"ON");
+        wait_for(&messages, &mut seen, "home/front door/availability", b"online");
+
+        live.notify(&Change::Started(MotionEvent::new("front door")));
+        let event: Value = serde_json::from_slice(&next(&messages, &mut seen, "home/front door/event")).unwrap();
+        assert_eq!(event["camera"], "front door");
+        wait_for(&messages, &mut seen, "home/front door/motion", b"ON");
+    }
+}
@@ -0,0 +1,414 @@
+/*
+Email and push notifications when motion starts (or once the event's over),
+with a picture.
+
+Starting notifications carry the event's snapshot, or failing that whatever the
+camera's showing. Ending ones carry the thumbnail, the frame with the most
+motion. Push goes to anything taking an HTTP POST: ntfy gets the picture as an
+attachment with the text in headers, "json" suits Gotify and home-grown
+services.
+
+Each camera notifies at most once per min_interval, and not at all during
+quiet hours. Nothing is retried, by the time a retry went through it'd be old
+news.
+
+Tampering (the camera covered, defocused or moved) is always notified as soon
+as it starts, whatever the hour.
+*/
+use crate::encryption;
+use crate::event::{self, MotionEvent};
+use crate::live::Live;
+use crate::monitor::Change;
+use crate::tamper::{Tamper, TamperEvent};
+
+use chrono::{Local, NaiveTime};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum When {
+    /// As soon as motion starts
+    Start,
+    /// Once the event's over and its thumbnail is written
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    None,
+    /// Plain connection upgraded with STARTTLS, usually port 587
+    StartTls,
+    /// TLS from the start, usually port 465
+    Tls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Smtp {
+    pub server: String,
+    pub port: u16,
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushFormat {
+    /// Picture as the body, text in Title and Message headers
+    Ntfy,
+    /// {"title", "message", "priority", "camera", "event"}, no picture
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Push {
+    pub url: String,
+    pub format: PushFormat,
+    /// Like Authorization or X-Gotify-Key
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Local times, e.g. 23:00:00 until 07:00:00
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            // goes past midnight
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NotifySettings {
+    pub smtp: Option<Smtp>,
+    pub push: Vec<Push>,
+    pub when: When,
+    /// Seconds between notifications for a camera
+    pub min_interval: u64,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        NotifySettings {
+            smtp: None,
+            push: Vec::new(),
+            when: When::Start,
+            min_interval: 300,
+            quiet_hours: None,
+        }
+    }
+}
+
+/// Watches every camera's events and sends notifications. Call from its own thread.
+pub fn run(settings: NotifySettings, cameras: Vec<Arc<Live>>) {
+    let mut threads = Vec::new();
+    for camera in cameras {
+        let settings = settings.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("{}-notify", camera.name))
+            .spawn(move || watch(&settings, &camera))
+            .unwrap();
+        threads.push(thread);
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+fn watch(settings: &NotifySettings, camera: &Live) {
+    let mut last_sent: Option<Instant> = None;
+    for change in camera.subscribe_changes() {
+        if let Change::TamperStarted(tamper) = &change {
+            let (title, message) = tamper_text(tamper);
+            let name = event::file_stem(&tamper.camera, &tamper.started);
+            let picture = camera.snapshot().map(|f| f.jpeg.to_vec());
+            send(settings, camera, &name, &title, &message, picture.as_deref());
+            continue;
+        }
+
+        let (event, picture) = match (settings.when, change) {
+            (When::Start, Change::Started(event)) => {
+                let picture = read(&event.snapshot).or_else(|| camera.snapshot().map(|f| f.jpeg.to_vec()));
+                (event, picture)
+            }
+            (When::End, Change::Finalized(entry)) => {
+                let picture = [&entry.event.thumbnail, &entry.event.snapshot].iter().find_map(|p| read(p));
+                (entry.event, picture)
+            }
+            _ => continue,
+        };
+
+        if let Some(quiet) = settings.quiet_hours {
+            if quiet.contains(Local::now().time()) {
+                continue;
+            }
+        }
+        if let Some(sent) = last_sent {
+            if sent.elapsed() < Duration::from_secs(settings.min_interval) {
+                continue;
+            }
+        }
+        last_sent = Some(Instant::now());
+
+        let (title, message) = text(&event);
+        send(settings, camera, &event.name(), &title, &message, picture.as_deref());
+    }
+}
+
+/// Email and push, `name` is for the picture's file name
+fn send(settings: &NotifySettings, camera: &Live, name: &str, title: &str, message: &str, picture: Option<&[u8]>) {
+    if let Some(smtp) = &settings.smtp {
+        if let Err(e) = email(smtp, name, title, message, picture) {
+            println!("{}: unable to send email: {}", camera.name, e);
+        }
+    }
+    for push in &settings.push {
+        if let Err(e) = send_push(push, &camera.name, name, title, message, picture) {
+            println!("{}: unable to notify {}: {}", camera.name, push.url, e);
+        }
+    }
+}
+
+fn text(event: &MotionEvent) -> (String, String) {
+    // "Person on camera0" if the classifier's had a look
+    let what = match event.labels.first() {
+        Some(label) => {
+            let mut chars = label.label.chars();
+            chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+        }
+        None => "Motion".to_string(),
+    };
+    let title = format!("{} on {}", what, event.camera);
+    let mut message = format!("{} at {}", title, event.started.format("%Y-%m-%d %H:%M:%S"));
+    if let Some(ended) = event.ended {
+        message.push_str(&format!(
+            ", lasting {}s with a peak score of {:.1}",
+            (ended - event.started).num_seconds(),
+            event.peak_score
+        ));
+    }
+    (title, message)
+}
+
+fn tamper_text(tamper: &TamperEvent) -> (String, String) {
+    let title = format!("{} tampered with", tamper.camera);
+    let what = match tamper.tamper {
+        Tamper::Covered => "looks covered",
+        Tamper::Defocused => "has lost focus, it may have been sprayed",
+        Tamper::Moved => "has been pointed somewhere else",
+    };
+    let message = format!("{} {} since {}", tamper.camera, what, tamper.started.format("%Y-%m-%d %H:%M:%S"));
+    (title, message)
+}
+
+fn email(smtp: &Smtp, name: &str, title: &str, message: &str, picture: Option<&[u8]>) -> Result<(), String> {
+    let from: Mailbox = smtp.from.parse().map_err(|e| format!("{}: {}", smtp.from, e))?;
+    let mut builder = Message::builder().from(from).subject(title);
+    for to in &smtp.to {
+        builder = builder.to(to.parse().map_err(|e| format!("{}: {}", to, e))?);
+    }
+    let mut body = MultiPart::mixed().singlepart(SinglePart::plain(message.to_string()));
+    if let Some(picture) = picture {
+        let attachment = Attachment::new(format!("{}.jpg", name)).body(picture.to_vec(), ContentType::parse("image/jpeg").unwrap());
+        body = body.singlepart(attachment);
+    }
+    let email = builder.multipart(body).map_err(|e| e.to_string())?;
+
+    let mut transport = match smtp.security {
+        Security::None => SmtpTransport::builder_dangerous(&smtp.server),
+        Security::StartTls => SmtpTransport::starttls_relay(&smtp.server).map_err(|e| e.to_string())?,
+        Security::Tls => SmtpTransport::relay(&smtp.server).map_err(|e| e.to_string())?,
+    }
+    .port(smtp.port)
+    .timeout(Some(Duration::from_secs(30)));
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport.build().send(&email).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn send_push(push: &Push, camera: &str, name: &str, title: &str, message: &str, picture: Option<&[u8]>) -> Result<(), String> {
+    let mut request = ureq::post(&push.url).timeout(Duration::from_secs(30));
+    for (name, value) in &push.headers {
+        request = request.set(name, value);
+    }
+    let result = match (push.format, picture) {
+        (PushFormat::Ntfy, Some(picture)) => request
+            .set("Title", title)
+            .set("Message", message)
+            .set("Tags", "rotating_light")
+            .set("Filename", &format!("{}.jpg", name))
+            .send_bytes(picture),
+        (PushFormat::Ntfy, None) => request.set("Title", title).set("Tags", "rotating_light").send_string(message),
+        (PushFormat::Json, _) => request.set("Content-Type", "application/json").send_string(
+            &json!({
+                "title": title,
+                "message": message,
+                "priority": 8,
+                "camera": camera,
+                "event": name,
+            })
+            .to_string(),
+        ),
+    };
+    result.map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// None for encrypted pictures, they're no use to anyone
+fn read(path: &Option<PathBuf>) -> Option<Vec<u8>> {
+    path.as_ref().filter(|p| !encryption::is_encrypted(p)).and_then(|p| fs::read(p).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Just enough of an SMTP server to take one message. Returns the port and
+    /// a thread giving back what came after DATA.
+    fn smtp_sink() -> (u16, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let thread = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 sink ESMTP\r\n").unwrap();
+            let mut data = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let command = line.to_uppercase();
+                if command.starts_with("EHLO") {
+                    writer.write_all(b"250-sink\r\n250 8BITMIME\r\n").unwrap();
+                } else if command.starts_with("DATA") {
+                    writer.write_all(b"354 go ahead\r\n").unwrap();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+                    writer.write_all(b"250 queued\r\n").unwrap();
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    writer.write_all(b"250 ok\r\n").unwrap();
+                }
+            }
+            data
+        });
+        (port, thread)
+    }
+
+    fn event() -> MotionEvent {
+        let mut event = MotionEvent::new("porch");
+        event.labels.push(event::Label {
+            label: "person".to_string(),
+            confidence: 0.9,
+        });
+        event
+    }
+
+    #[test]
+    fn emails_with_picture() {
+        let (port, sink) = smtp_sink();
+        let smtp = Smtp {
+            server: "127.0.0.1".to_string(),
+            port: port,
+            security: Security::None,
+            username: None,
+            password: None,
+            from: "camera@example.com".to_string(),
+            to: vec!["me@example.com".to_string()],
+        };
+        let (title, message) = text(&event());
+        email(&smtp, "porch_20240101-120000-000", &title, &message, Some(b"\xff\xd8jpeg")).unwrap();
+
+        let data = sink.join().unwrap();
+        assert!(data.contains("Subject: Person on porch"));
+        assert!(data.contains("To: me@example.com"));
+        assert!(data.contains("porch_20240101-120000-000.jpg"));
+        assert!(data.contains("image/jpeg"));
+    }
+
+    #[test]
+    fn pushes_to_ntfy() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cameras", server.server_addr().to_ip().unwrap());
+        let sink = std::thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let headers = request.headers().to_vec();
+            let header = |name: &'static str| headers.iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str().to_string());
+            let seen = (header("Title"), header("Filename"), header("X-Key"));
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            request.respond(tiny_http::Response::empty(200)).unwrap();
+            (seen, body)
+        });
+
+        let push = Push {
+            url: url,
+            format: PushFormat::Ntfy,
+            headers: vec![("X-Key".to_string(), "secret".to_string())].into_iter().collect(),
+        };
+        send_push(&push, "porch", "porch_x", "Motion on porch", "Motion", Some(b"picture")).unwrap();
+
+        let ((title, filename, key), body) = sink.join().unwrap();
+        assert_eq!(title.as_deref(), Some("Motion on porch"));
+        assert_eq!(filename.as_deref(), Some("porch_x.jpg"));
+        assert_eq!(key.as_deref(), Some("secret"));
+        assert_eq!(body, b"picture");
+    }
+
+    #[test]
+    fn quiet_hours_past_midnight() {
+        let quiet = QuietHours {
+            start: NaiveTime::from_hms_opt(23, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+        };
+        assert!(quiet.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(quiet.contains(NaiveTime::from_hms_opt(3, 0, 0).unwrap()));
+        assert!(!quiet.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn text_without_labels() {
+        let mut event = MotionEvent::new("porch");
+        event.peak_score = 0.5;
+        event.ended = Some(event.started + chrono::Duration::seconds(12));
+        let (title, message) = text(&event);
+        assert_eq!(title, "Motion on porch");
+        assert!(message.ends_with("lasting 12s with a peak score of 0.5"));
+    }
+}
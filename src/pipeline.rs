@@ -0,0 +1,384 @@
+/*
+The video half of a camera. A splitter lets one video port feed two things,
+like RaspiVid's preview splitter with the preview swapped for a resizer:
+
+camera video port -> splitter -> H.264 encoder -> live.video, and events' .h264 files
+                              -> resizer -> I420 at the motion detection size
+
+Both ends come back through port callbacks on MMAL's threads. H.264 is published
+straight from the encoder's callback so streams never wait on the camera thread.
+Access units and motion frames go to the camera thread over channels: every
+access unit is kept, since events need all of them, but only the latest couple
+of frames are, so a camera thread busy taking a still skips frames instead of
+falling behind.
+*/
+use crate::ffi;
+use crate::h264::AccessUnit;
+use crate::i420;
+use crate::live::Live;
+use crate::motion::MotionSettings;
+use crate::settings::CameraSettings;
+use crate::{CameraError, MMAL_ENCODING_I420};
+
+use std::mem::{self, MaybeUninit};
+use std::os::raw::c_char;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MMAL_ENCODING_H264: u32 = 875967048; //fourcc('H', '2', '6', '4');
+// raspistill uses it by name too, it's not in mmal_default_components.h
+const MMAL_COMPONENT_RESIZER: &[u8] = b"vc.ril.resize\0";
+// defined with INT64_C, which bindgen doesn't follow
+const MMAL_TIME_UNKNOWN: i64 = i64::MIN;
+// frames waiting for the camera thread, older ones aren't worth looking at
+const FRAME_QUEUE: usize = 2;
+
+/// port->userdata of the encoder output port
+struct H264Output {
+    live: Arc<Live>,
+    units: Mutex<Sender<AccessUnit>>,
+    pool: *mut ffi::MMAL_POOL_T,
+    /// A frame spread over several buffers, or SPS and PPS waiting for their keyframe
+    pending: Mutex<Vec<u8>>,
+    started: Instant,
+}
+
+/// port->userdata of the resizer output port
+struct FrameOutput {
+    frames: Mutex<SyncSender<Vec<u8>>>,
+    pool: *mut ffi::MMAL_POOL_T,
+}
+
+unsafe extern "C" fn h264_callback(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    let output = &*((*port).userdata as *const H264Output);
+    if (*buffer).length > 0 {
+        if let Ok(mut pending) = output.pending.lock() {
+            ffi::mmal_buffer_header_mem_lock(buffer);
+            let bytes = slice::from_raw_parts((*buffer).data.offset((*buffer).offset as isize), (*buffer).length as usize);
+            pending.extend_from_slice(bytes);
+            ffi::mmal_buffer_header_mem_unlock(buffer);
+
+            // SPS and PPS come in a CONFIG buffer of their own, so they stay
+            // pending and go out with the keyframe after them
+            let flags = (*buffer).flags;
+            if flags & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END != 0 && flags & ffi::MMAL_BUFFER_HEADER_FLAG_CONFIG == 0 {
+                let pts = (*buffer).pts;
+                let timestamp = if pts == MMAL_TIME_UNKNOWN || pts < 0 {
+                    output.started.elapsed()
+                } else {
+                    Duration::from_micros(pts as u64)
+                };
+                let unit = AccessUnit::from_annexb(&pending, timestamp);
+                pending.clear();
+                if let Ok(units) = output.units.lock() {
+                    let _ = units.send(unit.clone());
+                }
+                output.live.video.publish(unit);
+            }
+        }
+    }
+    ffi::mmal_buffer_header_release(buffer);
+    send_buffer(port, output.pool);
+}
+
+unsafe extern "C" fn frame_callback(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    let output = &*((*port).userdata as *const FrameOutput);
+    if (*buffer).length > 0 {
+        ffi::mmal_buffer_header_mem_lock(buffer);
+        let frame = slice::from_raw_parts((*buffer).data.offset((*buffer).offset as isize), (*buffer).length as usize).to_vec();
+        ffi::mmal_buffer_header_mem_unlock(buffer);
+        if let Ok(frames) = output.frames.lock() {
+            // full means the camera thread is busy, it can have the next one
+            let _ = frames.try_send(frame);
+        }
+    }
+    ffi::mmal_buffer_header_release(buffer);
+    send_buffer(port, output.pool);
+}
+
+/// Gives an output port a buffer from its pool in place of one it's done with
+unsafe fn send_buffer(port: *mut ffi::MMAL_PORT_T, pool: *mut ffi::MMAL_POOL_T) {
+    if (*port).is_enabled == 0 {
+        return;
+    }
+    let buffer = ffi::mmal_queue_get((*pool).queue);
+    if buffer.is_null() || ffi::mmal_port_send_buffer(port, buffer) != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        println!("Unable to return a buffer to a video port");
+    }
+}
+
+fn check(status: ffi::MMAL_STATUS_T, message: &str) -> Result<(), CameraError> {
+    if status != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        return Err(CameraError {
+            code: 1,
+            message: message.to_string()
+        })
+    }
+    Ok(())
+}
+
+/// Buffer size and count the port asks for, and at least its minimums
+unsafe fn recommended_buffers(port: *mut ffi::MMAL_PORT_T) {
+    (*port).buffer_size = (*port).buffer_size_recommended.max((*port).buffer_size_min);
+    (*port).buffer_num = (*port).buffer_num_recommended.max((*port).buffer_num_min);
+}
+
+/// Sets a port to I420 at `width` x `height`, padded the way i420.rs expects.
+/// The caller commits the format.
+pub unsafe fn set_i420(port: *mut ffi::MMAL_PORT_T, width: u32, height: u32) {
+    let format = (*port).format;
+    (*format).encoding = MMAL_ENCODING_I420;
+    (*format).encoding_variant = MMAL_ENCODING_I420;
+    let es = (*format).es;
+    let (padded_width, padded_height) = i420::padded(width, height);
+    (*es).video.width = padded_width;
+    (*es).video.height = padded_height;
+    (*es).video.crop.x = 0;
+    (*es).video.crop.y = 0;
+    (*es).video.crop.width = width as i32;
+    (*es).video.crop.height = height as i32;
+}
+
+/// Everything hanging off the camera's video port. Whatever has been set up is
+/// torn down when this drops, so a failure partway through `new` cleans up after itself.
+pub struct VideoPipeline {
+    video_port: *mut ffi::MMAL_PORT_T,
+    capturing: bool,
+    splitter: Option<NonNull<ffi::MMAL_COMPONENT_T>>,
+    encoder: Option<NonNull<ffi::MMAL_COMPONENT_T>>,
+    resizer: Option<NonNull<ffi::MMAL_COMPONENT_T>>,
+    encoder_pool: Option<NonNull<ffi::MMAL_POOL_T>>,
+    resizer_pool: Option<NonNull<ffi::MMAL_POOL_T>>,
+    connections: Vec<NonNull<ffi::MMAL_CONNECTION_T>>,
+    // what the output ports' userdata point at, dropped after the ports are disabled
+    h264: Option<Arc<H264Output>>,
+    frames: Option<Arc<FrameOutput>>,
+}
+
+impl VideoPipeline {
+    /// Hooks everything up to the video port and starts capturing. The video
+    /// port's format has to be committed before the camera was enabled.
+    ///
+    /// Returns the pipeline along with motion frames and access units for the camera thread.
+    pub fn new(
+        video_port: *mut ffi::MMAL_PORT_T,
+        settings: &CameraSettings,
+        motion: &MotionSettings,
+        live: Arc<Live>,
+    ) -> Result<(VideoPipeline, Receiver<Vec<u8>>, Receiver<AccessUnit>), CameraError> {
+        let mut pipeline = VideoPipeline {
+            video_port: video_port,
+            capturing: false,
+            splitter: None,
+            encoder: None,
+            resizer: None,
+            encoder_pool: None,
+            resizer_pool: None,
+            connections: Vec::new(),
+            h264: None,
+            frames: None,
+        };
+
+        // splitter, same format in as out
+        let splitter = create(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_SPLITTER.as_ptr() as *const c_char, "video splitter")?;
+        pipeline.splitter = Some(splitter);
+        if unsafe { splitter.as_ref().output_num } < 2 {
+            return Err(CameraError {
+                code: 1,
+                message: "Video splitter doesn't have two outputs".to_string()
+            })
+        }
+        let splitter_input = unsafe { *splitter.as_ref().input };
+        let splitter_outputs = unsafe { [*splitter.as_ref().output, *splitter.as_ref().output.offset(1)] };
+        unsafe {
+            ffi::mmal_format_copy((*splitter_input).format, (*video_port).format);
+            (*splitter_input).buffer_num = (*splitter_input).buffer_num.max(3);
+            check(ffi::mmal_port_format_commit(splitter_input), "Unable to set video splitter input format")?;
+            for output in splitter_outputs.iter() {
+                ffi::mmal_format_copy((**output).format, (*splitter_input).format);
+                check(ffi::mmal_port_format_commit(*output), "Unable to set video splitter output format")?;
+            }
+            check(ffi::mmal_component_enable(splitter.as_ptr()), "Unable to enable video splitter")?;
+        }
+
+        // H.264 encoder, set up the way RaspiVid does it
+        let encoder = create(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_ENCODER.as_ptr() as *const c_char, "video encoder")?;
+        pipeline.encoder = Some(encoder);
+        let (encoder_input, encoder_output) = unsafe { (*encoder.as_ref().input, *encoder.as_ref().output) };
+        unsafe {
+            ffi::mmal_format_copy((*encoder_output).format, (*encoder_input).format);
+            let format = (*encoder_output).format;
+            (*format).encoding = MMAL_ENCODING_H264;
+            (*format).bitrate = settings.bitrate;
+            // frame rate comes from the timestamps
+            (*(*format).es).video.frame_rate.num = 0;
+            (*(*format).es).video.frame_rate.den = 1;
+            recommended_buffers(encoder_output);
+            check(ffi::mmal_port_format_commit(encoder_output), "Unable to set video encoder output format")?;
+
+            // a keyframe a second: how long new viewers wait, and how far back event files start
+            let status = ffi::mmal_port_parameter_set_uint32(encoder_output, ffi::MMAL_PARAMETER_INTRAPERIOD as u32, settings.framerate.max(1));
+            check(status, "Unable to set video encoder intra period")?;
+
+            let mut profile: ffi::MMAL_PARAMETER_VIDEO_PROFILE_T = mem::zeroed();
+            profile.hdr.id = ffi::MMAL_PARAMETER_PROFILE as u32;
+            profile.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_VIDEO_PROFILE_T>() as u32;
+            // the one profile every WebRTC browser can decode
+            profile.profile[0].profile = ffi::MMAL_VIDEO_PROFILE_T_MMAL_VIDEO_PROFILE_H264_CONSTRAINED_BASELINE;
+            profile.profile[0].level = ffi::MMAL_VIDEO_LEVEL_T_MMAL_VIDEO_LEVEL_H264_4;
+            check(ffi::mmal_port_parameter_set(encoder_output, &profile.hdr), "Unable to set H.264 profile")?;
+
+            // SPS and PPS before every keyframe, so a stream or file can start at any of them
+            let status = ffi::mmal_port_parameter_set_boolean(encoder_output, ffi::MMAL_PARAMETER_VIDEO_ENCODE_INLINE_HEADER as u32, 1);
+            check(status, "Unable to turn on inline H.264 headers")?;
+
+            check(ffi::mmal_component_enable(encoder.as_ptr()), "Unable to enable video encoder")?;
+        }
+        pipeline.encoder_pool = Some(create_pool(encoder_output, "video encoder")?);
+
+        // resizer down to the motion detection size
+        let resizer = create(MMAL_COMPONENT_RESIZER.as_ptr() as *const c_char, "resizer")?;
+        pipeline.resizer = Some(resizer);
+        let (resizer_input, resizer_output) = unsafe { (*resizer.as_ref().input, *resizer.as_ref().output) };
+        unsafe {
+            ffi::mmal_format_copy((*resizer_input).format, (*splitter_outputs[1]).format);
+            check(ffi::mmal_port_format_commit(resizer_input), "Unable to set resizer input format")?;
+            ffi::mmal_format_copy((*resizer_output).format, (*resizer_input).format);
+            set_i420(resizer_output, motion.width, motion.height);
+            recommended_buffers(resizer_output);
+            check(ffi::mmal_port_format_commit(resizer_output), "Unable to set resizer output format")?;
+            check(ffi::mmal_component_enable(resizer.as_ptr()), "Unable to enable resizer")?;
+        }
+        pipeline.resizer_pool = Some(create_pool(resizer_output, "resizer")?);
+
+        pipeline.connect(video_port, splitter_input)?;
+        pipeline.connect(splitter_outputs[0], encoder_input)?;
+        pipeline.connect(splitter_outputs[1], resizer_input)?;
+
+        let (units_sender, units) = mpsc::channel();
+        let h264 = Arc::new(H264Output {
+            live: live,
+            units: Mutex::new(units_sender),
+            pool: pipeline.encoder_pool.unwrap().as_ptr(),
+            pending: Mutex::new(Vec::new()),
+            started: Instant::now(),
+        });
+        pipeline.h264 = Some(h264.clone());
+        let userdata = Arc::as_ptr(&h264) as *mut ffi::MMAL_PORT_USERDATA_T;
+        unsafe { enable_output(encoder_output, userdata, h264_callback, h264.pool, "video encoder")? };
+
+        let (frames_sender, frames) = mpsc::sync_channel(FRAME_QUEUE);
+        let output = Arc::new(FrameOutput {
+            frames: Mutex::new(frames_sender),
+            pool: pipeline.resizer_pool.unwrap().as_ptr(),
+        });
+        pipeline.frames = Some(output.clone());
+        let userdata = Arc::as_ptr(&output) as *mut ffi::MMAL_PORT_USERDATA_T;
+        unsafe { enable_output(resizer_output, userdata, frame_callback, output.pool, "resizer")? };
+
+        let status = unsafe { ffi::mmal_port_parameter_set_boolean(video_port, ffi::MMAL_PARAMETER_CAPTURE as u32, 1) };
+        check(status, "Unable to start video capture")?;
+        pipeline.capturing = true;
+
+        Ok((pipeline, frames, units))
+    }
+
+    /// Tunnels `output` into `input`
+    fn connect(&mut self, output: *mut ffi::MMAL_PORT_T, input: *mut ffi::MMAL_PORT_T) -> Result<(), CameraError> {
+        let mut connection_ptr = MaybeUninit::<*mut ffi::MMAL_CONNECTION_T>::uninit();
+        let status = unsafe {
+            ffi::mmal_connection_create(connection_ptr.as_mut_ptr(), output, input, ffi::MMAL_CONNECTION_FLAG_TUNNELLING | ffi::MMAL_CONNECTION_FLAG_ALLOCATION_ON_INPUT)
+        };
+        check(status, "Unable to connect video ports")?;
+        let connection = NonNull::new(unsafe { connection_ptr.assume_init() }).unwrap();
+        self.connections.push(connection);
+        check(unsafe { ffi::mmal_connection_enable(connection.as_ptr()) }, "Unable to enable video port connection")
+    }
+}
+
+fn create(name: *const c_char, what: &str) -> Result<NonNull<ffi::MMAL_COMPONENT_T>, CameraError> {
+    let mut component = MaybeUninit::<*mut ffi::MMAL_COMPONENT_T>::uninit();
+    let status = unsafe { ffi::mmal_component_create(name, component.as_mut_ptr()) };
+    check(status, &format!("Failed to create {}", what))?;
+    let component = NonNull::new(unsafe { component.assume_init() });
+    match component {
+        Some(component) if unsafe { component.as_ref().input_num > 0 && component.as_ref().output_num > 0 } => Ok(component),
+        Some(component) => {
+            unsafe { ffi::mmal_component_destroy(component.as_ptr()) };
+            Err(CameraError {
+                code: 1,
+                message: format!("The {} doesn't have input/output ports", what)
+            })
+        }
+        None => Err(CameraError {
+            code: 1,
+            message: format!("Failed to create {}", what)
+        }),
+    }
+}
+
+fn create_pool(port: *mut ffi::MMAL_PORT_T, what: &str) -> Result<NonNull<ffi::MMAL_POOL_T>, CameraError> {
+    let pool = unsafe { ffi::mmal_port_pool_create(port, (*port).buffer_num, (*port).buffer_size) };
+    NonNull::new(pool).ok_or_else(|| CameraError {
+        code: 1,
+        message: format!("Failed to create buffer header pool for {} output port", what)
+    })
+}
+
+/// Points the port's userdata at what its callback needs, enables it, and
+/// hands it every buffer in its pool
+unsafe fn enable_output(
+    port: *mut ffi::MMAL_PORT_T,
+    userdata: *mut ffi::MMAL_PORT_USERDATA_T,
+    callback: unsafe extern "C" fn(*mut ffi::MMAL_PORT_T, *mut ffi::MMAL_BUFFER_HEADER_T),
+    pool: *mut ffi::MMAL_POOL_T,
+    what: &str,
+) -> Result<(), CameraError> {
+    (*port).userdata = userdata;
+    if ffi::mmal_port_enable(port, Some(callback)) != ffi::MMAL_STATUS_T_MMAL_SUCCESS {
+        (*port).userdata = ptr::null_mut();
+        return Err(CameraError {
+            code: 1,
+            message: format!("Unable to enable {} output port", what)
+        })
+    }
+    for _ in 0..ffi::mmal_queue_length((*pool).queue) {
+        send_buffer(port, pool);
+    }
+    Ok(())
+}
+
+impl Drop for VideoPipeline {
+    fn drop(&mut self) {
+        unsafe {
+            if self.capturing {
+                ffi::mmal_port_parameter_set_boolean(self.video_port, ffi::MMAL_PARAMETER_CAPTURE as u32, 0);
+            }
+            // callbacks have to stop before what their userdata points at goes
+            for component in [self.encoder, self.resizer].iter().flatten() {
+                let output = *component.as_ref().output;
+                if (*output).is_enabled != 0 {
+                    ffi::mmal_port_disable(output);
+                }
+                (*output).userdata = ptr::null_mut();
+            }
+            // destroying a connection disables it first
+            for connection in self.connections.drain(..).rev() {
+                ffi::mmal_connection_destroy(connection.as_ptr());
+            }
+            if let (Some(encoder), Some(pool)) = (self.encoder, self.encoder_pool) {
+                ffi::mmal_port_pool_destroy(*encoder.as_ref().output, pool.as_ptr());
+            }
+            if let (Some(resizer), Some(pool)) = (self.resizer, self.resizer_pool) {
+                ffi::mmal_port_pool_destroy(*resizer.as_ref().output, pool.as_ptr());
+            }
+            for component in [self.encoder, self.resizer, self.splitter].iter().flatten() {
+                ffi::mmal_component_disable(component.as_ptr());
+                ffi::mmal_component_destroy(component.as_ptr());
+            }
+        }
+    }
+}
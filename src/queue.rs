@@ -0,0 +1,123 @@
+/*
+Jobs waiting on disk, one JSON file each, so they survive restarts and
+whatever they're waiting for being down. Webhook deliveries (hooks.rs) and
+uploads (upload.rs) both queue through this.
+
+Files are named after when they were queued, with a counter for ones queued
+in the same millisecond, so sorting the names gives the order they went in.
+Jobs are rewritten through a temporary file, so a crash can't leave half of
+one, and a file that doesn't parse is dropped rather than tried forever.
+*/
+use chrono::Local;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct DiskQueue {
+    dir: PathBuf,
+    /// What the queue's for, like "upload", to start messages with
+    name: &'static str,
+    sequence: AtomicU64,
+}
+
+impl DiskQueue {
+    /// Makes the directory if it isn't there
+    pub fn open(dir: &Path, name: &'static str) -> io::Result<DiskQueue> {
+        fs::create_dir_all(dir)?;
+        Ok(DiskQueue {
+            dir: dir.to_path_buf(),
+            name: name,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Adds a job at the back. Returns its file.
+    pub fn push<T: Serialize>(&self, job: &T) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!(
+            "{}-{:06}.json",
+            Local::now().format("%Y%m%d-%H%M%S%.3f"),
+            self.sequence.fetch_add(1, Ordering::SeqCst) % 1_000_000
+        ));
+        save(&path, job)?;
+        Ok(path)
+    }
+
+    /// Every job's file, oldest first
+    pub fn paths(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("json"))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Every job, oldest first, along with its file. Unreadable ones are dropped.
+    pub fn jobs<T: DeserializeOwned>(&self) -> Vec<(PathBuf, T)> {
+        let paths = match self.paths() {
+            Ok(paths) => paths,
+            Err(e) => {
+                println!("{}: {}: {}", self.name, self.dir.display(), e);
+                return Vec::new();
+            }
+        };
+        let mut jobs = Vec::new();
+        for path in paths {
+            match fs::read_to_string(&path).ok().and_then(|json| serde_json::from_str(&json).ok()) {
+                Some(job) => jobs.push((path, job)),
+                // unless it went while we were looking
+                None if path.exists() => {
+                    println!("{}: dropping unreadable {}", self.name, path.display());
+                    remove(&path);
+                }
+                None => {}
+            }
+        }
+        jobs
+    }
+}
+
+/// Written to a temporary file first, so a crash can't leave half a job
+pub fn save<T: Serialize>(path: &Path, job: &T) -> io::Result<()> {
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, serde_json::to_string(job)?)?;
+    fs::rename(&temp, path)
+}
+
+/// Done with a job, one way or another
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_first() {
+        let dir = std::env::temp_dir().join(format!("queue-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let queue = DiskQueue::open(&dir, "test").unwrap();
+        let first = queue.push(&1u32).unwrap();
+        // the same millisecond, most likely
+        let second = queue.push(&2u32).unwrap();
+        fs::write(dir.join("0-junk.json"), "{").unwrap();
+        fs::write(dir.join("not-a-job.txt"), "3").unwrap();
+
+        let jobs: Vec<(PathBuf, u32)> = queue.jobs();
+        assert_eq!(jobs, vec![(first.clone(), 1), (second, 2)]);
+        assert!(!dir.join("0-junk.json").exists());
+
+        save(&first, &5u32).unwrap();
+        remove(&jobs[1].0);
+        assert_eq!(queue.jobs::<u32>(), vec![(first, 5)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
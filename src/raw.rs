@@ -0,0 +1,261 @@
+/*
+Raw Bayer data from MMAL_PARAMETER_ENABLE_RAW_CAPTURE.
+
+With raw capture on, the firmware appends a "BRCM" block to the end of the JPEG.
+It's a 32k header followed by packed sensor data, one block size per sensor.
+Layout details are from picamera's PiBayerArray.
+*/
+use crate::illumination::CameraGains;
+use crate::tiff::{self, Entry};
+
+use std::io;
+
+pub struct Sensor {
+    pub name: &'static str,
+    /// Size of the BRCM block appended to the JPEG
+    block_size: usize,
+    bits: u32,
+    black_level: u16,
+    /// XYZ (D65) to camera, row major. Rough calibrations, good enough to get
+    /// sensible colors out of a raw converter.
+    color_matrix: [f64; 9],
+}
+
+pub const SENSORS: [Sensor; 3] = [
+    // v1 camera module
+    Sensor {
+        name: "OV5647",
+        block_size: 6404096,
+        bits: 10,
+        black_level: 16,
+        color_matrix: [1.2782, -0.4059, -0.0379, -0.0478, 0.9066, 0.1413, 0.1340, 0.1513, 0.5176],
+    },
+    // v2 camera module
+    Sensor {
+        name: "IMX219",
+        block_size: 10270208,
+        bits: 10,
+        black_level: 64,
+        color_matrix: [1.9549, -0.7877, -0.2582, -0.5724, 1.0121, 0.1917, -0.1267, -0.0110, 0.6621],
+    },
+    // HQ camera
+    Sensor {
+        name: "IMX477",
+        block_size: 18711040,
+        bits: 12,
+        black_level: 256,
+        color_matrix: [0.5603, -0.1351, -0.0600, -0.2872, 1.2164, 0.0835, -0.0478, 0.0908, 0.6052],
+    },
+];
+
+const HEADER_SIZE: usize = 32768;
+// where the BroadcomRawHeader struct starts inside the block
+const HEADER_OFFSET: usize = 176;
+
+pub struct RawImage {
+    pub sensor: &'static Sensor,
+    pub width: u32,
+    pub height: u32,
+    /// 0 = RGGB, 1 = GBRG, 2 = BGGR, 3 = GRBG
+    pub bayer_order: u8,
+    /// One unpacked sample per pixel, width * height of them
+    pub pixels: Vec<u16>,
+}
+
+/// Pulls the Bayer data off the end of a JPEG captured with raw capture enabled
+pub fn extract(jpeg: &[u8]) -> io::Result<RawImage> {
+    let sensor = SENSORS
+        .iter()
+        .find(|s| jpeg.len() >= s.block_size && &jpeg[jpeg.len() - s.block_size..][..4] == b"BRCM")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No BRCM raw block found"))?;
+
+    let block = &jpeg[jpeg.len() - sensor.block_size..];
+    let header = &block[HEADER_OFFSET..];
+    let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+    let width = u16_at(32) as usize;
+    let height = u16_at(34) as usize;
+    let bayer_order = header[68];
+
+    // rows are padded to 32 bytes, and there are spare rows at the bottom
+    let stride = (width * sensor.bits as usize / 8 + 31) & !31;
+    let data = &block[HEADER_SIZE..];
+    if width == 0 || height == 0 || data.len() < stride * height {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Raw block is smaller than its header says"));
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = &data[y * stride..(y + 1) * stride];
+        if sensor.bits == 10 {
+            // 4 pixels in 5 bytes: high 8 bits each, then a byte of the low 2 bits
+            for chunk in row.chunks_exact(5).take((width + 3) / 4) {
+                for i in 0..4 {
+                    pixels.push(((chunk[i] as u16) << 2) | ((chunk[4] as u16 >> (i * 2)) & 3));
+                }
+            }
+        } else {
+            // 2 pixels in 3 bytes: high 8 bits each, then a byte of the low 4 bits
+            for chunk in row.chunks_exact(3).take((width + 1) / 2) {
+                pixels.push(((chunk[0] as u16) << 4) | (chunk[2] as u16 & 0x0F));
+                pixels.push(((chunk[1] as u16) << 4) | (chunk[2] as u16 >> 4));
+            }
+        }
+        pixels.truncate((y + 1) * width);
+    }
+
+    Ok(RawImage {
+        sensor: sensor,
+        width: width as u32,
+        height: height as u32,
+        bayer_order: bayer_order,
+        pixels: pixels,
+    })
+}
+
+impl RawImage {
+    /// A DNG that raw converters (darktable, RawTherapee, Lightroom) can open.
+    ///
+    /// `gains` is what the camera reported at capture time, used for the as-shot white balance.
+    pub fn to_dng(&self, camera: &str, gains: Option<CameraGains>) -> Vec<u8> {
+        // CFA colors: 0 = red, 1 = green, 2 = blue
+        let cfa: [u8; 4] = match self.bayer_order {
+            1 => [1, 2, 0, 1],
+            2 => [2, 1, 1, 0],
+            3 => [1, 0, 2, 1],
+            _ => [0, 1, 1, 2],
+        };
+        let matrix: Vec<(i32, i32)> = self.sensor.color_matrix.iter().map(|v| ((v * 10000.0).round() as i32, 10000)).collect();
+        let neutral = match gains {
+            Some(g) if g.awb_red_gain > 0.0 && g.awb_blue_gain > 0.0 => [
+                (10000, (g.awb_red_gain * 10000.0).round() as u32),
+                (1, 1),
+                (10000, (g.awb_blue_gain * 10000.0).round() as u32),
+            ],
+            _ => [(1, 1), (1, 1), (1, 1)],
+        };
+        let model = format!("{} ({})", camera, self.sensor.name);
+        let strip_size = self.pixels.len() * 2;
+
+        let mut ifd0 = vec![
+            Entry::long(0x00FE, 0), // NewSubFileType: main image
+            Entry::long(0x0100, self.width),
+            Entry::long(0x0101, self.height),
+            Entry::short(0x0102, 16), // BitsPerSample
+            Entry::short(0x0103, 1), // Compression: none
+            Entry::short(0x0106, 32803), // PhotometricInterpretation: CFA
+            Entry::ascii(0x010F, "RaspberryPi"),
+            Entry::ascii(0x0110, &model),
+            Entry::long(0x0111, 0), // StripOffsets, filled in below
+            Entry::short(0x0112, 1), // Orientation
+            Entry::short(0x0115, 1), // SamplesPerPixel
+            Entry::long(0x0116, self.height), // RowsPerStrip
+            Entry::long(0x0117, strip_size as u32), // StripByteCounts
+            Entry::short(0x011C, 1), // PlanarConfiguration
+            Entry::shorts(0x828D, &[2, 2]), // CFARepeatPatternDim
+            Entry::bytes(0x828E, &cfa), // CFAPattern
+            Entry::bytes(0xC612, &[1, 4, 0, 0]), // DNGVersion
+            Entry::ascii(0xC614, &model), // UniqueCameraModel
+            Entry::long(0xC61A, self.sensor.black_level as u32), // BlackLevel
+            Entry::long(0xC61D, (1 << self.sensor.bits) - 1), // WhiteLevel
+            Entry::srationals(0xC621, &matrix), // ColorMatrix1
+            Entry::rationals(0xC628, &neutral), // AsShotNeutral
+            Entry::short(0xC65A, 21), // CalibrationIlluminant1: D65
+        ];
+        ifd0.sort_by_key(|e| e.tag);
+
+        let strip_offset = 8 + tiff::ifd_size(&ifd0);
+        for entry in ifd0.iter_mut() {
+            if entry.tag == 0x0111 {
+                entry.data = (strip_offset as u32).to_be_bytes().to_vec();
+            }
+        }
+
+        let mut out = tiff::header();
+        tiff::write_ifd(&mut out, &ifd0);
+        out.reserve(strip_size);
+        for pixel in &self.pixels {
+            out.extend_from_slice(&pixel.to_be_bytes());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JPEG with a BRCM block for `sensor` on the end, holding `data`
+    fn capture(sensor: &Sensor, width: u16, height: u16, bayer_order: u8, data: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xD9];
+        let mut block = vec![0; sensor.block_size];
+        block[..4].copy_from_slice(b"BRCM");
+        block[HEADER_OFFSET + 32..HEADER_OFFSET + 34].copy_from_slice(&width.to_le_bytes());
+        block[HEADER_OFFSET + 34..HEADER_OFFSET + 36].copy_from_slice(&height.to_le_bytes());
+        block[HEADER_OFFSET + 68] = bayer_order;
+        block[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+        jpeg.extend_from_slice(&block);
+        jpeg
+    }
+
+    #[test]
+    fn unpacks_10_bit() {
+        // one row of 4 pixels, then the second row a 32 byte stride later
+        let mut data = vec![0; 64];
+        data[..5].copy_from_slice(&[0x01, 0x02, 0x03, 0xFF, 0b11_10_01_00]);
+        data[32..37].copy_from_slice(&[0x10, 0, 0, 0, 0b00_00_00_11]);
+        let raw = extract(&capture(&SENSORS[0], 4, 2, 2, &data)).unwrap();
+        assert_eq!(raw.sensor.name, "OV5647");
+        assert_eq!((raw.width, raw.height, raw.bayer_order), (4, 2, 2));
+        assert_eq!(raw.pixels, vec![0x04, 0x09, 0x0E, 0x3FF, 0x43, 0, 0, 0]);
+    }
+
+    #[test]
+    fn unpacks_12_bit() {
+        // 3 pixels, so half of the second pair is padding
+        let data = [0x12, 0x34, 0x56, 0xAB, 0xCD, 0x0F];
+        let raw = extract(&capture(&SENSORS[2], 3, 1, 0, &data)).unwrap();
+        assert_eq!(raw.sensor.name, "IMX477");
+        assert_eq!(raw.pixels, vec![0x126, 0x345, 0xABF]);
+    }
+
+    #[test]
+    fn refuses_bad_blocks() {
+        assert!(extract(&[0xFF, 0xD8, 0xFF, 0xD9]).is_err());
+        assert!(extract(&capture(&SENSORS[0], 0, 2, 0, &[])).is_err());
+        // more rows than the block has room for
+        assert!(extract(&capture(&SENSORS[0], 2592, 4000, 0, &[])).is_err());
+    }
+
+    #[test]
+    fn writes_dng() {
+        let raw = RawImage {
+            sensor: &SENSORS[1],
+            width: 2,
+            height: 2,
+            bayer_order: 2,
+            pixels: vec![1, 2, 3, 1023],
+        };
+        let gains = CameraGains {
+            exposure: 10000,
+            analog_gain: 1.0,
+            digital_gain: 1.0,
+            awb_red_gain: 2.0,
+            awb_blue_gain: 1.25,
+        };
+        let dng = raw.to_dng("porch", Some(gains));
+        let ifd = tiff::read_ifd(&dng, 8);
+        let value = |tag: u16| ifd.iter().find(|e| e.0 == tag).map(|e| e.3.clone()).unwrap();
+
+        assert_eq!(value(0x0110), b"porch (IMX219)\0");
+        assert_eq!(value(0x828E), [2, 1, 1, 0]);
+        assert_eq!(value(0xC61A), 64u32.to_be_bytes());
+        assert_eq!(value(0xC61D), 1023u32.to_be_bytes());
+        assert_eq!(&value(0xC628)[..8], [0, 0, 0x27, 0x10, 0, 0, 0x4E, 0x20]);
+
+        let offset = value(0x0111);
+        let strip = u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]) as usize;
+        // the pixels come last, big endian
+        assert_eq!(dng.len(), strip + 8);
+        assert_eq!(&dng[strip..], [0, 1, 0, 2, 0, 3, 3, 255]);
+    }
+}
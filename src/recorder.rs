@@ -0,0 +1,156 @@
+/*
+Writes the H.264 for an event to its .h264 file, as a raw Annex B stream that
+VLC and ffmpeg play as is.
+
+Every access unit from the encoder comes through here, event or not, so the
+ones since the last keyframe are always on hand. A file that started in the
+middle of a GOP couldn't be decoded until the next keyframe, so each event's
+file starts with that keyframe instead, which also gets a moment of footage
+from before the motion.
+
+With encryption set up the file is an age stream, written as it goes, and
+gets .age on the end of its name.
+*/
+use crate::encryption::{self, Encryption, FileWriter};
+use crate::h264::AccessUnit;
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// keyframes come every second or so, this is in case the encoder stops sending them
+const MAX_GOP: usize = 300;
+
+pub struct Recorder {
+    /// Access units from the last keyframe on
+    gop: Vec<AccessUnit>,
+    file: Option<(PathBuf, FileWriter)>,
+    encryption: Option<Encryption>,
+}
+
+impl Recorder {
+    pub fn new(encryption: Option<Encryption>) -> Recorder {
+        Recorder {
+            gop: Vec::new(),
+            file: None,
+            encryption: encryption,
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Starts writing to `path`, from the last keyframe
+    pub fn start(&mut self, path: &Path) -> io::Result<()> {
+        self.stop()?;
+        let (path, mut file) = encryption::create(self.encryption.as_ref(), path)?;
+        for unit in &self.gop {
+            write_unit(&mut file, unit)?;
+        }
+        self.file = Some((path, file));
+        Ok(())
+    }
+
+    /// Every access unit from the encoder
+    pub fn unit(&mut self, unit: AccessUnit) -> io::Result<()> {
+        let result = match self.file.as_mut() {
+            Some((_, file)) => write_unit(file, &unit),
+            None => Ok(()),
+        };
+        if unit.keyframe {
+            self.gop.clear();
+        }
+        if unit.keyframe || (!self.gop.is_empty() && self.gop.len() < MAX_GOP) {
+            self.gop.push(unit);
+        }
+        result
+    }
+
+    /// Finishes the file, returning where it went
+    pub fn stop(&mut self) -> io::Result<Option<PathBuf>> {
+        match self.file.take() {
+            Some((path, file)) => {
+                file.finish()?;
+                Ok(Some(path))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn write_unit<W: Write>(out: &mut W, unit: &AccessUnit) -> io::Result<()> {
+    for nal in &unit.nals {
+        out.write_all(&[0, 0, 0, 1])?;
+        out.write_all(nal)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264;
+    use std::time::Duration;
+
+    fn unit(kind: u8, n: u8) -> AccessUnit {
+        AccessUnit::from_annexb(&[0, 0, 0, 1, kind, n], Duration::from_millis(n as u64 * 40))
+    }
+
+    #[test]
+    fn starts_at_the_last_keyframe() {
+        let dir = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("porch-20210830-153012-250.h264");
+
+        let mut recorder = Recorder::new(None);
+        // nothing to start from before the first keyframe
+        recorder.unit(unit(0x41, 1)).unwrap();
+        recorder.unit(unit(0x65, 2)).unwrap();
+        recorder.unit(unit(0x41, 3)).unwrap();
+        recorder.start(&path).unwrap();
+        assert!(recorder.recording());
+        recorder.unit(unit(0x41, 4)).unwrap();
+        recorder.unit(unit(0x65, 5)).unwrap();
+        assert_eq!(recorder.stop().unwrap(), Some(path.clone()));
+        assert!(!recorder.recording());
+        recorder.unit(unit(0x41, 6)).unwrap();
+        assert_eq!(recorder.stop().unwrap(), None);
+
+        let data = std::fs::read(&path).unwrap();
+        let nals: Vec<&[u8]> = h264::split_annexb(&data);
+        assert_eq!(nals, vec![&[0x65, 2][..], &[0x41, 3], &[0x41, 4], &[0x65, 5]]);
+
+        // the next event starts from the latest keyframe
+        recorder.start(&path).unwrap();
+        recorder.stop().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(h264::split_annexb(&data), vec![&[0x65, 5][..], &[0x41, 6]]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_as_it_goes() {
+        let dir = std::env::temp_dir().join(format!("recorder-age-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = age::x25519::Identity::generate();
+        let encryption = Encryption::new(&encryption::EncryptionSettings {
+            recipients: vec![identity.to_public().to_string()],
+        })
+        .unwrap();
+
+        let mut recorder = Recorder::new(Some(encryption));
+        recorder.unit(unit(0x65, 1)).unwrap();
+        recorder.start(&dir.join("porch.h264")).unwrap();
+        recorder.unit(unit(0x41, 2)).unwrap();
+        let path = recorder.stop().unwrap().unwrap();
+        assert_eq!(path, dir.join("porch.h264.age"));
+        assert!(!dir.join("porch.h264").exists());
+
+        let decrypted = encryption::decrypt_file(&[identity], &path).unwrap();
+        let data = std::fs::read(&decrypted).unwrap();
+        assert_eq!(h264::split_annexb(&data), vec![&[0x65, 1][..], &[0x41, 2]]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+/*
+The one place recordings get deleted, whether from the API or by the uploader
+once an event's files are up.
+
+With the hash chain on (chain.json), an event isn't deleted until the chain
+has hashed and logged its files, otherwise a quick upload could take them away
+before there was any record of them. The API refuses with a 409 until then.
+The uploader's deletions wait here instead and are tried again on every sweep.
+They're only kept in memory, so after a restart an event that was waiting just
+stays until it's deleted some other way.
+
+Deleting through the index takes the whole event and its index entry. Events
+the index doesn't know, or no index at all, only lose the files asked for.
+*/
+use crate::chain;
+use crate::index::{EventIndex, IndexEntry};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What came of a deletion
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Outcome {
+    /// Gone, along with its index entry if it had one
+    Deleted(Option<IndexEntry>),
+    /// Nothing to delete by that name
+    NotFound,
+    /// The hash chain hasn't logged it yet
+    NotLogged,
+}
+
+pub struct Retention {
+    index: Option<EventIndex>,
+    /// The hash chain's log, when the chain's on
+    chain: Option<PathBuf>,
+    /// Events, and files of theirs, waiting on the chain
+    waiting: Vec<(String, Vec<PathBuf>)>,
+}
+
+impl Retention {
+    pub fn new(index: Option<&Path>, chain: Option<&Path>) -> Retention {
+        Retention {
+            index: index.map(EventIndex::new),
+            chain: chain.map(Path::to_path_buf),
+            waiting: Vec::new(),
+        }
+    }
+
+    pub fn index(&self) -> Option<&EventIndex> {
+        self.index.as_ref()
+    }
+
+    /// Deletes an event now, if the chain has logged it. `files` are what goes
+    /// when the index doesn't know the event.
+    pub fn delete(&self, event: &str, files: &[PathBuf]) -> Result<Outcome, String> {
+        let indexed = match &self.index {
+            Some(index) => index.get(event).map_err(|e| e.to_string())?.is_some(),
+            None => false,
+        };
+        if !indexed && files.is_empty() {
+            return Ok(Outcome::NotFound);
+        }
+        if let Some(log) = &self.chain {
+            if !chain::logged(log, event)? {
+                return Ok(Outcome::NotLogged);
+            }
+        }
+
+        if let (Some(index), true) = (&self.index, indexed) {
+            return match index.remove(event).map_err(|e| e.to_string())? {
+                Some(entry) => Ok(Outcome::Deleted(Some(entry))),
+                // someone else got there first
+                None => Ok(Outcome::NotFound),
+            };
+        }
+        for file in files {
+            match fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(format!("{}: {}", file.display(), e)),
+                _ => {}
+            }
+        }
+        Ok(Outcome::Deleted(None))
+    }
+
+    /// Deletes now if it can, otherwise once the chain's logged the event
+    pub fn delete_when_logged(&mut self, event: &str, files: &[PathBuf]) -> Result<Outcome, String> {
+        let outcome = self.delete(event, files)?;
+        if let Outcome::NotLogged = outcome {
+            match self.waiting.iter_mut().find(|(name, _)| name == event) {
+                Some((_, waiting)) => waiting.extend_from_slice(files),
+                None => self.waiting.push((event.to_string(), files.to_vec())),
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Tries the deletions waiting on the chain again. Returns the ones that
+    /// aren't waiting any more, and how they went.
+    pub fn sweep(&mut self) -> Vec<(String, Result<Outcome, String>)> {
+        let mut done = Vec::new();
+        let mut waiting = Vec::new();
+        for (event, files) in std::mem::take(&mut self.waiting) {
+            match self.delete(&event, &files) {
+                Ok(Outcome::NotLogged) => waiting.push((event, files)),
+                result => done.push((event, result)),
+            }
+        }
+        self.waiting = waiting;
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::{Chain, ChainSettings};
+    use crate::event::MotionEvent;
+
+    #[test]
+    fn waits_for_the_chain() {
+        let dir = std::env::temp_dir().join(format!("retention-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let settings = ChainSettings {
+            log: dir.join("chain.jsonl"),
+            key: dir.join("chain-key.pk8"),
+        };
+        let index = EventIndex::new(&dir.join("index.jsonl"));
+
+        let mut event = MotionEvent::new("porch");
+        event.end();
+        let name = event.name();
+        let video = dir.join(format!("{}.h264", name));
+        fs::write(&video, b"video").unwrap();
+        let entry = index.append(&event, &dir).unwrap();
+
+        let mut retention = Retention::new(Some(&dir.join("index.jsonl")), Some(&settings.log));
+        assert!(matches!(retention.delete("porch_nothing", &[]), Ok(Outcome::NotFound)));
+        assert!(matches!(retention.delete_when_logged(&name, &[]), Ok(Outcome::NotLogged)));
+        assert!(retention.sweep().is_empty());
+        assert!(video.exists());
+
+        Chain::open(&settings).unwrap().append(&entry).unwrap();
+        let done = retention.sweep();
+        assert_eq!(done.len(), 1);
+        assert!(matches!(&done[0].1, Ok(Outcome::Deleted(Some(_)))));
+        assert!(!video.exists());
+        assert!(index.get(&name).unwrap().is_none());
+        assert!(retention.sweep().is_empty());
+
+        // without an index only the files asked for go, once they're logged
+        let loose = dir.join("loose.jpg");
+        fs::write(&loose, b"jpeg").unwrap();
+        let retention = Retention::new(None, Some(&settings.log));
+        let files = std::slice::from_ref(&loose);
+        assert!(matches!(retention.delete("porch_unlogged", files), Ok(Outcome::NotLogged)));
+        assert!(matches!(retention.delete(&name, files), Ok(Outcome::Deleted(None))));
+        assert!(!loose.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
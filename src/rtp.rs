@@ -0,0 +1,147 @@
+/*
+RTP packetization of H.264, RFC 6184.
+
+NAL units that fit go in a packet on their own, bigger ones are split into
+FU-A fragments. The marker bit goes on the last packet of each access unit.
+*/
+use crate::h264::AccessUnit;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const PAYLOAD_TYPE: u8 = 96;
+pub const CLOCK_RATE: u32 = 90000;
+// leaves room for IP/UDP headers, or SRTP's and TURN's overhead
+const MAX_PAYLOAD: usize = 1200;
+
+pub struct Packetizer {
+    pub ssrc: u32,
+    sequence: u16,
+    timestamp_offset: u32,
+    payload_type: u8,
+}
+
+impl Packetizer {
+    pub fn new(payload_type: u8) -> Packetizer {
+        // doesn't need to be cryptographically random, just different per stream
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let seed = seed ^ (std::process::id() << 16);
+        Packetizer {
+            ssrc: seed.wrapping_mul(2654435761),
+            sequence: (seed >> 7) as u16,
+            timestamp_offset: seed.wrapping_mul(40503),
+            payload_type: payload_type,
+        }
+    }
+
+    /// Sequence number of the next packet, for RTP-Info
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    pub fn rtp_timestamp(&self, unit: &AccessUnit) -> u32 {
+        let ticks = unit.timestamp.as_micros() as u64 * CLOCK_RATE as u64 / 1_000_000;
+        self.timestamp_offset.wrapping_add(ticks as u32)
+    }
+
+    /// RTP packets for an access unit, with `extra` NAL units (like SPS and PPS) sent first
+    pub fn packetize(&mut self, unit: &AccessUnit, extra: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let timestamp = self.rtp_timestamp(unit);
+        let nals: Vec<&Vec<u8>> = extra.iter().chain(unit.nals.iter()).filter(|nal| !nal.is_empty()).collect();
+
+        let mut packets = Vec::new();
+        for (i, nal) in nals.iter().enumerate() {
+            let last_nal = i == nals.len() - 1;
+            if nal.len() <= MAX_PAYLOAD {
+                let packet = self.packet(timestamp, last_nal, &[], nal);
+                packets.push(packet);
+                continue;
+            }
+
+            // FU-A: indicator keeps NRI with type 28, header has S/E bits and the original type
+            let indicator = (nal[0] & 0x60) | 28;
+            let kind = nal[0] & 0x1F;
+            let chunks: Vec<&[u8]> = nal[1..].chunks(MAX_PAYLOAD - 2).collect();
+            for (j, chunk) in chunks.iter().enumerate() {
+                let first = j == 0;
+                let last = j == chunks.len() - 1;
+                let header = kind | if first { 0x80 } else { 0 } | if last { 0x40 } else { 0 };
+                let packet = self.packet(timestamp, last_nal && last, &[indicator, header], chunk);
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, prefix: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + prefix.len() + payload.len());
+        packet.push(0x80); // version 2, no padding, extension or CSRCs
+        packet.push(self.payload_type | if marker { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(prefix);
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn unit(nals: Vec<Vec<u8>>, timestamp: Duration) -> AccessUnit {
+        AccessUnit {
+            timestamp: timestamp,
+            nals: nals,
+            keyframe: true,
+        }
+    }
+
+    #[test]
+    fn small_nals_go_whole() {
+        let mut packetizer = Packetizer::new(PAYLOAD_TYPE);
+        let first = packetizer.sequence();
+        let packets = packetizer.packetize(&unit(vec![vec![0x65, 1, 2]], Duration::from_secs(1)), &[vec![0x67, 9], vec![]]);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0][12..], &[0x67, 9]);
+        assert_eq!(&packets[1][12..], &[0x65, 1, 2]);
+        // marker only on the last packet of the access unit
+        assert_eq!(packets[0][1], PAYLOAD_TYPE);
+        assert_eq!(packets[1][1], PAYLOAD_TYPE | 0x80);
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet[0], 0x80);
+            assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), first.wrapping_add(i as u16));
+            assert_eq!(&packet[8..12], &packetizer.ssrc.to_be_bytes());
+        }
+        assert_eq!(packetizer.sequence(), first.wrapping_add(2));
+
+        // 90kHz clock from the access unit's timestamp
+        let later = unit(vec![vec![0x41]], Duration::from_millis(1500));
+        let timestamp = u32::from_be_bytes([packets[0][4], packets[0][5], packets[0][6], packets[0][7]]);
+        assert_eq!(packetizer.rtp_timestamp(&later).wrapping_sub(timestamp), 45000);
+    }
+
+    #[test]
+    fn big_nals_are_fragmented() {
+        let mut nal = vec![0x65];
+        nal.extend((0..3000).map(|i| i as u8));
+        let mut packetizer = Packetizer::new(PAYLOAD_TYPE);
+        let packets = packetizer.packetize(&unit(vec![nal.clone()], Duration::ZERO), &[]);
+        assert_eq!(packets.len(), 3);
+
+        let mut joined = vec![0x65];
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= 12 + MAX_PAYLOAD);
+            // FU indicator keeps the NRI, FU header the type
+            assert_eq!(packet[12], 0x60 | 28);
+            assert_eq!(packet[13] & 0x1F, 5);
+            assert_eq!(packet[13] & 0x80 != 0, i == 0);
+            assert_eq!(packet[13] & 0x40 != 0, i == 2);
+            assert_eq!(packet[1] & 0x80 != 0, i == 2);
+            joined.extend_from_slice(&packet[14..]);
+        }
+        assert_eq!(joined, nal);
+    }
+}
@@ -0,0 +1,648 @@
+/*
+RTSP server for the live H.264 stream, so NVRs (Frigate, Blue Iris, Shinobi)
+can pull video straight off the Pi.
+
+rtsp://<pi>:8554/<camera name>
+
+Supports RTP over UDP and RTP interleaved on the RTSP connection (what most
+clients fall back to behind NAT, and ffmpeg's -rtsp_transport tcp). Only PLAY,
+no recording or PAUSE. One thread per connection plus one sending video while
+playing.
+
+With TLS it's rtsps://<pi>:8554/<camera name>, and only interleaved transport
+is offered, since RTP over UDP would go out in the clear. With auth, clients
+need a user or token with the view role (rtsp://user:password@<pi>:8554/...).
+*/
+use crate::auth::{Auth, AuthSettings, Denied, Role};
+use crate::h264;
+use crate::live::Live;
+use crate::rtp::{self, Packetizer};
+use crate::tls::{self, TlsSettings};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::{ServerConfig, ServerConnection};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RtspSettings {
+    pub address: String,
+    /// Users and tokens. None lets anyone watch.
+    pub auth: Option<AuthSettings>,
+    /// Serve rtsps:// instead of rtsp://
+    pub tls: Option<TlsSettings>,
+}
+
+impl Default for RtspSettings {
+    fn default() -> Self {
+        RtspSettings {
+            address: "0.0.0.0:8554".to_string(),
+            auth: None,
+            tls: None,
+        }
+    }
+}
+
+/// Accepts connections until the listener fails. Call from its own thread.
+pub fn serve(settings: RtspSettings, cameras: Vec<Arc<Live>>) -> Result<(), String> {
+    let tls = match &settings.tls {
+        Some(tls_settings) => Some(tls::server_config(tls_settings)?),
+        None => None,
+    };
+    let auth = settings.auth.clone().map(|auth| Arc::new(Auth::new(auth)));
+    let listener = TcpListener::bind(&settings.address).map_err(|e| format!("Unable to listen on {}: {}", settings.address, e))?;
+    let scheme = if tls.is_some() { "rtsps" } else { "rtsp" };
+    println!("rtsp: listening on {}://{}", scheme, settings.address);
+
+    let cameras = Arc::new(cameras);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let cameras = cameras.clone();
+        let auth = auth.clone();
+        let tls = tls.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            if let Err(e) = Connection::new(stream, cameras, auth, tls).and_then(|mut c| c.run()) {
+                println!("rtsp: {}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    url: String,
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|s| s.as_str())
+    }
+}
+
+enum Transport {
+    Udp {
+        rtp: UdpSocket,
+        // never read, just kept open so the port we told the client stays ours
+        _rtcp: UdpSocket,
+        client: SocketAddr,
+    },
+    Interleaved {
+        channel: u8,
+    },
+}
+
+/// A client connection, plain or TLS, shared by the thread reading requests
+/// and the one sending video
+struct Socket {
+    tcp: TcpStream,
+    tls: Option<Mutex<ServerConnection>>,
+    // a response and a video packet mustn't interleave
+    writing: Mutex<()>,
+}
+
+impl Socket {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        match &self.tls {
+            Some(tls) => {
+                let mut tls = tls.lock().unwrap();
+                tls.writer().write_all(data)?;
+                while tls.wants_write() {
+                    tls.write_tls(&mut &self.tcp)?;
+                }
+                Ok(())
+            }
+            None => (&self.tcp).write_all(data),
+        }
+    }
+}
+
+/// Plaintext from the client, for reading requests
+struct SocketReader(Arc<Socket>);
+
+impl Read for SocketReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match &self.0.tls {
+            Some(tls) => tls,
+            None => return (&self.0.tcp).read(buf),
+        };
+        loop {
+            match tls.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // wait for the client without holding the lock, so video keeps going out
+            let mut raw = [0u8; 4096];
+            let length = (&self.0.tcp).read(&mut raw)?;
+            if length == 0 {
+                return Ok(0);
+            }
+            let mut tls = tls.lock().unwrap();
+            let mut data = &raw[..length];
+            while !data.is_empty() {
+                tls.read_tls(&mut data)?;
+                tls.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            // handshake messages and the like
+            while tls.wants_write() {
+                tls.write_tls(&mut &self.0.tcp)?;
+            }
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<SocketReader>,
+    socket: Arc<Socket>,
+    cameras: Arc<Vec<Arc<Live>>>,
+    auth: Option<Arc<Auth>>,
+    session: String,
+    camera: Option<Arc<Live>>,
+    transport: Option<Transport>,
+    packetizer: Option<Packetizer>,
+    playing: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, cameras: Arc<Vec<Arc<Live>>>, auth: Option<Arc<Auth>>, tls: Option<Arc<ServerConfig>>) -> io::Result<Connection> {
+        let tls = match tls {
+            Some(config) => Some(Mutex::new(ServerConnection::new(config).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?)),
+            None => None,
+        };
+        let socket = Arc::new(Socket {
+            tcp: stream,
+            tls: tls,
+            writing: Mutex::new(()),
+        });
+        let packetizer = Packetizer::new(rtp::PAYLOAD_TYPE);
+        Ok(Connection {
+            reader: BufReader::new(SocketReader(socket.clone())),
+            socket: socket,
+            cameras: cameras,
+            auth: auth,
+            session: format!("{:08X}", packetizer.ssrc),
+            camera: None,
+            transport: None,
+            packetizer: Some(packetizer),
+            playing: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let result = self.requests();
+        self.playing.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn requests(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_request()? {
+            let cseq = request.header("CSeq").unwrap_or("0").to_string();
+            let (status, headers, body) = self.handle(&request);
+
+            let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", status, cseq);
+            for (name, value) in headers {
+                response.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            if !body.is_empty() {
+                response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            response.push_str("\r\n");
+            response.push_str(&body);
+            self.socket.send(response.as_bytes())?;
+
+            match (request.method.as_str(), status) {
+                // after the response, so it doesn't end up behind video
+                ("PLAY", "200 OK") => self.play(),
+                ("TEARDOWN", _) => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Next request, skipping any interleaved RTCP the client sends.
+    /// None once the client hangs up.
+    fn read_request(&mut self) -> io::Result<Option<Request>> {
+        loop {
+            let first = match self.reader.fill_buf()?.first() {
+                Some(b) => *b,
+                None => return Ok(None),
+            };
+            if first != b'$' {
+                break;
+            }
+            let mut header = [0u8; 4];
+            self.reader.read_exact(&mut header)?;
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            io::copy(&mut (&mut self.reader).take(length as u64), &mut io::sink())?;
+        }
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or("").to_string();
+        let url = parts.next().unwrap_or("").to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(i) = line.find(':') {
+                headers.insert(line[..i].trim().to_lowercase(), line[i + 1..].trim().to_string());
+            }
+        }
+        // we don't use request bodies, but they have to be read past
+        let length: u64 = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+        io::copy(&mut (&mut self.reader).take(length), &mut io::sink())?;
+
+        Ok(Some(Request {
+            method: method,
+            url: url,
+            headers: headers,
+        }))
+    }
+
+    fn handle(&mut self, request: &Request) -> (&'static str, Vec<(&'static str, String)>, String) {
+        let session = format!("{};timeout=60", self.session);
+        if let (Some(auth), false) = (&self.auth, request.method == "OPTIONS") {
+            match auth.check(request.header("Authorization"), Role::View) {
+                Ok(_) => {}
+                Err(Denied::Unauthorized) => {
+                    return (
+                        "401 Unauthorized",
+                        vec![("WWW-Authenticate", "Basic realm=\"camera\"".to_string())],
+                        String::new(),
+                    )
+                }
+                Err(Denied::Forbidden) => return ("403 Forbidden", vec![], String::new()),
+            }
+        }
+        if !self.session_matches(request) {
+            return ("454 Session Not Found", vec![], String::new());
+        }
+        match request.method.as_str() {
+            "OPTIONS" => (
+                "200 OK",
+                vec![("Public", "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string())],
+                String::new(),
+            ),
+            "DESCRIBE" => {
+                let camera = match self.find_camera(&request.url) {
+                    Some(camera) => camera,
+                    None => return ("404 Not Found", vec![], String::new()),
+                };
+                let (sps, pps) = match camera.video.parameter_sets() {
+                    Some(sets) => sets,
+                    // nothing from the encoder yet
+                    None => return ("503 Service Unavailable", vec![], String::new()),
+                };
+                let address = self.socket.tcp.local_addr().map(|a| a.ip()).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+                let sdp = format!(
+                    "v=0\r\n\
+                     o=- {session} 1 IN {family} {address}\r\n\
+                     s={name}\r\n\
+                     c=IN {family} 0.0.0.0\r\n\
+                     t=0 0\r\n\
+                     a=control:*\r\n\
+                     m=video 0 RTP/AVP {pt}\r\n\
+                     a=rtpmap:{pt} H264/{clock}\r\n\
+                     a=fmtp:{pt} packetization-mode=1;profile-level-id={profile};sprop-parameter-sets={sps},{pps}\r\n\
+                     a=control:trackID=0\r\n",
+                    session = u32::from_str_radix(&self.session, 16).unwrap_or(0),
+                    family = if address.is_ipv4() { "IP4" } else { "IP6" },
+                    address = address,
+                    name = camera.name,
+                    pt = rtp::PAYLOAD_TYPE,
+                    clock = rtp::CLOCK_RATE,
+                    profile = h264::profile_level_id(&sps),
+                    sps = BASE64.encode(&sps),
+                    pps = BASE64.encode(&pps),
+                );
+                self.camera = Some(camera);
+                let base = format!("{}/", request.url.trim_end_matches('/'));
+                (
+                    "200 OK",
+                    vec![("Content-Base", base), ("Content-Type", "application/sdp".to_string())],
+                    sdp,
+                )
+            }
+            "SETUP" => {
+                if self.camera.is_none() {
+                    self.camera = self.find_camera(&request.url);
+                }
+                if self.camera.is_none() {
+                    return ("404 Not Found", vec![], String::new());
+                }
+                let requested = request.header("Transport").unwrap_or("");
+                match self.setup(requested) {
+                    Ok(transport) => ("200 OK", vec![("Transport", transport), ("Session", session)], String::new()),
+                    Err(_) => ("461 Unsupported Transport", vec![], String::new()),
+                }
+            }
+            "PLAY" => {
+                if self.transport.is_none() || self.packetizer.is_none() {
+                    return ("455 Method Not Valid in This State", vec![], String::new());
+                }
+                let info = format!("url={};seq={}", request.url, self.packetizer.as_ref().unwrap().sequence());
+                (
+                    "200 OK",
+                    vec![("Session", session), ("Range", "npt=0.000-".to_string()), ("RTP-Info", info)],
+                    String::new(),
+                )
+            }
+            "TEARDOWN" => {
+                self.playing.store(false, Ordering::SeqCst);
+                ("200 OK", vec![("Session", session)], String::new())
+            }
+            // clients send these as keepalives
+            "GET_PARAMETER" | "SET_PARAMETER" => ("200 OK", vec![("Session", session)], String::new()),
+            _ => ("501 Not Implemented", vec![], String::new()),
+        }
+    }
+
+    /// Whether the request's Session header, if it needs one, is ours
+    fn session_matches(&self, request: &Request) -> bool {
+        let session = request.header("Session").map(|s| s.split(';').next().unwrap_or("").trim());
+        match (request.method.as_str(), session) {
+            (_, Some(session)) => session == self.session,
+            ("PLAY", None) | ("TEARDOWN", None) => false,
+            // before SETUP there isn't one, and keepalives don't always bother
+            _ => true,
+        }
+    }
+
+    /// Camera named by the first path segment, or the first camera
+    fn find_camera(&self, url: &str) -> Option<Arc<Live>> {
+        let path = url.splitn(4, '/').nth(3).unwrap_or("");
+        let name = path.split(&['/', '?'][..]).next().unwrap_or("");
+        if name.is_empty() {
+            return self.cameras.first().cloned();
+        }
+        self.cameras.iter().find(|c| c.name == name).cloned()
+    }
+
+    /// Sets up the transport the client asked for and returns our Transport header
+    fn setup(&mut self, requested: &str) -> io::Result<String> {
+        let ssrc = format!("{:08X}", self.packetizer.as_ref().map(|p| p.ssrc).unwrap_or(0));
+        let param = |name: &str| {
+            requested
+                .split(';')
+                .find_map(|p| p.trim().strip_prefix(name))
+                .and_then(|p| p.strip_prefix('='))
+                .map(|p| p.split('-').filter_map(|n| n.parse::<u16>().ok()).collect::<Vec<u16>>())
+        };
+
+        if requested.starts_with("RTP/AVP/TCP") {
+            // RTP goes on an even channel with RTCP on the one after, so 254 is the last that fits in a byte
+            let channel = param("interleaved").and_then(|c| c.first().copied()).unwrap_or(0);
+            if channel % 2 != 0 || channel > 254 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Bad interleaved channel"));
+            }
+            let channel = channel as u8;
+            self.transport = Some(Transport::Interleaved { channel: channel });
+            return Ok(format!("RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={}", channel, channel + 1, ssrc));
+        }
+
+        if self.socket.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No UDP over TLS"));
+        }
+        let ports = param("client_port").unwrap_or_default();
+        let client_port = match ports.first() {
+            Some(port) => *port,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No client_port")),
+        };
+        let client_ip = self.socket.tcp.peer_addr()?.ip();
+        let rtp = UdpSocket::bind("0.0.0.0:0")?;
+        let rtcp = UdpSocket::bind("0.0.0.0:0")?;
+        let server_ports = (rtp.local_addr()?.port(), rtcp.local_addr()?.port());
+        self.transport = Some(Transport::Udp {
+            rtp: rtp,
+            _rtcp: rtcp,
+            client: SocketAddr::new(client_ip, client_port),
+        });
+        Ok(format!(
+            "RTP/AVP;unicast;client_port={}-{};server_port={}-{};ssrc={}",
+            client_port,
+            ports.get(1).copied().unwrap_or(client_port.saturating_add(1)),
+            server_ports.0,
+            server_ports.1,
+            ssrc
+        ))
+    }
+
+    /// Starts sending video on its own thread, until TEARDOWN or the connection closes
+    fn play(&mut self) {
+        let (camera, transport, mut packetizer) = match (self.camera.clone(), self.transport.take(), self.packetizer.take()) {
+            (Some(c), Some(t), Some(p)) => (c, t, p),
+            _ => return,
+        };
+        let socket = self.socket.clone();
+        let playing = self.playing.clone();
+        playing.store(true, Ordering::SeqCst);
+
+        std::thread::spawn(move || {
+            let units = camera.video.subscribe();
+            while playing.load(Ordering::SeqCst) {
+                let unit = match units.recv_timeout(Duration::from_secs(1)) {
+                    Ok(unit) => unit,
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(_) => break,
+                };
+                // decoders joining mid-stream need SPS and PPS before the keyframe
+                let mut extra = Vec::new();
+                if unit.keyframe && !unit.has(h264::NAL_SPS) {
+                    if let Some((sps, pps)) = camera.video.parameter_sets() {
+                        extra = vec![sps, pps];
+                    }
+                }
+                for packet in packetizer.packetize(&unit, &extra) {
+                    let sent = match &transport {
+                        Transport::Udp { rtp, client, .. } => rtp.send_to(&packet, client).map(|_| ()),
+                        Transport::Interleaved { channel } => {
+                            let mut frame = vec![b'$', *channel];
+                            frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                            frame.extend_from_slice(&packet);
+                            socket.send(&frame)
+                        }
+                    };
+                    if sent.is_err() {
+                        playing.store(false, Ordering::SeqCst);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::AccessUnit;
+
+    /// A server for one camera, with a keyframe already published
+    fn start() -> (String, Arc<Live>) {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let camera = Live::new("porch", 5);
+        camera.video.publish(AccessUnit::keyframe(0));
+        let settings = RtspSettings {
+            address: address.clone(),
+            ..Default::default()
+        };
+        let cameras = vec![camera.clone()];
+        std::thread::spawn(move || serve(settings, cameras));
+        for _ in 0..50 {
+            if TcpStream::connect(&address).is_ok() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        (address, camera)
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        cseq: u32,
+    }
+
+    impl Client {
+        fn connect(address: &str) -> Client {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            Client {
+                reader: BufReader::new(stream),
+                cseq: 0,
+            }
+        }
+
+        /// Status line and headers, lowercased names
+        fn request(&mut self, method: &str, url: &str, headers: &[(&str, &str)]) -> (String, HashMap<String, String>, String) {
+            self.cseq += 1;
+            let mut request = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, url, self.cseq);
+            for (name, value) in headers {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            request.push_str("\r\n");
+            self.reader.get_mut().write_all(request.as_bytes()).unwrap();
+
+            let mut status = String::new();
+            self.reader.read_line(&mut status).unwrap();
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_at(line.find(':').unwrap());
+                headers.insert(name.to_lowercase(), value[1..].trim().to_string());
+            }
+            let length: usize = headers.get("content-length").map(|l| l.parse().unwrap()).unwrap_or(0);
+            let mut body = vec![0u8; length];
+            self.reader.read_exact(&mut body).unwrap();
+            (status.trim_end().to_string(), headers, String::from_utf8(body).unwrap())
+        }
+
+        /// Next interleaved packet
+        fn packet(&mut self) -> (u8, Vec<u8>) {
+            let mut header = [0u8; 4];
+            self.reader.read_exact(&mut header).unwrap();
+            assert_eq!(header[0], b'$');
+            let mut packet = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+            self.reader.read_exact(&mut packet).unwrap();
+            (header[1], packet)
+        }
+    }
+
+    #[test]
+    fn plays_interleaved() {
+        let (address, camera) = start();
+        let url = format!("rtsp://{}/porch", address);
+        let mut client = Client::connect(&address);
+
+        let (status, headers, sdp) = client.request("DESCRIBE", &url, &[]);
+        assert_eq!(status, "RTSP/1.0 200 OK");
+        assert_eq!(headers["content-type"], "application/sdp");
+        assert!(sdp.contains("profile-level-id=42c01f"));
+        let nals = AccessUnit::keyframe(0).nals;
+        assert!(sdp.contains(&format!("sprop-parameter-sets={},{}", BASE64.encode(&nals[0]), BASE64.encode(&nals[1]))));
+
+        let setup_url = format!("{}/trackID=0", url);
+        let (status, headers, _) = client.request("SETUP", &setup_url, &[("Transport", "RTP/AVP/TCP;unicast;interleaved=2-3")]);
+        assert_eq!(status, "RTSP/1.0 200 OK");
+        assert!(headers["transport"].contains("interleaved=2-3"));
+        let session = headers["session"].split(';').next().unwrap().to_string();
+
+        let (status, _, _) = client.request("PLAY", &url, &[("Session", &session)]);
+        assert_eq!(status, "RTSP/1.0 200 OK");
+
+        // playing starts from the next keyframe
+        let publisher = {
+            let camera = camera.clone();
+            std::thread::spawn(move || {
+                for i in 1..20 {
+                    camera.video.publish(AccessUnit::keyframe(i * 40));
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            })
+        };
+        let mut nal_types = Vec::new();
+        while nal_types.len() < 3 {
+            let (channel, packet) = client.packet();
+            assert_eq!(channel, 2);
+            assert_eq!(packet[0] >> 6, 2);
+            assert_eq!(packet[1] & 0x7F, rtp::PAYLOAD_TYPE);
+            nal_types.push(packet[12] & 0x1F);
+        }
+        assert_eq!(nal_types, vec![h264::NAL_SPS, h264::NAL_PPS, h264::NAL_IDR]);
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_channels_and_sessions() {
+        let (address, _camera) = start();
+        let url = format!("rtsp://{}/porch", address);
+        let mut client = Client::connect(&address);
+
+        for transport in ["RTP/AVP/TCP;unicast;interleaved=255", "RTP/AVP/TCP;unicast;interleaved=1-2", "RTP/AVP/TCP;unicast;interleaved=300-301"].iter() {
+            let (status, _, _) = client.request("SETUP", &url, &[("Transport", transport)]);
+            assert_eq!(status, "RTSP/1.0 461 Unsupported Transport");
+        }
+
+        let (status, _, _) = client.request("SETUP", &url, &[("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1")]);
+        assert_eq!(status, "RTSP/1.0 200 OK");
+        let (status, _, _) = client.request("PLAY", &url, &[]);
+        assert_eq!(status, "RTSP/1.0 454 Session Not Found");
+        let (status, _, _) = client.request("PLAY", &url, &[("Session", "DEADBEEF")]);
+        assert_eq!(status, "RTSP/1.0 454 Session Not Found");
+    }
+
+    #[test]
+    fn unknown_camera() {
+        let (address, _camera) = start();
+        let mut client = Client::connect(&address);
+        let (status, _, _) = client.request("DESCRIBE", &format!("rtsp://{}/garage", address), &[]);
+        assert_eq!(status, "RTSP/1.0 404 Not Found");
+    }
+}
@@ -0,0 +1,299 @@
+use crate::settings::CameraSettings;
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// What decides when it's night. In JSON the kind goes in "type", e.g.
+/// { "type": "clock", "night_start": "19:00:00", "day_start": "07:00:00" }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Trigger {
+    /// Fixed local times, e.g. night from 19:00 until 07:00
+    Clock { night_start: NaiveTime, day_start: NaiveTime },
+    /// Night between sunset and sunrise at this location (degrees, east is positive)
+    Sun { latitude: f64, longitude: f64 },
+    /// Night once the mean luma (0-255) of frames drops below `night_below`,
+    /// day again once it goes above `day_above`. Keep a gap between the two
+    /// since the night profile usually brightens the picture.
+    Brightness { night_below: f32, day_above: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Night,
+}
+
+/// Day and night exposure profiles and when to switch between them.
+///
+/// Only the exposure related parts of the profiles are applied: ISO, shutter
+/// speed, exposure mode, AWB and grayscale. Those can be changed without
+/// restarting the camera. Profiles left out of the JSON are the defaults,
+/// auto everything in color.
+///
+/// A switch replaces those parts of the camera's settings, so the status, the
+/// settings API and EXIF report the profile in use. Exposure changes made
+/// through the API last until the next switch, other settings are left alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub day: CameraSettings,
+    #[serde(default)]
+    pub night: CameraSettings,
+}
+
+pub struct Scheduler {
+    schedule: Schedule,
+    current: Option<Period>,
+    // smoothed mean luma, so one dark frame doesn't flip us to night
+    brightness: Option<f32>,
+}
+
+impl Scheduler {
+    pub fn new(schedule: Schedule) -> Scheduler {
+        Scheduler {
+            schedule: schedule,
+            current: None,
+            brightness: None,
+        }
+    }
+
+    pub fn period(&self) -> Option<Period> {
+        self.current
+    }
+
+    /// Call once per frame with the frame's mean luma.
+    ///
+    /// Returns the profile to apply when we've switched between day and night.
+    pub fn update(&mut self, now: DateTime<Local>, mean_luma: f32) -> Option<&CameraSettings> {
+        let brightness = match self.brightness {
+            Some(b) => b * 0.95 + mean_luma * 0.05,
+            None => mean_luma,
+        };
+        self.brightness = Some(brightness);
+
+        let period = match &self.schedule.trigger {
+            Trigger::Clock { night_start, day_start } => {
+                clock_period(now.time(), *night_start, *day_start)
+            }
+            Trigger::Sun { latitude, longitude } => sun_period(now, *latitude, *longitude),
+            Trigger::Brightness { night_below, day_above } => {
+                match self.current {
+                    Some(Period::Night) if brightness < *day_above => Period::Night,
+                    Some(Period::Day) if brightness > *night_below => Period::Day,
+                    _ => {
+                        if brightness < *night_below {
+                            Period::Night
+                        } else {
+                            Period::Day
+                        }
+                    }
+                }
+            }
+        };
+
+        if self.current == Some(period) {
+            return None;
+        }
+        self.current = Some(period);
+        match period {
+            Period::Day => Some(&self.schedule.day),
+            Period::Night => Some(&self.schedule.night),
+        }
+    }
+}
+
+fn clock_period(time: NaiveTime, night_start: NaiveTime, day_start: NaiveTime) -> Period {
+    let night = if night_start > day_start {
+        // night wraps past midnight
+        time >= night_start || time < day_start
+    } else {
+        time >= night_start && time < day_start
+    };
+    if night {
+        Period::Night
+    } else {
+        Period::Day
+    }
+}
+
+fn sun_period(now: DateTime<Local>, latitude: f64, longitude: f64) -> Period {
+    // use the local solar day, otherwise the UTC date can roll over before sunset
+    let solar = now.with_timezone(&Utc) + Duration::seconds((longitude / 360.0 * 86400.0) as i64);
+    match sun_times(solar, latitude, longitude) {
+        SunTimes::AlwaysDay => Period::Day,
+        SunTimes::AlwaysNight => Period::Night,
+        SunTimes::Normal { sunrise, sunset } => {
+            let now = now.with_timezone(&Utc);
+            if now >= sunrise && now < sunset {
+                Period::Day
+            } else {
+                Period::Night
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SunTimes {
+    Normal { sunrise: DateTime<Utc>, sunset: DateTime<Utc> },
+    /// Midnight sun
+    AlwaysDay,
+    /// Polar night
+    AlwaysNight,
+}
+
+/// Sunrise and sunset for the day containing `date`, using the sunrise equation
+/// from https://en.wikipedia.org/wiki/Sunrise_equation
+pub fn sun_times(date: DateTime<Utc>, latitude: f64, longitude: f64) -> SunTimes {
+    let noon = Utc.with_ymd_and_hms(date.year(), date.month(), date.day(), 12, 0, 0).unwrap();
+    let julian_date = noon.timestamp() as f64 / 86400.0 + 2440587.5;
+
+    let n = (julian_date - 2451545.0 + 0.0008).round();
+    let mean_solar_time = n - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_time) % 360.0;
+    let m = anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic_longitude = ((anomaly + center + 180.0 + 102.9372) % 360.0).to_radians();
+    let transit = 2451545.0 + mean_solar_time + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if cos_hour_angle > 1.0 {
+        return SunTimes::AlwaysNight;
+    }
+    if cos_hour_angle < -1.0 {
+        return SunTimes::AlwaysDay;
+    }
+    let hour_angle = cos_hour_angle.acos() * 180.0 / PI;
+
+    SunTimes::Normal {
+        sunrise: from_julian(transit - hour_angle / 360.0),
+        sunset: from_julian(transit + hour_angle / 360.0),
+    }
+}
+
+fn from_julian(julian_date: f64) -> DateTime<Utc> {
+    let seconds = (julian_date - 2440587.5) * 86400.0;
+    Utc.timestamp_opt(seconds as i64, 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn minutes_apart(a: DateTime<Utc>, b: DateTime<Utc>) -> i64 {
+        (a - b).num_minutes().abs()
+    }
+
+    #[test]
+    fn sunrise_and_sunset() {
+        // London at midsummer, 03:43 and 20:21 UTC
+        match sun_times(utc("2021-06-21T10:00:00Z"), 51.5074, -0.1278) {
+            SunTimes::Normal { sunrise, sunset } => {
+                assert!(minutes_apart(sunrise, utc("2021-06-21T03:43:00Z")) <= 3, "{}", sunrise);
+                assert!(minutes_apart(sunset, utc("2021-06-21T20:21:00Z")) <= 3, "{}", sunset);
+            }
+            times => panic!("{:?}", times),
+        }
+        // Sydney in midwinter, 20:59 UTC the day before and 06:54 UTC
+        match sun_times(utc("2021-06-21T02:00:00Z"), -33.8688, 151.2093) {
+            SunTimes::Normal { sunrise, sunset } => {
+                assert!(minutes_apart(sunrise, utc("2021-06-20T20:59:00Z")) <= 3, "{}", sunrise);
+                assert!(minutes_apart(sunset, utc("2021-06-21T06:54:00Z")) <= 3, "{}", sunset);
+            }
+            times => panic!("{:?}", times),
+        }
+        // Tromsø
+        assert_eq!(sun_times(utc("2021-06-21T12:00:00Z"), 69.6492, 18.9553), SunTimes::AlwaysDay);
+        assert_eq!(sun_times(utc("2021-12-21T12:00:00Z"), 69.6492, 18.9553), SunTimes::AlwaysNight);
+    }
+
+    #[test]
+    fn clock_wraps_past_midnight() {
+        let time = |text: &str| NaiveTime::parse_from_str(text, "%H:%M").unwrap();
+        let (night, day) = (time("19:00"), time("07:00"));
+        assert_eq!(clock_period(time("23:30"), night, day), Period::Night);
+        assert_eq!(clock_period(time("03:00"), night, day), Period::Night);
+        assert_eq!(clock_period(time("07:00"), night, day), Period::Day);
+        assert_eq!(clock_period(time("12:00"), night, day), Period::Day);
+        // the odd way round, night in the middle of the day
+        assert_eq!(clock_period(time("12:00"), time("11:00"), time("13:00")), Period::Night);
+        assert_eq!(clock_period(time("14:00"), time("11:00"), time("13:00")), Period::Day);
+    }
+
+    #[test]
+    fn brightness_switches_once() {
+        let night = CameraSettings {
+            grayscale: true,
+            ..CameraSettings::default()
+        };
+        let mut scheduler = Scheduler::new(Schedule {
+            trigger: Trigger::Brightness {
+                night_below: 40.0,
+                day_above: 80.0,
+            },
+            day: CameraSettings::default(),
+            night: night,
+        });
+        let now = Local::now();
+        assert!(!scheduler.update(now, 120.0).unwrap().grayscale);
+        assert!(scheduler.update(now, 120.0).is_none());
+        // one dark frame isn't night
+        assert!(scheduler.update(now, 0.0).is_none());
+        let mut frames = 0;
+        while scheduler.update(now, 10.0).is_none() {
+            frames += 1;
+            assert!(frames < 200);
+        }
+        assert_eq!(scheduler.period(), Some(Period::Night));
+        // the night profile brightens things, but not enough to go back
+        for _ in 0..200 {
+            assert!(scheduler.update(now, 60.0).is_none());
+        }
+    }
+
+    #[test]
+    fn from_json() {
+        let schedule: Schedule = serde_json::from_str(
+            r#"{ "trigger": { "type": "clock", "night_start": "19:00:00", "day_start": "07:00:00" },
+                 "night": { "grayscale": true, "exposure_mode": 2 } }"#,
+        )
+        .unwrap();
+        assert!(matches!(schedule.trigger, Trigger::Clock { .. }));
+        assert!(schedule.night.grayscale);
+        assert!(!schedule.day.grayscale);
+
+        let schedule: Schedule =
+            serde_json::from_str(r#"{ "trigger": { "type": "sun", "latitude": 51.5, "longitude": -0.1 } }"#).unwrap();
+        assert!(matches!(schedule.trigger, Trigger::Sun { .. }));
+        assert!(serde_json::from_str::<Schedule>(r#"{ "trigger": { "type": "moon" } }"#).is_err());
+    }
+
+    #[test]
+    fn profile_only_changes_exposure() {
+        let settings = CameraSettings {
+            name: "porch".to_string(),
+            width: 1280,
+            iso: 200,
+            ..CameraSettings::default()
+        };
+        let night = CameraSettings {
+            iso: 800,
+            shutter_speed: 50_000,
+            grayscale: true,
+            ..CameraSettings::default()
+        };
+        let applied = settings.with_exposure(&night);
+        assert_eq!((applied.iso, applied.shutter_speed, applied.grayscale), (800, 50_000, true));
+        assert_eq!((applied.name.as_str(), applied.width), ("porch", 1280));
+    }
+}
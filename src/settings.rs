@@ -1,5 +1,8 @@
+use crate::exif::ExifSettings;
 use crate::ffi;
 
+use serde::{Deserialize, Serialize};
+
 use std::os::raw::c_uint;
 
 pub type ISO = u32;
@@ -22,6 +25,70 @@ pub const ISO_2000: ISO = 2000;
 pub const ISO_2500: ISO = 2500;
 pub const ISO_3200: ISO = 3200;
 
+pub type ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T;
+
+pub const EXPOSURE_OFF: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_OFF;
+pub const EXPOSURE_AUTO: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_AUTO;
+pub const EXPOSURE_NIGHT: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_NIGHT;
+pub const EXPOSURE_NIGHTPREVIEW: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_NIGHTPREVIEW;
+pub const EXPOSURE_BACKLIGHT: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_BACKLIGHT;
+pub const EXPOSURE_SPOTLIGHT: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_SPOTLIGHT;
+pub const EXPOSURE_SPORTS: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_SPORTS;
+pub const EXPOSURE_VERYLONG: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_VERYLONG;
+pub const EXPOSURE_FIXEDFPS: ExposureMode = ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_FIXEDFPS;
+
+pub type AwbMode = ffi::MMAL_PARAM_AWBMODE_T;
+
+pub const AWB_OFF: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_OFF;
+pub const AWB_AUTO: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_AUTO;
+pub const AWB_SUNLIGHT: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_SUNLIGHT;
+pub const AWB_CLOUDY: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_CLOUDY;
+pub const AWB_SHADE: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_SHADE;
+pub const AWB_TUNGSTEN: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_TUNGSTEN;
+pub const AWB_FLUORESCENT: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_FLUORESCENT;
+pub const AWB_INCANDESCENT: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_INCANDESCENT;
+pub const AWB_FLASH: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_FLASH;
+pub const AWB_HORIZON: AwbMode = ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_HORIZON;
+
+pub type Justify = u32;
+
+pub const JUSTIFY_CENTER: Justify = 0;
+pub const JUSTIFY_LEFT: Justify = 1;
+pub const JUSTIFY_RIGHT: Justify = 2;
+
+/// Text burned into the picture by the firmware, refreshed every second.
+///
+/// `text` is strftime-style, so "%Y-%m-%d %H:%M:%S" gives the date and time.
+/// `{name}` is replaced with the camera name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Annotation {
+    pub text: String,
+    /// 6 - 160, 0 = firmware default
+    pub text_size: u8,
+    /// Colors are packed YUV like raspistill: 0xVVUUYY. None = firmware default
+    pub text_color: Option<u32>,
+    /// Draw a box behind the text
+    pub background_color: Option<u32>,
+    pub justify: Justify,
+    pub x_offset: u32,
+    pub y_offset: u32,
+}
+
+impl Default for Annotation {
+    fn default() -> Self {
+        Annotation {
+            text: "{name} %Y-%m-%d %H:%M:%S".to_string(),
+            text_size: 0,
+            text_color: None,
+            background_color: None,
+            justify: JUSTIFY_LEFT,
+            x_offset: 0,
+            y_offset: 0,
+        }
+    }
+}
+
 // TODO: dedupe this
 fn fourcc(a: char, b: char, c: char, d: char) -> u32 {
     let a: u32 = a as u32;
@@ -50,12 +117,36 @@ fn fourcc(a: char, b: char, c: char, d: char) -> u32 {
 /// };
 /// camera.configure(settings);
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CameraSettings {
+    /// Which camera to open. Compute Module boards have two CSI ports: 0 and 1
+    pub camera_num: i32,
+    /// Used when naming events and files
+    pub name: String,
     pub encoding: c_uint,
     pub width: u32,  // 0 = max
     pub height: u32, // 0 = max
+    /// Video frames per second, which is also how often motion detection runs
+    pub framerate: u32,
+    /// H.264 bits per second
+    pub bitrate: u32,
+    /// Size of stills from the capture port. 0 = same as width/height
+    pub still_width: u32,
+    pub still_height: u32,
     pub iso: ISO,
+    /// In microseconds, 0 = auto
+    pub shutter_speed: u32,
+    pub exposure_mode: ExposureMode,
+    pub awb_mode: AwbMode,
+    /// Uses the color effects parameter to drop the U/V channels.
+    /// Handy at night when IR lighting makes color useless anyway.
+    pub grayscale: bool,
+    pub annotation: Option<Annotation>,
+    /// None turns EXIF off in the encoder
+    pub exif: Option<ExifSettings>,
+    /// Append the sensor's raw Bayer data to stills, see raw.rs
+    pub raw: bool,
     pub zero_copy: bool,
     /// `use_encoder` will go away
     pub use_encoder: bool,
@@ -64,12 +155,40 @@ pub struct CameraSettings {
 impl Default for CameraSettings {
     fn default() -> Self {
         CameraSettings {
+            camera_num: 0,
+            name: "camera0".to_string(),
             encoding: fourcc('J', 'P', 'E', 'G'),
             width: 0,
             height: 0,
+            framerate: 30,
+            bitrate: 4_000_000,
+            still_width: 0,
+            still_height: 0,
             iso: ISO_AUTO,
+            shutter_speed: 0,
+            exposure_mode: EXPOSURE_AUTO,
+            awb_mode: AWB_AUTO,
+            grayscale: false,
+            annotation: Some(Annotation::default()),
+            exif: Some(ExifSettings::default()),
+            raw: false,
             zero_copy: false,
             use_encoder: true,
         }
     }
+}
+
+impl CameraSettings {
+    /// These settings with the exposure related ones taken from `profile`:
+    /// ISO, shutter speed, exposure mode, AWB and grayscale
+    pub fn with_exposure(&self, profile: &CameraSettings) -> CameraSettings {
+        CameraSettings {
+            iso: profile.iso,
+            shutter_speed: profile.shutter_speed,
+            exposure_mode: profile.exposure_mode,
+            awb_mode: profile.awb_mode,
+            grayscale: profile.grayscale,
+            ..self.clone()
+        }
+    }
 }
\ No newline at end of file
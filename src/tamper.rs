@@ -0,0 +1,418 @@
+/*
+Tamper detection: the lens being covered or sprayed, the camera knocked out of
+focus, or turned to point somewhere else.
+
+Each frame's luma is boiled down to its brightness, contrast, sharpness and an
+8x8 grid of block averages, and compared against a slowly learned version of
+the same for the usual scene. Anything has to last `hold_frames` before it's
+reported, so someone walking right up to the lens doesn't count.
+
+Covering the lens and switching off the only light in a room look the same:
+dark and flat all of a sudden. Dusk is learned as it happens, so it doesn't
+trigger anything, but for cameras watching a lit room or porch where the
+lights go off at night, set `dark_is_covered` to false. Frames too dark to
+judge are then skipped instead.
+*/
+use crate::monitor::Change;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+// the background model is the mean luma of each block in a GRID x GRID grid
+const GRID: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tamper {
+    /// Lens covered or blacked out
+    Covered,
+    /// Sprayed, smeared or knocked out of focus
+    Defocused,
+    /// Pointed somewhere else
+    Moved,
+}
+
+/// A stretch of time where the camera looked tampered with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TamperEvent {
+    pub camera: String,
+    pub tamper: Tamper,
+    pub started: DateTime<Local>,
+    pub ended: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TamperSettings {
+    /// Frames darker than this, on average...
+    pub max_covered_luma: f32,
+    /// ...and this uniform (standard deviation of luma) are covered
+    pub max_covered_stddev: f32,
+    /// Whether a view that's usually lit going dark counts as covered.
+    /// Turn off where lights going out is normal.
+    pub dark_is_covered: bool,
+    /// Sharpness dropping below this fraction of what it usually is counts as defocused
+    pub min_sharpness_ratio: f32,
+    /// How well the picture has to match the usual scene, from -1 to 1, before it counts as moved
+    pub min_scene_match: f32,
+    /// Frames something has to last before it's reported, or before it's over.
+    /// People walking right past the lens shouldn't count.
+    pub hold_frames: u32,
+    /// Roughly how many frames the usual scene is averaged over. A camera that's
+    /// been moved on purpose is taken as its new view after this many frames.
+    pub learn_frames: u32,
+}
+
+impl Default for TamperSettings {
+    fn default() -> Self {
+        TamperSettings {
+            max_covered_luma: 25.0,
+            max_covered_stddev: 8.0,
+            dark_is_covered: true,
+            min_sharpness_ratio: 0.3,
+            min_scene_match: 0.5,
+            hold_frames: 75,
+            learn_frames: 3000,
+        }
+    }
+}
+
+/// What a frame looks like, for comparing against the usual scene
+struct Measures {
+    mean: f32,
+    stddev: f32,
+    /// Edges relative to contrast, so it doesn't change much with the lighting
+    sharpness: f32,
+    blocks: Vec<f32>,
+}
+
+/// Spots the camera being covered, defocused or moved, by comparing each frame
+/// against a slowly learned picture of the usual scene
+pub struct TamperWatch {
+    settings: TamperSettings,
+    camera: String,
+    width: usize,
+    height: usize,
+    frames: u32,
+    luma: f32,
+    sharpness: f32,
+    blocks: Vec<f32>,
+    /// What looks wrong now, and for how many frames in a row
+    suspect: Option<(Tamper, u32)>,
+    clear_frames: u32,
+    event: Option<TamperEvent>,
+    event_frames: u32,
+}
+
+impl TamperWatch {
+    pub fn new(settings: TamperSettings, camera: &str, width: u32, height: u32) -> TamperWatch {
+        TamperWatch {
+            settings: settings,
+            camera: camera.to_string(),
+            width: width as usize,
+            height: height as usize,
+            frames: 0,
+            luma: 0.0,
+            sharpness: 0.0,
+            blocks: vec![0.0; GRID * GRID],
+            suspect: None,
+            clear_frames: 0,
+            event: None,
+            event_frames: 0,
+        }
+    }
+
+    /// The tampering going on now, if any
+    pub fn event(&self) -> Option<&TamperEvent> {
+        self.event.as_ref()
+    }
+
+    /// Feed the luma plane of each frame. Returns a change when tampering
+    /// starts or stops.
+    pub fn frame(&mut self, luma: &[u8]) -> Option<Change> {
+        if self.width < 2 || self.height < 2 || luma.len() < self.width * self.height {
+            return None;
+        }
+        let measures = self.measure(luma);
+
+        // the first few frames only go towards the usual scene
+        if self.frames < self.settings.hold_frames {
+            self.frames += 1;
+            self.learn(&measures, 1.0 / self.frames as f32);
+            return None;
+        }
+
+        if !self.settings.dark_is_covered && self.dark(&measures) {
+            // nothing to go on, and nothing worth learning
+            return None;
+        }
+
+        match self.check(&measures) {
+            Some(tamper) => {
+                self.clear_frames = 0;
+                let count = match self.suspect {
+                    Some((suspect, count)) if suspect == tamper => count + 1,
+                    _ => 1,
+                };
+                self.suspect = Some((tamper, count));
+                if let Some(event) = self.event.as_ref() {
+                    self.event_frames += 1;
+                    if event.tamper == Tamper::Moved && self.event_frames >= self.settings.learn_frames {
+                        // it's staying put, so this is the view now
+                        self.relearn(&measures);
+                    }
+                } else if count >= self.settings.hold_frames {
+                    let event = TamperEvent {
+                        camera: self.camera.clone(),
+                        tamper: tamper,
+                        started: Local::now(),
+                        ended: None,
+                    };
+                    self.event = Some(event.clone());
+                    self.event_frames = 0;
+                    return Some(Change::TamperStarted(event));
+                }
+                None
+            }
+            None => {
+                self.suspect = None;
+                if self.event.is_some() {
+                    self.clear_frames += 1;
+                    if self.clear_frames >= self.settings.hold_frames {
+                        self.clear_frames = 0;
+                        let mut event = self.event.take().unwrap();
+                        event.ended = Some(Local::now());
+                        return Some(Change::TamperEnded(event));
+                    }
+                    return None;
+                }
+                self.learn(&measures, 1.0 / self.settings.learn_frames.max(1) as f32);
+                None
+            }
+        }
+    }
+
+    fn check(&self, measures: &Measures) -> Option<Tamper> {
+        let settings = &self.settings;
+        // a scene that's dark anyway, like at night, can't get covered
+        if self.dark(measures) && self.luma > settings.max_covered_luma * 2.0 {
+            return Some(Tamper::Covered);
+        }
+        if measures.sharpness < self.sharpness * settings.min_sharpness_ratio {
+            return Some(Tamper::Defocused);
+        }
+        if correlation(&measures.blocks, &self.blocks) < settings.min_scene_match {
+            return Some(Tamper::Moved);
+        }
+        None
+    }
+
+    fn dark(&self, measures: &Measures) -> bool {
+        measures.mean < self.settings.max_covered_luma && measures.stddev < self.settings.max_covered_stddev
+    }
+
+    /// Moves the usual scene `rate` of the way towards this frame
+    fn learn(&mut self, measures: &Measures, rate: f32) {
+        self.luma += (measures.mean - self.luma) * rate;
+        self.sharpness += (measures.sharpness - self.sharpness) * rate;
+        for (block, value) in self.blocks.iter_mut().zip(&measures.blocks) {
+            *block += (value - *block) * rate;
+        }
+    }
+
+    fn relearn(&mut self, measures: &Measures) {
+        self.luma = measures.mean;
+        self.sharpness = measures.sharpness;
+        self.blocks = measures.blocks.clone();
+        self.suspect = None;
+    }
+
+    fn measure(&self, luma: &[u8]) -> Measures {
+        let (width, height) = (self.width, self.height);
+        let mut sum = 0u64;
+        let mut squares = 0u64;
+        let mut gradient = 0u64;
+        let mut samples = 0u64;
+        let mut blocks = vec![0u64; GRID * GRID];
+        let mut block_samples = vec![0u64; GRID * GRID];
+
+        // every other pixel is plenty
+        for y in (0..height - 1).step_by(2) {
+            let row = &luma[y * width..(y + 1) * width];
+            let below = &luma[(y + 1) * width..(y + 2) * width];
+            let block_row = y * GRID / height * GRID;
+            for x in (0..width - 1).step_by(2) {
+                let p = row[x] as i32;
+                sum += p as u64;
+                squares += (p * p) as u64;
+                gradient += ((p - row[x + 1] as i32).abs() + (p - below[x] as i32).abs()) as u64;
+                samples += 1;
+                let block = block_row + x * GRID / width;
+                blocks[block] += p as u64;
+                block_samples[block] += 1;
+            }
+        }
+
+        let samples = samples.max(1) as f32;
+        let mean = sum as f32 / samples;
+        let stddev = (squares as f32 / samples - mean * mean).max(0.0).sqrt();
+        Measures {
+            mean: mean,
+            stddev: stddev,
+            sharpness: gradient as f32 / samples / stddev.max(1.0),
+            blocks: blocks.iter().zip(&block_samples).map(|(&b, &n)| b as f32 / n.max(1) as f32).collect(),
+        }
+    }
+}
+
+/// Pearson correlation, so a brighter or darker version of the same scene still matches
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len()) as f32;
+    if n == 0.0 {
+        return 0.0;
+    }
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a) * (x - mean_a);
+        variance_b += (y - mean_b) * (y - mean_b);
+    }
+    // a flat picture doesn't match anything, but nothing can be learned from a flat scene either
+    if variance_b < 1.0 {
+        return 1.0;
+    }
+    if variance_a < 1.0 {
+        return 0.0;
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 48;
+
+    fn settings() -> TamperSettings {
+        TamperSettings {
+            hold_frames: 5,
+            learn_frames: 50,
+            ..Default::default()
+        }
+    }
+
+    /// Bright on the left, dark on the right, with sharp stripes
+    fn scene() -> Vec<u8> {
+        let mut luma = vec![0u8; WIDTH * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let stripe = if (x / 3 + y / 3) % 2 == 0 { 40 } else { 0 };
+                luma[y * WIDTH + x] = (200 - x * 2) as u8 - stripe;
+            }
+        }
+        luma
+    }
+
+    fn mirrored(luma: &[u8]) -> Vec<u8> {
+        let mut out = luma.to_vec();
+        for row in out.chunks_mut(WIDTH) {
+            row.reverse();
+        }
+        out
+    }
+
+    /// The same brightness from left to right, without the stripes
+    fn blurred() -> Vec<u8> {
+        let mut luma = vec![0u8; WIDTH * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                luma[y * WIDTH + x] = (180 - x * 2) as u8;
+            }
+        }
+        luma
+    }
+
+    /// Feeds a frame `count` times and returns any changes
+    fn feed(watch: &mut TamperWatch, luma: &[u8], count: u32) -> Vec<Change> {
+        (0..count).filter_map(|_| watch.frame(luma)).collect()
+    }
+
+    fn started(changes: &[Change]) -> Option<Tamper> {
+        changes.iter().find_map(|c| match c {
+            Change::TamperStarted(event) => Some(event.tamper),
+            _ => None,
+        })
+    }
+
+    fn ended(changes: &[Change]) -> bool {
+        changes.iter().any(|c| matches!(c, Change::TamperEnded(_)))
+    }
+
+    fn learned(settings: TamperSettings) -> TamperWatch {
+        let mut watch = TamperWatch::new(settings, "test", WIDTH as u32, HEIGHT as u32);
+        assert!(feed(&mut watch, &scene(), 20).is_empty());
+        watch
+    }
+
+    #[test]
+    fn usual_scene_is_fine() {
+        let mut watch = learned(settings());
+        assert!(feed(&mut watch, &scene(), 100).is_empty());
+        assert!(watch.event().is_none());
+    }
+
+    #[test]
+    fn covered() {
+        let mut watch = learned(settings());
+        let black = vec![5u8; WIDTH * HEIGHT];
+        // too short to count
+        assert!(feed(&mut watch, &black, 3).is_empty());
+        assert!(feed(&mut watch, &scene(), 5).is_empty());
+
+        assert_eq!(started(&feed(&mut watch, &black, 10)), Some(Tamper::Covered));
+        assert_eq!(watch.event().map(|e| e.tamper), Some(Tamper::Covered));
+        assert!(ended(&feed(&mut watch, &scene(), 10)));
+        assert!(watch.event().is_none());
+    }
+
+    #[test]
+    fn lights_off_when_dark_isnt_covered() {
+        let mut watch = learned(TamperSettings {
+            dark_is_covered: false,
+            ..settings()
+        });
+        let black = vec![5u8; WIDTH * HEIGHT];
+        assert!(feed(&mut watch, &black, 100).is_empty());
+        assert!(feed(&mut watch, &scene(), 10).is_empty());
+    }
+
+    #[test]
+    fn defocused() {
+        let mut watch = learned(settings());
+        assert_eq!(started(&feed(&mut watch, &blurred(), 10)), Some(Tamper::Defocused));
+    }
+
+    #[test]
+    fn moved_then_relearned() {
+        let mut watch = learned(settings());
+        let elsewhere = mirrored(&scene());
+        assert_eq!(started(&feed(&mut watch, &elsewhere, 10)), Some(Tamper::Moved));
+        // left pointing the new way, it becomes the usual scene
+        assert!(ended(&feed(&mut watch, &elsewhere, 100)));
+        assert!(feed(&mut watch, &elsewhere, 20).is_empty());
+    }
+
+    #[test]
+    fn correlation_ignores_brightness() {
+        let a = [10.0, 50.0, 90.0, 20.0];
+        let brighter: Vec<f32> = a.iter().map(|v| v * 1.5 + 30.0).collect();
+        assert!((correlation(&brighter, &a) - 1.0).abs() < 0.001);
+        let inverted: Vec<f32> = a.iter().map(|v| 100.0 - v).collect();
+        assert!(correlation(&inverted, &a) < 0.0);
+    }
+}
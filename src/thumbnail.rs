@@ -0,0 +1,227 @@
+use crate::encryption::{self, Encryption};
+use crate::event::MotionEvent;
+use crate::exif::{self, Exif};
+use crate::i420::Layout;
+
+use chrono::{DateTime, Local};
+use jpeg_encoder::{ColorType, Encoder};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailSettings {
+    /// Width of thumbnails, height follows the frame's aspect ratio
+    pub width: u32,
+    /// Also make an animated GIF strip out of this many keyframes
+    pub keyframes: Option<u32>,
+    /// Frames between keyframes
+    pub keyframe_interval: u32,
+}
+
+impl Default for ThumbnailSettings {
+    fn default() -> Self {
+        ThumbnailSettings {
+            width: 160,
+            keyframes: Some(10),
+            keyframe_interval: 15,
+        }
+    }
+}
+
+/// Collects frames during an event, keeping the one with the highest motion
+/// score for the thumbnail and every so often one for the preview strip.
+///
+/// Frames are small (motion detection size), so keeping copies is cheap.
+pub struct Thumbnailer {
+    settings: ThumbnailSettings,
+    width: u32,
+    height: u32,
+    /// The highest scoring frame so far, when it was seen, scaled to RGB
+    best: Option<(f32, DateTime<Local>, Vec<u8>)>,
+    keyframes: Vec<Vec<u8>>,
+    frames: u32,
+}
+
+impl Thumbnailer {
+    /// `width` and `height` are the size of the I420 frames we'll be given
+    pub fn new(settings: ThumbnailSettings, width: u32, height: u32) -> Thumbnailer {
+        Thumbnailer {
+            settings: settings,
+            width: width,
+            height: height,
+            best: None,
+            keyframes: Vec::new(),
+            frames: 0,
+        }
+    }
+
+    /// Call for every frame during an event, with its motion score if it had any
+    pub fn frame(&mut self, frame: &[u8], score: Option<f32>) {
+        if let Some(score) = score {
+            let better = match &self.best {
+                Some((best, _, _)) => score > *best,
+                None => true,
+            };
+            if better {
+                self.best = Some((score, Local::now(), self.scale(frame)));
+            }
+        }
+
+        if let Some(keyframes) = self.settings.keyframes {
+            let interval = self.settings.keyframe_interval.max(1);
+            if self.frames % interval == 0 && (self.keyframes.len() as u32) < keyframes {
+                let scaled = self.scale(frame);
+                self.keyframes.push(scaled);
+            }
+        }
+        self.frames += 1;
+    }
+
+    /// Writes the thumbnail and preview next to the event's other files,
+    /// records them on the event, and gets ready for the next one. The
+    /// thumbnail gets `exif`, timed for the frame it came from.
+    pub fn finish(&mut self, event: &mut MotionEvent, dir: &Path, encryption: Option<&Encryption>, exif: Option<Exif>) -> io::Result<()> {
+        let (width, height) = self.size();
+        let best = self.best.take();
+        let keyframes = std::mem::take(&mut self.keyframes);
+        self.frames = 0;
+
+        if let Some((_, time, rgb)) = best {
+            let path = dir.join(format!("{}-thumb.jpg", event.name()));
+            let mut jpeg = Vec::new();
+            Encoder::new(&mut jpeg, 80)
+                .encode(&rgb, width as u16, height as u16, ColorType::Rgb)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            if let Some(mut exif) = exif {
+                exif.time = time;
+                jpeg = exif::insert_into_jpeg(&jpeg, &exif)?;
+            }
+            event.thumbnail = Some(encryption::write(encryption, &path, &jpeg)?);
+        }
+
+        if keyframes.len() > 1 {
+            let path = dir.join(format!("{}-preview.gif", event.name()));
+            let mut gif = Vec::new();
+            let to_io = |e: gif::EncodingError| io::Error::new(io::ErrorKind::Other, e.to_string());
+            let mut encoder = gif::Encoder::new(&mut gif, width as u16, height as u16, &[]).map_err(to_io)?;
+            encoder.set_repeat(gif::Repeat::Infinite).map_err(to_io)?;
+            for rgb in &keyframes {
+                let mut frame = gif::Frame::from_rgb_speed(width as u16, height as u16, rgb, 10);
+                // hundredths of a second
+                frame.delay = 50;
+                encoder.write_frame(&frame).map_err(to_io)?;
+            }
+            drop(encoder);
+            event.preview = Some(encryption::write(encryption, &path, &gif)?);
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> (u32, u32) {
+        let width = self.settings.width.min(self.width).max(1);
+        let height = (self.height * width / self.width.max(1)).max(1);
+        (width, height)
+    }
+
+    /// Nearest neighbor scale of an I420 frame down to thumbnail size, as RGB.
+    /// Falls back to grayscale if we only got the Y plane.
+    fn scale(&self, frame: &[u8]) -> Vec<u8> {
+        let (width, height) = self.size();
+        let layout = Layout::new(self.width, self.height);
+
+        let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+        for ty in 0..height {
+            let y = (ty * self.height / height) as usize;
+            for tx in 0..width {
+                let x = (tx * self.width / width) as usize;
+                rgb.extend(layout.rgb(frame, x, y).iter().map(|&value| clamp(value)));
+            }
+        }
+        rgb
+    }
+}
+
+fn clamp(value: f32) -> u8 {
+    value.clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // 32x16 frames, all one shade
+    fn frame(luma: u8) -> Vec<u8> {
+        Layout::new(32, 16).frame(&[luma; 32 * 16])
+    }
+
+    #[test]
+    fn keeps_the_best_frame() {
+        let settings = ThumbnailSettings {
+            width: 16,
+            keyframes: None,
+            ..ThumbnailSettings::default()
+        };
+        let mut thumbnailer = Thumbnailer::new(settings, 32, 16);
+        assert_eq!(thumbnailer.size(), (16, 8));
+        thumbnailer.frame(&frame(50), Some(0.2));
+        thumbnailer.frame(&frame(200), Some(0.9));
+        thumbnailer.frame(&frame(100), Some(0.5));
+        // quiet frames never win
+        thumbnailer.frame(&frame(250), None);
+        let (score, _, rgb) = thumbnailer.best.as_ref().unwrap();
+        assert_eq!(*score, 0.9);
+        assert_eq!(rgb.len(), 16 * 8 * 3);
+        assert!(rgb.iter().all(|&value| value == 200));
+        assert!(thumbnailer.keyframes.is_empty());
+
+        let dir = std::env::temp_dir().join(format!("thumbnail-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut event = MotionEvent::new("porch");
+        thumbnailer.finish(&mut event, &dir, None, None).unwrap();
+        let thumbnail = event.thumbnail.clone().unwrap();
+        assert_eq!(thumbnail, dir.join(format!("{}-thumb.jpg", event.name())));
+        assert_eq!(&fs::read(&thumbnail).unwrap()[..2], &[0xFF, 0xD8]);
+        assert!(event.preview.is_none());
+        assert!(thumbnailer.best.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn samples_keyframes_for_the_preview() {
+        let settings = ThumbnailSettings {
+            width: 16,
+            keyframes: Some(3),
+            keyframe_interval: 2,
+        };
+        let mut thumbnailer = Thumbnailer::new(settings, 32, 16);
+        for i in 0..10 {
+            thumbnailer.frame(&frame(i * 20), None);
+        }
+        // every other frame from the start, until there are enough
+        let shades: Vec<u8> = thumbnailer.keyframes.iter().map(|rgb| rgb[0]).collect();
+        assert_eq!(shades, vec![0, 40, 80]);
+
+        let dir = std::env::temp_dir().join(format!("preview-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut event = MotionEvent::new("porch");
+        thumbnailer.finish(&mut event, &dir, None, None).unwrap();
+        assert!(event.thumbnail.is_none());
+        let preview = fs::File::open(event.preview.as_ref().unwrap()).unwrap();
+        let mut decoder = gif::DecodeOptions::new().read_info(preview).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (16, 8));
+        let mut frames = 0;
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, 50);
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+
+        // the next event starts sampling afresh
+        thumbnailer.frame(&frame(240), None);
+        assert_eq!(thumbnailer.keyframes.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+/*
+Just enough TIFF to write EXIF blocks and DNGs. Everything is big endian ("MM").
+*/
+
+pub const BYTE: u16 = 1;
+pub const ASCII: u16 = 2;
+pub const SHORT: u16 = 3;
+pub const LONG: u16 = 4;
+pub const RATIONAL: u16 = 5;
+pub const UNDEFINED: u16 = 7;
+pub const SRATIONAL: u16 = 10;
+
+pub struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+    /// Already big endian
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    pub fn ascii(tag: u16, value: &str) -> Entry {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        Entry { tag: tag, kind: ASCII, count: data.len() as u32, data: data }
+    }
+
+    pub fn bytes(tag: u16, values: &[u8]) -> Entry {
+        Entry { tag: tag, kind: BYTE, count: values.len() as u32, data: values.to_vec() }
+    }
+
+    pub fn undefined(tag: u16, values: &[u8]) -> Entry {
+        Entry { tag: tag, kind: UNDEFINED, count: values.len() as u32, data: values.to_vec() }
+    }
+
+    pub fn short(tag: u16, value: u16) -> Entry {
+        Entry::shorts(tag, &[value])
+    }
+
+    pub fn shorts(tag: u16, values: &[u16]) -> Entry {
+        let mut data = Vec::new();
+        for value in values {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        Entry { tag: tag, kind: SHORT, count: values.len() as u32, data: data }
+    }
+
+    pub fn long(tag: u16, value: u32) -> Entry {
+        Entry { tag: tag, kind: LONG, count: 1, data: value.to_be_bytes().to_vec() }
+    }
+
+    pub fn rationals(tag: u16, values: &[(u32, u32)]) -> Entry {
+        let mut data = Vec::new();
+        for (num, den) in values {
+            data.extend_from_slice(&num.to_be_bytes());
+            data.extend_from_slice(&den.to_be_bytes());
+        }
+        Entry { tag: tag, kind: RATIONAL, count: values.len() as u32, data: data }
+    }
+
+    pub fn srationals(tag: u16, values: &[(i32, i32)]) -> Entry {
+        let mut data = Vec::new();
+        for (num, den) in values {
+            data.extend_from_slice(&num.to_be_bytes());
+            data.extend_from_slice(&den.to_be_bytes());
+        }
+        Entry { tag: tag, kind: SRATIONAL, count: values.len() as u32, data: data }
+    }
+}
+
+/// TIFF header pointing at an IFD right after it
+pub fn header() -> Vec<u8> {
+    vec![b'M', b'M', 0, 42, 0, 0, 0, 8]
+}
+
+/// Bytes an IFD takes up, including values too big to fit in the entry itself
+pub fn ifd_size(entries: &[Entry]) -> usize {
+    let mut size = 2 + entries.len() * 12 + 4;
+    for entry in entries {
+        if entry.data.len() > 4 {
+            size += entry.data.len() + entry.data.len() % 2;
+        }
+    }
+    size
+}
+
+/// Writes the IFD at the end of `out`, which must start with the TIFF header.
+/// Entries have to be sorted by tag.
+pub fn write_ifd(out: &mut Vec<u8>, entries: &[Entry]) {
+    let start = out.len();
+    let data_start = start + 2 + entries.len() * 12 + 4;
+    let mut data = Vec::new();
+
+    out.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    for entry in entries {
+        out.extend_from_slice(&entry.tag.to_be_bytes());
+        out.extend_from_slice(&entry.kind.to_be_bytes());
+        out.extend_from_slice(&entry.count.to_be_bytes());
+        if entry.data.len() <= 4 {
+            let mut value = entry.data.clone();
+            value.resize(4, 0);
+            out.extend_from_slice(&value);
+        } else {
+            out.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            data.extend_from_slice(&entry.data);
+            if entry.data.len() % 2 == 1 {
+                data.push(0);
+            }
+        }
+    }
+    // no next IFD
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// The IFD at `offset` in `tiff`: tag, type, count and the value's bytes,
+/// wherever they are
+#[cfg(test)]
+pub fn read_ifd(tiff: &[u8], offset: usize) -> Vec<(u16, u16, u32, Vec<u8>)> {
+    let u16_at = |i: usize| u16::from_be_bytes([tiff[i], tiff[i + 1]]);
+    let u32_at = |i: usize| u32::from_be_bytes([tiff[i], tiff[i + 1], tiff[i + 2], tiff[i + 3]]);
+    let mut entries = Vec::new();
+    for n in 0..u16_at(offset) as usize {
+        let at = offset + 2 + n * 12;
+        let (tag, kind, count) = (u16_at(at), u16_at(at + 2), u32_at(at + 4));
+        let size = match kind {
+            SHORT => 2,
+            LONG => 4,
+            RATIONAL | SRATIONAL => 8,
+            _ => 1,
+        } * count as usize;
+        let value = if size <= 4 { at + 8 } else { u32_at(at + 8) as usize };
+        entries.push((tag, kind, count, tiff[value..value + size].to_vec()));
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ifds() {
+        let first = vec![Entry::short(0x0100, 640), Entry::ascii(0x010F, "Pi"), Entry::ascii(0x0110, "camera")];
+        let second = vec![Entry::rationals(0x829A, &[(1, 100)]), Entry::srationals(0x9204, &[(-1, 3)])];
+        let mut out = header();
+        write_ifd(&mut out, &first);
+        // "camera\0" is 7 bytes, padded to keep the next IFD on a word boundary
+        assert_eq!(out.len(), 8 + ifd_size(&first));
+        assert_eq!(out.len() % 2, 0);
+        let second_offset = out.len();
+        write_ifd(&mut out, &second);
+        assert_eq!(out.len(), second_offset + ifd_size(&second));
+
+        assert_eq!(&out[..8], b"MM\0\x2a\0\0\0\x08");
+        assert_eq!(
+            read_ifd(&out, 8),
+            vec![
+                (0x0100, SHORT, 1, vec![2, 128]),
+                (0x010F, ASCII, 3, b"Pi\0".to_vec()),
+                (0x0110, ASCII, 7, b"camera\0".to_vec()),
+            ]
+        );
+        assert_eq!(
+            read_ifd(&out, second_offset),
+            vec![
+                (0x829A, RATIONAL, 1, vec![0, 0, 0, 1, 0, 0, 0, 100]),
+                (0x9204, SRATIONAL, 1, vec![255, 255, 255, 255, 0, 0, 0, 3]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+/*
+TLS for the HTTP and RTSP servers, turned on by tls.json:
+
+{ "cert": "tls/cert.pem", "key": "tls/key.pem", "generate": true }
+
+Those are the defaults, so {} will do. If the certificate and key aren't there
+and generate is on, a self-signed pair gets made and saved, so it's the same one
+after a restart and browsers only have to be told to trust it once. Without
+tls.json the servers speak plain HTTP and RTSP.
+*/
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key, PKCS#8 or RSA
+    pub key: PathBuf,
+    /// Make a self-signed certificate if there isn't one
+    pub generate: bool,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            cert: PathBuf::from("tls").join("cert.pem"),
+            key: PathBuf::from("tls").join("key.pem"),
+            generate: true,
+        }
+    }
+}
+
+/// The certificate chain and private key, as PEM
+pub fn load(settings: &TlsSettings) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if settings.generate && !settings.cert.exists() && !settings.key.exists() {
+        generate(settings)?;
+    }
+    let cert = fs::read(&settings.cert).map_err(|e| format!("{}: {}", settings.cert.display(), e))?;
+    let key = fs::read(&settings.key).map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+    Ok((cert, key))
+}
+
+/// rustls config for servers doing TLS themselves, like RTSP
+pub fn server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, String> {
+    let (cert, key) = load(settings)?;
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut cert.as_slice())
+        .map_err(|e| format!("{}: {}", settings.cert.display(), e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates", settings.cert.display()));
+    }
+
+    let mut reader = key.as_slice();
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| format!("{}: {}", settings.key.display(), e))? {
+            Some(rustls_pemfile::Item::PKCS8Key(key)) | Some(rustls_pemfile::Item::RSAKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(format!("{}: no private key", settings.key.display())),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("{}: {}", settings.cert.display(), e))?;
+    Ok(Arc::new(config))
+}
+
+/// Self-signed certificate for this Pi's hostname
+fn generate(settings: &TlsSettings) -> Result<(), String> {
+    let mut names = vec!["localhost".to_string()];
+    if let Ok(hostname) = fs::read_to_string("/etc/hostname") {
+        let hostname = hostname.trim();
+        if !hostname.is_empty() {
+            names.push(hostname.to_string());
+            names.push(format!("{}.local", hostname));
+        }
+    }
+    let cert = rcgen::generate_simple_self_signed(names).map_err(|e| e.to_string())?;
+    let cert_pem = cert.serialize_pem().map_err(|e| e.to_string())?;
+    let key_pem = cert.serialize_private_key_pem();
+
+    for path in [&settings.cert, &settings.key].iter() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+        }
+    }
+    // nobody else on the Pi has any business reading the key
+    let mut key = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&settings.key)
+        .map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+    key.write_all(key_pem.as_bytes()).map_err(|e| format!("{}: {}", settings.key.display(), e))?;
+    fs::write(&settings.cert, cert_pem).map_err(|e| format!("{}: {}", settings.cert.display(), e))?;
+    println!("tls: made a self-signed certificate, {}", settings.cert.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_json_and_generated_once() {
+        let dir = std::env::temp_dir().join(format!("tls-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let defaults: TlsSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(defaults.cert, TlsSettings::default().cert);
+        assert!(defaults.generate);
+
+        let json = serde_json::json!({ "cert": dir.join("cert.pem"), "key": dir.join("key.pem"), "generate": false });
+        let mut settings: TlsSettings = serde_json::from_value(json).unwrap();
+        assert!(load(&settings).is_err());
+
+        settings.generate = true;
+        let (cert, key) = load(&settings).unwrap();
+        assert!(String::from_utf8(cert.clone()).unwrap().starts_with("-----BEGIN CERTIFICATE-----"));
+        assert_eq!(load(&settings).unwrap(), (cert, key));
+        assert!(server_config(&settings).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,473 @@
+/*
+Follows blobs of motion from frame to frame, so each moving thing gets an ID,
+a velocity and the path it took.
+
+That path is what tells a person walking up the drive from a branch swaying
+in the wind or a patch of flickering light. Both of those change pixels, but
+neither goes anywhere. With `min_travel` or `rules` set, motion only starts an
+event once some object has moved far enough, or been through zones in the
+right order:
+
+{ "camera0": {
+    "min_travel": 40,
+    "zones": [
+      { "name": "street", "x": 0, "y": 160, "width": 320, "height": 80 },
+      { "name": "drive", "x": 100, "y": 60, "width": 120, "height": 100 } ],
+    "rules": [["street", "drive"]] } }
+
+goes in tracking.json, per camera, with positions in motion detection pixels.
+That rule is "came in off the street and up the drive", so cars going past
+don't count. Without tracking.json, or for cameras not in it, objects are
+still followed and end up in the event metadata, they just don't hold events
+off.
+*/
+use crate::config;
+use crate::motion::{Blob, BoundingBox};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Plenty for a minute or so at motion detection rates, after which the rest
+// of the path isn't kept
+const MAX_PATH_POINTS: usize = 2000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackingSettings {
+    /// Blobs with fewer changed pixels than this aren't followed
+    pub min_area: u32,
+    /// Furthest an object can get from where it was expected, in pixels, and still be the same object
+    pub max_jump: f32,
+    /// Frames an object can go unseen, standing still or behind something, before it's forgotten
+    pub max_missed: u32,
+    /// Frames an object has to be seen on before it counts. Flickering light rarely lasts.
+    pub min_frames: u32,
+    /// Pixels an object has to get from where it was first seen before it counts.
+    /// Swaying branches go back and forth without getting far.
+    pub min_travel: f32,
+    pub zones: Vec<Zone>,
+    /// Each a list of zone names an object has to enter in that order, not
+    /// necessarily one straight after another. An object counts if it follows
+    /// any of them.
+    pub rules: Vec<Vec<String>>,
+}
+
+impl TrackingSettings {
+    /// Checks every zone a rule names exists
+    pub fn check(&self) -> Result<(), String> {
+        for rule in &self.rules {
+            if rule.is_empty() {
+                return Err("Empty rule".to_string());
+            }
+            for name in rule {
+                if !self.zones.iter().any(|zone| &zone.name == name) {
+                    return Err(format!("Rule names zone \"{}\", which isn't in zones", name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for TrackingSettings {
+    fn default() -> Self {
+        TrackingSettings {
+            min_area: 30,
+            max_jump: 40.0,
+            max_missed: 15,
+            min_frames: 3,
+            min_travel: 0.0,
+            zones: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// Part of the frame, in motion detection pixels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Zone {
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x as f32 && y >= self.y as f32 && x < self.x.saturating_add(self.width) as f32 && y < self.y.saturating_add(self.height) as f32
+    }
+}
+
+/// Tracking settings by camera name, none if there's no file. Rules naming a
+/// zone that isn't there could never be followed, so they're an error rather
+/// than a camera that quietly never records.
+pub fn load(path: &Path) -> Result<HashMap<String, TrackingSettings>, String> {
+    let cameras: HashMap<String, TrackingSettings> = config::load_json(path)?.unwrap_or_default();
+    for (camera, settings) in &cameras {
+        settings.check().map_err(|e| format!("{}: {}: {}", path.display(), camera, e))?;
+    }
+    Ok(cameras)
+}
+
+/// Where an object was on one frame
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PathPoint {
+    pub time: DateTime<Local>,
+    pub x: f32,
+    pub y: f32,
+    pub bounding_box: BoundingBox,
+}
+
+/// Something that moved, and where it went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedObject {
+    /// Counts up from 1 for each camera, until it restarts
+    pub id: u64,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    /// Furthest it got from where it was first seen, in pixels
+    pub travel: f32,
+    /// Pixels per second, the last time it was seen
+    pub velocity: [f32; 2],
+    /// Zones it entered, in order
+    pub zones: Vec<String>,
+    pub path: Vec<PathPoint>,
+}
+
+struct Track {
+    object: TrackedObject,
+    /// Pixels per frame, for guessing where it'll be next
+    step: [f32; 2],
+    frames: u32,
+    missed: u32,
+    /// Zones its middle is in now
+    inside: Vec<usize>,
+    /// Once an object counts it keeps counting, even if it stops
+    counts: bool,
+}
+
+impl Track {
+    fn position(&self) -> (f32, f32) {
+        let last = self.object.path.last().unwrap();
+        (last.x, last.y)
+    }
+}
+
+/// Matches up each frame's blobs with the objects seen on the frames before
+pub struct Tracker {
+    settings: TrackingSettings,
+    tracks: Vec<Track>,
+    next_id: u64,
+}
+
+impl Tracker {
+    pub fn new(settings: TrackingSettings) -> Tracker {
+        Tracker {
+            settings: settings,
+            tracks: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// True if motion has to come from an object that counts before it starts an event
+    pub fn gates(&self) -> bool {
+        self.settings.min_travel > 0.0 || !self.settings.rules.is_empty()
+    }
+
+    /// True if something being followed now has moved the way the settings want
+    pub fn counting(&self) -> bool {
+        self.tracks.iter().any(|t| t.counts)
+    }
+
+    /// Objects being followed now that count
+    pub fn objects(&self) -> Vec<TrackedObject> {
+        self.tracks.iter().filter(|t| t.counts).map(|t| t.object.clone()).collect()
+    }
+
+    /// Feed the blobs from every frame, none when there wasn't motion.
+    /// Returns the objects that counted and have now gone.
+    pub fn update(&mut self, blobs: &[Blob]) -> Vec<TrackedObject> {
+        let now = Local::now();
+        let blobs: Vec<&Blob> = blobs.iter().filter(|b| b.pixels >= self.settings.min_area).collect();
+
+        // closest pairs first, each track and blob used once
+        let mut pairs = Vec::new();
+        for (t, track) in self.tracks.iter().enumerate() {
+            let (x, y) = track.position();
+            let frames = (track.missed + 1) as f32;
+            let expected_x = x + track.step[0] * frames;
+            let expected_y = y + track.step[1] * frames;
+            for (b, blob) in blobs.iter().enumerate() {
+                let distance = (blob.center_x - expected_x).hypot(blob.center_y - expected_y);
+                if distance <= self.settings.max_jump {
+                    pairs.push((distance, t, b));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut matched_tracks = vec![false; self.tracks.len()];
+        let mut matched_blobs = vec![false; blobs.len()];
+        for (_, t, b) in pairs {
+            if matched_tracks[t] || matched_blobs[b] {
+                continue;
+            }
+            matched_tracks[t] = true;
+            matched_blobs[b] = true;
+            self.follow(t, blobs[b], now);
+        }
+
+        let mut gone = Vec::new();
+        let mut t = 0;
+        for matched in matched_tracks {
+            if !matched {
+                self.tracks[t].missed += 1;
+                if self.tracks[t].missed > self.settings.max_missed {
+                    let track = self.tracks.remove(t);
+                    if track.counts {
+                        gone.push(track.object);
+                    }
+                    continue;
+                }
+            }
+            t += 1;
+        }
+
+        for (blob, matched) in blobs.iter().zip(matched_blobs) {
+            if !matched {
+                self.start(blob, now);
+            }
+        }
+        gone
+    }
+
+    fn start(&mut self, blob: &Blob, now: DateTime<Local>) {
+        let mut track = Track {
+            object: TrackedObject {
+                id: self.next_id,
+                first_seen: now,
+                last_seen: now,
+                travel: 0.0,
+                velocity: [0.0, 0.0],
+                zones: Vec::new(),
+                path: vec![point(blob, now)],
+            },
+            step: [0.0, 0.0],
+            frames: 1,
+            missed: 0,
+            inside: Vec::new(),
+            counts: false,
+        };
+        self.next_id += 1;
+        enter_zones(&self.settings, &mut track, blob);
+        track.counts = counts(&self.settings, &track);
+        self.tracks.push(track);
+    }
+
+    fn follow(&mut self, t: usize, blob: &Blob, now: DateTime<Local>) {
+        let track = &mut self.tracks[t];
+        let (x, y) = track.position();
+        let frames = (track.missed + 1) as f32;
+        let step = [(blob.center_x - x) / frames, (blob.center_y - y) / frames];
+        track.step = if track.frames == 1 {
+            step
+        } else {
+            // smoothed, since the middle of a blob wobbles about
+            [track.step[0] * 0.6 + step[0] * 0.4, track.step[1] * 0.6 + step[1] * 0.4]
+        };
+
+        let object = &mut track.object;
+        let seconds = (now - object.last_seen).num_milliseconds() as f32 / 1000.0;
+        if seconds > 0.0 {
+            object.velocity = [(blob.center_x - x) / seconds, (blob.center_y - y) / seconds];
+        }
+        object.last_seen = now;
+        let first = object.path[0];
+        object.travel = object.travel.max((blob.center_x - first.x).hypot(blob.center_y - first.y));
+        if object.path.len() < MAX_PATH_POINTS {
+            object.path.push(point(blob, now));
+        }
+        track.frames += 1;
+        track.missed = 0;
+
+        enter_zones(&self.settings, track, blob);
+        if !track.counts {
+            track.counts = counts(&self.settings, track);
+        }
+    }
+}
+
+/// Notes down zones the blob's middle has just gone into
+fn enter_zones(settings: &TrackingSettings, track: &mut Track, blob: &Blob) {
+    let inside: Vec<usize> = (0..settings.zones.len())
+        .filter(|&z| settings.zones[z].contains(blob.center_x, blob.center_y))
+        .collect();
+    for &z in &inside {
+        if !track.inside.contains(&z) {
+            track.object.zones.push(settings.zones[z].name.clone());
+        }
+    }
+    track.inside = inside;
+}
+
+fn counts(settings: &TrackingSettings, track: &Track) -> bool {
+    if track.frames < settings.min_frames || track.object.travel < settings.min_travel {
+        return false;
+    }
+    settings.rules.is_empty() || settings.rules.iter().any(|rule| follows(&track.object.zones, rule))
+}
+
+/// Whether `zones` has the zones in `rule` in it, in order
+fn follows(zones: &[String], rule: &[String]) -> bool {
+    let mut zones = zones.iter();
+    rule.iter().all(|wanted| zones.any(|zone| zone == wanted))
+}
+
+fn point(blob: &Blob, time: DateTime<Local>) -> PathPoint {
+    PathPoint {
+        time: time,
+        x: blob.center_x,
+        y: blob.center_y,
+        bounding_box: blob.bounding_box,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(x: f32, y: f32) -> Blob {
+        Blob {
+            bounding_box: BoundingBox {
+                x: x as u32 - 10,
+                y: y as u32 - 10,
+                width: 20,
+                height: 20,
+            },
+            center_x: x,
+            center_y: y,
+            pixels: 400,
+        }
+    }
+
+    fn zone(name: &str, x: u32, width: u32) -> Zone {
+        Zone {
+            name: name.to_string(),
+            x: x,
+            y: 0,
+            width: width,
+            height: 240,
+        }
+    }
+
+    fn street_then_drive() -> TrackingSettings {
+        TrackingSettings {
+            min_travel: 40.0,
+            zones: vec![zone("street", 0, 100), zone("drive", 200, 120)],
+            rules: vec![vec!["street".to_string(), "drive".to_string()]],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn follows_rule_in_order() {
+        let mut tracker = Tracker::new(street_then_drive());
+        assert!(tracker.gates());
+        for i in 0..70 {
+            tracker.update(&[blob(20.0 + i as f32 * 4.0, 50.0)]);
+        }
+        assert!(tracker.counting());
+        let objects = tracker.objects();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].zones, vec!["street", "drive"]);
+        assert!(objects[0].travel > 200.0);
+        assert!(objects[0].velocity[0] >= 0.0);
+
+        let mut gone = Vec::new();
+        for _ in 0..20 {
+            gone.extend(tracker.update(&[]));
+        }
+        assert_eq!(gone.len(), 1);
+        assert_eq!(gone[0].id, 1);
+        assert!(!tracker.counting());
+    }
+
+    #[test]
+    fn wrong_order_doesnt_count() {
+        let mut tracker = Tracker::new(street_then_drive());
+        for i in 0..70 {
+            tracker.update(&[blob(300.0 - i as f32 * 4.0, 50.0)]);
+        }
+        assert!(!tracker.counting());
+    }
+
+    #[test]
+    fn swaying_doesnt_count() {
+        let mut tracker = Tracker::new(TrackingSettings {
+            min_travel: 40.0,
+            ..Default::default()
+        });
+        for i in 0..70 {
+            let sway = if i % 4 < 2 { 0.0 } else { 6.0 };
+            tracker.update(&[blob(150.0 + sway, 180.0)]);
+        }
+        assert!(!tracker.counting());
+        assert_eq!(tracker.tracks.len(), 1);
+    }
+
+    #[test]
+    fn separate_objects() {
+        let mut tracker = Tracker::new(TrackingSettings::default());
+        for i in 0..10 {
+            let x = i as f32 * 4.0;
+            tracker.update(&[blob(20.0 + x, 50.0), blob(300.0 - x, 200.0)]);
+        }
+        let mut ids: Vec<u64> = tracker.objects().iter().map(|o| o.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn small_blobs_ignored() {
+        let mut tracker = Tracker::new(TrackingSettings::default());
+        let mut small = blob(50.0, 50.0);
+        small.pixels = 5;
+        for _ in 0..10 {
+            tracker.update(&[small]);
+        }
+        assert!(tracker.tracks.is_empty());
+    }
+
+    #[test]
+    fn rule_order() {
+        let zones = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<String>>();
+        assert!(follows(&zones(&["street", "lawn", "drive"]), &zones(&["street", "drive"])));
+        assert!(!follows(&zones(&["drive", "street"]), &zones(&["street", "drive"])));
+        assert!(!follows(&zones(&["street"]), &zones(&["street", "drive"])));
+    }
+
+    #[test]
+    fn zone_to_end_of_range() {
+        let zone = Zone {
+            name: "all".to_string(),
+            x: 10,
+            y: 0,
+            width: u32::MAX,
+            height: u32::MAX,
+        };
+        assert!(!zone.contains(5.0, 5.0));
+        assert!(zone.contains(20.0, 5.0));
+    }
+
+    #[test]
+    fn rules_need_zones() {
+        let mut settings = street_then_drive();
+        assert!(settings.check().is_ok());
+        settings.rules.push(vec!["street".to_string(), "garage".to_string()]);
+        assert!(settings.check().unwrap_err().contains("garage"));
+        settings.rules = vec![vec![]];
+        assert!(settings.check().is_err());
+    }
+}
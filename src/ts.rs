@@ -0,0 +1,255 @@
+/*
+Just enough MPEG-TS muxing for HLS: one H.264 stream, PAT and PMT at the start
+of every segment, a PCR on every frame. No B-frames from the Pi's encoder, so
+PTS is all we need.
+*/
+use crate::h264::{self, AccessUnit};
+
+const PACKET_SIZE: usize = 188;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+// H.264 stream type
+const STREAM_TYPE: u8 = 0x1B;
+// keeps the first PTS away from zero, some players don't like that
+const PTS_OFFSET: u64 = 90000;
+
+pub struct Muxer {
+    pat_counter: u8,
+    pmt_counter: u8,
+    video_counter: u8,
+}
+
+impl Default for Muxer {
+    fn default() -> Self {
+        Muxer::new()
+    }
+}
+
+impl Muxer {
+    pub fn new() -> Muxer {
+        Muxer {
+            pat_counter: 0,
+            pmt_counter: 0,
+            video_counter: 0,
+        }
+    }
+
+    /// PAT and PMT, which every segment has to start with
+    pub fn tables(&mut self, out: &mut Vec<u8>) {
+        let pat = [
+            0x00, // table id
+            0xB0, 0x0D, // section length 13
+            0x00, 0x01, // transport stream id
+            0xC1, 0x00, 0x00, // version 0, current, section numbers
+            0x00, 0x01, // program 1
+            0xE0 | (PMT_PID >> 8) as u8, PMT_PID as u8,
+        ];
+        let counter = next(&mut self.pat_counter);
+        write_section(out, 0, counter, &pat);
+
+        let pmt = [
+            0x02, // table id
+            0xB0, 0x12, // section length 18
+            0x00, 0x01, // program 1
+            0xC1, 0x00, 0x00,
+            0xE0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, // PCR pid
+            0xF0, 0x00, // no program info
+            STREAM_TYPE,
+            0xE0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8,
+            0xF0, 0x00, // no stream info
+        ];
+        let counter = next(&mut self.pmt_counter);
+        write_section(out, PMT_PID, counter, &pmt);
+    }
+
+    /// One access unit as a PES packet, with `extra` NAL units (SPS and PPS) first
+    pub fn write(&mut self, out: &mut Vec<u8>, unit: &AccessUnit, extra: &[Vec<u8>]) {
+        let pts = unit.timestamp.as_micros() as u64 * 9 / 100 + PTS_OFFSET;
+
+        let mut pes = vec![0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05];
+        pes.extend_from_slice(&timestamp(0x20, pts));
+        // access unit delimiter first, HLS players expect one
+        pes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x09, 0xF0]);
+        for nal in extra.iter().chain(unit.nals.iter()) {
+            if h264::nal_type(nal) == 9 {
+                continue;
+            }
+            pes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            pes.extend_from_slice(nal);
+        }
+
+        let mut rest = &pes[..];
+        let mut first = true;
+        while !rest.is_empty() {
+            let mut packet = Vec::with_capacity(PACKET_SIZE);
+            packet.push(0x47);
+            packet.push(if first { 0x40 } else { 0x00 } | (VIDEO_PID >> 8) as u8);
+            packet.push(VIDEO_PID as u8);
+
+            // adaptation field carries the PCR on the first packet and pads the last one
+            let mut adaptation = None;
+            if first {
+                let mut field = vec![if unit.keyframe { 0x50 } else { 0x10 }]; // random access, PCR
+                field.extend_from_slice(&pcr(pts));
+                adaptation = Some(field);
+            }
+            let used = adaptation.as_ref().map(|a: &Vec<u8>| 1 + a.len()).unwrap_or(0);
+            if rest.len() < PACKET_SIZE - 4 - used {
+                let mut stuffing = PACKET_SIZE - 4 - used - rest.len();
+                if adaptation.is_none() {
+                    // the length byte takes one
+                    stuffing -= 1;
+                    adaptation = Some(Vec::new());
+                }
+                let field = adaptation.as_mut().unwrap();
+                if field.is_empty() && stuffing > 0 {
+                    field.push(0x00); // no flags
+                    stuffing -= 1;
+                }
+                field.resize(field.len() + stuffing, 0xFF);
+            }
+            let counter = next(&mut self.video_counter);
+            match &adaptation {
+                Some(field) => {
+                    packet.push(0x30 | counter);
+                    packet.push(field.len() as u8);
+                    packet.extend_from_slice(field);
+                }
+                None => packet.push(0x10 | counter),
+            }
+
+            let take = (PACKET_SIZE - packet.len()).min(rest.len());
+            packet.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            out.extend_from_slice(&packet);
+            first = false;
+        }
+    }
+}
+
+fn next(counter: &mut u8) -> u8 {
+    let value = *counter;
+    *counter = (*counter + 1) & 0x0F;
+    value
+}
+
+fn write_section(out: &mut Vec<u8>, pid: u16, counter: u8, section: &[u8]) {
+    let start = out.len();
+    out.extend_from_slice(&[0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | counter, 0x00]);
+    out.extend_from_slice(section);
+    out.extend_from_slice(&crc32(section).to_be_bytes());
+    out.resize(start + PACKET_SIZE, 0xFF);
+}
+
+/// 5 byte PTS with the given 4 bit prefix
+fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        prefix | (((ts >> 30) & 0x07) << 1) as u8 | 1,
+        (ts >> 22) as u8,
+        (((ts >> 15) & 0x7F) << 1) as u8 | 1,
+        (ts >> 7) as u8,
+        ((ts & 0x7F) << 1) as u8 | 1,
+    ]
+}
+
+/// 6 byte PCR, base only
+fn pcr(base: u64) -> [u8; 6] {
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base & 1) << 7) as u8 | 0x7E,
+        0x00,
+    ]
+}
+
+/// CRC-32/MPEG-2
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pid(packet: &[u8]) -> u16 {
+        ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16
+    }
+
+    /// The section in a table packet, up to and including its CRC
+    fn section(packet: &[u8]) -> &[u8] {
+        let length = (((packet[6] as usize) & 0x0F) << 8) | packet[7] as usize;
+        &packet[5..8 + length]
+    }
+
+    #[test]
+    fn pat_and_pmt() {
+        let mut out = Vec::new();
+        Muxer::new().tables(&mut out);
+        assert_eq!(out.len(), 2 * PACKET_SIZE);
+        let (pat, pmt) = out.split_at(PACKET_SIZE);
+
+        assert_eq!(pat[0], 0x47);
+        assert_eq!(pid(pat), 0);
+        let table = section(pat);
+        // the CRC of a section including its own CRC comes out as zero
+        assert_eq!(crc32(table), 0);
+        // program 1 is in the PMT
+        assert_eq!(&table[8..10], &[0x00, 0x01]);
+        assert_eq!(((table[10] as u16 & 0x1F) << 8) | table[11] as u16, PMT_PID);
+
+        assert_eq!(pid(pmt), PMT_PID);
+        let table = section(pmt);
+        assert_eq!(crc32(table), 0);
+        assert_eq!(table[12], STREAM_TYPE);
+        assert_eq!(((table[13] as u16 & 0x1F) << 8) | table[14] as u16, VIDEO_PID);
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0x0376E6E7);
+    }
+
+    #[test]
+    fn video_packets() {
+        let mut muxer = Muxer::new();
+        let mut nal = vec![0x65];
+        nal.resize(1000, 0xAB);
+        let unit = AccessUnit {
+            timestamp: Duration::from_secs(1),
+            nals: vec![nal],
+            keyframe: true,
+        };
+        let mut out = Vec::new();
+        muxer.write(&mut out, &unit, &[vec![0x67, 0x42], vec![0x68, 0xce]]);
+        assert_eq!(out.len() % PACKET_SIZE, 0);
+
+        let packets: Vec<&[u8]> = out.chunks(PACKET_SIZE).collect();
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet[0], 0x47);
+            assert_eq!(pid(packet), VIDEO_PID);
+            assert_eq!(packet[1] & 0x40 != 0, i == 0, "payload start only on the first");
+            assert_eq!(packet[3] & 0x0F, i as u8 & 0x0F);
+        }
+
+        // first packet: adaptation field with random access and PCR, then the PES header
+        let first = packets[0];
+        assert_eq!(first[5], 0x50);
+        let pes = &first[5 + first[4] as usize..];
+        assert_eq!(&pes[..4], &[0x00, 0x00, 0x01, 0xE0]);
+        let pts = PTS_OFFSET + 90000;
+        assert_eq!(&pes[9..14], &timestamp(0x20, pts));
+        // access unit delimiter, then SPS
+        assert_eq!(&pes[14..24], &[0, 0, 0, 1, 0x09, 0xF0, 0, 0, 0, 1]);
+        assert_eq!(pes[24], 0x67);
+    }
+}
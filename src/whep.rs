@@ -0,0 +1,313 @@
+/*
+WebRTC live view with WHEP signaling, for sub-second latency on the LAN.
+
+POST   /whep/<camera>        SDP offer in, SDP answer out (201, Location header)
+DELETE /whep/<camera>/<id>   hang up, with the login that made the offer or an admin's
+
+The encoder's H.264 goes out as-is, webrtc packetizes it, nothing is re-encoded.
+ICE candidates are all in the answer (no trickle), which is fine on a LAN.
+
+webrtc-rs is async, so it gets its own tokio runtime here and the rest of the
+program stays on plain threads.
+*/
+use crate::h264;
+use crate::live::Live;
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+use webrtc::api::APIBuilder;
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+struct Session {
+    connection: Arc<RTCPeerConnection>,
+    running: Arc<AtomicBool>,
+    /// Authorization header the offer came with
+    owner: Option<String>,
+}
+
+pub struct Whep {
+    runtime: Runtime,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Whep {
+    pub fn new() -> Result<Whep, String> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("whep")
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Unable to start WebRTC runtime: {}", e))?;
+        Ok(Whep {
+            runtime: runtime,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Answers an offer and starts sending video. Returns the session id and the answer SDP.
+    /// `authorization` is kept so only the same login can hang up.
+    pub fn offer(&self, camera: Arc<Live>, offer: String, authorization: Option<&str>) -> Result<(String, String), String> {
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_string(),
+                ..Default::default()
+            },
+            "video".to_string(),
+            camera.name.clone(),
+        ));
+
+        let (connection, answer) = self.runtime.block_on(answer(track.clone(), offer)).map_err(|e| e.to_string())?;
+
+        // random, so nobody can hang up sessions by counting
+        let id = format!("{:032x}", rand::random::<u128>());
+        let running = Arc::new(AtomicBool::new(true));
+        {
+            let running = running.clone();
+            // weak, or the connection would keep itself alive through its own handler
+            let failed = Arc::downgrade(&connection);
+            connection.on_peer_connection_state_change(Box::new(move |state| {
+                if state == RTCPeerConnectionState::Failed || state == RTCPeerConnectionState::Closed {
+                    running.store(false, Ordering::SeqCst);
+                }
+                if state == RTCPeerConnectionState::Failed {
+                    if let Some(connection) = failed.upgrade() {
+                        // closing changes the state again, and this handler's
+                        // lock is held until it returns, so not from in here
+                        tokio::spawn(async move {
+                            let _ = connection.close().await;
+                        });
+                    }
+                }
+                Box::pin(async {})
+            }));
+        }
+
+        let handle = self.runtime.handle().clone();
+        let sending = running.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("{}-whep-{}", camera.name, id))
+            .spawn(move || {
+                let units = camera.video.subscribe();
+                let mut previous: Option<Duration> = None;
+                while sending.load(Ordering::SeqCst) {
+                    let unit = match units.recv_timeout(Duration::from_secs(1)) {
+                        Ok(unit) => unit,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    // webrtc wants Annex B, with parameter sets ahead of keyframes
+                    let mut data = Vec::new();
+                    if unit.keyframe && !unit.has(h264::NAL_SPS) {
+                        if let Some((sps, pps)) = camera.video.parameter_sets() {
+                            for nal in [sps, pps].iter() {
+                                data.extend_from_slice(&[0, 0, 0, 1]);
+                                data.extend_from_slice(nal);
+                            }
+                        }
+                    }
+                    for nal in &unit.nals {
+                        data.extend_from_slice(&[0, 0, 0, 1]);
+                        data.extend_from_slice(nal);
+                    }
+                    let duration = previous.map(|p| unit.timestamp.saturating_sub(p)).unwrap_or(Duration::from_millis(33));
+                    previous = Some(unit.timestamp);
+                    let sample = Sample {
+                        data: Bytes::from(data),
+                        duration: duration,
+                        ..Default::default()
+                    };
+                    if handle.block_on(track.write_sample(&sample)).is_err() {
+                        break;
+                    }
+                }
+                sending.store(false, Ordering::SeqCst);
+            });
+        if let Err(e) = thread {
+            let _ = self.runtime.block_on(connection.close());
+            return Err(e.to_string());
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        // forget sessions that ended without a DELETE, closing any that haven't been
+        let ended: Vec<String> = sessions.iter().filter(|(_, s)| !s.running.load(Ordering::SeqCst)).map(|(id, _)| id.clone()).collect();
+        for id in ended {
+            if let Some(session) = sessions.remove(&id) {
+                self.runtime.spawn(async move {
+                    let _ = session.connection.close().await;
+                });
+            }
+        }
+        sessions.insert(
+            id.clone(),
+            Session {
+                connection: connection,
+                running: running,
+                owner: authorization.map(|a| a.to_string()),
+            },
+        );
+        Ok((id, answer))
+    }
+
+    /// Whether the session was started with this Authorization header, None
+    /// if there's no such session
+    pub fn started_by(&self, id: &str, authorization: Option<&str>) -> Option<bool> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id).map(|session| session.owner.as_deref() == authorization)
+    }
+
+    /// Hangs up a session. False if there was no such session.
+    pub fn delete(&self, id: &str) -> bool {
+        let session = match self.sessions.lock().unwrap().remove(id) {
+            Some(session) => session,
+            None => return false,
+        };
+        session.running.store(false, Ordering::SeqCst);
+        let _ = self.runtime.block_on(session.connection.close());
+        true
+    }
+}
+
+async fn answer(track: Arc<TrackLocalStaticSample>, offer: String) -> Result<(Arc<RTCPeerConnection>, String), webrtc::Error> {
+    let mut media = MediaEngine::default();
+    media.register_default_codecs()?;
+    let registry = register_default_interceptors(Registry::new(), &mut media)?;
+    let api = APIBuilder::new().with_media_engine(media).with_interceptor_registry(registry).build();
+
+    let connection = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+    match negotiate(&connection, track, offer).await {
+        Ok(sdp) => Ok((connection, sdp)),
+        Err(e) => {
+            // a connection that's never closed keeps its tasks and sockets
+            let _ = connection.close().await;
+            Err(e)
+        }
+    }
+}
+
+async fn negotiate(connection: &RTCPeerConnection, track: Arc<TrackLocalStaticSample>, offer: String) -> Result<String, webrtc::Error> {
+    let sender = connection.add_track(track as Arc<dyn TrackLocal + Send + Sync>).await?;
+    // RTCP has to be read for the interceptors (NACKs and so on) to work
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 1500];
+        while sender.read(&mut buffer).await.is_ok() {}
+    });
+
+    connection.set_remote_description(RTCSessionDescription::offer(offer)?).await?;
+    let answer = connection.create_answer(None).await?;
+    let mut gathered = connection.gathering_complete_promise().await;
+    connection.set_local_description(answer).await?;
+    let _ = gathered.recv().await;
+
+    match connection.local_description().await {
+        Some(description) => Ok(description.sdp),
+        None => Err(webrtc::Error::new("No local description".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::AccessUnit;
+    use std::sync::mpsc;
+    use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+    use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+    use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+    /// A browser-like peer that only receives. Returns it, its offer, and the
+    /// RTP payloads it gets.
+    async fn viewer() -> Result<(Arc<RTCPeerConnection>, String, mpsc::Receiver<Vec<u8>>), webrtc::Error> {
+        let mut media = MediaEngine::default();
+        media.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media)?;
+        let api = APIBuilder::new().with_media_engine(media).with_interceptor_registry(registry).build();
+        let connection = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+        connection
+            .add_transceiver_from_kind(
+                RTPCodecType::Video,
+                &[RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: vec![],
+                }],
+            )
+            .await?;
+
+        let (sender, payloads) = mpsc::channel();
+        let sender = Arc::new(std::sync::Mutex::new(sender));
+        connection.on_track(Box::new(move |track, _| {
+            let sender = sender.clone();
+            Box::pin(async move {
+                if let Some(track) = track {
+                    tokio::spawn(async move {
+                        while let Ok((packet, _)) = track.read_rtp().await {
+                            if sender.lock().unwrap().send(packet.payload.to_vec()).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            })
+        }));
+
+        let offer = connection.create_offer(None).await?;
+        let mut gathered = connection.gathering_complete_promise().await;
+        connection.set_local_description(offer).await?;
+        let _ = gathered.recv().await;
+        let sdp = connection.local_description().await.map(|d| d.sdp).unwrap_or_default();
+        Ok((connection, sdp, payloads))
+    }
+
+    #[test]
+    fn sends_video_to_viewer() {
+        let whep = Whep::new().unwrap();
+        let camera = Live::new("porch", 5);
+        let (viewer, offer, payloads) = whep.runtime.block_on(viewer()).unwrap();
+
+        let (id, answer) = whep.offer(camera.clone(), offer, Some("Bearer viewer")).unwrap();
+        assert!(answer.contains("H264"));
+        whep.runtime
+            .block_on(viewer.set_remote_description(RTCSessionDescription::answer(answer).unwrap()))
+            .unwrap();
+
+        let mut payload = None;
+        for i in 0..200 {
+            camera.video.publish(AccessUnit::keyframe(i * 40));
+            if let Ok(p) = payloads.recv_timeout(Duration::from_millis(50)) {
+                payload = Some(p);
+                break;
+            }
+        }
+        let payload = payload.expect("no video reached the viewer");
+        // parameter sets go out bundled (STAP-A), or the IDR on its own
+        assert!([24, h264::NAL_SPS, h264::NAL_IDR].contains(&(payload[0] & 0x1F)));
+
+        assert_eq!(whep.started_by(&id, Some("Bearer viewer")), Some(true));
+        assert_eq!(whep.started_by(&id, Some("Bearer someone-else")), Some(false));
+        assert_eq!(whep.started_by(&id, None), Some(false));
+        assert!(whep.delete(&id));
+        assert_eq!(whep.started_by(&id, Some("Bearer viewer")), None);
+        assert!(!whep.delete(&id));
+        let _ = whep.runtime.block_on(viewer.close());
+    }
+
+    #[test]
+    fn bad_offer() {
+        let whep = Whep::new().unwrap();
+        assert!(whep.offer(Live::new("porch", 5), "not sdp".to_string(), None).is_err());
+        assert!(whep.sessions.lock().unwrap().is_empty());
+    }
+}